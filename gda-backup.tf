@@ -86,7 +86,8 @@ resource "aws_dynamodb_table" "gda_backup_table" {
   billing_mode = "PAY_PER_REQUEST"
   hash_key     = "hash"
 
-  # gda_backup assumes that the hash attribute is named "hash".
+  # gda_backup assumes that the hash attribute is named "hash", unless
+  # DYNAMO_PARTITION_KEY is set.
   attribute {
     name = "hash"
     type = "S"
@@ -99,8 +100,9 @@ resource "aws_dynamodb_table" "gda_backup_table" {
 # capable of.
 # If you would rather only allow gda_backup to backup your files, grant only
 # these permissions:
-# s3:DeleteObject, s3:PutObject, s3:RestoreObject, dynamodb:DeleteItem, 
-# dynamodb:GetItem, dynamodb:PutItem
+# s3:DeleteObject, s3:PutObject, s3:RestoreObject, dynamodb:BatchGetItem,
+# dynamodb:BatchWriteItem, dynamodb:DeleteItem, dynamodb:GetItem,
+# dynamodb:PutItem, dynamodb:UpdateItem
 data "aws_iam_policy_document" "gda_backup_policy" {
   statement {
     effect = "Allow"
@@ -118,13 +120,32 @@ data "aws_iam_policy_document" "gda_backup_policy" {
       "${aws_s3_bucket.gda_backup_bucket.arn}/*",
     ]
   }
+  # Only needed to upload objects with OBJECT_LOCK_MODE or
+  # OBJECT_LOCK_LEGAL_HOLD, and to read locks with "lock status".
   statement {
     effect = "Allow"
     actions = [
+      "s3:GetBucketObjectLockConfiguration",
+      "s3:GetObjectLegalHold",
+      "s3:GetObjectRetention",
+      "s3:PutObjectLegalHold",
+      "s3:PutObjectRetention",
+    ]
+    resources = [
+      aws_s3_bucket.gda_backup_bucket.arn,
+      "${aws_s3_bucket.gda_backup_bucket.arn}/*",
+    ]
+  }
+  statement {
+    effect = "Allow"
+    actions = [
+      "dynamodb:BatchGetItem",
+      "dynamodb:BatchWriteItem",
       "dynamodb:DeleteItem",
       "dynamodb:GetItem",
       "dynamodb:PutItem",
       "dynamodb:Scan",
+      "dynamodb:UpdateItem",
     ]
     resources = [
       aws_dynamodb_table.gda_backup_table.arn,
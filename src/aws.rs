@@ -1,12 +1,69 @@
+use std::time::Duration;
+
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::retry::RetryConfig;
 use aws_config::BehaviorVersion;
+use aws_smithy_runtime_api::client::result::SdkError;
+use aws_smithy_runtime_api::http::Response;
+use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+
+use crate::environment::RetryArgs;
+
+// Error codes returned by S3 and DynamoDB when a request is throttled or fails temporarily
+const TRANSIENT_ERROR_CODES: [&str; 13] = [
+    "InternalError",
+    "InternalServerError",
+    "LimitExceededException",
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "RequestThrottled",
+    "RequestTimeout",
+    "ServiceUnavailable",
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "TooManyRequestsException",
+    "TransactionInProgressException",
+];
 
 /// The function `get_config` asynchronously retrieves the AWS SDK configuration
 /// with a default region provider set to "us-east-1".
-pub async fn get_config() -> aws_config::SdkConfig {
+/// 
+/// Arguments:
+/// 
+/// * `retry`: How requests failing with throttling or transient errors are
+/// retried.
+pub async fn get_config(retry: &RetryArgs) -> aws_config::SdkConfig {
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    let retry_config = RetryConfig::standard()
+        .with_retry_mode(retry.aws_retry_mode)
+        .with_max_attempts(retry.aws_max_attempts)
+        .with_initial_backoff(Duration::from_millis(retry.aws_initial_backoff_ms))
+        .with_max_backoff(Duration::from_secs(retry.aws_max_backoff));
+
     aws_config::defaults(BehaviorVersion::latest())
         .region(region_provider)
+        .retry_config(retry_config)
         .load()
         .await
-}
\ No newline at end of file
+}
+
+/// The function `is_transient` classifies a failed AWS request as transient,
+/// meaning that the same request may succeed if it is sent again later.
+/// 
+/// Arguments:
+/// 
+/// * `error`: The error returned by the request, after the SDK's own retries.
+/// 
+/// Returns:
+/// 
+/// `true` for timeouts, connection failures, throttling and server errors, and
+/// `false` for errors which would fail again, such as missing permissions.
+pub fn is_transient<E: ProvideErrorMetadata>(error: &SdkError<E, Response>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(context) => context.raw().status().is_server_error() ||
+            context.err().code().is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code)),
+        _ => false,
+    }
+}
@@ -1,29 +1,43 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
 use std::path::Path;
+use std::sync::LazyLock;
+use std::time::SystemTime;
 use log::{debug, error, info};
 use walkdir::WalkDir;
 
-use crate::dynamodb::HashTracker;
-use crate::environment::{BackupArgs, Cli};
+use crate::dynamodb::{namespaced, HashTracker};
+use crate::environment::{AwsArgs, BackupArgs, Cli, StorageClasses};
+use crate::error::GdaError;
+use crate::index::{self, IndexEntry, IndexReader, IndexWriter};
+use crate::lock::{LeaseStatus, LockError};
+use crate::metrics::METRICS;
 use crate::models::{GlacierFile, LocalFile};
+use crate::plan::{Plan, PlanAction, PlannedFile, PlannedHash, Planner};
+use crate::progress::{self, Progress, Unit};
+use crate::report::{self, FileAction, FileReport};
+use crate::retention::{RetainedFile, RetainedKind};
+use crate::safety::{self, ChangeCounter, ChangeStats};
 
-use crate::s3;
+use crate::storage::{MetadataStore, ObjectStore, StorageError};
 
-use aws_sdk_s3::Client as S3Client;
-use aws_sdk_dynamodb::Client as DynamoClient;
 use chrono::{
     DateTime, Duration, Utc
 };
-use diesel::prelude::PgConnection;
+use diesel::prelude::*;
 
 use crate::{
-    get_glacier_file,
+    count_glacier_files,
     get_changed_files,
+    get_glacier_file,
     get_missing_files,
     get_new_files,
 };
 
-use checksums::hash_file;
+use checksums::{hash_file, hash_reader, Algorithm};
 use regex::Regex;
 
 // Use BLAKE2B if running on 64 bit CPU
@@ -34,6 +48,17 @@ use checksums::Algorithm::BLAKE2B as HASH_ALGO;
 #[cfg(not(target_pointer_width = "64"))]
 use checksums::Algorithm::BLAKE2S as HASH_ALGO;
 
+// Files inserted into the local database per statement, within Postgres' limit of 65535 bound parameters
+const INSERT_BATCH_SIZE: usize = 10_000;
+// Changes read from the local database and backed up at a time
+const PAGE_SIZE: i64 = 10_000;
+
+// Hashes of an empty file, from hosts of either pointer width. Empty files are never uploaded to S3.
+static EMPTY_HASHES: LazyLock<[String; 2]> = LazyLock::new(|| [
+    hash_reader(&mut io::empty(), Algorithm::BLAKE2B),
+    hash_reader(&mut io::empty(), Algorithm::BLAKE2S),
+]);
+
 /// The `FileChange` struct in Rust represents a change in a GlacierFile with an
 /// optional old hash value.
 /// 
@@ -68,12 +93,15 @@ struct FileChange {
 /// * `deleted_files`: The `deleted_files` property in the `HashTrackerChange`
 /// struct is a vector of `GlacierFile` instances representing the files that were
 /// deleted in the change.
+/// * `locked`: The lock of an object which was deleted while Object Lock
+/// protected it, described for reports.
 #[derive(Clone, Debug)]
 struct HashTrackerChange {
     new: HashTracker,
     old: HashTracker,
     created_files: Vec<GlacierFile>,
     deleted_files: Vec<GlacierFile>,
+    locked: Option<String>,
 }
 
 /// The above Rust code defines an implementation for the `HashTrackerChange`
@@ -85,10 +113,79 @@ impl HashTrackerChange {
     fn changed(&self) -> bool {
         self.new != self.old
     }
+
+    /// The function `object_action` decides what publishing the change does
+    /// to the hash's object.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `hash`: The hash being changed.
+    fn object_action(&self, hash: &str) -> ObjectAction {
+        // Empty files have no S3 object, only a hash tracker
+        if is_empty_hash(hash) {
+            ObjectAction::None
+        }
+        else if !self.old.has_files() && self.old.is_expired() {
+            if self.new.has_files() { ObjectAction::Upload } else { ObjectAction::None }
+        }
+        else if !self.old.has_files() && self.new.has_files() {
+            ObjectAction::Undelete
+        }
+        else if self.old.has_files() && !self.new.has_files() {
+            ObjectAction::Delete
+        }
+        else {
+            ObjectAction::None
+        }
+    }
+}
+
+/// What publishing a `HashTrackerChange` does to the hash's object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ObjectAction {
+    Upload,
+    Undelete,
+    Delete,
+    None,
+}
+
+/// The `Retry` struct holds a change which failed with a transient error, to
+/// be published again once every other new and changed file was.
+struct Retry {
+    hash: String,
+    hash_tracker_change: HashTrackerChange,
+    // Deleted files of the change which still exist locally, and so must not be removed
+    existing_g_files: HashSet<String>,
+}
+
+/// The `SavedState` trait records the files of published changes, so that
+/// the next backup only backs up what changed since.
+trait SavedState {
+
+    /// Records that a file was backed up.
+    fn save(&mut self, g_file: &GlacierFile) -> Result<(), GdaError>;
+
+    /// Records that a file no longer exists.
+    fn remove(&mut self, g_file: &GlacierFile) -> Result<(), GdaError>;
+}
+
+/// The local database records files in the `glacier_state` table.
+impl SavedState for PgConnection {
+    fn save(&mut self, g_file: &GlacierFile) -> Result<(), GdaError> {
+        g_file.insert(self)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, g_file: &GlacierFile) -> Result<(), GdaError> {
+        g_file.delete(self)?;
+        Ok(())
+    }
 }
 
 /// The function `load` iterates through files in a directory, extracts metadata,
-/// and inserts file information into a database.
+/// and inserts file information into a database. Files are inserted in
+/// batches within a single transaction, so that loading millions of files
+/// does not take millions of round trips.
 /// 
 /// Arguments:
 /// 
@@ -100,46 +197,119 @@ impl HashTrackerChange {
 /// a `PgConnection`, which is a connection to a PostgreSQL database. This parameter
 /// allows the function to interact with the database to load data from the local
 /// file system into the database.
-pub fn load(args: BackupArgs, conn: &mut PgConnection) {
+/// 
+/// Returns:
+/// 
+/// `GdaError::ConfigError` if a filter is not a valid regex, or the error
+/// encountered reading a file's modified time. No file is loaded if an error
+/// is returned.
+pub fn load(args: BackupArgs, conn: &mut PgConnection) -> Result<(), GdaError> {
+    let scanning = Progress::start("Scanning", Unit::Files, None);
+    let files = scan(&args, Some(scanning.clone()))?;
+
     // Load local_state into database
-    for file in WalkDir::new(args.target_dir.clone()).into_iter().filter_map(|e: Result<walkdir::DirEntry, walkdir::Error>| e.ok()) {
+    conn.transaction::<_, GdaError, _>(|conn| {
+        let mut batch: Vec<LocalFile> = Vec::with_capacity(INSERT_BATCH_SIZE);
 
-        let Ok(metadata) = file.metadata() else {continue };
+        for file in files {
+            let (l_file, _) = file?;
+            batch.push(l_file);
 
-        if metadata.is_file() {
+            if batch.len() == INSERT_BATCH_SIZE {
+                insert_batch(conn, &mut batch);
+            }
+        }
 
-            let file_path = file.path().display().to_string();
-            
-            let mut filtered = false;
-            let mut i = 0;
-    
-            while !filtered && i < args.filter.len() {
-                filtered = Regex::new(&args.filter[i]).unwrap()
-                    .is_match(&file_path);
-                i += 1;
+        insert_batch(conn, &mut batch);
+
+        Ok(())
+    })?;
+
+    scanning.finish();
+
+    Ok(())
+}
+
+/// The function `scan` walks the target directory of a backup, visiting
+/// files in the order of `index::path_cmp` and skipping filtered files.
+/// 
+/// Arguments:
+/// 
+/// * `args`: The target directory and filters of the backup.
+/// * `scanning`: The progress of the scanning stage, which also counts the
+/// scanned files in the metrics. `None` for a walk which only checks changes.
+/// 
+/// Returns:
+/// 
+/// Each file with its size, or the error encountered reading its modified
+/// time. `GdaError::ConfigError` if a filter is not a valid regex.
+fn scan(args: &BackupArgs, scanning: Option<Progress>) -> Result<impl Iterator<Item = io::Result<(LocalFile, u64)>>, GdaError> {
+    let filters = args.filter.iter()
+        .map(|filter| Regex::new(filter).map_err(|error| GdaError::ConfigError(format!("Invalid filter {filter}: {error}"))))
+        .collect::<Result<Vec<Regex>, GdaError>>()?;
+
+    let files = WalkDir::new(args.target_dir.clone())
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e: Result<walkdir::DirEntry, walkdir::Error>| e.ok())
+        .filter_map(move |file| {
+            let metadata = file.metadata().ok().filter(|metadata| metadata.is_file())?;
+
+            if let Some(scanning) = &scanning {
+                METRICS.files_scanned.inc();
+                scanning.inc(1);
             }
 
-            if filtered {
+            let file_path = file.path().display().to_string();
+
+            if filters.iter().any(|filter| filter.is_match(&file_path)) {
                 debug!("File filtered out of tracked files: {file_path}");
-                continue;
+                return None;
             }
-    
-            let result = LocalFile {
-                file_path,
-                modified: metadata.modified().expect("Error: OS does not support modified time metadata.")
-            }.insert(conn);
-
-            match result {
-                Ok(_) => (),
-                Err(error) => error!("Failed to load file into local database: {:?}\n Error: {:?}", file, error),
+
+            Some(metadata.modified().map(|modified| (LocalFile { file_path, modified }, metadata.len())))
+        });
+
+    Ok(files)
+}
+
+/// The function `insert_batch` inserts a batch of files into the local
+/// database and empties it. If the batch fails, its files are inserted one at
+/// a time, so that only the files which fail are left out.
+/// 
+/// Arguments:
+/// 
+/// * `conn`: The connection to the local database, within a transaction.
+/// Each insert runs in a savepoint, so that a failure does not abort the
+/// transaction.
+/// * `batch`: The files to insert.
+fn insert_batch(conn: &mut PgConnection, batch: &mut Vec<LocalFile>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    if let Err(error) = conn.transaction(|conn| LocalFile::insert_many(conn, batch)) {
+        debug!("Failed to load batch of {} files into local database, loading them one at a time: {:?}", batch.len(), error);
+
+        for file in batch.iter() {
+            if let Err(error) = conn.transaction(|conn| file.insert(conn)) {
+                error!("Failed to load file into local database: {:?}\n Error: {:?}", file, error);
             }
         }
     }
+
+    batch.clear();
 }
 
 /// The `backup` function in Rust asynchronously manages file backups by tracking
 /// changes, updating databases, and interacting with S3 and DynamoDB services.
 /// 
+/// Changes are read from the local database and backed up a page at a time,
+/// so that memory use does not grow with the number of changed files. New and
+/// changed files, and the retries of those which failed with a transient
+/// error, are backed up before missing files, so that the object of a file
+/// which was moved is not deleted before its new path is recorded.
+/// 
 /// Arguments:
 /// 
 /// * `args`: The `args` parameter in the `backup` function seems to be a struct or
@@ -152,70 +322,935 @@ pub fn load(args: BackupArgs, conn: &mut PgConnection) {
 /// This connection is used to interact with the database to perform operations like
 /// querying for files, updating records, and deleting entries during the backup
 /// process.
-/// * `s3_client`: The `s3_client` parameter in the `backup` function is a reference
-/// to an S3 client that is used to interact with an Amazon Simple Storage Service
-/// (S3) bucket. This client is responsible for performing operations such as
-/// uploading files to S3, deleting files from S3, and
-/// * `dynamo_client`: The `dynamo_client` parameter in the `backup` function is an
-/// instance of the `DynamoClient` struct, which is used to interact with DynamoDB
-/// for storing and retrieving data related to hash trackers. This client is
-/// responsible for performing operations such as updating hash trackers in DynamoDB
-/// and retrieving
-pub async fn backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, s3_client: &S3Client, dynamo_client: &DynamoClient) -> (usize, usize) {
+/// * `objects`: The object store which file contents are uploaded to, deleted
+/// from and undeleted in, such as S3.
+/// * `metadata`: The metadata store which hash trackers are read from and
+/// written to, such as DynamoDB.
+/// * `planner`: Collects the plan of a dry run.
+/// * `lease`: The status of the backup lease, checked before every page.
+/// 
+/// Returns:
+/// 
+/// The number of hashes which were and were not backed up, or the error
+/// encountered querying the local database for changes.
+/// `GdaError::SafetyAbort` if the changes exceed a safety threshold, in which
+/// case nothing is backed up.
+/// `GdaError::LockError` if the lease was lost, in which case the backup stops
+/// before its next page.
+#[allow(clippy::too_many_arguments)]
+pub async fn backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, planner: &mut Planner, lease: &LeaseStatus) -> Result<(usize, usize), GdaError> {
+
+    // Changed files delete their previous objects as they are backed up, so every change is checked first
+    if safety::enabled(&args.safety) {
+        info!("Preparing to back up: Checking changes are safe...");
+        let stats = count_changes(&args, conn)?;
+        safety::check(&args.safety, &stats, cli.dry_run)?;
+    }
+
+    info!("Preparing to back up: Finding changed files...");
+
+    // Measuring every change is only worth an extra pass over the database if it is reported
+    let hash_bytes = match progress::enabled() {
+        true => Some(pending_bytes(conn)?),
+        false => None,
+    };
+
+    let hashing = Progress::start("Hashing", Unit::Bytes, hash_bytes);
+    let uploading = Progress::start_transfer("Uploading", Unit::Bytes, Some(0));
+
+    if cli.dry_run {
+        info!("Preparation complete. Dry run output:");
+    }
+    else {
+        info!("Preparation complete. Backing up...");
+    }
+
+    let mut succeeded = 0;
+    let mut failed = 0;
+    let mut retries = vec![];
+
+    'kinds: for kind in [ChangeKind::New, ChangeKind::Changed, ChangeKind::Missing] {
+        if kind == ChangeKind::Missing {
+            let (retry_succeeded, retry_failed) = retry_changes(&cli, &args, conn, objects, metadata, mem::take(&mut retries)).await;
+            succeeded += retry_succeeded;
+            failed += retry_failed;
+        }
+
+        let mut after = String::new();
+
+        loop {
+            let (file_changes, last) = next_page(conn, kind, &after, &hashing)?;
+
+            let Some(last) = last else {
+                break;
+            };
+
+            if lease.is_lost() {
+                hashing.finish();
+                uploading.finish();
+                return Err(GdaError::LockError(Box::new(LockError::LeaseLost)));
+            }
+
+            match backup_page(&cli, &args, conn, objects, metadata, file_changes, &uploading, planner).await {
+                Ok((page_succeeded, page_failed, page_retries)) => {
+                    succeeded += page_succeeded;
+                    failed += page_failed;
+                    retries.extend(page_retries);
+                },
+                Err(page_failed) => {
+                    failed += page_failed;
+                    break 'kinds;
+                },
+            }
+
+            after = last;
+        }
+    }
+
+    // Missing files are retried last, as are changes left if the backup stopped before its missing files
+    let (retry_succeeded, retry_failed) = retry_changes(&cli, &args, conn, objects, metadata, retries).await;
+    succeeded += retry_succeeded;
+    failed += retry_failed;
+
+    hashing.finish();
+    uploading.finish();
+
+    Ok((succeeded, failed))
+}
+
+/// The function `apply_plan` backs up the changes of a plan saved by a dry
+/// run, rather than every change to the target directory. The plan is only
+/// applied if none of its files changed since it was made, so that what is
+/// backed up is what was reviewed.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The arguments of the backup.
+/// * `plan_file`: The plan, saved by a dry run with `--save-plan`.
+/// * `conn`: The connection to the local database, which the plan was made
+/// against.
+/// * `objects`: The object store which file contents are uploaded to, deleted
+/// from and undeleted in, such as S3.
+/// * `metadata`: The metadata store which hash trackers are read from and
+/// written to, such as DynamoDB.
+/// * `planner`: Collects the plan of a dry run.
+/// * `lease`: The status of the backup lease, checked before every page.
+/// 
+/// Returns:
+/// 
+/// The number of hashes which were and were not backed up.
+/// `GdaError::ConfigError` if the plan was made for another backup, or any of
+/// its files changed since, in which case nothing is backed up.
+/// `GdaError::LockError` if the lease was lost, in which case the plan stops
+/// being applied before its next page.
+#[allow(clippy::too_many_arguments)]
+pub async fn apply_plan(cli: Cli, args: BackupArgs, plan_file: &Path, conn: &mut PgConnection, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, planner: &mut Planner, lease: &LeaseStatus) -> Result<(usize, usize), GdaError> {
+    let plan = Plan::load(plan_file)?;
+
+    if plan.target_dir != args.target_dir || plan.host_id != args.host_id {
+        return Err(GdaError::ConfigError(format!(
+            "The plan {} was made for a backup of {} with host id {:?}.", plan_file.display(), plan.target_dir, plan.host_id
+        )));
+    }
+
+    // Gather the change of every file from the hashes it was planned under
+    let mut file_changes: BTreeMap<String, FileChange> = BTreeMap::new();
+
+    let missing = |file_path: &String| FileChange {
+        g_file: GlacierFile {
+            file_path: file_path.clone(),
+            file_hash: None,
+            modified: SystemTime::UNIX_EPOCH,
+        },
+        old_hash: None,
+    };
+
+    for planned_hash in &plan.hashes {
+        for p_file in &planned_hash.created_files {
+            let file_change = file_changes.entry(p_file.file_path.clone()).or_insert_with(|| missing(&p_file.file_path));
+            file_change.g_file.file_hash = Some(planned_hash.hash.clone());
+            file_change.g_file.modified = p_file.modified;
+        }
+
+        for file_path in &planned_hash.deleted_files {
+            file_changes.entry(file_path.clone()).or_insert_with(|| missing(file_path)).old_hash = Some(planned_hash.hash.clone());
+        }
+    }
+
+    // Check that neither the files nor the local database changed since the plan was made
+    let mut stale = 0;
+
+    for file_change in file_changes.values_mut() {
+        let file_path = &file_change.g_file.file_path;
+
+        let current_hash = match get_glacier_file(conn, file_path.clone()) {
+            Ok(g_file) => g_file.file_hash,
+            Err(diesel::result::Error::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+
+        // A touched file keeps its hash, so it is not planned as deleted
+        let touched = file_change.old_hash.is_none() && current_hash.is_some() && current_hash == file_change.g_file.file_hash;
+
+        let file_changed = match file_change.g_file.file_hash {
+            Some(_) => {
+                let modified = fs::metadata(file_path).and_then(|metadata| metadata.modified()).ok();
+                modified.and_then(micros) != micros(file_change.g_file.modified)
+            },
+            None => Path::new(file_path).exists(),
+        };
+
+        if file_changed || (current_hash != file_change.old_hash && !touched) {
+            debug!("File changed since the plan was made: {file_path}");
+            stale += 1;
+        }
+
+        file_change.old_hash = current_hash;
+    }
+
+    if stale > 0 {
+        return Err(GdaError::ConfigError(format!(
+            "{stale} files changed since the plan {} was made. Make a new plan with a dry run.", plan_file.display()
+        )));
+    }
+
+    info!("Applying plan of {} hashes made at {}...", plan.hashes.len(), plan.created);
+
+    let uploading = Progress::start_transfer("Uploading", Unit::Bytes, Some(0));
+
+    // New and changed files, and their retries, are backed up before missing files, as in a backup
+    let (file_changes, missing_changes): (Vec<FileChange>, Vec<FileChange>) = file_changes.into_values()
+        .partition(|file_change| file_change.g_file.file_hash.is_some());
+
+    let mut succeeded = 0;
+    let mut failed = 0;
+    let mut retries = vec![];
+
+    'kinds: for (missing, file_changes) in [(false, file_changes), (true, missing_changes)] {
+        if missing {
+            let (retry_succeeded, retry_failed) = retry_changes(&cli, &args, conn, objects, metadata, mem::take(&mut retries)).await;
+            succeeded += retry_succeeded;
+            failed += retry_failed;
+        }
+
+        let mut file_changes = file_changes.into_iter();
+
+        loop {
+            let page: Vec<FileChange> = file_changes.by_ref().take(PAGE_SIZE as usize).collect();
+
+            if page.is_empty() {
+                break;
+            }
+
+            if lease.is_lost() {
+                uploading.finish();
+                return Err(GdaError::LockError(Box::new(LockError::LeaseLost)));
+            }
+
+            match backup_page(&cli, &args, conn, objects, metadata, page, &uploading, planner).await {
+                Ok((page_succeeded, page_failed, page_retries)) => {
+                    succeeded += page_succeeded;
+                    failed += page_failed;
+                    retries.extend(page_retries);
+                },
+                Err(page_failed) => {
+                    failed += page_failed;
+                    break 'kinds;
+                },
+            }
+        }
+    }
+
+    // Missing files are retried last, as are changes left if the plan stopped before its missing files
+    let (retry_succeeded, retry_failed) = retry_changes(&cli, &args, conn, objects, metadata, retries).await;
+    succeeded += retry_succeeded;
+    failed += retry_failed;
+
+    uploading.finish();
+
+    Ok((succeeded, failed))
+}
+
+/// The function `micros` converts a time to microseconds since the epoch,
+/// the precision the local database stores times with.
+fn micros(time: SystemTime) -> Option<u128> {
+    time.duration_since(SystemTime::UNIX_EPOCH).ok().map(|duration| duration.as_micros())
+}
+
+/// The kinds of change found in the local database, in the order they are
+/// backed up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChangeKind {
+    New,
+    Changed,
+    Missing,
+}
+
+/// The function `next_page` reads the next page of changes of one kind from
+/// the local database, hashing new and changed files.
+/// 
+/// Arguments:
+/// 
+/// * `conn`: The connection to the local database.
+/// * `kind`: The kind of change to read.
+/// * `after`: The path of the last file of the previous page, or "" for the
+/// first page.
+/// * `hashing`: The progress of the hashing stage.
+/// 
+/// Returns:
+/// 
+/// The changes of the page, and the path of its last file, which is `None`
+/// once every page was read.
+fn next_page(conn: &mut PgConnection, kind: ChangeKind, after: &str, hashing: &Progress) -> QueryResult<(Vec<FileChange>, Option<String>)> {
+    Ok(match kind {
+        ChangeKind::New => {
+            let new_files = get_new_files(conn, after, PAGE_SIZE)?;
+            let last = new_files.last().map(|l_file| l_file.file_path.clone());
+
+            let file_changes = new_files.into_iter().map(|l_file| FileChange {
+                g_file: GlacierFile {
+                    file_hash: Some(hash_path(hashing, &l_file.file_path)),
+                    file_path: l_file.file_path,
+                    modified: l_file.modified,
+                },
+                old_hash: None,
+            }).collect();
+
+            (file_changes, last)
+        },
+        ChangeKind::Changed => {
+            let changed_files = get_changed_files(conn, after, PAGE_SIZE)?;
+            let last = changed_files.last().map(|(l_file, _)| l_file.file_path.clone());
+
+            let file_changes = changed_files.into_iter().map(|(l_file, mut g_file)| {
+                let old_hash = g_file.file_hash;
+
+                g_file.file_hash = Some(hash_path(hashing, &l_file.file_path));
+                g_file.modified = l_file.modified;
+
+                FileChange {
+                    g_file,
+                    old_hash,
+                }
+            }).collect();
+
+            (file_changes, last)
+        },
+        ChangeKind::Missing => {
+            let missing_files = get_missing_files(conn, after, PAGE_SIZE)?;
+            let last = missing_files.last().map(|g_file| g_file.file_path.clone());
+
+            let file_changes = missing_files.into_iter().map(|mut g_file| {
+                let old_hash = g_file.file_hash.take();
+
+                FileChange {
+                    g_file,
+                    old_hash,
+                }
+            }).collect();
+
+            (file_changes, last)
+        },
+    })
+}
+
+/// The function `count_changes` counts the changes in the local database for
+/// the safety checks, reading only their paths.
+/// 
+/// Arguments:
+/// 
+/// * `args`: The arguments of the backup.
+/// * `conn`: The connection to the local database.
+/// 
+/// Returns:
+/// 
+/// The counted changes, or the error encountered querying the local
+/// database.
+fn count_changes(args: &BackupArgs, conn: &mut PgConnection) -> QueryResult<ChangeStats> {
+    let mut counter = ChangeCounter::new(&args.safety);
+    counter.previous(count_glacier_files(conn)?);
+
+    let mut after = String::new();
+    loop {
+        let new_files = get_new_files(conn, &after, PAGE_SIZE)?;
+        let Some(last) = new_files.last() else {
+            break;
+        };
+
+        after = last.file_path.clone();
+        new_files.iter().for_each(|l_file| counter.new_file(&l_file.file_path));
+    }
+
+    let mut after = String::new();
+    loop {
+        let changed_files = get_changed_files(conn, &after, PAGE_SIZE)?;
+        let Some((last, _)) = changed_files.last() else {
+            break;
+        };
+
+        after = last.file_path.clone();
+        changed_files.iter().for_each(|(l_file, _)| counter.changed_file(&l_file.file_path));
+    }
+
+    let mut after = String::new();
+    loop {
+        let missing_files = get_missing_files(conn, &after, PAGE_SIZE)?;
+        let Some(last) = missing_files.last() else {
+            break;
+        };
+
+        after = last.file_path.clone();
+        missing_files.iter().for_each(|g_file| counter.deleted_file(&g_file.file_path));
+    }
+
+    Ok(counter.finish())
+}
+
+/// The function `pending_bytes` sums the sizes of the new and changed files
+/// which the backup will hash, reading only their paths.
+/// 
+/// Arguments:
+/// 
+/// * `conn`: The connection to the local database.
+/// 
+/// Returns:
+/// 
+/// The number of bytes, or the error encountered querying the local database.
+fn pending_bytes(conn: &mut PgConnection) -> QueryResult<u64> {
+    let mut bytes = 0;
+
+    let mut after = String::new();
+    loop {
+        let new_files = get_new_files(conn, &after, PAGE_SIZE)?;
+        let Some(last) = new_files.last() else {
+            break;
+        };
+
+        after = last.file_path.clone();
+        bytes += new_files.iter().map(|l_file| file_size(&l_file.file_path)).sum::<u64>();
+    }
+
+    let mut after = String::new();
+    loop {
+        let changed_files = get_changed_files(conn, &after, PAGE_SIZE)?;
+        let Some((last, _)) = changed_files.last() else {
+            break;
+        };
+
+        after = last.file_path.clone();
+        bytes += changed_files.iter().map(|(l_file, _)| file_size(&l_file.file_path)).sum::<u64>();
+    }
+
+    Ok(bytes)
+}
+
+/// The function `backup_index` backs up the changes to a target directory
+/// since the backup which wrote its index, without a local database. The
+/// walk of the directory and the index are both sorted by path, so they are
+/// merged in a single streaming pass to find the same new, changed and missing
+/// files as the local database would.
+/// 
+/// New and changed files are backed up a page at a time as they are found,
+/// while missing files are set aside and backed up after them and their
+/// retries, so that the object of a file which was moved is not deleted before
+/// its new path is recorded. The next index is written alongside, and only replaces the index
+/// once every change was backed up.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The arguments of the backup.
+/// * `index_file`: The index of the previous backup. If it is missing, it is
+/// rebuilt from the metadata and object stores first.
+/// * `objects`: The object store which file contents are uploaded to, deleted
+/// from and undeleted in, such as S3.
+/// * `metadata`: The metadata store which hash trackers are read from and
+/// written to, such as DynamoDB.
+/// * `planner`: Collects the plan of a dry run.
+/// * `lease`: The status of the backup lease, checked before every page.
+/// 
+/// Returns:
+/// 
+/// The number of hashes which were and were not backed up, or the error
+/// encountered reading or writing the index. `GdaError::SafetyAbort` if the
+/// changes exceed a safety threshold, in which case nothing is backed up.
+/// `GdaError::LockError` if the lease was lost, in which case the changes
+/// found after are left for the next backup once the index is written.
+#[allow(clippy::too_many_arguments)]
+pub async fn backup_index(cli: Cli, args: BackupArgs, index_file: &Path, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, planner: &mut Planner, lease: &LeaseStatus) -> Result<(usize, usize), GdaError> {
+
+    let rebuilt_entries = if index_file.exists() {
+        None
+    }
+    // A dry run never writes, so it compares against the index it would rebuild without writing it
+    else if cli.dry_run {
+        info!("Index missing. Reading the state it would be rebuilt from in DynamoDB and S3...");
+        Some(index::rebuilt_entries(args.host_id.as_deref(), objects, metadata).await.map_err(Box::new)?)
+    }
+    else {
+        info!("Index missing. Rebuilding it from DynamoDB and S3...");
+        index::rebuild(args.host_id.as_deref(), index_file, objects, metadata).await.map_err(Box::new)?;
+        None
+    };
+
+    let open_previous = || match &rebuilt_entries {
+        Some(entries) => Ok(IndexReader::from_entries(entries.clone())),
+        None => IndexReader::open(index_file),
+    };
+
+    // Changes are backed up as the walk finds them, so the walk is made once beforehand to check them
+    if safety::enabled(&args.safety) {
+        info!("Preparing to back up: Checking changes are safe...");
+        let stats = count_index_changes(&args, open_previous()?)?;
+        safety::check(&args.safety, &stats, cli.dry_run)?;
+    }
+
+    let previous = open_previous()?;
+
+    let kept_file = index::sibling(index_file, "kept");
+    let missing_file = index::sibling(index_file, "missing");
+    let retained_file = index::sibling(index_file, "retained");
 
     info!("Preparing to back up: Scanning all files...");
 
-    // Keeps track of files that still exist locally
-    let mut existing_g_files: HashSet<String> = HashSet::new();
+    let scanning = Progress::start("Scanning", Unit::Files, None);
+    let mut backup = IndexBackup {
+        cli: &cli,
+        args: &args,
+        objects,
+        metadata,
+        planner,
+        lease,
+        hashing: Progress::start("Hashing", Unit::Bytes, Some(0)),
+        uploading: Progress::start_transfer("Uploading", Unit::Bytes, Some(0)),
+        succeeded: 0,
+        failed: 0,
+        retries: vec![],
+        stopped: false,
+    };
 
-    // Keeps track of GlacierFiles that have been saved to the local database
-    let mut saved_g_files: HashSet<String> = HashSet::new();
+    if cli.dry_run {
+        info!("Dry run output:");
+    }
+    else {
+        info!("Backing up...");
+    }
 
-    // Keeps track of GlacierFiles that have been deleted from the local database
-    let mut deleted_g_files: HashSet<String> = HashSet::new();
+    // Back up new and changed files, keeping the entries of every file which still exists. A
+    // dry run never writes to disk, so it only keeps the missing files it still has to plan.
+    let (mut kept, mut missing) = match cli.dry_run {
+        true => (IndexWriter::discard(), IndexWriter::memory()),
+        false => (IndexWriter::create(&kept_file)?, IndexWriter::create(&missing_file)?),
+    };
+    let mut page = IndexPage::default();
+
+    let mut files = scan(&args, Some(scanning.clone()))?;
+    let mut entries = previous;
+
+    let mut file = files.next().transpose()?;
+    let mut entry = entries.next().transpose()?;
+
+    loop {
+        let ordering = match (&file, &entry) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((l_file, _)), Some(g_entry)) => index::path_cmp(&l_file.file_path, &g_entry.file_path),
+        };
+
+        // A file without an entry is new, and an entry without a file is missing
+        let l_file = if ordering.is_gt() { None } else { file.take() };
+        let g_entry = if ordering.is_lt() { None } else { entry.take() };
+
+        match (l_file, g_entry) {
+            (Some((l_file, size)), g_entry) => backup.push_file(&mut page, l_file, size, g_entry),
+            (None, Some(g_entry)) => missing.write(&g_entry)?,
+            (None, None) => (),
+        }
+
+        if ordering.is_le() {
+            file = files.next().transpose()?;
+        }
+        if ordering.is_ge() {
+            entry = entries.next().transpose()?;
+        }
+
+        if page.is_full() {
+            backup.flush(&mut page, &mut kept).await?;
+        }
+    }
+
+    backup.flush(&mut page, &mut kept).await?;
+    scanning.finish();
+
+    let kept = kept.finish()?;
+    let missing = missing.finish()?;
+
+    // Changes which were retried were written to the index as they were before, and are replaced by what the retry saved
+    let mut retried = IndexPage::default();
+    backup.retry(&mut retried).await;
+
+    // Back up missing files, keeping the entries of those which failed to be deleted
+    let mut retained = match cli.dry_run {
+        true => IndexWriter::discard(),
+        false => IndexWriter::create(&retained_file)?,
+    };
 
-    // Get all changes
-    let file_changes: Vec<FileChange> = 
-        get_new_files(conn).iter().flat_map(|l_file| { 
-            let g_file = GlacierFile {
-                file_path: l_file.file_path.clone(),
-                file_hash: Some(hash_file(Path::new(&l_file.file_path), HASH_ALGO)),
-                modified: l_file.modified,
+    for g_entry in missing {
+        backup.push_missing(&mut page, g_entry?);
+
+        if page.is_full() {
+            backup.flush(&mut page, &mut retained).await?;
+        }
+    }
+
+    backup.flush(&mut page, &mut retained).await?;
+    let retained = retained.finish()?;
+
+    // Missing files which failed with a transient error are retried last
+    backup.retry(&mut retried).await;
+
+    backup.hashing.finish();
+    backup.uploading.finish();
+
+    if cli.dry_run {
+        return Ok((backup.succeeded, backup.failed));
+    }
+
+    let retried_file = index::sibling(index_file, "retried");
+
+    match retried.saved.is_empty() && retried.removed.is_empty() {
+        true => index::merge(kept, retained, IndexWriter::create(index_file)?)?,
+        false => {
+            let mut saved: Vec<IndexEntry> = retried.saved.values()
+                .map(|g_file| IndexEntry::new(g_file, file_size(&g_file.file_path)))
+                .collect();
+            saved.sort_by(|a, b| index::path_cmp(&a.file_path, &b.file_path));
+
+            let not_removed = |entry: &io::Result<IndexEntry>| !matches!(entry, Ok(entry) if retried.removed.contains(&entry.file_path));
+
+            index::merge(IndexReader::from_entries(saved), kept.filter(not_removed), IndexWriter::create(&retried_file)?)?;
+            index::merge(IndexReader::open(&retried_file)?, retained.filter(not_removed), IndexWriter::create(index_file)?)?;
+        },
+    }
+
+    for file in [kept_file, missing_file, retained_file, retried_file] {
+        if !file.exists() {
+            continue;
+        }
+        if let Err(error) = fs::remove_file(&file) {
+            error!("Failed to remove {}: {:?}", file.display(), error);
+        }
+    }
+
+    if lease.is_lost() {
+        return Err(GdaError::LockError(Box::new(LockError::LeaseLost)));
+    }
+
+    Ok((backup.succeeded, backup.failed))
+}
+
+/// The function `count_index_changes` counts the changes to a target
+/// directory since the backup which wrote its index for the safety checks,
+/// merging the walk of the directory with the index as `backup_index` does.
+/// 
+/// Arguments:
+/// 
+/// * `args`: The arguments of the backup.
+/// * `previous`: The index of the previous backup.
+/// 
+/// Returns:
+/// 
+/// The counted changes, or the error encountered walking the directory or
+/// reading the index.
+fn count_index_changes(args: &BackupArgs, previous: IndexReader) -> Result<ChangeStats, GdaError> {
+    let mut counter = ChangeCounter::new(&args.safety);
+
+    let mut files = scan(args, None)?;
+    let mut entries = previous;
+
+    let mut file = files.next().transpose()?;
+    let mut entry = entries.next().transpose()?;
+
+    loop {
+        let ordering = match (&file, &entry) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((l_file, _)), Some(g_entry)) => index::path_cmp(&l_file.file_path, &g_entry.file_path),
+        };
+
+        match (&file, &entry) {
+            (Some((l_file, _)), _) if ordering.is_lt() => counter.new_file(&l_file.file_path),
+            (_, Some(g_entry)) if ordering.is_gt() => counter.deleted_file(&g_entry.file_path),
+            (Some((l_file, _)), Some(g_entry)) if g_entry.modified < l_file.modified => counter.changed_file(&l_file.file_path),
+            _ => (),
+        }
+
+        if ordering.is_le() {
+            file = files.next().transpose()?;
+        }
+        if ordering.is_ge() {
+            counter.previous(1);
+            entry = entries.next().transpose()?;
+        }
+    }
+
+    Ok(counter.finish())
+}
+
+/// The `IndexBackup` struct holds what every page of a backup against an
+/// index needs, and counts the hashes the pages backed up.
+struct IndexBackup<'a> {
+    cli: &'a Cli,
+    args: &'a BackupArgs,
+    objects: &'a dyn ObjectStore,
+    metadata: &'a dyn MetadataStore,
+    planner: &'a mut Planner,
+    lease: &'a LeaseStatus,
+    hashing: Progress,
+    uploading: Progress,
+    succeeded: usize,
+    failed: usize,
+    /// Changes which failed with a transient error, published again once
+    /// the pages of new and changed files, and then of missing files, were
+    /// backed up.
+    retries: Vec<Retry>,
+    /// Whether a page could not read its hash trackers, or the lease was
+    /// lost. Changes found after are left for the next backup.
+    stopped: bool,
+}
+
+impl IndexBackup<'_> {
+
+    /// The function `push_file` adds a file found by the walk to a page,
+    /// hashing it if it is new or changed.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `page`: The page.
+    /// * `l_file`: The file.
+    /// * `size`: The size of the file in bytes.
+    /// * `g_entry`: The entry of the file in the index, unless it is new.
+    fn push_file(&self, page: &mut IndexPage, l_file: LocalFile, size: u64, g_entry: Option<IndexEntry>) {
+        match g_entry {
+            Some(g_entry) if g_entry.modified >= l_file.modified || self.stopped => {
+                page.rows.push(IndexRow::Unchanged(IndexEntry { size, ..g_entry }));
+            },
+            _ if self.stopped => (),
+            g_entry => {
+                self.hashing.add_total(size);
+
+                page.changes.push(FileChange {
+                    g_file: GlacierFile {
+                        file_hash: Some(hash_path(&self.hashing, &l_file.file_path)),
+                        file_path: l_file.file_path.clone(),
+                        modified: l_file.modified,
+                    },
+                    old_hash: g_entry.as_ref().and_then(|g_entry| g_entry.file_hash.clone()),
+                });
+
+                page.rows.push(IndexRow::Changed { file_path: l_file.file_path, size, previous: g_entry });
+            },
+        }
+    }
+
+    /// The function `push_missing` adds a file which no longer exists to a
+    /// page.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `page`: The page.
+    /// * `g_entry`: The entry of the file in the index.
+    fn push_missing(&self, page: &mut IndexPage, g_entry: IndexEntry) {
+        if !self.stopped {
+            page.changes.push(FileChange {
+                g_file: GlacierFile {
+                    file_path: g_entry.file_path.clone(),
+                    file_hash: None,
+                    modified: g_entry.modified,
+                },
+                old_hash: g_entry.file_hash.clone(),
+            });
+        }
+
+        page.rows.push(IndexRow::Changed { file_path: g_entry.file_path.clone(), size: g_entry.size, previous: Some(g_entry) });
+    }
+
+    /// The function `retry` publishes the changes which failed with a
+    /// transient error once more, unless the lease was lost, in which case
+    /// they are left for the next backup.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `retried`: Where the files of published changes are recorded.
+    async fn retry(&mut self, retried: &mut IndexPage) {
+        let retries = mem::take(&mut self.retries);
+
+        if self.lease.is_lost() {
+            self.stopped = true;
+            return;
+        }
+
+        let (succeeded, failed) = retry_changes(self.cli, self.args, retried, self.objects, self.metadata, retries).await;
+        self.succeeded += succeeded;
+        self.failed += failed;
+    }
+
+    /// The function `flush` backs up the changes of a page, then writes the
+    /// entries of its files to the next index and empties it.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `page`: The page.
+    /// * `writer`: The index the entries are written to.
+    /// 
+    /// Returns:
+    /// 
+    /// The error encountered writing the index.
+    async fn flush(&mut self, page: &mut IndexPage, writer: &mut IndexWriter) -> io::Result<()> {
+        let file_changes = mem::take(&mut page.changes);
+
+        // Another backup may have taken the lease over, so the changes are left to it
+        if self.lease.is_lost() {
+            self.stopped = true;
+        }
+        else if !file_changes.is_empty() {
+            match backup_page(self.cli, self.args, page, self.objects, self.metadata, file_changes, &self.uploading, self.planner).await {
+                Ok((succeeded, failed, retries)) => {
+                    self.succeeded += succeeded;
+                    self.failed += failed;
+                    self.retries.extend(retries);
+                },
+                Err(failed) => {
+                    self.failed += failed;
+                    self.stopped = true;
+                },
+            }
+        }
+
+        for row in page.rows.drain(..) {
+            let g_entry = match row {
+                IndexRow::Unchanged(g_entry) => Some(g_entry),
+                IndexRow::Changed { file_path, size, previous } => match page.saved.remove(&file_path) {
+                    Some(g_file) => Some(IndexEntry::new(&g_file, size)),
+                    None if page.removed.contains(&file_path) => None,
+                    // A change which failed is backed up again by the next backup
+                    None => previous,
+                },
             };
 
-            Some(FileChange {
-                g_file,
-                old_hash: None
-            })
-        })
-        
-        .chain(get_missing_files(conn).iter_mut().flat_map(|g_file| {
-            let old_hash = g_file.file_hash.clone();
+            if let Some(g_entry) = g_entry {
+                writer.write(&g_entry)?;
+            }
+        }
+
+        page.saved.clear();
+        page.removed.clear();
+
+        Ok(())
+    }
+}
 
-            g_file.file_hash = None;
+/// A file of a page of a backup against an index.
+enum IndexRow {
+    /// A file which has not changed since the previous backup.
+    Unchanged(IndexEntry),
+    /// A file which is new, changed or missing, with its entry in the
+    /// previous index, if it had one.
+    Changed { file_path: String, size: u64, previous: Option<IndexEntry> },
+}
 
-            Some(FileChange {
-                g_file: g_file.to_owned(),
-                old_hash,
-            })
-        }))
+/// The `IndexPage` struct holds a page of a backup against an index, in the
+/// order of the index, and records which of its files were saved and removed.
+#[derive(Default)]
+struct IndexPage {
+    rows: Vec<IndexRow>,
+    changes: Vec<FileChange>,
+    saved: HashMap<String, GlacierFile>,
+    removed: HashSet<String>,
+}
 
-        .chain(get_changed_files(conn).iter().flat_map(|l_file| { 
-            let mut g_file = get_glacier_file(conn, l_file.file_path.clone()).ok()?; // TODO do this in the get_changed_files query
-            let old_hash = g_file.file_hash;
+impl IndexPage {
 
-            g_file.file_hash = Some(hash_file(Path::new(&l_file.file_path), HASH_ALGO));
-            g_file.modified = l_file.modified;
+    /// The function `is_full` checks whether the page should be backed up
+    /// before more files are added to it.
+    fn is_full(&self) -> bool {
+        self.rows.len() >= PAGE_SIZE as usize
+    }
+}
 
-            Some(FileChange {
-                g_file,
-                old_hash,
-            })
-        }))
-        
-    .collect();
+/// A page records its files until they are written to the next index.
+impl SavedState for IndexPage {
+    fn save(&mut self, g_file: &GlacierFile) -> Result<(), GdaError> {
+        self.saved.insert(g_file.file_path.clone(), g_file.clone());
+        Ok(())
+    }
 
-    info!("Preparing to back up: Determining which files need to be backed up...");
+    fn remove(&mut self, g_file: &GlacierFile) -> Result<(), GdaError> {
+        self.removed.insert(g_file.file_path.clone());
+        Ok(())
+    }
+}
+
+/// The function `backup_page` backs up one page of changes.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The arguments of the backup.
+/// * `state`: Where the files of published changes are recorded, such as the
+/// local database.
+/// * `objects`: The object store which file contents are uploaded to.
+/// * `metadata`: The metadata store which hash trackers are written to.
+/// * `file_changes`: The changes of the page.
+/// * `uploading`: The progress of the uploading stage, whose total grows by
+/// the size of the objects the page uploads.
+/// * `planner`: Collects the plan of a dry run, which is planned instead of
+/// backed up.
+/// 
+/// Returns:
+/// 
+/// The number of hashes which were and were not backed up, and the changes
+/// which failed with a transient error, which are counted by neither and are
+/// published again by `retry_changes` once every page was backed up. If the hash
+/// trackers could not be read, every change failed and the backup should stop,
+/// so the number of changes is returned as an error. They are backed up by the
+/// next backup.
+#[allow(clippy::too_many_arguments)]
+async fn backup_page(cli: &Cli, args: &BackupArgs, state: &mut dyn SavedState, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, file_changes: Vec<FileChange>, uploading: &Progress, planner: &mut Planner) -> Result<(usize, usize, Vec<Retry>), usize> {
+
+    debug!("Backing up page of {} changes...", file_changes.len());
+
+    // Keeps track of files that still exist locally
+    let mut existing_g_files: HashSet<String> = HashSet::new();
+
+    // Keeps track of GlacierFiles that have been saved to the local database
+    let mut saved_g_files: HashSet<String> = HashSet::new();
+
+    // Keeps track of GlacierFiles that have been deleted from the local database
+    let mut deleted_g_files: HashSet<String> = HashSet::new();
+
+    // Fetch the HashTrackers of all changes in as few requests as possible
+    let hashes: HashSet<String> = file_changes.iter()
+        .flat_map(|file_change| [file_change.g_file.file_hash.clone(), file_change.old_hash.clone()])
+        .flatten()
+        .collect();
+
+    let mut hash_trackers = match metadata.get_many(hashes.clone()).await {
+        Ok(value) => value,
+        Err(error) => {
+            error!("Failed to get hash trackers from DynamoDB: {:?}", error);
+            METRICS.failure("metadata");
+            return Err(file_changes.len());
+        }
+    };
+
+    // A dry run writes nothing, so each page sees the trackers as the pages before it would have left them
+    if cli.dry_run {
+        planner.overlay(&hashes, &mut hash_trackers);
+    }
 
     // Get HashTrackers for all changes and update them to reflect the current state
     let mut hash_tracker_changes: HashMap<String, HashTrackerChange> = HashMap::new();
@@ -223,16 +1258,29 @@ pub async fn backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, s3_clie
 
         // If a file version was created 
         if let Some(hash) = file_change.g_file.file_hash.clone() { 
-            let h_t_c = get_hash_tracker_change(args.clone(), dynamo_client, &mut hash_tracker_changes, hash).await;
-            h_t_c.new.add_file_name(file_change.g_file.file_path.clone());
+            let h_t_c = get_hash_tracker_change(args.clone(), &mut hash_trackers, &mut hash_tracker_changes, hash);
+            h_t_c.new.add_file_name(namespaced(args.host_id.as_deref(), &file_change.g_file.file_path));
             h_t_c.created_files.push(file_change.g_file.clone());
             existing_g_files.insert(file_change.g_file.file_path.clone());
         };
 
-        // If a file version was deleted 
-        if let Some(hash) = file_change.old_hash {
-            let h_t_c = get_hash_tracker_change(args.clone(), dynamo_client, &mut hash_tracker_changes, hash).await;
-            h_t_c.new.del_file_name(file_change.g_file.file_path.clone());
+        // If a file version was deleted. A file touched without changing its
+        // contents keeps its version, which must not be deleted from under it.
+        if let Some(hash) = file_change.old_hash.filter(|old_hash| Some(old_hash) != file_change.g_file.file_hash.as_ref()) {
+            let file_name = namespaced(args.host_id.as_deref(), &file_change.g_file.file_path);
+            let h_t_c = get_hash_tracker_change(args.clone(), &mut hash_trackers, &mut hash_tracker_changes, hash);
+            h_t_c.new.del_file_name(file_name.clone());
+
+            // Keeping the version in the tracker keeps its object from being deleted
+            let kind = if file_change.g_file.file_hash.is_some() { RetainedKind::Replaced } else { RetainedKind::Deleted };
+            let retained = match kind {
+                RetainedKind::Deleted => args.retention.keeps_deleted(&file_change.g_file.file_path),
+                RetainedKind::Replaced => args.retention.keeps_versions(&file_change.g_file.file_path),
+            };
+            if retained {
+                h_t_c.new.add_file_name(RetainedFile::new(kind, file_name).name());
+            }
+
             h_t_c.deleted_files.push(file_change.g_file.clone());
         };
     };
@@ -241,130 +1289,413 @@ pub async fn backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, s3_clie
     let mut failures = 0;
 
     if cli.dry_run {
-        info!("Preparation complete. Dry run output:");
-        for (_, hash_tracker_change) in hash_tracker_changes {
-            for file in hash_tracker_change.created_files {
+        let storage_classes = AwsArgs::from(args.clone()).storage_classes;
+
+        for (hash, hash_tracker_change) in hash_tracker_changes {
+            for file in &hash_tracker_change.created_files {
                 info!("Backup: {}", file.file_path);
             };
 
-            for file in hash_tracker_change.deleted_files {
+            for file in &hash_tracker_change.deleted_files {
                 info!("Delete: {}", file.file_path);
             };
+
+            planner.record(plan_change(objects, &storage_classes, &hash, &hash_tracker_change).await, hash_tracker_change.new.clone());
+            report_change(cli, &hash, &hash_tracker_change, None, &HashMap::new());
         };
 
-        return (num_changes - failures, failures);
+        return Ok((num_changes - failures, failures, vec![]));
     }
 
-    info!("Preparation complete. Backing up...");
+    uploading.add_total(hash_tracker_changes.iter()
+        .filter(|(hash, hash_tracker_change)| hash_tracker_change.changed() && hash_tracker_change.object_action(hash) == ObjectAction::Upload)
+        .filter_map(|(_, hash_tracker_change)| hash_tracker_change.created_files.first())
+        .map(|g_file| file_size(&g_file.file_path))
+        .sum());
+
+    let mut retries = vec![];
 
-    // Make all updates in the order S3 -> DynamoDB -> PostgreSQL, and continue on any failure
     for (hash, mut hash_tracker_change) in hash_tracker_changes {
-        
-        if hash_tracker_change.changed() {
 
-            // Publish S3 changes
-
-            // Delete
-            if hash_tracker_change.old.has_files() {
-                if !hash_tracker_change.new.has_files() {
-                    debug!("Deleting hash: {} from S3.", hash.clone());
-                    match s3::delete(args.clone().into(), s3_client, hash.clone()).await {
-                        Ok(_) => (),
-                        Err(error) => {
-                            error!("Failed to delete file from S3: {:?}\n Error: {:?}", hash_tracker_change, error);
-                            failures += 1;
-                            continue;
-                        }
-                    }
+        if hash_tracker_change.changed() {
+            if let Err(error) = publish_change(args, objects, metadata, &hash, &mut hash_tracker_change).await {
+                if error.is_transient() {
+                    let existing_g_files = hash_tracker_change.deleted_files.iter()
+                        .filter(|d_file| existing_g_files.contains(&d_file.file_path))
+                        .map(|d_file| d_file.file_path.clone())
+                        .collect();
+                    retries.push(Retry { hash, hash_tracker_change, existing_g_files });
                 }
-            }
-
-            // Put
-            else if hash_tracker_change.old.is_expired() {
-                if hash_tracker_change.new.has_files() {
-                    debug!("Uploading hash: {} to S3.", hash.clone());
-                    
-                    let g_file = match hash_tracker_change.created_files.first() {
-                        Some(value) => value,
-                        None => {
-                            error!("Internal error. File missing from hash tracker: {:?}", hash_tracker_change);
-                            failures += 1;
-                            continue;
-                        }
-                    };
-                    match s3::put(args.clone().into(), s3_client, hash.clone(), g_file.file_path.to_string()).await {
-                        Ok(_) => (),
-                        Err(error) => {
-                            error!("Failed to upload file to S3: {:?}\n Error: {:?}", hash_tracker_change, error);
-                            failures += 1;
-                            continue;
-                        }
-                    }
+                else {
+                    failures += 1;
+                    report_change(cli, &hash, &hash_tracker_change, Some(&error), &HashMap::new());
                 }
+                continue;
             }
+        }
 
-            // Undelete
-            else {
-                if hash_tracker_change.new.has_files() {
-                    debug!("Undeleting hash: {} to S3.", hash.clone());
-                    match s3::restore(args.clone().into(), s3_client, hash.clone()).await {
-                        Ok(_) => (),
-                        Err(error) => {
-                            error!("Failed to remove delete marker from file in S3: {:?}\n Error: {:?}", hash_tracker_change, error);
-                            failures += 1;
-                            continue;
-                        }
-                    }
-                    hash_tracker_change.new.expiration = new_expiration(args.min_storage_duration.clone());
-                }
+        let file_errors = save_change(state, &hash_tracker_change, &existing_g_files, &mut saved_g_files, &mut deleted_g_files);
+        failures += file_errors.len();
+        report_change(cli, &hash, &hash_tracker_change, None, &file_errors);
+    };
+
+    Ok((num_changes - retries.len() - failures, failures, retries))
+}
+
+/// The function `retry_changes` publishes the changes which failed with a
+/// transient error once more, after the pages of new and changed files were
+/// backed up and before those of missing files, waiting the maximum backoff
+/// first so that throttling has time to subside.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line, whose retry arguments set the wait.
+/// * `args`: The arguments of the backup.
+/// * `state`: Where the files of published changes are recorded.
+/// * `objects`: The object store.
+/// * `metadata`: The metadata store.
+/// * `retries`: The changes to publish again.
+/// 
+/// Returns:
+/// 
+/// The number of hashes which were and were not backed up. Changes which fail
+/// again are backed up by the next backup.
+async fn retry_changes(cli: &Cli, args: &BackupArgs, state: &mut dyn SavedState, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, retries: Vec<Retry>) -> (usize, usize) {
+    if retries.is_empty() {
+        return (0, 0);
+    }
+
+    info!("Retrying {} hashes which failed with transient errors in {} seconds...", retries.len(), cli.retry.aws_max_backoff);
+    tokio::time::sleep(std::time::Duration::from_secs(cli.retry.aws_max_backoff)).await;
+
+    let num_changes = retries.len();
+    let mut failures = 0;
+
+    let mut saved_g_files: HashSet<String> = HashSet::new();
+    let mut deleted_g_files: HashSet<String> = HashSet::new();
+
+    for Retry { hash, mut hash_tracker_change, existing_g_files } in retries {
+        if let Err(error) = publish_change(args, objects, metadata, &hash, &mut hash_tracker_change).await {
+            failures += 1;
+            report_change(cli, &hash, &hash_tracker_change, Some(&error), &HashMap::new());
+            continue;
+        }
+
+        let file_errors = save_change(state, &hash_tracker_change, &existing_g_files, &mut saved_g_files, &mut deleted_g_files);
+        failures += file_errors.len();
+        report_change(cli, &hash, &hash_tracker_change, None, &file_errors);
+    }
+
+    (num_changes - failures, failures)
+}
+
+/// The function `plan_change` records what publishing the change of one hash
+/// would do, without writing anything.
+/// 
+/// Arguments:
+/// 
+/// * `objects`: The object store, which the size of an object which would be
+/// deleted is read from.
+/// * `storage_classes`: Chooses the storage class of an object.
+/// * `hash`: The hash being changed.
+/// * `hash_tracker_change`: The change.
+/// 
+/// Returns:
+/// 
+/// The planned change. The size of an object which would be deleted is
+/// unknown if it could not be read.
+async fn plan_change(objects: &dyn ObjectStore, storage_classes: &StorageClasses, hash: &str, hash_tracker_change: &HashTrackerChange) -> PlannedHash {
+    let action = match hash_tracker_change.object_action(hash) {
+        _ if !hash_tracker_change.changed() => PlanAction::None,
+        ObjectAction::Upload => PlanAction::Upload,
+        ObjectAction::Undelete => PlanAction::Undelete,
+        ObjectAction::Delete => PlanAction::Delete,
+        ObjectAction::None => PlanAction::Update,
+    };
+
+    // The object has the contents of the files being created, or being deleted if there are none
+    let (g_file, size) = match action {
+        PlanAction::Upload | PlanAction::Undelete => {
+            let g_file = hash_tracker_change.created_files.first();
+            (g_file, g_file.map(|g_file| file_size(&g_file.file_path)))
+        },
+        PlanAction::Delete => match objects.head(hash.to_string()).await {
+            Ok(object_info) => (hash_tracker_change.deleted_files.first(), object_info.map(|object_info| object_info.size)),
+            Err(error) => {
+                debug!("Failed to read the size of object {hash}: {:?}", error);
+                (None, None)
+            },
+        },
+        PlanAction::Update | PlanAction::None => (None, None),
+    };
+
+    let storage_class = g_file.zip(size)
+        .and_then(|(g_file, size)| storage_classes.select(&g_file.file_path, size))
+        .map(|storage_class| storage_class.as_str().to_string());
+
+    PlannedHash {
+        hash: hash.to_string(),
+        action,
+        size,
+        storage_class,
+        metadata_writes: if hash_tracker_change.changed() { 1 } else { 0 },
+        created_files: hash_tracker_change.created_files.iter()
+            .map(|g_file| PlannedFile { file_path: g_file.file_path.clone(), modified: g_file.modified })
+            .collect(),
+        deleted_files: hash_tracker_change.deleted_files.iter()
+            .map(|g_file| g_file.file_path.clone())
+            .collect(),
+    }
+}
+
+/// The function `hash_path` hashes the contents of a file, counting the bytes
+/// read.
+/// 
+/// Arguments:
+/// 
+/// * `hashing`: The progress of the hashing stage.
+/// * `file_path`: The path of the file.
+/// 
+/// Returns:
+/// 
+/// The hash of the file's contents.
+fn hash_path(hashing: &Progress, file_path: &str) -> String {
+    let hash = hash_file(Path::new(file_path), HASH_ALGO);
+    let size = file_size(file_path);
+
+    METRICS.hashed_bytes.inc_by(size);
+    hashing.inc(size);
+
+    hash
+}
+
+/// The function `file_size` reads the size of a file, or 0 if it cannot be
+/// read.
+fn file_size(file_path: &str) -> u64 {
+    fs::metadata(file_path).map(|metadata| metadata.len()).unwrap_or_default()
+}
+
+/// The function `publish_change` publishes the change of one hash to the
+/// object and metadata stores, logging the step which failed, if any.
+/// 
+/// Changes are made in the order S3 -> DynamoDB. Deletions are the exception:
+/// DynamoDB is updated first, so that an object is only deleted from S3 once a
+/// conditional write confirms that no other process still references it.
+/// The lock of an object is read before it is deleted, as a locked object
+/// only gets a delete marker, which is recorded in the change to be reported.
+/// 
+/// Arguments:
+/// 
+/// * `args`: The backup arguments, used for the minimum storage duration.
+/// * `objects`: The object store.
+/// * `metadata`: The metadata store.
+/// * `hash`: The hash being changed.
+/// * `hash_tracker_change`: The change. The new tracker is updated with what
+/// was written, so the change can be published again if it failed.
+/// 
+/// Returns:
+/// 
+/// The error of the step which failed.
+async fn publish_change(args: &BackupArgs, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, hash: &str, hash_tracker_change: &mut HashTrackerChange) -> Result<(), StorageError> {
+
+    // Publish S3 changes
+    let object_action = hash_tracker_change.object_action(hash);
+
+    if is_empty_hash(hash) {
+        debug!("Hash: {hash} is an empty file. Skipping S3.");
+    }
+
+    // Put
+    else if object_action == ObjectAction::Upload {
+        debug!("Uploading hash: {hash} to S3.");
+
+        let g_file = match hash_tracker_change.created_files.first() {
+            Some(value) => value,
+            None => {
+                error!("Internal error. File missing from hash tracker: {:?}", hash_tracker_change);
+                return Err(StorageError::InternalError(format!("File missing from hash tracker {hash}.")));
             }
+        };
+        match objects.put(hash.to_string(), g_file.file_path.to_string()).await {
+            Ok(checksum) => {
+                hash_tracker_change.new.checksum = Some(checksum);
+                METRICS.uploaded_objects.inc();
+                METRICS.uploaded_bytes.inc_by(file_size(&g_file.file_path));
+            },
+            Err(error) => {
+                error!("Failed to upload file to S3: {:?}\n Error: {:?}", hash_tracker_change, error);
+                METRICS.failure("upload");
+                return Err(error);
+            }
+        }
+    }
+
+    // Undelete
+    else if object_action == ObjectAction::Undelete {
+        debug!("Undeleting hash: {hash} to S3.");
+        if let Err(error) = objects.undelete(hash.to_string()).await {
+            error!("Failed to remove delete marker from file in S3: {:?}\n Error: {:?}", hash_tracker_change, error);
+            METRICS.failure("undelete");
+            return Err(error);
+        }
+        hash_tracker_change.new.expiration = new_expiration(args.min_storage_duration);
+    }
+
+    // Publish HashTrackers
+    debug!("Uploading hash tracker: {hash} to DynamoDB.");
+    if let Err(error) = metadata.update(&mut hash_tracker_change.new).await {
+        error!("Failed to upload hash tracker to DynamoDB: {:?}\n Error: {:?}", hash_tracker_change, error);
+        METRICS.failure("metadata");
+        return Err(error);
+    }
+
+    // Delete after DynamoDB. Another process may have added a file with this hash while it was being updated
+    if object_action == ObjectAction::Delete && !hash_tracker_change.new.has_files() {
+
+        // A locked object only gets a delete marker, and its version is kept until the lock ends
+        let now = Utc::now();
+        match objects.head(hash.to_string()).await {
+            Ok(object) => {
+                hash_tracker_change.locked = object.map(|object| object.lock)
+                    .filter(|lock| lock.is_locked(now))
+                    .map(|lock| lock.describe(now));
+            },
+            Err(error) => {
+                error!("Failed to read the lock of file in S3: {:?}\n Error: {:?}", hash_tracker_change, error);
+                METRICS.failure("delete");
+                return Err(error);
+            },
+        }
+
+        if let Some(lock) = &hash_tracker_change.locked {
+            info!("Object {hash} is locked ({lock}). Its version is kept until the lock ends.");
+        }
 
-            // Publish HashTrackers
-            debug!("Uploading hash tracker: {} to DynamoDB.", hash.clone());
-            match hash_tracker_change.new.update(args.clone().into(), dynamo_client).await {
-                Ok(_) => (),
+        debug!("Deleting hash: {hash} from S3.");
+        if let Err(error) = objects.delete(hash.to_string()).await {
+            error!("Failed to delete file from S3: {:?}\n Error: {:?}", hash_tracker_change, error);
+            METRICS.failure("delete");
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// The function `save_change` records the files of a published change in the
+/// local database, continuing on any failure.
+/// 
+/// Arguments:
+/// 
+/// * `state`: Where the files of the change are recorded.
+/// * `hash_tracker_change`: The published change.
+/// * `existing_g_files`: Files which still exist locally, and so must not be
+/// removed.
+/// * `saved_g_files`: Files already saved.
+/// * `deleted_g_files`: Files already removed.
+/// 
+/// Returns:
+/// 
+/// The error of each file which failed to be saved or deleted, by path.
+fn save_change(state: &mut dyn SavedState, hash_tracker_change: &HashTrackerChange, existing_g_files: &HashSet<String>, saved_g_files: &mut HashSet<String>, deleted_g_files: &mut HashSet<String>) -> HashMap<String, String> {
+    let mut failures = HashMap::new();
+
+    // Publish GlacierFiles
+    for d_file in &hash_tracker_change.deleted_files {
+        if !deleted_g_files.contains(&d_file.file_path) && !existing_g_files.contains(&d_file.file_path) {
+            debug!("Deleting file entry: {} from local database.", d_file.file_path.clone());
+            match state.remove(d_file) {
+                Ok(_) => info!("Deleted: {}", d_file.file_path),
                 Err(error) => {
-                    error!("Failed to upload hash tracker to DynamoDB: {:?}\n Error: {:?}", hash_tracker_change, error);
-                    failures += 1;
+                    error!("Failed to remove file from local database: {:?}\n Error: {:?}", d_file, error);
+                    METRICS.failure("local_database");
+                    failures.insert(d_file.file_path.clone(), error.to_string());
                     continue;
                 }
             }
+            deleted_g_files.insert(d_file.file_path.clone());
         }
+    }
 
-        // Publish GlacierFiles
-        for d_file in hash_tracker_change.deleted_files {
-            if !deleted_g_files.contains(&d_file.file_path) && !existing_g_files.contains(&d_file.file_path) {
-                debug!("Deleting file entry: {} from local database.", d_file.file_path.clone());
-                match d_file.delete(conn) {
-                    Ok(_) => info!("Deleted: {}", d_file.file_path),
-                    Err(error) => {
-                        error!("Failed to remove file from local database: {:?}\n Error: {:?}", d_file, error);
-                        failures += 1;
-                        continue;
-                    }
+    for c_file in &hash_tracker_change.created_files {
+        if !saved_g_files.contains(&c_file.file_path) {
+            debug!("Inserting file entry: {} to local database.", c_file.file_path.clone());
+            match state.save(c_file) {
+                Ok(_) => info!("Uploaded: {}", c_file.file_path),
+                Err(error) => {
+                    error!("Failed to insert/update file into local database: {:?}\n Error: {:?}", c_file, error);
+                    METRICS.failure("local_database");
+                    failures.insert(c_file.file_path.clone(), error.to_string());
+                    continue;
                 }
-                deleted_g_files.insert(d_file.file_path.clone());
-            }
+            };
+            saved_g_files.insert(c_file.file_path.clone());
         }
+    }
 
-        for c_file in hash_tracker_change.created_files {
-            if !saved_g_files.contains(&c_file.file_path) {
-                debug!("Inserting file entry: {} to local database.", c_file.file_path.clone());
-                match c_file.insert(conn) {
-                    Ok(_) => info!("Uploaded: {}", c_file.file_path),
-                    Err(error) => {
-                        error!("Failed to insert/update file into local database: {:?}\n Error: {:?}", c_file, error);
-                        failures += 1;
-                        continue;
-                    }
-                };
-                saved_g_files.insert(c_file.file_path.clone());
-            }
-        }
+    failures
+}
+
+/// The function `report_change` reports every file of a change, if JSON
+/// output is enabled.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `hash`: The hash of the change.
+/// * `hash_tracker_change`: The change.
+/// * `error`: The error the change failed to be published with, which every
+/// file failed with.
+/// * `file_errors`: The errors of files which failed to be saved to the local
+/// database, by path.
+fn report_change(cli: &Cli, hash: &str, hash_tracker_change: &HashTrackerChange, error: Option<&StorageError>, file_errors: &HashMap<String, String>) {
+    if !report::enabled(cli) && error.is_none() && file_errors.is_empty() {
+        return;
+    }
+
+    let object_action = hash_tracker_change.object_action(hash);
+
+    let reason = match object_action {
+        ObjectAction::Upload => "new object",
+        ObjectAction::Undelete => "undeleted object",
+        _ if is_empty_hash(hash) => "empty file",
+        _ => "existing object",
     };
 
+    for c_file in &hash_tracker_change.created_files {
+        let mut file_report = FileReport::new(cli, c_file.file_path.clone(), FileAction::Backup, Some(hash.to_string()));
+        file_report.size = fs::metadata(&c_file.file_path).ok().map(|metadata| metadata.len());
+        file_report.reason = Some(reason.to_string());
+        report_file(cli, file_report, error, file_errors);
+    }
 
-    (num_changes - failures, failures)
+    let reason = match object_action {
+        ObjectAction::Delete => match &hash_tracker_change.locked {
+            Some(lock) => format!("object locked: {lock}"),
+            None => "object deleted".to_string(),
+        },
+        _ if is_empty_hash(hash) => "empty file".to_string(),
+        _ if hash_tracker_change.new.live_files().next().is_none() => "object retained".to_string(),
+        _ => "object still referenced".to_string(),
+    };
+
+    for d_file in &hash_tracker_change.deleted_files {
+        let mut file_report = FileReport::new(cli, d_file.file_path.clone(), FileAction::Delete, Some(hash.to_string()));
+        file_report.reason = Some(reason.clone());
+        report_file(cli, file_report, error, file_errors);
+    }
+}
+
+/// The function `report_file` reports a file of a change, marking it as failed
+/// if the change or the file failed.
+fn report_file(cli: &Cli, file_report: FileReport, error: Option<&StorageError>, file_errors: &HashMap<String, String>) {
+    let file_report = match (error, file_errors.get(&file_report.path)) {
+        (Some(error), _) => file_report.failed(error),
+        (None, Some(error)) => file_report.failed(error),
+        (None, None) => file_report,
+    };
+
+    report::file(cli, &file_report);
 }
 
 /// The function `get_hash_tracker_change` retrieves or creates a
@@ -376,10 +1707,8 @@ pub async fn backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, s3_clie
 /// contains various configuration or input arguments needed for the function to
 /// operate. It likely includes information such as the DynamoDB table name, minimum
 /// storage duration, and possibly other settings required for the function's logic.
-/// * `dynamo_client`: The `dynamo_client` parameter in the function
-/// `get_hash_tracker_change` is a reference to a `DynamoClient` instance. This
-/// parameter is used to interact with a DynamoDB database in order to retrieve or
-/// store data related to hash tracking. The `DynamoClient` likely provides methods
+/// * `hash_trackers`: The hash trackers prefetched from DynamoDB. A tracker is
+/// moved out of this map the first time its hash is requested.
 /// * `hash_tracker_changes`: The `hash_tracker_changes` parameter is a mutable
 /// reference to a `HashMap` that stores `String` keys and `HashTrackerChange`
 /// values. This HashMap is used to keep track of changes related to a specific hash
@@ -391,20 +1720,20 @@ pub async fn backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, s3_clie
 /// 
 /// A mutable reference to the `HashTrackerChange` object corresponding to the
 /// provided `hash` key in the `hash_tracker_changes` HashMap is being returned.
-async fn get_hash_tracker_change<'a>(args: BackupArgs, dynamo_client: &DynamoClient, hash_tracker_changes: &'a mut HashMap<String, HashTrackerChange>, hash: String) -> &'a mut HashTrackerChange {
+fn get_hash_tracker_change<'a>(args: BackupArgs, hash_trackers: &mut HashMap<String, HashTracker>, hash_tracker_changes: &'a mut HashMap<String, HashTrackerChange>, hash: String) -> &'a mut HashTrackerChange {
 
     if !hash_tracker_changes.contains_key(&hash) {
 
         let new;
         let old;
         
-        match HashTracker::get(args.clone().into(), dynamo_client, hash.clone()).await {
+        match hash_trackers.remove(&hash) {
             Some(hash_tracker) => {
                 new = hash_tracker.clone();
                 old = hash_tracker;
             },
             None => {
-                new = HashTracker::new(hash.clone(), new_expiration(args.min_storage_duration.clone()));
+                new = HashTracker::new(hash.clone(), new_expiration(args.min_storage_duration));
                 old = HashTracker::new(hash.clone(), DateTime::UNIX_EPOCH);
             },
         };
@@ -416,6 +1745,7 @@ async fn get_hash_tracker_change<'a>(args: BackupArgs, dynamo_client: &DynamoCli
                 old,
                 created_files: vec![],
                 deleted_files: vec![],
+                locked: None,
             }
         );
     }
@@ -423,6 +1753,19 @@ async fn get_hash_tracker_change<'a>(args: BackupArgs, dynamo_client: &DynamoCli
     hash_tracker_changes.get_mut(&hash).unwrap()
 }
 
+/// The function `is_empty_hash` checks if a hash is the hash of an empty file.
+/// 
+/// Arguments:
+/// 
+/// * `hash`: The hash of a file.
+/// 
+/// Returns:
+/// 
+/// `true` if the file is empty, in which case it has no object in S3.
+pub fn is_empty_hash(hash: &str) -> bool {
+    EMPTY_HASHES.iter().any(|empty_hash| empty_hash == hash)
+}
+
 /// The function `new_expiration` calculates a new expiration date based on a
 /// minimum storage duration provided as an input.
 /// 
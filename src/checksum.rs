@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::{Error as IoError, Read};
+
+use aws_smithy_types::base64;
+use sha2::{Digest, Sha256};
+
+// The size of the buffer files are read through while being hashed
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+// Multipart uploads split files into parts of a whole number of MiB
+const PART_SIZE_UNIT: u64 = 1024 * 1024;
+
+/// The function `sha256_file` computes the SHA-256 checksum of a file, in the
+/// base64 encoding used by S3's `x-amz-checksum-sha256` header.
+///
+/// Arguments:
+///
+/// * `file_path`: The path of the file.
+///
+/// Returns:
+///
+/// The base64 encoded checksum, or the error encountered reading the file.
+pub fn sha256_file(file_path: &str) -> Result<String, IoError> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(base64::encode(hasher.finalize()))
+}
+
+/// The function `sha256_file_parts` computes the composite checksum S3
+/// reports for a file uploaded in parts of `part_size` bytes, so that a
+/// restored file can be compared with the checksum of a multipart upload.
+///
+/// Arguments:
+///
+/// * `file_path`: The path of the file.
+/// * `part_size`: The size of every part but the last, in bytes.
+///
+/// Returns:
+///
+/// The composite checksum, formatted as "<checksum>-<parts>", or the error
+/// encountered reading the file.
+pub fn sha256_file_parts(file_path: &str, part_size: u64) -> Result<String, IoError> {
+    let mut file = File::open(file_path)?;
+    let mut checksum = MultipartChecksum::default();
+    let mut part = Vec::with_capacity(part_size as usize);
+
+    loop {
+        part.clear();
+        let read = file.by_ref().take(part_size).read_to_end(&mut part)?;
+        if read == 0 {
+            break;
+        }
+
+        checksum.add_part(&part);
+    }
+
+    Ok(checksum.composite())
+}
+
+/// The function `sha256_file_multipart` computes the composite checksum of a
+/// file uploaded in as many parts as the "-<parts>" suffix of the recorded
+/// checksum, without knowing the size of the parts. Every whole MiB part size
+/// which splits the file into that many parts is tried until one matches.
+///
+/// Arguments:
+///
+/// * `file_path`: The path of the file.
+/// * `expected`: The composite checksum recorded for the upload.
+///
+/// Returns:
+///
+/// The composite checksum which matches `expected`, or otherwise the one of
+/// the first part size tried, or the error encountered reading the file.
+pub fn sha256_file_multipart(file_path: &str, expected: &str) -> Result<String, IoError> {
+    let file_size = File::open(file_path)?.metadata()?.len();
+    let parts = expected.rsplit_once('-')
+        .and_then(|(_, parts)| parts.parse::<u64>().ok())
+        .unwrap_or(1)
+        .max(1);
+
+    let mut first = None;
+
+    for part_size in part_sizes(file_size, parts) {
+        let actual = sha256_file_parts(file_path, part_size)?;
+        if actual == expected {
+            return Ok(actual);
+        }
+
+        first.get_or_insert(actual);
+    }
+
+    // No whole MiB part size gives that many parts, so the file does not match however it was split
+    match first {
+        Some(actual) => Ok(actual),
+        None => sha256_file_parts(file_path, file_size.div_ceil(parts).max(1)),
+    }
+}
+
+/// The function `part_sizes` lists the whole MiB part sizes which split a
+/// file into a number of parts, smallest first. Every part but the last has
+/// the same size, so the first `parts - 1` parts must be smaller than the
+/// file, and all of them must hold it.
+fn part_sizes(file_size: u64, parts: u64) -> impl Iterator<Item = u64> {
+    let smallest = file_size.div_ceil(parts).div_ceil(PART_SIZE_UNIT).max(1) * PART_SIZE_UNIT;
+    let largest = match parts {
+        1 => smallest,
+        _ => file_size.div_ceil(parts - 1).saturating_sub(1),
+    };
+
+    (smallest..=largest).step_by(PART_SIZE_UNIT as usize)
+}
+
+/// The `MultipartChecksum` struct computes the checksums of a multipart
+/// upload as its parts are read: the checksum of each part, and the
+/// composite checksum S3 reports for the completed upload.
+#[derive(Default)]
+pub struct MultipartChecksum {
+    part_digests: Vec<Vec<u8>>,
+}
+
+impl MultipartChecksum {
+
+    /// The function `add_part` adds the next part of the upload.
+    ///
+    /// Arguments:
+    ///
+    /// * `bytes`: The contents of the part.
+    ///
+    /// Returns:
+    ///
+    /// The base64 encoded checksum of the part.
+    pub fn add_part(&mut self, bytes: &[u8]) -> String {
+        let digest = Sha256::digest(bytes).to_vec();
+        let checksum = base64::encode(&digest);
+        self.part_digests.push(digest);
+
+        checksum
+    }
+
+    /// The function `composite` returns the checksum S3 reports for the
+    /// completed upload: the SHA-256 of the concatenated digests of every
+    /// part, followed by the number of parts.
+    pub fn composite(&self) -> String {
+        let mut hasher = Sha256::new();
+
+        for digest in &self.part_digests {
+            hasher.update(digest);
+        }
+
+        format!("{}-{}", base64::encode(hasher.finalize()), self.part_digests.len())
+    }
+}
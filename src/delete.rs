@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+
+use aws_sdk_s3::types::StorageClass;
+use chrono::Utc;
+use log::{error, info};
+
+use crate::environment::{Cli, DeleteBackupArgs};
+use crate::metrics::METRICS;
+use crate::plan;
+use crate::report::{self, FileAction, FileReport};
+use crate::storage::{ObjectStore, ObjectVersion, StorageError};
+
+/// The `Deletion` struct holds the object versions a deletion of a backup
+/// would delete, along with every version of the same keys, so that keys
+/// left without a version can be found once they are deleted.
+pub struct Deletion {
+    /// The versions and delete markers to delete.
+    pub versions: Vec<ObjectVersion>,
+    // Every version of the keys matching the prefix, including ones too new to delete
+    all_versions: Vec<ObjectVersion>,
+}
+
+impl Deletion {
+
+    /// The function `find` lists the object versions and delete markers a
+    /// deletion of a backup deletes. Every page of versions is listed.
+    ///
+    /// Arguments:
+    ///
+    /// * `args`: The scope of the deletion.
+    /// * `objects`: The object store of the backup.
+    ///
+    /// Returns:
+    ///
+    /// The versions to delete, or the error encountered listing them.
+    pub async fn find(args: &DeleteBackupArgs, objects: &dyn ObjectStore) -> Result<Deletion, StorageError> {
+        let all_versions = objects.list_versions(args.prefix.as_deref().unwrap_or_default()).await?;
+
+        let now = Utc::now();
+        let versions = all_versions.iter()
+            .filter(|version| args.older_than.is_none_or(|older_than| now - version.last_modified > older_than))
+            .cloned()
+            .collect();
+
+        Ok(Deletion { versions, all_versions })
+    }
+
+    /// The function `log` logs what the deletion deletes, and estimates the
+    /// fee for deleting versions before their minimum storage duration.
+    pub fn log(&self) {
+        let now = Utc::now();
+
+        let delete_markers = self.versions.iter().filter(|version| version.is_delete_marker).count();
+        let keys = self.versions.iter().map(|version| &version.key).collect::<HashSet<_>>().len();
+        let bytes: u64 = self.versions.iter().map(|version| version.size).sum();
+
+        let fee: f64 = self.versions.iter()
+            .map(|version| plan::early_deletion_fee(
+                version.size,
+                version.storage_class.as_deref().map(StorageClass::from).as_ref(),
+                (now - version.last_modified).num_days(),
+            ))
+            .sum();
+
+        info!("Delete backup: {} object versions and {delete_markers} delete markers of {keys} objects, {bytes} bytes.",
+            self.versions.len() - delete_markers,
+        );
+        info!("Delete backup: Deleting before the minimum storage duration is estimated to cost ${fee:.4}.");
+    }
+
+    /// The function `report` reports every version the deletion would
+    /// delete, for dry runs.
+    ///
+    /// Arguments:
+    ///
+    /// * `cli`: The parsed command line.
+    pub fn report(&self, cli: &Cli) {
+        for version in &self.versions {
+            report::file(cli, &version_report(cli, version));
+        }
+    }
+
+    /// The function `delete` permanently deletes the versions and delete
+    /// markers, reporting each.
+    ///
+    /// Arguments:
+    ///
+    /// * `cli`: The parsed command line.
+    /// * `objects`: The object store of the backup.
+    ///
+    /// Returns:
+    ///
+    /// The number of versions deleted and failed, and the keys which no
+    /// longer have any version, whose metadata can be deleted.
+    pub async fn delete(self, cli: &Cli, objects: &dyn ObjectStore) -> (usize, usize, HashSet<String>) {
+        let mut deleted = 0;
+        let mut failed = 0;
+        let mut deleted_versions = HashSet::new();
+
+        for (version, result) in objects.delete_versions(self.versions).await {
+            let file_report = version_report(cli, &version);
+
+            match result {
+                Ok(()) => {
+                    deleted += 1;
+                    deleted_versions.insert((version.key.clone(), version.version_id.clone()));
+                    report::file(cli, &file_report);
+                },
+                Err(error) => {
+                    failed += 1;
+                    error!("Failed to delete version {:?} of {}\nError: {:?}", version.version_id, version.key, error);
+                    METRICS.failure("delete_backup");
+                    report::file(cli, &file_report.failed(error));
+                },
+            }
+        }
+
+        // A key is gone once every version of it was deleted. Delete markers left behind hide nothing.
+        let mut emptied: HashMap<&str, bool> = HashMap::new();
+        for version in &self.all_versions {
+            let gone = version.is_delete_marker || deleted_versions.contains(&(version.key.clone(), version.version_id.clone()));
+            *emptied.entry(&version.key).or_insert(true) &= gone;
+        }
+
+        let emptied = emptied.into_iter()
+            .filter(|(_, gone)| *gone)
+            .map(|(key, _)| key.to_string())
+            .collect();
+
+        (deleted, failed, emptied)
+    }
+}
+
+/// The function `version_report` reports a version as deleted, describing
+/// which kind of version it is.
+fn version_report(cli: &Cli, version: &ObjectVersion) -> FileReport {
+    let reason = match (version.is_delete_marker, version.is_latest) {
+        (true, _) => "delete marker",
+        (false, true) => "current version",
+        (false, false) => "noncurrent version",
+    };
+
+    FileReport {
+        size: Some(version.size),
+        reason: Some(reason.to_string()),
+        ..FileReport::new(cli, version.key.clone(), FileAction::Delete, Some(version.key.clone()))
+    }
+}
+
@@ -1,28 +1,52 @@
 use std::collections::hash_set::Iter as SetIter;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 
 use chrono::{
     DateTime, Utc
 };
+use log::debug;
+use rand::Rng;
 
 use crate::aws;
-use crate::environment::AwsArgs;
+use crate::environment::{AwsArgs, Cli, TableSchema};
+use crate::metrics::AwsMetricsInterceptor;
+use crate::retention::RetainedFile;
 
 use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::config::Builder;
+use aws_sdk_dynamodb::error::BuildError;
+use aws_sdk_dynamodb::operation::batch_get_item::BatchGetItemError;
+use aws_sdk_dynamodb::operation::batch_write_item::BatchWriteItemError;
 use aws_sdk_dynamodb::operation::get_item::GetItemError;
 use aws_sdk_dynamodb::operation::scan::ScanError;
-use aws_sdk_dynamodb::operation::put_item::{PutItemError, PutItemOutput};
-use aws_sdk_dynamodb::operation::delete_item::{DeleteItemError, DeleteItemOutput};
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::operation::scan::builders::ScanFluentBuilder;
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::operation::delete_item::DeleteItemError;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{
+    AttributeValue, DeleteRequest, KeysAndAttributes, WriteRequest
+};
 use aws_smithy_runtime_api::client::result::SdkError;
 use aws_smithy_runtime_api::http::Response;
 
 use thiserror::Error;
 
-const HASH_KEY: &str = "hash";
-const FILE_NAMES_KEY: &str = "file_names";
-const EXPIRATION_KEY: &str = "expiration";
 const NONE_STR: &str = "NONE";
+// Items which are not hash trackers (such as the backup lease) contain this in their key.
+pub(crate) const RESERVED_KEY_SEPARATOR: char = '#';
+// Number of times a conflicting write is retried before giving up.
+const MAX_WRITE_ATTEMPTS: u32 = 10;
+// Maximum number of keys in a BatchGetItem request.
+const BATCH_GET_LIMIT: usize = 100;
+// Maximum number of requests in a BatchWriteItem request.
+const BATCH_WRITE_LIMIT: usize = 25;
+// Number of times unprocessed keys or items of a batch are retried before giving up.
+const MAX_BATCH_ATTEMPTS: u32 = 8;
+// Bytes of file names stored in one item, leaving headroom below DynamoDB's 400 KB item limit.
+const MAX_SHARD_BYTES: usize = 350_000;
+// Separates the host id from the path in file names stored by a host with a host id.
+pub const HOST_SEPARATOR: char = ':';
 
 #[derive(Error, Debug)]
 pub enum HashTrackerError {
@@ -38,22 +62,92 @@ pub enum HashTrackerError {
     #[error("DynamoDbSdkErrorDelete")]
     DynamoDbSdkErrorDelete(#[from] SdkError<DeleteItemError, Response>),
 
+    #[error("DynamoDbSdkErrorUpdate")]
+    DynamoDbSdkErrorUpdate(#[from] SdkError<UpdateItemError, Response>),
+
+    #[error("DynamoDbSdkErrorBatchGet")]
+    DynamoDbSdkErrorBatchGet(#[from] SdkError<BatchGetItemError, Response>),
+
+    #[error("DynamoDbSdkErrorBatchWrite")]
+    DynamoDbSdkErrorBatchWrite(#[from] SdkError<BatchWriteItemError, Response>),
+
+    #[error("DynamoDbBuildError")]
+    DynamoDbBuildError(#[from] BuildError),
+
+    #[error("DynamoDbBatchError")]
+    DynamoDbBatchError(String),
+
     #[error("DynamoDbGetItemError")]
     DynamoDbGetItemError(String),
+
+    #[error("DynamoDbConflictError")]
+    DynamoDbConflictError(String),
+}
+
+impl HashTrackerError {
+
+    /// The function `is_conflict` checks if an error was caused by another
+    /// process modifying a hash tracker since it was read.
+    /// 
+    /// Returns:
+    /// 
+    /// `true` if a conditional write failed its version check.
+    fn is_conflict(&self) -> bool {
+        match self {
+            HashTrackerError::DynamoDbSdkErrorUpdate(error) => error.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()),
+            HashTrackerError::DynamoDbSdkErrorDelete(error) => error.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()),
+            _ => false,
+        }
+    }
+
+    /// The function `is_transient` checks whether the error may not occur if
+    /// the same change is made again later.
+    /// 
+    /// Returns:
+    /// 
+    /// `true` if a request was throttled or failed temporarily.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            HashTrackerError::DynamoDbSdkErrorGet(error) => aws::is_transient(error),
+            HashTrackerError::DynamoDbSdkErrorScan(error) => aws::is_transient(error),
+            HashTrackerError::DynamoDbSdkErrorPut(error) => aws::is_transient(error),
+            HashTrackerError::DynamoDbSdkErrorDelete(error) => aws::is_transient(error),
+            HashTrackerError::DynamoDbSdkErrorUpdate(error) => aws::is_transient(error),
+            HashTrackerError::DynamoDbSdkErrorBatchGet(error) => aws::is_transient(error),
+            HashTrackerError::DynamoDbSdkErrorBatchWrite(error) => aws::is_transient(error),
+            // Unprocessed items are left behind when a batch is throttled
+            HashTrackerError::DynamoDbBatchError(_) => true,
+            _ => false,
+        }
+    }
 }
 
 
 /// The function `get_client` asynchronously retrieves a client using AWS
 /// configuration.
 /// 
+/// Arguments:
+/// 
+/// * `cli`: The global arguments, which may point the client at a DynamoDB
+/// compatible endpoint.
+/// 
 /// Returns:
 /// 
 /// The `get_client` function is returning a `Client` instance. The function first
 /// awaits the result of `aws::get_config()` to get the AWS configuration, then
 /// creates a new `Client` instance using that configuration and returns it.
-pub async fn get_client() -> Client {
-    let config = aws::get_config().await;
-    Client::new(&config)
+pub async fn get_client(cli: &Cli) -> Client {
+    let config = aws::get_config(&cli.retry).await;
+    let mut builder = Builder::from(&config)
+        .interceptor(AwsMetricsInterceptor);
+
+    if let Some(endpoint) = &cli.dynamo_endpoint {
+        builder = builder.endpoint_url(endpoint);
+    }
+
+    Client::from_conf(builder.build())
 }
 
 /// The `HashTracker` struct in Rust represents a data structure that tracks a hash
@@ -67,14 +161,31 @@ pub async fn get_client() -> Client {
 /// the date and time when the hash value will expire. It is of type
 /// `DateTime<Utc>`, which is a datetime type provided by the `chrono` crate that
 /// represents a specific point in time with a timezone of UTC.
+/// * `checksum`: The base64 encoded SHA-256 checksum of the object, used to
+/// verify it when restored. `None` for objects uploaded before checksums were
+/// recorded.
 /// * `file_names`: The `file_names` property in the `HashTracker` struct is a
 /// private field of type `HashSet<String>`. This field is not accessible outside
 /// the struct and can only be accessed or modified through the struct's methods.
+/// * `version`: The version of the item in DynamoDB when it was read. `0` means
+/// the item did not exist, or was written before versions were tracked.
+/// * `added`: File names added since the tracker was read.
+/// * `removed`: File names removed since the tracker was read.
+/// * `shard_bytes`: The bytes of file names stored in each item of the tracker.
+/// Shard `0` is the tracker's own item, and every other shard is an overflow
+/// item with the key `hash#shard`.
+/// * `shard_of`: The shard which stores each file name.
 #[derive(Clone, Debug)]
 pub struct HashTracker {
     pub hash: String,
     pub expiration: DateTime<Utc>,
+    pub checksum: Option<String>,
     file_names: HashSet<String>,
+    version: u64,
+    added: HashSet<String>,
+    removed: HashSet<String>,
+    shard_bytes: Vec<usize>,
+    shard_of: HashMap<String, usize>,
 }
 
 impl PartialEq for HashTracker {
@@ -107,7 +218,13 @@ impl HashTracker {
         HashTracker {
             hash,
             expiration,
+            checksum: None,
             file_names: HashSet::new(),
+            version: 0,
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            shard_bytes: vec![0],
+            shard_of: HashMap::new(),
         }
     }
 
@@ -125,24 +242,140 @@ impl HashTracker {
     /// represents a specific point in time in the UTC timezone.
     /// * `file_names`: The `file_names` parameter in the `import` function is a
     /// vector of strings that contains the names of files to be imported.
+    /// * `version`: The version of the item in DynamoDB.
+    /// * `shards`: The number of overflow items holding more file names.
     /// 
     /// Returns:
     /// 
     /// a `HashTracker` struct after creating an instance of it and removing a file
     /// name with the value `NONE_STR`.
-    fn import(hash: String, expiration: DateTime<Utc>, file_names: Vec<String>) -> HashTracker { 
+    pub(crate) fn import(hash: String, expiration: DateTime<Utc>, file_names: Vec<String>, version: u64, shards: usize) -> HashTracker { 
 
         let mut hash_tracker = HashTracker {
             hash,
             expiration,
-            file_names: file_names.iter().cloned().collect(),
+            checksum: None,
+            file_names: HashSet::new(),
+            version,
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            shard_bytes: vec![0; shards + 1],
+            shard_of: HashMap::new(),
         };
 
-        hash_tracker.del_file_name(NONE_STR.to_string());
+        hash_tracker.merge_shard(0, file_names);
+
+        // Items written before file names were edited in place stored an empty set as NONE_STR
+        if hash_tracker.file_names.remove(NONE_STR) {
+            hash_tracker.shard_of.remove(NONE_STR);
+            hash_tracker.removed.insert(NONE_STR.to_string());
+        }
 
         hash_tracker
     }
 
+    /// The function `from_item` converts a DynamoDB item into a `HashTracker`.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `table_schema`: The key schema and attribute names of the table.
+    /// * `item`: The attributes of the item.
+    /// 
+    /// Returns:
+    /// 
+    /// `None` if the item is not a valid hash tracker.
+    fn from_item(table_schema: &TableSchema, item: &HashMap<String, AttributeValue>) -> Option<HashTracker> {
+        let hash = table_schema.id(item)?;
+
+        if hash.contains(RESERVED_KEY_SEPARATOR) {
+            return None;
+        }
+
+        let seconds = item.get(&table_schema.dynamo_expiration_attribute)?.as_n().ok()?.parse().ok()?;
+        let expiration: DateTime<Utc> = DateTime::from_timestamp(seconds, 0)?;
+
+        // DynamoDB removes a string set once its last element is deleted
+        let file_names = match item.get(&table_schema.dynamo_file_names_attribute) {
+            Some(value) => value.as_ss().ok()?.to_owned(),
+            None => vec![],
+        };
+
+        let version = match item.get(&table_schema.dynamo_version_attribute) {
+            Some(value) => value.as_n().ok()?.parse().ok()?,
+            None => 0,
+        };
+
+        let shards = match item.get(&table_schema.dynamo_shards_attribute) {
+            Some(value) => value.as_n().ok()?.parse().ok()?,
+            None => 0,
+        };
+
+        let mut hash_tracker = HashTracker::import(hash, expiration, file_names, version, shards);
+        hash_tracker.checksum = item.get(&table_schema.dynamo_checksum_attribute)
+            .and_then(|value| value.as_s().ok())
+            .cloned();
+
+        Some(hash_tracker)
+    }
+
+    /// The function `shard_from_item` converts a DynamoDB item into the file
+    /// names of an overflow shard.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `table_schema`: The key schema and attribute names of the table.
+    /// * `item`: The attributes of the item.
+    /// 
+    /// Returns:
+    /// 
+    /// The hash the shard belongs to, the shard number and its file names, or
+    /// `None` if the item is not a shard.
+    fn shard_from_item(table_schema: &TableSchema, item: &HashMap<String, AttributeValue>) -> Option<(String, usize, Vec<String>)> {
+        let id = table_schema.id(item)?;
+        let (hash, shard) = id.split_once(RESERVED_KEY_SEPARATOR)?;
+        let shard: usize = shard.parse().ok().filter(|shard| *shard > 0)?;
+
+        let file_names = match item.get(&table_schema.dynamo_file_names_attribute) {
+            Some(value) => value.as_ss().ok()?.to_owned(),
+            None => vec![],
+        };
+
+        Some((hash.to_string(), shard, file_names))
+    }
+
+    /// The function `merge_shard` adds the file names read from one of the
+    /// tracker's items.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `shard`: The shard the file names were read from.
+    /// * `file_names`: The file names stored in the shard.
+    fn merge_shard(&mut self, shard: usize, file_names: Vec<String>) {
+        if shard >= self.shard_bytes.len() {
+            return;
+        }
+
+        for file_name in file_names {
+            self.shard_bytes[shard] += file_name.len();
+            self.shard_of.insert(file_name.clone(), shard);
+            self.file_names.insert(file_name);
+        }
+    }
+
+    /// The function `shard_key` builds the id of one of the tracker's overflow
+    /// items.
+    fn shard_key(&self, shard: usize) -> String {
+        format!("{}{RESERVED_KEY_SEPARATOR}{shard}", self.hash)
+    }
+
+    /// The function `item_ids` returns the ids of every item storing the
+    /// tracker, for use when deleting it.
+    pub fn item_ids(&self) -> Vec<String> {
+        std::iter::once(self.hash.clone())
+            .chain((1..self.shard_bytes.len()).map(|shard| self.shard_key(shard)))
+            .collect()
+    }
+
     /// The function `files` returns an iterator over the file names stored in a
     /// HashTracker.
     /// 
@@ -154,6 +387,62 @@ impl HashTracker {
         self.file_names.iter()
     }
 
+    /// The function `host_files` returns the paths of the files stored by a
+    /// single host.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `host_id`: The host whose files should be returned. `None` returns
+    /// files stored without a host id.
+    /// 
+    /// Returns:
+    /// 
+    /// The local paths of the host's files, without the host id. Files kept
+    /// by a retention policy are not included.
+    pub fn host_files(&self, host_id: Option<&str>) -> Vec<String> {
+        self.file_names.iter()
+            .filter(|file_name| !RetainedFile::is_retained(file_name))
+            .filter_map(|file_name| match split_host(file_name) {
+                (host, path) if host == host_id => Some(path.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The function `restore_paths` returns the paths files should be restored
+    /// to, relative to the restore target directory.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `host_id`: If supplied, only the files of this host are returned, at
+    /// their original paths. Otherwise all files are returned, and files stored
+    /// with a host id are placed under a directory named after the host.
+    /// 
+    /// Returns:
+    /// 
+    /// A vector of absolute paths, to be appended to the target directory.
+    /// Files kept by a retention policy are not included.
+    pub fn restore_paths(&self, host_id: Option<&str>) -> Vec<String> {
+        self.file_names.iter()
+            .filter(|file_name| !RetainedFile::is_retained(file_name))
+            .filter_map(|file_name| restore_path(host_id, file_name))
+            .collect()
+    }
+
+    /// The function `live_files` returns the file names of the files which
+    /// currently have this hash, without those kept by a retention policy.
+    pub fn live_files(&self) -> impl Iterator<Item = &String> {
+        self.file_names.iter().filter(|file_name| !RetainedFile::is_retained(file_name))
+    }
+
+    /// The function `retained_files` returns the deleted files and previous
+    /// versions of files which a retention policy kept with this hash.
+    pub fn retained_files(&self) -> Vec<RetainedFile> {
+        self.file_names.iter()
+            .filter_map(|file_name| RetainedFile::parse(file_name))
+            .collect()
+    }
+
     /// This Rust function retrieves an item from a table using a hash key and
     /// constructs a HashTracker object from the retrieved data.
     /// 
@@ -174,28 +463,137 @@ impl HashTracker {
     /// 
     /// The function `get` returns an `Option` containing a `HashTracker` struct.
     pub async fn get(aws_args: AwsArgs, client: &Client, hash: String) -> Option<HashTracker> {
+        HashTracker::load(client, &aws_args, hash, false).await.ok()?
+    }
 
+    /// The function `load` reads a single hash tracker from DynamoDB.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `client`: The DynamoDB client.
+    /// * `aws_args`: Contains the table containing the hash tracker and its schema.
+    /// * `hash`: The hash of the tracker to read.
+    /// * `consistent`: Whether to use a strongly consistent read.
+    /// 
+    /// Returns:
+    /// 
+    /// `Ok(None)` if the tracker does not exist.
+    async fn load(client: &Client, aws_args: &AwsArgs, hash: String, consistent: bool) -> Result<Option<HashTracker>, HashTrackerError> {
         let result = client.get_item()
-            .table_name(aws_args.dynamo_table)
-            .key(HASH_KEY, AttributeValue::S(hash.clone()))
-            .send().await.ok()?.item?;
+            .table_name(aws_args.dynamo_table.clone())
+            .set_key(Some(aws_args.table_schema.key(&hash)))
+            .consistent_read(consistent)
+            .send().await?;
 
-        let file_names = result.get(FILE_NAMES_KEY)?.as_ss().ok()?;
+        let hash_tracker = result.item.as_ref().and_then(|item| HashTracker::from_item(&aws_args.table_schema, item));
 
-    
-        // Create a normal DateTime from the NaiveDateTime
-        let seconds = result.get(EXPIRATION_KEY)?.as_n().ok()?.parse().ok()?;
-        let expiration: DateTime<Utc> = DateTime::from_timestamp(seconds, 0)?;
+        let mut hash_trackers = HashMap::from_iter(hash_tracker.map(|hash_tracker| (hash_tracker.hash.clone(), hash_tracker)));
+        HashTracker::load_shards(client, aws_args, &mut hash_trackers, consistent).await?;
 
-        let mut hash_tracker = HashTracker::import (
-            hash,
-            expiration,
-            file_names.clone(),
-        );
+        Ok(hash_trackers.into_values().next())
+    }
+
+    /// The function `load_shards` reads the overflow items of hash trackers
+    /// whose file names did not fit in a single item.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `client`: The DynamoDB client.
+    /// * `aws_args`: Contains the table containing the hash trackers and its schema.
+    /// * `hash_trackers`: The trackers to complete, by hash.
+    /// * `consistent`: Whether to use strongly consistent reads.
+    async fn load_shards(client: &Client, aws_args: &AwsArgs, hash_trackers: &mut HashMap<String, HashTracker>, consistent: bool) -> Result<(), HashTrackerError> {
+        let keys: Vec<HashMap<String, AttributeValue>> = hash_trackers.values()
+            .flat_map(|hash_tracker| hash_tracker.item_ids().into_iter().skip(1))
+            .map(|id| aws_args.table_schema.key(&id))
+            .collect();
 
-        hash_tracker.del_file_name(NONE_STR.to_string());
+        for item in batch_get(client, aws_args, keys, consistent).await? {
+            if let Some((hash, shard, file_names)) = HashTracker::shard_from_item(&aws_args.table_schema, &item) {
+                if let Some(hash_tracker) = hash_trackers.get_mut(&hash) {
+                    hash_tracker.merge_shard(shard, file_names);
+                }
+            }
+        }
 
-        Some(hash_tracker)
+        Ok(())
+    }
+
+    /// The function `get_many` reads many hash trackers at once using
+    /// `BatchGetItem` requests of up to 100 keys, followed by the overflow items
+    /// of any trackers with too many file names for a single item.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `aws_args`: Contains the DynamoDB table.
+    /// * `client`: The DynamoDB client.
+    /// * `hashes`: The hashes of the trackers to read.
+    /// 
+    /// Returns:
+    /// 
+    /// A map from hash to `HashTracker`. Hashes without a tracker are absent.
+    pub async fn get_many(aws_args: AwsArgs, client: &Client, hashes: HashSet<String>) -> Result<HashMap<String, HashTracker>, HashTrackerError> {
+        let keys = hashes.iter().map(|hash| aws_args.table_schema.key(hash)).collect();
+
+        let mut hash_trackers: HashMap<String, HashTracker> = batch_get(client, &aws_args, keys, false).await?
+            .iter()
+            .filter_map(|item| HashTracker::from_item(&aws_args.table_schema, item))
+            .map(|hash_tracker| (hash_tracker.hash.clone(), hash_tracker))
+            .collect();
+
+        HashTracker::load_shards(client, &aws_args, &mut hash_trackers, false).await?;
+
+        Ok(hash_trackers)
+    }
+
+    /// The function `delete_many` deletes many hash trackers at once using
+    /// `BatchWriteItem` requests of up to 25 deletes, retrying any items
+    /// DynamoDB leaves unprocessed. Batched deletes cannot be conditional, so
+    /// this must only be used on trackers no backup is about to modify.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `aws_args`: Contains the DynamoDB table.
+    /// * `client`: The DynamoDB client.
+    /// * `ids`: The ids of the items to delete, as returned by `item_ids`.
+    pub async fn delete_many(aws_args: AwsArgs, client: &Client, ids: Vec<String>) -> Result<(), HashTrackerError> {
+        let table_name = aws_args.dynamo_table.clone();
+
+        for chunk in ids.chunks(BATCH_WRITE_LIMIT) {
+            let mut requests = chunk.iter()
+                .map(|id| DeleteRequest::builder()
+                    .set_key(Some(aws_args.table_schema.key(id)))
+                    .build()
+                    .map(|delete_request| WriteRequest::builder().delete_request(delete_request).build()))
+                .collect::<Result<Vec<WriteRequest>, BuildError>>()?;
+
+            for attempt in 0.. {
+                if requests.is_empty() {
+                    break;
+                }
+
+                if attempt == MAX_BATCH_ATTEMPTS {
+                    return Err(HashTrackerError::DynamoDbBatchError(
+                        format!("{} items were still unprocessed after {MAX_BATCH_ATTEMPTS} attempts.", requests.len())
+                    ));
+                }
+
+                if attempt > 0 {
+                    backoff(attempt).await;
+                }
+
+                let response = client.batch_write_item()
+                    .request_items(table_name.clone(), requests)
+                    .send().await?;
+
+                requests = response.unprocessed_items()
+                    .and_then(|unprocessed| unprocessed.get(&table_name))
+                    .cloned()
+                    .unwrap_or_default();
+            }
+        }
+
+        Ok(())
     }
 
     /// The `pub async fn get_all` function in the provided Rust code snippet is
@@ -203,70 +601,196 @@ impl HashTracker {
     /// database and converting them into a collection of `HashTracker` instances.
     /// Here is a breakdown of what the function is doing:
     pub async fn get_all(client: &Client, aws_args: AwsArgs) -> Option<Vec<HashTracker>> {
-        Some(client
-            // Get all items in given table
-            .scan().table_name(aws_args.dynamo_table)
+        let table_schema = &aws_args.table_schema;
+
+        // Get all items in given table
+        let items = table_schema
+            .scan(client, aws_args.dynamo_table.clone())
             .into_paginator().items().send()
-            .collect::<Result<Vec<HashMap<String, AttributeValue>>, _>>().await.ok()?
+            .collect::<Result<Vec<HashMap<String, AttributeValue>>, _>>().await.ok()?;
 
-            // Convert each valid item into a HashTracker
-            .iter().map(|value| {
-                let hash = value.get(HASH_KEY)?.as_s().ok()?.to_owned();
-                let seconds = value.get(EXPIRATION_KEY)?.as_n().ok()?.parse().ok()?;
-                let expiration: DateTime<Utc> = DateTime::from_timestamp(seconds, 0)?;
-                let file_names = value.get(FILE_NAMES_KEY)?.as_ss().ok()?.to_owned();
+        // Convert each valid item into a HashTracker
+        let mut hash_trackers: HashMap<String, HashTracker> = items.iter()
+            .filter_map(|item| HashTracker::from_item(table_schema, item))
+            .map(|hash_tracker| (hash_tracker.hash.clone(), hash_tracker))
+            .collect();
 
-                Some(HashTracker::import(hash, expiration, file_names))
-            })
+        // Merge the file names of overflow items into their trackers
+        for (hash, shard, file_names) in items.iter().filter_map(|item| HashTracker::shard_from_item(table_schema, item)) {
+            if let Some(hash_tracker) = hash_trackers.get_mut(&hash) {
+                hash_tracker.merge_shard(shard, file_names);
+            }
+        }
 
-            // Get rid of None items
-            .flatten()
-            
-            // Return as Vec
-            .collect()
-        )
+        // Return as Vec
+        Some(hash_trackers.into_values().collect())
     }
 
-    /// The function `put` in Rust asynchronously puts an item into a table with
-    /// specified attributes and returns the result.
+    /// The function `put` writes the file names added and removed since the
+    /// tracker was read, along with its expiration, using conditional
+    /// `UpdateItem` requests which only succeed if the item is still at the
+    /// version that was read.
+    /// 
+    /// DynamoDB does not allow `ADD` and `DELETE` on the same attribute in one
+    /// expression, so additions are written first. If the process stops in
+    /// between, the tracker is left with extra file names rather than missing
+    /// ones, which can never cause an object in use to be deleted.
     /// 
     /// Arguments:
     /// 
     /// * `client`: The `client` parameter is an instance of the `Client` struct,
     /// which is used to interact with a database or service. In this context, it is
     /// likely being used to make a PUT request to store an item in a table.
-    /// * `table_name`: The `table_name` parameter in the `put` function represents
-    /// the name of the table in which you want to put an item. This function is
-    /// responsible for putting an item into a DynamoDB table using the AWS SDK for
-    /// Rust.
+    /// * `aws_args`: The `aws_args` parameter in the `put` function contains the
+    /// name of the table in which you want to put an item, and the schema of that
+    /// table.
     /// 
     /// Returns:
     /// 
-    /// The `put` function returns a `Result` containing either a `PutItemOutput` or
-    /// a `HashTrackerError`.
-    async fn put(&self, client: &Client, table_name: String) -> Result<PutItemOutput, HashTrackerError> {
+    /// The `put` function returns a `Result` containing either `()` or a
+    /// `HashTrackerError`.
+    async fn put(&mut self, client: &Client, aws_args: &AwsArgs) -> Result<(), HashTrackerError> {
 
-        let file_names: Vec<String>;
+        if !self.added.is_empty() || self.removed.is_empty() {
+            let added = self.assign_shards();
+            self.write(client, aws_args, "ADD", added).await?;
+            self.added.clear();
+        }
+
+        if !self.removed.is_empty() {
+            let mut removed: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+            for file_name in &self.removed {
+                let shard = self.shard_of.get(file_name).copied().unwrap_or(0);
+                removed.entry(shard).or_default().push(file_name.clone());
+            }
+
+            self.write(client, aws_args, "DELETE", removed).await?;
 
-        if self.has_files() {
-            file_names = self.file_names.iter().cloned().collect::<Vec<_>>();
+            for file_name in std::mem::take(&mut self.removed) {
+                if let Some(shard) = self.shard_of.remove(&file_name) {
+                    self.shard_bytes[shard] -= file_name.len();
+                }
+            }
         }
-        else {
-            file_names = vec![NONE_STR.to_string()];
+
+        Ok(())
+    }
+
+    /// The function `assign_shards` chooses the item each added file name will
+    /// be stored in, filling the tracker's own item first and creating a new
+    /// overflow item once every existing item is full.
+    /// 
+    /// Returns:
+    /// 
+    /// The added file names grouped by shard.
+    fn assign_shards(&mut self) -> BTreeMap<usize, Vec<String>> {
+        let mut assigned: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+
+        for file_name in &self.added {
+            let shard = match self.shard_of.get(file_name) {
+                Some(shard) => *shard,
+                None => {
+                    let shard = match self.shard_bytes.iter().position(|bytes| bytes + file_name.len() <= MAX_SHARD_BYTES) {
+                        Some(shard) => shard,
+                        None => {
+                            self.shard_bytes.push(0);
+                            self.shard_bytes.len() - 1
+                        },
+                    };
+
+                    self.shard_bytes[shard] += file_name.len();
+                    self.shard_of.insert(file_name.clone(), shard);
+                    shard
+                },
+            };
+
+            assigned.entry(shard).or_default().push(file_name.clone());
         }
 
-        let response = client.put_item()
-            .table_name(table_name)
-            .item(HASH_KEY, AttributeValue::S(self.hash.clone()))
-            .item(FILE_NAMES_KEY, AttributeValue::Ss(file_names))
-            .item(EXPIRATION_KEY, AttributeValue::N(self.expiration.timestamp().to_string()))
+        assigned
+    }
+
+    /// The function `write` sends a conditional `UpdateItem` request for
+    /// `put`, and increments the version of the tracker if it succeeds. File
+    /// names which belong in overflow items are written after the version check
+    /// passed, with one `UpdateItem` request per overflow item, so that a write
+    /// touching any number of items stays within DynamoDB's request limits. If
+    /// an overflow item fails to be written, the error is returned and the
+    /// change is written again by the next attempt.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `client`: The DynamoDB client.
+    /// * `aws_args`: Contains the table containing the hash tracker and its schema.
+    /// * `operation`: Either `ADD` or `DELETE`.
+    /// * `file_names`: The file names to add or delete, by shard. If empty, only
+    /// the expiration and version are written.
+    async fn write(&mut self, client: &Client, aws_args: &AwsArgs, operation: &str, mut file_names: BTreeMap<usize, Vec<String>>) -> Result<(), HashTrackerError> {
+
+        let table_schema = &aws_args.table_schema;
+        let shards = self.shard_bytes.len() - 1;
+
+        let mut update_expression = "SET #expiration = :expiration, #version = :next_version".to_string();
+        let mut names = HashMap::from([
+            ("#expiration".to_string(), table_schema.dynamo_expiration_attribute.clone()),
+            ("#version".to_string(), table_schema.dynamo_version_attribute.clone()),
+        ]);
+        let mut values = HashMap::from([
+            (":expiration".to_string(), AttributeValue::N(self.expiration.timestamp().to_string())),
+            (":next_version".to_string(), AttributeValue::N((self.version + 1).to_string())),
+        ]);
+
+        if self.version > 0 {
+            values.insert(":version".to_string(), AttributeValue::N(self.version.to_string()));
+        }
+
+        if let Some(checksum) = &self.checksum {
+            update_expression += ", #checksum = :checksum";
+            names.insert("#checksum".to_string(), table_schema.dynamo_checksum_attribute.clone());
+            values.insert(":checksum".to_string(), AttributeValue::S(checksum.clone()));
+        }
+
+        if shards > 0 {
+            update_expression += ", #shards = :shards";
+            names.insert("#shards".to_string(), table_schema.dynamo_shards_attribute.clone());
+            values.insert(":shards".to_string(), AttributeValue::N(shards.to_string()));
+        }
+
+        if let Some(file_names) = file_names.remove(&0) {
+            update_expression += &format!(" {operation} #file_names :file_names");
+            names.insert("#file_names".to_string(), table_schema.dynamo_file_names_attribute.clone());
+            values.insert(":file_names".to_string(), AttributeValue::Ss(file_names));
+        }
+
+        client.update_item()
+            .table_name(aws_args.dynamo_table.clone())
+            .set_key(Some(table_schema.key(&self.hash)))
+            .condition_expression(self.version_condition())
+            .update_expression(update_expression)
+            .set_expression_attribute_names(Some(names))
+            .set_expression_attribute_values(Some(values))
             .send().await?;
 
-        Ok(response)
+        self.version += 1;
+
+        // Each overflow item holds at most MAX_SHARD_BYTES of file names, which fits in one request
+        for (shard, file_names) in file_names {
+            client.update_item()
+                .table_name(aws_args.dynamo_table.clone())
+                .set_key(Some(table_schema.key(&self.shard_key(shard))))
+                .update_expression(format!("{operation} #file_names :file_names"))
+                .expression_attribute_names("#file_names", &table_schema.dynamo_file_names_attribute)
+                .expression_attribute_values(":file_names", AttributeValue::Ss(file_names))
+                .send().await?;
+        }
+
+        Ok(())
     }
 
     /// The function `delete` in Rust asynchronously deletes an item from a table
-    /// using a provided client and table name.
+    /// using a provided client and table name, as long as it is still at the
+    /// version that was read. Overflow items are deleted in batches once the
+    /// tracker's own item is, as nothing reads them without it.
     /// 
     /// Arguments:
     /// 
@@ -274,24 +798,116 @@ impl HashTracker {
     /// which is used to interact with the AWS DynamoDB service. It is passed as a
     /// reference to the `delete` function to perform the delete operation on a
     /// specific table in DynamoDB.
-    /// * `table_name`: The `table_name` parameter in the `delete` function
-    /// represents the name of the table from which you want to delete an item.
+    /// * `aws_args`: The `aws_args` parameter in the `delete` function contains
+    /// the name of the table from which you want to delete an item, and its schema.
+    /// * `unreferenced`: Whether the stored item must also have no file names,
+    /// for trackers which were read without any rather than emptied by this
+    /// process.
     /// 
     /// Returns:
     /// 
-    /// The `delete` function returns a `Result` containing either a
-    /// `DeleteItemOutput` on success or a `HashTrackerError` on failure.
-    async fn delete(&self, client: &Client, table_name: String) -> Result<DeleteItemOutput, HashTrackerError> {
-        let response = client.delete_item()
-            .table_name(table_name)
-            .key(HASH_KEY, AttributeValue::S(self.hash.clone()))
+    /// The `delete` function returns a `Result` containing either `()` on
+    /// success or a `HashTrackerError` on failure.
+    async fn delete(&mut self, client: &Client, aws_args: &AwsArgs, unreferenced: bool) -> Result<(), HashTrackerError> {
+        let mut condition = self.version_condition().to_string();
+        let mut names = HashMap::from([("#version".to_string(), aws_args.table_schema.dynamo_version_attribute.clone())]);
+
+        // Legacy trackers still store the NONE_STR placeholder, and are only protected by their version
+        if unreferenced && !self.removed.contains(NONE_STR) {
+            condition += " AND attribute_not_exists(#file_names)";
+            names.insert("#file_names".to_string(), aws_args.table_schema.dynamo_file_names_attribute.clone());
+        }
+
+        let version = match self.version {
+            0 => None,
+            version => Some(HashMap::from([(":version".to_string(), AttributeValue::N(version.to_string()))])),
+        };
+
+        client.delete_item()
+            .table_name(aws_args.dynamo_table.clone())
+            .set_key(Some(aws_args.table_schema.key(&self.hash)))
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(names))
+            .set_expression_attribute_values(version)
             .send().await?;
 
-        Ok(response)
+        let overflow_ids: Vec<String> = self.item_ids().into_iter().skip(1).collect();
+        if !overflow_ids.is_empty() {
+            HashTracker::delete_many(aws_args.clone(), client, overflow_ids).await?;
+        }
+
+        self.version = 0;
+        self.added.clear();
+        self.removed.clear();
+        self.shard_bytes = vec![0];
+        self.shard_of.clear();
+
+        Ok(())
+    }
+
+    /// The function `version_condition` builds the condition expression used
+    /// to detect writes by other processes.
+    /// 
+    /// Returns:
+    /// 
+    /// A condition expression using the `#version` name, and the `:version`
+    /// value if the tracker has a version.
+    fn version_condition(&self) -> &'static str {
+        if self.version == 0 {
+            "attribute_not_exists(#version)"
+        }
+        else {
+            "#version = :version"
+        }
+    }
+
+    /// The function `refresh` re-reads the tracker after a conflicting write,
+    /// and reapplies the file names added and removed by this process on top
+    /// of the latest state.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `client`: The DynamoDB client.
+    /// * `aws_args`: Contains the table containing the hash tracker and its schema.
+    /// 
+    /// Returns:
+    /// 
+    /// `HashTrackerError::DynamoDbConflictError` if another process removed
+    /// every file name the tracker was read with. In that case the object may
+    /// have been deleted from S3, so the change must be recalculated instead of
+    /// retried.
+    async fn refresh(&mut self, client: &Client, aws_args: &AwsArgs) -> Result<(), HashTrackerError> {
+
+        let had_files = self.file_names.iter().any(|file_name| !self.added.contains(file_name)) ||
+            self.removed.iter().any(|file_name| file_name != NONE_STR);
+
+        let latest = HashTracker::load(client, aws_args, self.hash.clone(), true).await?
+            .unwrap_or(HashTracker::new(self.hash.clone(), self.expiration));
+
+        if had_files && !latest.has_files() {
+            return Err(HashTrackerError::DynamoDbConflictError(
+                format!("All files referencing hash {} were removed by another process.", self.hash)
+            ));
+        }
+
+        self.file_names = latest.file_names.union(&self.added)
+            .filter(|file_name| !self.removed.contains(*file_name))
+            .cloned()
+            .collect();
+        self.removed.extend(latest.removed);
+        self.version = latest.version;
+        self.shard_bytes = latest.shard_bytes;
+        self.shard_of = latest.shard_of;
+        self.expiration = self.expiration.max(latest.expiration);
+        self.checksum = self.checksum.take().or(latest.checksum);
+
+        Ok(())
     }
 
     /// The `update` function in Rust asynchronously updates a table by either
     /// deleting expired files or putting new files based on certain conditions.
+    /// If another process modified the tracker since it was read, the latest
+    /// version is read and the update is retried.
     /// 
     /// Arguments:
     /// 
@@ -305,16 +921,48 @@ impl HashTracker {
     /// 
     /// Returns:
     /// 
-    /// The `update` function is returning a `Result<(), HashTrackerError>`.
-    pub async fn update(&self, aws_args: AwsArgs, client: &Client) -> Result<(), HashTrackerError> {
-        if !self.has_files() && self.is_expired() {
-            self.delete(client, aws_args.dynamo_table).await?;
-        }
-        else {
-            self.put(client, aws_args.dynamo_table).await?;
+    /// The `update` function is returning a `Result<(), HashTrackerError>`. On
+    /// success, the tracker reflects what was written, including file names
+    /// added by other processes.
+    pub async fn update(&mut self, aws_args: AwsArgs, client: &Client) -> Result<(), HashTrackerError> {
+        for attempt in 1..=MAX_WRITE_ATTEMPTS {
+            let result = if !self.has_files() && self.is_expired() {
+                self.delete(client, &aws_args, false).await
+            }
+            else {
+                self.put(client, &aws_args).await
+            };
+
+            match result {
+                Err(error) if error.is_conflict() => {
+                    debug!("Hash tracker {} was modified by another process. Retrying ({attempt}/{MAX_WRITE_ATTEMPTS})...", self.hash);
+                    backoff(attempt).await;
+                    self.refresh(client, &aws_args).await?;
+                },
+                result => return result,
+            }
         }
 
-        Ok(())
+        Err(HashTrackerError::DynamoDbConflictError(
+            format!("Gave up updating hash {} after {MAX_WRITE_ATTEMPTS} conflicting writes.", self.hash)
+        ))
+    }
+
+    /// The function `take_changes` returns the file names added and removed
+    /// since the tracker was read, for metadata stores which apply them
+    /// atomically instead of through `update`.
+    /// 
+    /// Returns:
+    /// 
+    /// A tuple of the added and removed file names.
+    pub(crate) fn take_changes(&mut self) -> (HashSet<String>, HashSet<String>) {
+        (std::mem::take(&mut self.added), std::mem::take(&mut self.removed))
+    }
+
+    /// The function `version` returns the version of the tracker when it was
+    /// read or last written.
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
     /// The function `add_file_name` inserts a file name into a set.
@@ -325,6 +973,8 @@ impl HashTracker {
     /// the name of a file to be added to a collection or set within the `self`
     /// object.
     pub fn add_file_name(&mut self, file_name: String) {
+        self.removed.remove(&file_name);
+        self.added.insert(file_name.clone());
         self.file_names.insert(file_name);
     }
 
@@ -336,7 +986,9 @@ impl HashTracker {
     /// `String` type that represents the name of the file to be deleted from the
     /// list of file names stored in the data structure managed by the `self` object.
     pub fn del_file_name(&mut self, file_name: String) {
+        self.added.remove(&file_name);
         self.file_names.remove(&file_name);
+        self.removed.insert(file_name);
     }
 
     /// The function `has_files` checks if a Rust struct has any file names
@@ -350,7 +1002,7 @@ impl HashTracker {
     /// indicating that there are files. Otherwise, it returns `false`, indicating
     /// that there are no files.
     pub fn has_files(&self) -> bool {
-        self.file_names.len() > 0
+        !self.file_names.is_empty()
     }
 
     /// The function `is_expired` checks if the expiration time is before the
@@ -383,12 +1035,266 @@ impl HashTracker {
     /// The function `permanently_delete_all` returns a `Result` with the success
     /// type `()` (unit type) and the error type `HashTrackerError`.
     pub async fn permanently_delete_all(aws_args: AwsArgs, client: &Client) -> Result<(), HashTrackerError> {
-        let hash_trackers = HashTracker::get_all(client, aws_args.clone()).await.unwrap();
+        let hash_trackers = HashTracker::get_all(client, aws_args.clone()).await
+            .ok_or(HashTrackerError::DynamoDbGetItemError("Unable to scan DynamoDB.".to_string()))?;
 
-        for hash_tracker in hash_trackers {
-            let _ = hash_tracker.delete(client, aws_args.dynamo_table.clone()).await?;
+        let ids = hash_trackers.iter().flat_map(HashTracker::item_ids).collect();
+
+        HashTracker::delete_many(aws_args, client, ids).await
+    }
+
+    /// The function `clean` removes dangling hash trackers: trackers with no
+    /// files whose minimum storage duration has passed are deleted unless
+    /// another process wrote to them since they were scanned,
+    /// and trackers still containing the legacy `NONE_STR` placeholder are
+    /// rewritten without it.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `aws_args`: Contains the DynamoDB table.
+    /// * `client`: The DynamoDB client.
+    /// 
+    /// Returns:
+    /// 
+    /// The number of trackers deleted and the number rewritten.
+    pub async fn clean(aws_args: AwsArgs, client: &Client) -> Result<(usize, usize), HashTrackerError> {
+        let hash_trackers = HashTracker::get_all(client, aws_args.clone()).await
+            .ok_or(HashTrackerError::DynamoDbGetItemError("Unable to scan DynamoDB.".to_string()))?;
+
+        let (dangling, remaining): (Vec<HashTracker>, Vec<HashTracker>) = hash_trackers.into_iter()
+            .partition(|hash_tracker| !hash_tracker.has_files() && hash_tracker.is_expired());
+
+        // Another process may add a file between the scan and the delete, so each tracker is deleted conditionally
+        let mut deleted = 0;
+        for mut hash_tracker in dangling {
+            match hash_tracker.delete(client, &aws_args, true).await {
+                Ok(()) => deleted += 1,
+                Err(error) if error.is_conflict() => debug!("Hash tracker {} was modified by another process. Keeping it.", hash_tracker.hash),
+                Err(error) => return Err(error),
+            }
         }
 
-        Ok(())
+        let mut rewritten = 0;
+        for mut hash_tracker in remaining.into_iter().filter(|hash_tracker| hash_tracker.removed.contains(NONE_STR)) {
+            hash_tracker.update(aws_args.clone(), client).await?;
+            rewritten += 1;
+        }
+
+        Ok((deleted, rewritten))
+    }
+}
+
+impl TableSchema {
+
+    /// The function `key` builds the primary key of an item written by
+    /// gda_backup.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `id`: The id of the item, such as a hash, without the key prefix.
+    /// 
+    /// Returns:
+    /// 
+    /// The partition key, and the sort key if the table has one.
+    pub(crate) fn key(&self, id: &str) -> HashMap<String, AttributeValue> {
+        let mut key = HashMap::from([(
+            self.dynamo_partition_key.clone(),
+            AttributeValue::S(format!("{}{id}", self.dynamo_key_prefix)),
+        )]);
+
+        if let Some(sort_key) = &self.dynamo_sort_key {
+            key.insert(sort_key.clone(), AttributeValue::S(self.dynamo_sort_key_value.clone()));
+        }
+
+        key
+    }
+
+    /// The function `id` reverses `key`, reading the id of an item.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `item`: The attributes of the item.
+    /// 
+    /// Returns:
+    /// 
+    /// `None` if the item was not written by gda_backup, such as an item of
+    /// another application sharing the table.
+    pub(crate) fn id(&self, item: &HashMap<String, AttributeValue>) -> Option<String> {
+        if let Some(sort_key) = &self.dynamo_sort_key {
+            if *item.get(sort_key)?.as_s().ok()? != self.dynamo_sort_key_value {
+                return None;
+            }
+        }
+
+        item.get(&self.dynamo_partition_key)?.as_s().ok()?
+            .strip_prefix(&self.dynamo_key_prefix)
+            .map(|id| id.to_string())
+    }
+
+    /// The function `scan` builds a scan of the table which, in a shared
+    /// table, skips items that were not written by gda_backup.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `client`: The DynamoDB client.
+    /// * `table_name`: The table to scan.
+    /// 
+    /// Returns:
+    /// 
+    /// A `ScanFluentBuilder` ready to be sent or paginated.
+    fn scan(&self, client: &Client, table_name: String) -> ScanFluentBuilder {
+        let mut filters = vec![];
+        let mut request = client.scan().table_name(table_name);
+
+        if !self.dynamo_key_prefix.is_empty() {
+            filters.push("begins_with(#partition_key, :key_prefix)");
+            request = request
+                .expression_attribute_names("#partition_key", &self.dynamo_partition_key)
+                .expression_attribute_values(":key_prefix", AttributeValue::S(self.dynamo_key_prefix.clone()));
+        }
+
+        if let Some(sort_key) = &self.dynamo_sort_key {
+            filters.push("#sort_key = :sort_key");
+            request = request
+                .expression_attribute_names("#sort_key", sort_key)
+                .expression_attribute_values(":sort_key", AttributeValue::S(self.dynamo_sort_key_value.clone()));
+        }
+
+        if filters.is_empty() {
+            request
+        }
+        else {
+            request.filter_expression(filters.join(" AND "))
+        }
+    }
+}
+
+/// The function `batch_get` reads many items at once using `BatchGetItem`
+/// requests of up to 100 keys, retrying any keys DynamoDB leaves unprocessed.
+/// 
+/// Arguments:
+/// 
+/// * `client`: The DynamoDB client.
+/// * `aws_args`: Contains the DynamoDB table.
+/// * `keys`: The keys of the items to read.
+/// * `consistent`: Whether to use strongly consistent reads.
+/// 
+/// Returns:
+/// 
+/// The items which exist, in no particular order.
+async fn batch_get(client: &Client, aws_args: &AwsArgs, keys: Vec<HashMap<String, AttributeValue>>, consistent: bool) -> Result<Vec<HashMap<String, AttributeValue>>, HashTrackerError> {
+    let table_name = aws_args.dynamo_table.clone();
+    let mut items = vec![];
+
+    for chunk in keys.chunks(BATCH_GET_LIMIT) {
+        let mut keys = chunk.to_vec();
+
+        for attempt in 0.. {
+            if keys.is_empty() {
+                break;
+            }
+
+            if attempt == MAX_BATCH_ATTEMPTS {
+                return Err(HashTrackerError::DynamoDbBatchError(
+                    format!("{} keys were still unprocessed after {MAX_BATCH_ATTEMPTS} attempts.", keys.len())
+                ));
+            }
+
+            if attempt > 0 {
+                backoff(attempt).await;
+            }
+
+            let request = KeysAndAttributes::builder()
+                .set_keys(Some(keys))
+                .consistent_read(consistent)
+                .build()?;
+
+            let response = client.batch_get_item()
+                .request_items(table_name.clone(), request)
+                .send().await?;
+
+            items.extend(response.responses().and_then(|responses| responses.get(&table_name)).into_iter().flatten().cloned());
+
+            keys = response.unprocessed_keys()
+                .and_then(|unprocessed| unprocessed.get(&table_name))
+                .map(|unprocessed| unprocessed.keys().to_vec())
+                .unwrap_or_default();
+        }
     }
-}
\ No newline at end of file
+
+    Ok(items)
+}
+
+/// The function `backoff` sleeps before a DynamoDB request is retried, for
+/// longer after each attempt and with jitter so that competing processes do
+/// not retry in lockstep.
+/// 
+/// Arguments:
+/// 
+/// * `attempt`: The number of attempts made so far.
+async fn backoff(attempt: u32) {
+    let millis = rand::rng().random_range(50..200) * 2_u64.pow(attempt.min(6) - 1);
+    tokio::time::sleep(Duration::from_millis(millis)).await;
+}
+
+/// The function `namespaced` builds the file name stored in a hash tracker
+/// for a local file.
+/// 
+/// Arguments:
+/// 
+/// * `host_id`: The id of the host which owns the file, if any.
+/// * `file_path`: The local path of the file.
+/// 
+/// Returns:
+/// 
+/// `host_id:file_path` if a host id is supplied, otherwise `file_path`.
+pub fn namespaced(host_id: Option<&str>, file_path: &str) -> String {
+    match host_id {
+        Some(host_id) => format!("{host_id}{HOST_SEPARATOR}{file_path}"),
+        None => file_path.to_string(),
+    }
+}
+
+/// The function `restore_path` returns the path a file should be restored
+/// to, relative to the restore target directory.
+/// 
+/// Arguments:
+/// 
+/// * `host_id`: If supplied, only files of this host are restored, at their
+/// original paths. Otherwise files stored with a host id are placed under a
+/// directory named after the host.
+/// * `file_name`: A file name stored in a hash tracker.
+/// 
+/// Returns:
+/// 
+/// The path, or `None` if the file belongs to another host.
+pub fn restore_path(host_id: Option<&str>, file_name: &str) -> Option<String> {
+    match (host_id, split_host(file_name)) {
+        (Some(_), (host, path)) if host == host_id => Some(path.to_string()),
+        (Some(_), _) => None,
+        (None, (Some(host), path)) => Some(format!("/{host}{path}")),
+        (None, (None, path)) => Some(path.to_string()),
+    }
+}
+
+/// The function `split_host` splits a stored file name into its host id and
+/// local path. Paths stored without a host id are absolute, so any name which
+/// does not start with `/` has a host id.
+/// 
+/// Arguments:
+/// 
+/// * `file_name`: A file name stored in a hash tracker.
+/// 
+/// Returns:
+/// 
+/// A tuple of the optional host id and the local path.
+pub fn split_host(file_name: &str) -> (Option<&str>, &str) {
+    if file_name.starts_with('/') {
+        return (None, file_name);
+    }
+
+    match file_name.split_once(HOST_SEPARATOR) {
+        Some((host_id, path)) => (Some(host_id), path),
+        None => (None, file_name),
+    }
+}
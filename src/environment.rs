@@ -1,4 +1,16 @@
-use clap::{Args, Parser, Subcommand};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use aws_config::retry::RetryMode;
+use aws_sdk_s3::types::{ObjectLockMode, StorageClass};
+use chrono::{DateTime, Duration, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use cron::Schedule;
+use regex::Regex;
+
+use crate::dynamodb::{HOST_SEPARATOR, RESERVED_KEY_SEPARATOR};
 
 #[derive(Debug, Parser, Clone)]
 #[command(version, about, long_about = None)]
@@ -7,7 +19,7 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
 
-    /// Set dry run to true to view the list of files that would be backed up without uploading anything. 
+    /// Set dry run to true to plan a backup, or view what a command would do, without changing anything.
     #[arg(long, default_value_t = false, env)]
     pub dry_run: bool,
 
@@ -18,40 +30,212 @@ pub struct Cli {
     #[arg(long, default_value_t = false, env)]
     pub quiet: bool,
 
+    /// "text" for log lines only, or "json" to also print a JSON record of every file and a final report to stdout.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, env)]
+    pub output: OutputFormat,
+
+    /// "auto" for a progress bar on terminals and periodic log lines otherwise, "bar", "log", or "never".
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto, env)]
+    pub progress: ProgressMode,
+    /// The number of seconds between progress log lines.
+    #[arg(long, default_value_t = 30, env)]
+    pub progress_interval: u64,
+
+    /// Write Prometheus metrics to this file when the command finishes, for node_exporter's textfile collector.
+    #[arg(long, env)]
+    pub metrics_textfile: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub notify: NotifyArgs,
+
+    /// The endpoint of an S3 compatible service such as MinIO, used instead of AWS S3.
+    #[arg(long, env)]
+    pub s3_endpoint: Option<String>,
+
+    /// Address buckets by path rather than by subdomain, as most S3 compatible services require.
+    #[arg(long, default_value_t = false, env)]
+    pub s3_force_path_style: bool,
+
+    /// The endpoint of a DynamoDB compatible service such as DynamoDB Local, used instead of AWS DynamoDB.
+    #[arg(long, env)]
+    pub dynamo_endpoint: Option<String>,
+
+    #[command(flatten)]
+    pub retry: RetryArgs,
+}
+
+/// How the results of a command are printed.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Log lines only.
+    Text,
+    /// Newline delimited JSON records on stdout, in addition to log lines on stderr.
+    Json,
+}
+
+/// How the progress of long running stages is reported.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ProgressMode {
+    /// A progress bar if stderr is a terminal, otherwise log lines.
+    Auto,
+    /// A progress bar on stderr, which is only drawn if stderr is a terminal.
+    Bar,
+    /// Periodic log lines.
+    Log,
+    /// No progress reporting.
+    Never,
+}
+
+/// Configures where notifications are sent, and which are sent. Every channel
+/// whose settings are supplied is used.
+#[derive(Debug, Args, Clone)]
+pub struct NotifyArgs {
+    /// "always" to notify when commands start and finish, "failure" to only notify when they fail, or "never".
+    #[arg(long, value_enum, default_value_t = NotifyOn::Always, env)]
+    pub notify_on: NotifyOn,
+    /// Also send a digest of every command run in the last day or week.
+    #[arg(long, value_enum, env)]
+    pub notify_digest: Option<NotifyDigest>,
+    /// The file which records the commands run since the last digest was sent.
+    #[arg(long, default_value = "notify_digest.json", env)]
+    pub notify_digest_file: PathBuf,
+    /// The maximum number of failed files listed in a notification.
+    #[arg(long, default_value_t = 20, env)]
+    pub notify_max_failures: usize,
+
     /// ntfy url.
     #[arg(long, env)]
     pub ntfy_url: Option<String>,
-
     /// ntfy username.
     #[arg(long, env)]
     pub ntfy_username: Option<String>,
-
     /// ntfy password.
     #[arg(long, env)]
     pub ntfy_password: Option<String>,
-
     /// ntfy topic.
     #[arg(long, env)]
     pub ntfy_topic: Option<String>,
+
+    /// A URL which every notification is POSTed to as JSON.
+    #[arg(long, env)]
+    pub notify_webhook_url: Option<String>,
+
+    /// The URL of a Gotify server.
+    #[arg(long, env)]
+    pub gotify_url: Option<String>,
+    /// The token of the Gotify application notifications are sent as.
+    #[arg(long, env)]
+    pub gotify_token: Option<String>,
+
+    /// The hostname of the SMTP server notifications are emailed through.
+    #[arg(long, env)]
+    pub smtp_host: Option<String>,
+    /// The port of the SMTP server.
+    #[arg(long, default_value_t = 587, env)]
+    pub smtp_port: u16,
+    /// "starttls" to upgrade the connection to TLS, "tls" to connect with TLS, or "none".
+    #[arg(long, value_enum, default_value_t = SmtpSecurity::Starttls, env)]
+    pub smtp_security: SmtpSecurity,
+    /// The SMTP username.
+    #[arg(long, env)]
+    pub smtp_username: Option<String>,
+    /// The SMTP password.
+    #[arg(long, env)]
+    pub smtp_password: Option<String>,
+    /// The address notifications are emailed from.
+    #[arg(long, env)]
+    pub smtp_from: Option<String>,
+    /// The addresses notifications are emailed to, separated by commas.
+    #[arg(long, env, value_delimiter = ',')]
+    pub smtp_to: Vec<String>,
+}
+
+/// Which notifications are sent when commands run.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum NotifyOn {
+    /// When commands start and finish.
+    Always,
+    /// When commands fail, entirely or for some files.
+    Failure,
+    /// Never, such as when only digests are wanted.
+    Never,
+}
+
+/// How often a digest of every command run is sent.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum NotifyDigest {
+    Daily,
+    Weekly,
+}
+
+/// How the connection to an SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SmtpSecurity {
+    /// Connect in plain text, then upgrade to TLS with STARTTLS.
+    Starttls,
+    /// Connect with TLS, usually on port 465.
+    Tls,
+    /// Never use TLS. Only suitable for a relay on a trusted network.
+    None,
+}
+
+/// Configures how AWS requests which fail with throttling or transient errors
+/// are retried. Each retry waits for a random time of up to the backoff, which
+/// doubles after every attempt.
+#[derive(Debug, Args, Clone)]
+pub struct RetryArgs {
+    /// The maximum number of attempts made for each AWS request, including the first.
+    #[arg(long, default_value_t = 5, env)]
+    pub aws_max_attempts: u32,
+    /// "standard", or "adaptive" to also slow down requests while AWS is throttling them.
+    #[arg(long, default_value = "standard", env)]
+    pub aws_retry_mode: RetryMode,
+    /// The backoff in milliseconds before the first retry.
+    #[arg(long, default_value_t = 1000, env)]
+    pub aws_initial_backoff_ms: u64,
+    /// The maximum backoff in seconds between retries.
+    #[arg(long, default_value_t = 20, env)]
+    pub aws_max_backoff: u64,
 }
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum Commands {
 
     /// Backups files.
-    Backup(BackupArgs),
+    Backup(Box<BackupArgs>),
+
+    /// Runs backups on a schedule, serving Prometheus metrics over HTTP.
+    Daemon(Box<DaemonArgs>),
 
     /// Restores files.
     Restore(RestoreArgs),
+
+    /// Checks that the object of every backed up file is stored, and matches its checksum.
+    Verify(VerifyArgs),
     
+    /// Alerts if the last successful backup of any host is too old.
+    CheckFreshness(CheckFreshnessArgs),
+
+    /// Removes deleted files and previous versions which retention policies no longer keep.
+    Prune(PruneArgs),
+
     /// Cleans up dangling dynamo entries.
     CleanDynamo(CleanDynamoArgs),
 
     /// Clears the local database.
     ClearDatabase(ClearDatabaseArgs),
+
+    /// Rebuilds the local database or index file from DynamoDB and S3.
+    RebuildState(RebuildStateArgs),
     
     /// Clears the remote data.
     DeleteBackup(DeleteBackupArgs),
+
+    /// Releases backup locks left behind by a crashed or stuck backup.
+    ForceUnlock(ForceUnlockArgs),
+
+    /// Inspects the S3 Object Lock protecting backed up objects.
+    Lock(LockArgs),
 }
 
 #[derive(Debug, Args, Clone)]
@@ -70,28 +254,90 @@ pub struct BackupArgs {
     #[arg(short = 's', long, env)]
     pub filter_delimiter: Option<String>,
 
+    /// The number of seconds to wait for another backup to release its lock before failing.
+    #[arg(long, env)]
+    pub wait_for_lock: Option<u64>,
+    /// The number of seconds a backup lease is valid for before it must be renewed.
+    #[arg(long, default_value_t = 900, env)]
+    pub lock_ttl: u64,
+
+    /// Identifies this host when several hosts back up into the same bucket and table. Files are stored as "HOST_ID:path".
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    /// A healthchecks.io style URL, pinged at "/start" when a backup starts, at the URL itself when it succeeds, and at "/fail" when it fails.
+    #[arg(long, env)]
+    pub healthcheck_url: Option<String>,
+
+    /// Save the plan of a dry run to this file as JSON, so that it can be reviewed and applied with "APPLY_PLAN".
+    #[arg(long, env, conflicts_with = "apply_plan")]
+    pub save_plan: Option<PathBuf>,
+    /// Back up only the changes of a plan saved by a dry run, failing if any of its files changed since.
+    #[arg(long, env, conflicts_with = "index_file")]
+    pub apply_plan: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub retention: RetentionArgs,
+    #[command(flatten)]
+    pub safety: SafetyArgs,
+
+    /// Where backups are stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
     /// The S3 bucket to which backups will be uploaded. 
-    #[arg(short = 'b', long, env)]
-    bucket_name: String,
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
     /// The DynamoDB table which will store backup related metadata.
-    #[arg(short = 'd', long, env)]
-    dynamo_table: String,
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+    #[command(flatten)]
+    storage_classes: StorageClasses,
+    #[command(flatten)]
+    object_lock: ObjectLockArgs,
+
+    /// Keep the state of the last backup in this compressed index file instead of the local database. It is rebuilt from DynamoDB if it is missing.
+    #[arg(long, env)]
+    pub index_file: Option<PathBuf>,
     
     /// The engine of the local database. (Only postgres is supported.)
-    #[arg(short = 'e', long, env)]
-    db_engine: String,
+    #[arg(short = 'e', long, env, required_unless_present = "index_file")]
+    db_engine: Option<String>,
     /// The username of the postgres database.
-    #[arg(short = 'u', long, env)]
-    postgres_user: String,
+    #[arg(short = 'u', long, env, required_unless_present = "index_file")]
+    postgres_user: Option<String>,
     /// The password to the postgres database.
-    #[arg(short = 'p', long, env)]
-    postgres_password: String,
+    #[arg(short = 'p', long, env, required_unless_present = "index_file")]
+    postgres_password: Option<String>,
     /// The hostname of the postgres database.
-    #[arg(short = 'a', long, env)]
-    postgres_host: String,
+    #[arg(short = 'a', long, env, required_unless_present = "index_file")]
+    postgres_host: Option<String>,
     /// The name of the postgres database.
-    #[arg(short = 'n', long, env)]
-    postgres_db: String,
+    #[arg(short = 'n', long, env, required_unless_present = "index_file")]
+    postgres_db: Option<String>,
+}
+
+impl BackupArgs {
+
+    /// The function `locks_objects` checks whether uploaded objects are
+    /// protected by Object Lock.
+    pub fn locks_objects(&self) -> bool {
+        self.object_lock.object_lock_mode.is_some() || self.object_lock.object_lock_legal_hold
+    }
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct DaemonArgs {
+    /// When backups run, as a cron expression in UTC such as "0 3 * * *". A seconds field may be prepended.
+    #[arg(long, env, value_parser = parse_cron)]
+    pub backup_cron: Schedule,
+    /// The address the Prometheus metrics endpoint listens on, served at "/metrics".
+    #[arg(long, default_value = "0.0.0.0:9898", env)]
+    pub metrics_listen: SocketAddr,
+
+    #[command(flatten)]
+    pub backup: BackupArgs,
 }
 
 #[derive(Debug, Args, Clone)]
@@ -100,19 +346,100 @@ pub struct RestoreArgs {
     #[arg(short = 't', long)]
     pub target_dir: String,
 
+    /// Only restore the files backed up by this host. Otherwise files from every host are restored, and files backed up with a host id are placed in a directory named after the host.
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    /// Also restore deleted files which are still kept by a retention policy, unless a file with the same path exists.
+    #[arg(long, default_value_t = false)]
+    pub include_deleted: bool,
+    /// Also restore previous versions of changed files which are still kept by a retention policy, next to the file as "path.YYYYMMDDTHHMMSSZ".
+    #[arg(long, default_value_t = false)]
+    pub include_versions: bool,
+
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
     /// The S3 bucket which contains your backup. 
-    #[arg(short = 'b', long, env)]
-    bucket_name: String,
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
     /// The DynamoDB contains your backup metadata.
-    #[arg(short = 'd', long, env)]
-    dynamo_table: String,
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct VerifyArgs {
+    /// Only verify the files backed up by this host. Otherwise files from every host are verified.
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
+    /// The S3 bucket which contains your backup. 
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
+    /// The DynamoDB contains your backup metadata.
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct CheckFreshnessArgs {
+    /// The maximum age of the last successful backup of each host, such as "36h" or "2d".
+    #[arg(long, default_value = "36h", env, value_parser = parse_duration)]
+    pub max_age: Duration,
+    /// Hosts which must have backed up, separated by commas, so that a host which never backed up is also stale. Hosts without a host id are named "default".
+    #[arg(long, env, value_delimiter = ',')]
+    pub expected_hosts: Vec<String>,
+
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
+    /// The DynamoDB contains your backup metadata.
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct PruneArgs {
+    /// Only prune the files backed up by this host. Otherwise files from every host are pruned.
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    #[command(flatten)]
+    pub retention: RetentionArgs,
+
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
+    /// The S3 bucket which contains your backup. 
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
+    /// The DynamoDB contains your backup metadata.
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
 }
 
 #[derive(Debug, Args, Clone)]
 pub struct CleanDynamoArgs {
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
     /// The DynamoDB contains your backup metadata.
-    #[arg(short = 'd', long, env)]
-    pub dynamo_table: String,
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
 }
 
 #[derive(Debug, Args, Clone)]
@@ -134,14 +461,495 @@ pub struct ClearDatabaseArgs {
     postgres_db: String,
 }
 
+#[derive(Debug, Args, Clone)]
+pub struct RebuildStateArgs {
+    /// Only rebuild the files backed up by this host, as set when backing up.
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
+    /// The S3 bucket which contains your backup. 
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
+    /// The DynamoDB contains your backup metadata.
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+
+    /// Rebuild this index file instead of the local database.
+    #[arg(long, env)]
+    pub index_file: Option<PathBuf>,
+
+    /// The engine of the local database. (Only postgres is supported.)
+    #[arg(short = 'e', long, env, required_unless_present = "index_file")]
+    db_engine: Option<String>,
+    /// The username of the postgres database.
+    #[arg(short = 'u', long, env, required_unless_present = "index_file")]
+    postgres_user: Option<String>,
+    /// The password to the postgres database.
+    #[arg(short = 'p', long, env, required_unless_present = "index_file")]
+    postgres_password: Option<String>,
+    /// The hostname of the postgres database.
+    #[arg(short = 'a', long, env, required_unless_present = "index_file")]
+    postgres_host: Option<String>,
+    /// The name of the postgres database.
+    #[arg(short = 'n', long, env, required_unless_present = "index_file")]
+    postgres_db: Option<String>,
+}
+
 #[derive(Debug, Args, Clone)]
 pub struct DeleteBackupArgs {
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
     /// The S3 bucket which contains your backup. 
-    #[arg(short = 'b', long, env)]
-    bucket_name: String,
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
+    /// The DynamoDB contains your backup metadata.
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+
+    /// Only delete objects whose key starts with this prefix, and the metadata of objects left with no versions. Otherwise the whole backup is deleted.
+    #[arg(long)]
+    pub prefix: Option<String>,
+    /// Only delete object versions last modified longer ago than this, such as "90d".
+    #[arg(long, value_parser = parse_duration)]
+    pub older_than: Option<Duration>,
+    /// Confirm the deletion with the name of the bucket, or the local backend, instead of typing it when prompted.
+    #[arg(long)]
+    pub confirm: Option<String>,
+}
+
+impl DeleteBackupArgs {
+
+    /// The function `is_scoped` checks whether only part of the backup is
+    /// deleted.
+    pub fn is_scoped(&self) -> bool {
+        self.prefix.is_some() || self.older_than.is_some()
+    }
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct LockArgs {
+    #[command(subcommand)]
+    pub command: LockCommands,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum LockCommands {
+    /// Reports the bucket's Object Lock configuration, and the retention and legal hold of every backed up object.
+    Status(LockStatusArgs),
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct LockStatusArgs {
+    /// Only report the objects of the files backed up by this host. Otherwise objects from every host are reported.
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    /// Where your backup is stored: "aws" (the default) for S3 and DynamoDB, or "local:/path" for a local directory.
+    #[arg(long, env, value_parser = parse_backend)]
+    pub backend: Option<Backend>,
+    /// The S3 bucket which contains your backup. 
+    #[arg(short = 'b', long, env, required_unless_present = "backend")]
+    bucket_name: Option<String>,
+    /// The DynamoDB contains your backup metadata.
+    #[arg(short = 'd', long, env, required_unless_present = "backend")]
+    dynamo_table: Option<String>,
+    #[command(flatten)]
+    table_schema: TableSchema,
+}
+
+#[derive(Debug, Args, Clone)]
+pub struct ForceUnlockArgs {
     /// The DynamoDB contains your backup metadata.
     #[arg(short = 'd', long, env)]
     dynamo_table: String,
+    #[command(flatten)]
+    table_schema: TableSchema,
+    /// The host id of the backup whose lease should be released.
+    #[arg(long, env, value_parser = parse_host_id)]
+    pub host_id: Option<String>,
+
+    /// The engine of the local database. (Only postgres is supported.)
+    #[arg(short = 'e', long, env)]
+    db_engine: String,
+    /// The username of the postgres database.
+    #[arg(short = 'u', long, env)]
+    postgres_user: String,
+    /// The password to the postgres database.
+    #[arg(short = 'p', long, env)]
+    postgres_password: String,
+    /// The hostname of the postgres database.
+    #[arg(short = 'a', long, env)]
+    postgres_host: String,
+    /// The name of the postgres database.
+    #[arg(short = 'n', long, env)]
+    postgres_db: String,
+}
+
+/// Describes how hash trackers are stored in the DynamoDB table. The defaults
+/// match the table created by gda-backup.tf. Set a sort key or key prefix to
+/// store trackers in a table shared with other applications.
+#[derive(Debug, Args, Clone)]
+pub struct TableSchema {
+    /// The name of the DynamoDB table's partition key.
+    #[arg(long, default_value = "hash", env)]
+    pub dynamo_partition_key: String,
+    /// The name of the DynamoDB table's sort key, if it has one.
+    #[arg(long, env)]
+    pub dynamo_sort_key: Option<String>,
+    /// The sort key value of every item written by gda_backup. (Only used with "DYNAMO_SORT_KEY".)
+    #[arg(long, default_value = "gda-backup", env)]
+    pub dynamo_sort_key_value: String,
+    /// A prefix added to every partition key value written by gda_backup.
+    #[arg(long, default_value = "", env)]
+    pub dynamo_key_prefix: String,
+    /// The name of the attribute storing the file names of a hash.
+    #[arg(long, default_value = "file_names", env)]
+    pub dynamo_file_names_attribute: String,
+    /// The name of the attribute storing the expiration of a hash.
+    #[arg(long, default_value = "expiration", env)]
+    pub dynamo_expiration_attribute: String,
+    /// The name of the attribute storing the version of a hash.
+    #[arg(long, default_value = "version", env)]
+    pub dynamo_version_attribute: String,
+    /// The name of the attribute storing the number of overflow items of a hash with too many file names for one item.
+    #[arg(long, default_value = "shards", env)]
+    pub dynamo_shards_attribute: String,
+    /// The name of the attribute storing the SHA-256 checksum of a hash's object.
+    #[arg(long, default_value = "checksum", env)]
+    pub dynamo_checksum_attribute: String,
+}
+
+/// Chooses the S3 storage class each object is uploaded with. Without any of
+/// these options, objects take the bucket's default storage class.
+#[derive(Debug, Args, Clone, Default)]
+pub struct StorageClasses {
+    /// The storage class objects are uploaded with, such as DEEP_ARCHIVE, GLACIER, GLACIER_IR or STANDARD_IA.
+    #[arg(long, env, value_parser = parse_storage_class)]
+    pub storage_class: Option<StorageClass>,
+    /// Overrides the storage class of files whose path matches a regular expression, formatted as "REGEX=CLASS". The first match wins.
+    #[arg(long, env, value_parser = parse_storage_class_override, value_delimiter = ';')]
+    pub storage_class_override: Vec<StorageClassOverride>,
+    /// The storage class of files smaller than "SMALL_FILE_THRESHOLD", which are expensive to store in Glacier.
+    #[arg(long, env, value_parser = parse_storage_class)]
+    pub small_file_storage_class: Option<StorageClass>,
+    /// The size in bytes below which files take "SMALL_FILE_STORAGE_CLASS".
+    #[arg(long, default_value_t = 128 * 1024, env)]
+    pub small_file_threshold: u64,
+}
+
+impl StorageClasses {
+
+    /// The function `select` chooses the storage class of a file. Overrides
+    /// take precedence over the small file storage class, which takes
+    /// precedence over the default storage class.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `file_path`: The path of the file being uploaded.
+    /// * `file_size`: The size of the file in bytes.
+    /// 
+    /// Returns:
+    /// 
+    /// The storage class, or `None` to use the bucket's default storage class.
+    pub fn select(&self, file_path: &str, file_size: u64) -> Option<StorageClass> {
+        if let Some(storage_class_override) = self.storage_class_override.iter().find(|o| o.pattern.is_match(file_path)) {
+            return Some(storage_class_override.storage_class.clone());
+        }
+
+        match &self.small_file_storage_class {
+            Some(storage_class) if file_size < self.small_file_threshold => Some(storage_class.clone()),
+            _ => self.storage_class.clone(),
+        }
+    }
+}
+
+/// Chooses which deleted files and previous versions of changed files are
+/// kept, so that they can be restored. Backups keep a file when a policy
+/// applies to it, and `prune` removes it once no policy does. Without any of
+/// these options, an object is deleted as soon as no file has its contents.
+#[derive(Debug, Args, Clone, Default)]
+pub struct RetentionArgs {
+    /// How long deleted files are kept after they were deleted, such as "30d".
+    #[arg(long, env, value_parser = parse_duration)]
+    pub keep_deleted: Option<Duration>,
+    /// The number of previous versions of each changed file which are kept.
+    #[arg(long, default_value_t = 0, env)]
+    pub keep_versions: usize,
+    /// Regular expressions matching files whose deleted files and previous versions are kept forever, separated by ';'.
+    #[arg(long, env, value_parser = parse_regex, value_delimiter = ';')]
+    pub keep_forever: Vec<Regex>,
+}
+
+impl RetentionArgs {
+
+    /// The function `keeps_deleted` checks whether a file should be kept when
+    /// it is deleted.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `file_path`: The local path of the file.
+    pub fn keeps_deleted(&self, file_path: &str) -> bool {
+        self.keep_deleted.is_some() || self.keeps_forever(file_path)
+    }
+
+    /// The function `keeps_versions` checks whether the previous version of a
+    /// file should be kept when it changes.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `file_path`: The local path of the file.
+    pub fn keeps_versions(&self, file_path: &str) -> bool {
+        self.keep_versions > 0 || self.keeps_forever(file_path)
+    }
+
+    /// The function `keeps_forever` checks whether a file matches a keep
+    /// forever pattern.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `file_path`: The local path of the file.
+    pub fn keeps_forever(&self, file_path: &str) -> bool {
+        self.keep_forever.iter().any(|pattern| pattern.is_match(file_path))
+    }
+}
+
+/// Guards against propagating a mass deletion, such as ransomware or an
+/// unmounted disk, into the backup. A backup counts its changes before backing
+/// any of them up, and aborts without changing anything if a threshold is
+/// exceeded or the sentinel file is missing.
+#[derive(Debug, Args, Clone, Default)]
+pub struct SafetyArgs {
+    /// Abort the backup if more than this percentage of the previously backed up files were deleted.
+    #[arg(long, env)]
+    pub max_delete_percent: Option<f64>,
+    /// Abort the backup if more than this number of previously backed up files were deleted.
+    #[arg(long, env)]
+    pub max_delete_count: Option<usize>,
+    /// A file, relative to the target directory, which must exist for the backup to run, such as ".gda-sentinel".
+    #[arg(long, env)]
+    pub sentinel_file: Option<String>,
+    /// Abort the backup if more than this percentage of the previously backed up files look encrypted, or were renamed to a new extension.
+    #[arg(long, env)]
+    pub max_suspicious_percent: Option<f64>,
+}
+
+/// Uploads objects with S3 Object Lock, so that their versions cannot be
+/// deleted or overwritten, even with the backup's credentials, until their
+/// retention passes. The bucket must have Object Lock enabled. Deleting a
+/// locked object only adds a delete marker, so the object can still be
+/// undeleted.
+#[derive(Debug, Args, Clone, Default)]
+pub struct ObjectLockArgs {
+    /// Lock uploaded objects for "MIN_STORAGE_DURATION" days, in "governance" mode, which users with s3:BypassGovernanceRetention can override, or "compliance" mode, which no one can.
+    #[arg(long, env, value_parser = parse_object_lock_mode, requires = "min_storage_duration")]
+    pub object_lock_mode: Option<ObjectLockMode>,
+    /// Place a legal hold on uploaded objects, which protects them until it is removed, whatever their retention.
+    #[arg(long, default_value_t = false, env)]
+    pub object_lock_legal_hold: bool,
+    /// The number of days objects are locked for, which is "MIN_STORAGE_DURATION".
+    #[arg(skip)]
+    pub retain_days: Option<i64>,
+}
+
+impl ObjectLockArgs {
+
+    /// The function `retain_until` returns when an object uploaded now should
+    /// be locked until. It matches the expiration of the object's hash
+    /// tracker, which is also its minimum storage duration.
+    /// 
+    /// Returns:
+    /// 
+    /// The time, or `None` if objects are not locked.
+    pub fn retain_until(&self) -> Option<DateTime<Utc>> {
+        self.object_lock_mode.as_ref()?;
+
+        Utc::now().checked_add_signed(Duration::try_days(self.retain_days?)?)
+    }
+}
+
+/// A storage class used for files whose path matches a regular expression.
+#[derive(Debug, Clone)]
+pub struct StorageClassOverride {
+    pub pattern: Regex,
+    pub storage_class: StorageClass,
+}
+
+/// Where backups and their metadata are stored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    /// Objects in S3 and hash trackers in DynamoDB.
+    Aws,
+    /// Objects and hash trackers in a directory, such as a mounted USB disk.
+    Local(PathBuf),
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Aws => write!(f, "aws"),
+            Backend::Local(path) => write!(f, "local:{}", path.display()),
+        }
+    }
+}
+
+/// The function `parse_backend` parses a backend supplied on the command line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: Either "aws", or "local:" followed by a directory.
+/// 
+/// Returns:
+/// 
+/// The backend, or an error if it is not recognised.
+fn parse_backend(value: &str) -> Result<Backend, String> {
+    match value.split_once(':') {
+        _ if value == "aws" => Ok(Backend::Aws),
+        Some(("local", path)) if !path.is_empty() => Ok(Backend::Local(PathBuf::from(path))),
+        _ => Err("backend must be \"aws\" or \"local:/path\"".to_string()),
+    }
+}
+
+/// The function `parse_storage_class` parses an S3 storage class supplied on
+/// the command line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: The name of the storage class, such as "DEEP_ARCHIVE".
+/// 
+/// Returns:
+/// 
+/// The storage class, or an error if S3 does not recognise it.
+fn parse_storage_class(value: &str) -> Result<StorageClass, String> {
+    if !StorageClass::values().contains(&value) {
+        return Err(format!("storage class must be one of: {}", StorageClass::values().join(", ")));
+    }
+
+    Ok(StorageClass::from(value))
+}
+
+/// The function `parse_object_lock_mode` parses an S3 Object Lock mode
+/// supplied on the command line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: "governance" or "compliance", in any case.
+/// 
+/// Returns:
+/// 
+/// The mode, or an error message if the mode is not known.
+fn parse_object_lock_mode(value: &str) -> Result<ObjectLockMode, String> {
+    match value.to_uppercase().as_str() {
+        mode @ ("GOVERNANCE" | "COMPLIANCE") => Ok(ObjectLockMode::from(mode)),
+        _ => Err("object lock mode must be \"governance\" or \"compliance\"".to_string()),
+    }
+}
+
+/// The function `parse_storage_class_override` parses a storage class
+/// override supplied on the command line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: A regular expression and a storage class, separated by the last '='.
+/// 
+/// Returns:
+/// 
+/// The override, or an error if either part is invalid.
+fn parse_storage_class_override(value: &str) -> Result<StorageClassOverride, String> {
+    let (pattern, storage_class) = value.rsplit_once('=')
+        .ok_or("storage class override must be formatted as \"REGEX=CLASS\"")?;
+
+    Ok(StorageClassOverride {
+        pattern: Regex::new(pattern).map_err(|error| error.to_string())?,
+        storage_class: parse_storage_class(storage_class)?,
+    })
+}
+
+/// The function `parse_regex` parses a regular expression supplied on the
+/// command line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: The regular expression.
+/// 
+/// Returns:
+/// 
+/// The compiled regular expression, or an error if it is invalid.
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|error| error.to_string())
+}
+
+/// The function `parse_host_id` validates a host id supplied on the command
+/// line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: The supplied host id.
+/// 
+/// Returns:
+/// 
+/// The host id, or an error if it is empty or contains a reserved character.
+fn parse_host_id(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.starts_with('/') || value.contains(HOST_SEPARATOR) || value.contains(RESERVED_KEY_SEPARATOR) {
+        return Err(format!("host id must not be empty, start with '/', or contain '{HOST_SEPARATOR}' or '{RESERVED_KEY_SEPARATOR}'"));
+    }
+
+    Ok(value.to_string())
+}
+
+/// The function `parse_duration` parses a duration supplied on the command
+/// line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: A whole number followed by "s", "m", "h", "d" or "w", such as "36h".
+/// 
+/// Returns:
+/// 
+/// The duration, or an error if it is not recognised.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let error = || "duration must be a whole number followed by s, m, h, d or w, such as \"36h\"".to_string();
+
+    let (amount, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).ok_or_else(error)?);
+    let amount: i64 = amount.parse().map_err(|_| error())?;
+
+    let duration = match unit {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    };
+
+    duration.ok_or_else(error)
+}
+
+/// The function `parse_cron` parses a cron expression supplied on the command
+/// line.
+/// 
+/// Arguments:
+/// 
+/// * `value`: A cron expression with five fields, or six or seven fields
+/// starting with seconds.
+/// 
+/// Returns:
+/// 
+/// The schedule, or an error if the expression is invalid.
+fn parse_cron(value: &str) -> Result<Schedule, String> {
+    let expression = match value.split_whitespace().count() {
+        5 => format!("0 {value}"),
+        _ => value.to_string(),
+    };
+
+    Schedule::from_str(&expression).map_err(|error| error.to_string())
 }
 
 // GENERIC ARGUMENT STRUCTS
@@ -157,6 +965,18 @@ pub struct DatabaseArgs {
 
 impl From<BackupArgs> for DatabaseArgs {
     fn from(value: BackupArgs) -> Self {
+        DatabaseArgs {
+            db_engine: value.db_engine.unwrap_or_default(),
+            postgres_user: value.postgres_user.unwrap_or_default(),
+            postgres_password: value.postgres_password.unwrap_or_default(),
+            postgres_host: value.postgres_host.unwrap_or_default(),
+            postgres_db: value.postgres_db.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ClearDatabaseArgs> for DatabaseArgs {
+    fn from(value: ClearDatabaseArgs) -> Self {
         DatabaseArgs {
             db_engine: value.db_engine,
             postgres_user: value.postgres_user,
@@ -167,8 +987,20 @@ impl From<BackupArgs> for DatabaseArgs {
     }
 }
 
-impl From<ClearDatabaseArgs> for DatabaseArgs {
-    fn from(value: ClearDatabaseArgs) -> Self {
+impl From<RebuildStateArgs> for DatabaseArgs {
+    fn from(value: RebuildStateArgs) -> Self {
+        DatabaseArgs {
+            db_engine: value.db_engine.unwrap_or_default(),
+            postgres_user: value.postgres_user.unwrap_or_default(),
+            postgres_password: value.postgres_password.unwrap_or_default(),
+            postgres_host: value.postgres_host.unwrap_or_default(),
+            postgres_db: value.postgres_db.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ForceUnlockArgs> for DatabaseArgs {
+    fn from(value: ForceUnlockArgs) -> Self {
         DatabaseArgs {
             db_engine: value.db_engine,
             postgres_user: value.postgres_user,
@@ -183,13 +1015,22 @@ impl From<ClearDatabaseArgs> for DatabaseArgs {
 pub struct AwsArgs {
     pub bucket_name: String,
     pub dynamo_table: String,
+    pub table_schema: TableSchema,
+    pub storage_classes: StorageClasses,
+    pub object_lock: ObjectLockArgs,
 }
 
 impl From<BackupArgs> for AwsArgs {
     fn from(value: BackupArgs) -> Self {
         AwsArgs {
-            bucket_name: value.bucket_name,
-            dynamo_table: value.dynamo_table,
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: value.storage_classes,
+            object_lock: ObjectLockArgs {
+                retain_days: value.min_storage_duration,
+                ..value.object_lock
+            },
         }
     }
 }
@@ -197,8 +1038,59 @@ impl From<BackupArgs> for AwsArgs {
 impl From<RestoreArgs> for AwsArgs {
     fn from(value: RestoreArgs) -> Self {
         AwsArgs {
-            bucket_name: value.bucket_name,
-            dynamo_table: value.dynamo_table,
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+
+impl From<VerifyArgs> for AwsArgs {
+    fn from(value: VerifyArgs) -> Self {
+        AwsArgs {
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+
+impl From<RebuildStateArgs> for AwsArgs {
+    fn from(value: RebuildStateArgs) -> Self {
+        AwsArgs {
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+
+impl From<PruneArgs> for AwsArgs {
+    fn from(value: PruneArgs) -> Self {
+        AwsArgs {
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+
+impl From<LockStatusArgs> for AwsArgs {
+    fn from(value: LockStatusArgs) -> Self {
+        AwsArgs {
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
         }
     }
 }
@@ -206,8 +1098,46 @@ impl From<RestoreArgs> for AwsArgs {
 impl From<DeleteBackupArgs> for AwsArgs {
     fn from(value: DeleteBackupArgs) -> Self {
         AwsArgs {
-            bucket_name: value.bucket_name,
+            bucket_name: value.bucket_name.unwrap_or_default(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+impl From<CheckFreshnessArgs> for AwsArgs {
+    fn from(value: CheckFreshnessArgs) -> Self {
+        AwsArgs {
+            bucket_name: "".to_string(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+
+impl From<CleanDynamoArgs> for AwsArgs {
+    fn from(value: CleanDynamoArgs) -> Self {
+        AwsArgs {
+            bucket_name: "".to_string(),
+            dynamo_table: value.dynamo_table.unwrap_or_default(),
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
+        }
+    }
+}
+
+impl From<ForceUnlockArgs> for AwsArgs {
+    fn from(value: ForceUnlockArgs) -> Self {
+        AwsArgs {
+            bucket_name: "".to_string(),
             dynamo_table: value.dynamo_table,
+            table_schema: value.table_schema,
+            storage_classes: StorageClasses::default(),
+            object_lock: ObjectLockArgs::default(),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,99 @@
+use std::io::Error as IoError;
+
+use diesel::ConnectionError;
+use thiserror::Error;
+
+use crate::dynamodb::HashTrackerError;
+use crate::lock::LockError;
+use crate::s3::{S3DeleteError, S3GetError, S3PutError};
+use crate::storage::StorageError;
+
+// Exit code of a command which failed entirely
+pub const EXIT_FAILURE: u8 = 1;
+// Exit code of a command which could not start because of its configuration, matching clap's usage errors
+pub const EXIT_CONFIG_ERROR: u8 = 2;
+// Exit code of a command which failed for some, but not all, files
+pub const EXIT_PARTIAL_FAILURE: u8 = 3;
+// Exit code of a command which could not start because another backup holds the lock
+pub const EXIT_LOCKED: u8 = 4;
+// Exit code of a backup which aborted because its changes looked like a mass deletion
+pub const EXIT_ABORTED: u8 = 5;
+
+#[derive(Error, Debug)]
+pub enum GdaError {
+    #[error("ConfigError: {0}")]
+    ConfigError(String),
+
+    #[error("ConnectionError: {0}")]
+    ConnectionError(#[from] ConnectionError),
+
+    #[error("DieselError: {0}")]
+    DieselError(#[from] diesel::result::Error),
+
+    #[error("LockError: {0}")]
+    LockError(#[from] Box<LockError>),
+
+    #[error("StorageError: {0}")]
+    StorageError(#[from] Box<StorageError>),
+
+    #[error("HashTrackerError: {0}")]
+    HashTrackerError(#[from] Box<HashTrackerError>),
+
+    #[error("S3GetError: {0}")]
+    S3GetError(#[from] Box<S3GetError>),
+
+    #[error("S3PutError: {0}")]
+    S3PutError(#[from] Box<S3PutError>),
+
+    #[error("S3DeleteError: {0}")]
+    S3DeleteError(#[from] Box<S3DeleteError>),
+
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+
+    #[error("PartialFailure: {succeeded} succeeded, {failed} failed")]
+    PartialFailure { succeeded: usize, failed: usize },
+
+    #[error("TotalFailure: {0} failed")]
+    TotalFailure(usize),
+
+    #[error("SafetyAbort: {0}")]
+    SafetyAbort(String),
+}
+
+impl GdaError {
+
+    /// The function `from_counts` reports the outcome of a command which
+    /// processes many files, such as a backup or a restore.
+    ///
+    /// Arguments:
+    ///
+    /// * `succeeded`: The number of files which were processed.
+    /// * `failed`: The number of files which failed to be processed.
+    ///
+    /// Returns:
+    ///
+    /// The counts if nothing failed, `GdaError::TotalFailure` if nothing
+    /// succeeded, and `GdaError::PartialFailure` otherwise.
+    pub fn from_counts(succeeded: usize, failed: usize) -> Result<(usize, usize), GdaError> {
+        match (succeeded, failed) {
+            (succeeded, 0) => Ok((succeeded, 0)),
+            (0, failed) => Err(GdaError::TotalFailure(failed)),
+            (succeeded, failed) => Err(GdaError::PartialFailure { succeeded, failed }),
+        }
+    }
+
+    /// The function `exit_code` returns the code the process exits with when a
+    /// command fails with this error, so that cron jobs and monitoring can tell
+    /// failures apart.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            GdaError::ConfigError(_) => EXIT_CONFIG_ERROR,
+            GdaError::StorageError(error) if matches!(**error, StorageError::ConfigError(_)) => EXIT_CONFIG_ERROR,
+            GdaError::LockError(error) if matches!(**error, LockError::LocalStateLocked | LockError::IndexLocked | LockError::LeaseHeld { .. } | LockError::LeaseLost) => EXIT_LOCKED,
+            GdaError::PartialFailure { .. } => EXIT_PARTIAL_FAILURE,
+            GdaError::SafetyAbort(_) => EXIT_ABORTED,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use log::{debug, error};
+
+// Pings must never hold up a backup for long.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `Ping` enum is the stage of a run reported to a healthcheck URL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ping {
+    Start,
+    Success,
+    Failure,
+}
+
+/// The function `ping` reports the stage of a run to a healthchecks.io style
+/// URL. Starts are POSTed to `<url>/start`, successes to `<url>` and failures
+/// to `<url>/fail`. Failed pings are logged, never returned, so that an
+/// unreachable monitor cannot fail a backup.
+///
+/// Arguments:
+///
+/// * `url`: The ping URL of the check.
+/// * `ping`: The stage of the run.
+/// * `body`: The body of the ping, such as the report of the run.
+pub async fn ping(url: &str, ping: Ping, body: String) {
+    let url = url.trim_end_matches('/');
+    let url = match ping {
+        Ping::Start => format!("{url}/start"),
+        Ping::Success => url.to_string(),
+        Ping::Failure => format!("{url}/fail"),
+    };
+
+    debug!("Pinging healthcheck: {url}");
+
+    let result = reqwest::Client::new()
+        .post(&url)
+        .timeout(PING_TIMEOUT)
+        .body(body)
+        .send().await
+        .and_then(reqwest::Response::error_for_status);
+
+    if let Err(error) = result {
+        error!("Failed to ping healthcheck {url}: {:?}", error);
+    }
+}
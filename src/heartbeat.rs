@@ -0,0 +1,159 @@
+use std::collections::{BTreeSet, HashMap};
+
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+
+use crate::dynamodb::HashTrackerError;
+use crate::environment::{AwsArgs, CheckFreshnessArgs, Cli};
+use crate::report::{self, FileAction, FileReport};
+use crate::storage::{MetadataStore, StorageError};
+
+// DynamoDB item recording the last successful backup of every host. It can never collide with a hex encoded hash.
+const HEARTBEAT_KEY: &str = "heartbeat#backup";
+// Map attribute of the heartbeat item, from host to the time of its last successful backup.
+const HOSTS_ATTRIBUTE: &str = "hosts";
+/// The name of hosts which back up without a host id.
+pub const DEFAULT_HOST: &str = "default";
+
+/// The function `host_name` names a host in heartbeats.
+///
+/// Arguments:
+///
+/// * `host_id`: The host id of the backup, if it has one.
+pub fn host_name(host_id: Option<&str>) -> &str {
+    host_id.unwrap_or(DEFAULT_HOST)
+}
+
+/// The function `put` records the time of the last successful backup of a
+/// host, leaving the heartbeats of other hosts untouched.
+///
+/// Arguments:
+///
+/// * `aws_args`: Contains the DynamoDB table and its schema.
+/// * `client`: The DynamoDB client.
+/// * `host`: The name of the host.
+/// * `last_success`: When the backup succeeded.
+pub async fn put(aws_args: &AwsArgs, client: &Client, host: &str, last_success: DateTime<Utc>) -> Result<(), HashTrackerError> {
+    let key = aws_args.table_schema.key(HEARTBEAT_KEY);
+
+    // A nested attribute can only be set once its map exists
+    client.update_item()
+        .table_name(aws_args.dynamo_table.clone())
+        .set_key(Some(key.clone()))
+        .update_expression("SET #hosts = if_not_exists(#hosts, :empty)")
+        .expression_attribute_names("#hosts", HOSTS_ATTRIBUTE)
+        .expression_attribute_values(":empty", AttributeValue::M(HashMap::new()))
+        .send().await?;
+
+    client.update_item()
+        .table_name(aws_args.dynamo_table.clone())
+        .set_key(Some(key))
+        .update_expression("SET #hosts.#host = :last_success")
+        .expression_attribute_names("#hosts", HOSTS_ATTRIBUTE)
+        .expression_attribute_names("#host", host)
+        .expression_attribute_values(":last_success", AttributeValue::N(last_success.timestamp().to_string()))
+        .send().await?;
+
+    Ok(())
+}
+
+/// The function `get_all` reads the time of the last successful backup of
+/// every host.
+///
+/// Arguments:
+///
+/// * `aws_args`: Contains the DynamoDB table and its schema.
+/// * `client`: The DynamoDB client.
+///
+/// Returns:
+///
+/// The time of the last successful backup of each host, by host. Hosts which
+/// never succeeded are absent.
+pub async fn get_all(aws_args: &AwsArgs, client: &Client) -> Result<HashMap<String, DateTime<Utc>>, HashTrackerError> {
+    let item = client.get_item()
+        .table_name(aws_args.dynamo_table.clone())
+        .set_key(Some(aws_args.table_schema.key(HEARTBEAT_KEY)))
+        .consistent_read(true)
+        .send().await?
+        .item
+        .unwrap_or_default();
+
+    let Some(Ok(hosts)) = item.get(HOSTS_ATTRIBUTE).map(AttributeValue::as_m) else {
+        return Ok(HashMap::new());
+    };
+
+    Ok(hosts.iter()
+        .filter_map(|(host, value)| {
+            let seconds = value.as_n().ok()?.parse().ok()?;
+            Some((host.clone(), DateTime::from_timestamp(seconds, 0)?))
+        })
+        .collect())
+}
+
+/// The function `delete` permanently deletes the heartbeats of every host.
+///
+/// Arguments:
+///
+/// * `aws_args`: Contains the DynamoDB table and its schema.
+/// * `client`: The DynamoDB client.
+pub async fn delete(aws_args: &AwsArgs, client: &Client) -> Result<(), HashTrackerError> {
+    client.delete_item()
+        .table_name(aws_args.dynamo_table.clone())
+        .set_key(Some(aws_args.table_schema.key(HEARTBEAT_KEY)))
+        .send().await?;
+
+    Ok(())
+}
+
+/// The function `check_freshness` checks that the last successful backup of
+/// every host is recent enough. Each host is reported like a file, so that
+/// stale hosts are listed in the notification of the command.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line.
+/// * `args`: The maximum age and the hosts which must have backed up.
+/// * `metadata`: The metadata store which heartbeats are read from.
+///
+/// Returns:
+///
+/// The number of hosts which are fresh and stale, or the error encountered
+/// reading the heartbeats.
+pub async fn check_freshness(cli: Cli, args: CheckFreshnessArgs, metadata: &dyn MetadataStore) -> Result<(usize, usize), StorageError> {
+    let heartbeats = metadata.get_heartbeats().await?;
+    let oldest = Utc::now() - args.max_age;
+
+    let hosts: BTreeSet<&String> = heartbeats.keys().chain(&args.expected_hosts).collect();
+
+    let mut fresh = 0;
+    let mut stale = 0;
+
+    for host in hosts {
+        let mut file_report = FileReport::new(&cli, host.clone(), FileAction::CheckFreshness, None);
+
+        match heartbeats.get(host) {
+            Some(last_success) if *last_success >= oldest => {
+                info!("{host} is fresh. Last successful backup at {last_success}.");
+                fresh += 1;
+            },
+            Some(last_success) => {
+                warn!("{host} is stale. Last successful backup at {last_success}.");
+                file_report = file_report.failed(format!("Stale: last successful backup at {}", last_success.format("%Y-%m-%d %H:%M UTC")));
+                stale += 1;
+            },
+            None => {
+                warn!("{host} is stale. No successful backup recorded.");
+                file_report = file_report.failed("Missing: no successful backup recorded");
+                stale += 1;
+            },
+        }
+
+        report::file(&cli, &file_report);
+    }
+
+    info!("Freshness check complete: {fresh} fresh, {stale} stale.");
+
+    Ok((fresh, stale))
+}
@@ -0,0 +1,351 @@
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::vec;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::models::GlacierFile;
+use crate::restore;
+use crate::storage::{MetadataStore, ObjectStore, StorageError};
+
+/// The `IndexEntry` struct records a backed up file in an index, standing in
+/// for a row of the `glacier_state` table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub file_path: String,
+    pub modified: SystemTime,
+    /// The size of the file in bytes when it was last scanned, or 0 if the
+    /// index was rebuilt since.
+    pub size: u64,
+    pub file_hash: Option<String>,
+}
+
+impl IndexEntry {
+
+    /// The function `new` records a backed up file.
+    ///
+    /// Arguments:
+    ///
+    /// * `g_file`: The backed up file.
+    /// * `size`: The size of the file in bytes.
+    pub fn new(g_file: &GlacierFile, size: u64) -> IndexEntry {
+        IndexEntry {
+            file_path: g_file.file_path.clone(),
+            modified: g_file.modified,
+            size,
+            file_hash: g_file.file_hash.clone(),
+        }
+    }
+
+    /// The function `glacier_file` converts the entry into the file it
+    /// records.
+    pub fn glacier_file(self) -> GlacierFile {
+        GlacierFile {
+            file_path: self.file_path,
+            file_hash: self.file_hash,
+            modified: self.modified,
+        }
+    }
+}
+
+/// The function `path_cmp` orders paths component by component, which is the
+/// order a directory walk sorted by file name visits them in. Every index is
+/// sorted in this order, so that it can be merged with a walk in a single
+/// pass.
+///
+/// Arguments:
+///
+/// * `a`: The first path.
+/// * `b`: The second path.
+pub fn path_cmp(a: &str, b: &str) -> Ordering {
+    Path::new(a).cmp(Path::new(b))
+}
+
+/// The function `sibling` names a file kept next to an index, such as its
+/// lock file.
+///
+/// Arguments:
+///
+/// * `index_file`: The path of the index.
+/// * `extension`: Appended to the index's file name after a '.'.
+pub fn sibling(index_file: &Path, extension: &str) -> PathBuf {
+    let mut path = OsString::from(index_file.as_os_str());
+    path.push(".");
+    path.push(extension);
+
+    PathBuf::from(path)
+}
+
+/// The `IndexReader` struct reads the entries of an index in order, one line
+/// at a time, so that reading an index of millions of files takes little
+/// memory.
+pub struct IndexReader {
+    entries: Entries,
+}
+
+/// Where the entries of an index are read from.
+enum Entries {
+    File(Box<Lines<BufReader<GzDecoder<File>>>>),
+    Memory(vec::IntoIter<IndexEntry>),
+}
+
+impl IndexReader {
+
+    /// The function `open` opens an index for reading.
+    ///
+    /// Arguments:
+    ///
+    /// * `index_file`: The path of the index.
+    pub fn open(index_file: &Path) -> io::Result<IndexReader> {
+        let file = File::open(index_file)?;
+
+        Ok(IndexReader {
+            entries: Entries::File(Box::new(BufReader::new(GzDecoder::new(file)).lines())),
+        })
+    }
+
+    /// The function `empty` reads an index without any entries, such as the
+    /// index of a directory which was never backed up.
+    pub fn empty() -> IndexReader {
+        IndexReader::from_entries(vec![])
+    }
+
+    /// The function `from_entries` reads an index held in memory.
+    ///
+    /// Arguments:
+    ///
+    /// * `entries`: The entries of the index, in order.
+    pub fn from_entries(entries: Vec<IndexEntry>) -> IndexReader {
+        IndexReader {
+            entries: Entries::Memory(entries.into_iter()),
+        }
+    }
+}
+
+impl Iterator for IndexReader {
+    type Item = io::Result<IndexEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.entries {
+            Entries::File(lines) => {
+                let line = lines.next()?;
+                Some(line.and_then(|line| Ok(serde_json::from_str(&line)?)))
+            },
+            Entries::Memory(entries) => entries.next().map(Ok),
+        }
+    }
+}
+
+/// The `IndexWriter` struct writes an index as gzip compressed JSON lines,
+/// sorted by `path_cmp`. Entries are written to a temporary file which only
+/// replaces the index once it is complete, so that a backup which is
+/// interrupted leaves the previous index intact.
+pub struct IndexWriter {
+    output: Output,
+    last: Option<String>,
+}
+
+/// Where the entries of an index are written to.
+enum Output {
+    File {
+        index_file: PathBuf,
+        partial: PathBuf,
+        encoder: GzEncoder<BufWriter<File>>,
+    },
+    Memory(Vec<IndexEntry>),
+    Discard,
+}
+
+impl IndexWriter {
+
+    /// The function `create` starts writing an index.
+    ///
+    /// Arguments:
+    ///
+    /// * `index_file`: The path the index is written to when it is finished.
+    pub fn create(index_file: &Path) -> io::Result<IndexWriter> {
+        let partial = sibling(index_file, "partial");
+        let file = File::create(&partial)?;
+
+        Ok(IndexWriter {
+            output: Output::File {
+                index_file: index_file.to_path_buf(),
+                partial,
+                encoder: GzEncoder::new(BufWriter::new(file), Compression::default()),
+            },
+            last: None,
+        })
+    }
+
+    /// The function `memory` starts writing an index which is kept in memory,
+    /// such as by a dry run, which must not write to disk.
+    pub fn memory() -> IndexWriter {
+        IndexWriter { output: Output::Memory(vec![]), last: None }
+    }
+
+    /// The function `discard` starts writing an index which is never read,
+    /// such as the next index of a dry run.
+    pub fn discard() -> IndexWriter {
+        IndexWriter { output: Output::Discard, last: None }
+    }
+
+    /// The function `write` appends an entry to the index.
+    ///
+    /// Arguments:
+    ///
+    /// * `entry`: The entry, which must come after every entry written before
+    /// it.
+    ///
+    /// Returns:
+    ///
+    /// `ErrorKind::InvalidInput` if the entry is out of order, or the error
+    /// encountered writing it.
+    pub fn write(&mut self, entry: &IndexEntry) -> io::Result<()> {
+        if let Some(last) = &self.last {
+            if path_cmp(last, &entry.file_path) != Ordering::Less {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("Index entry {} is out of order.", entry.file_path)));
+            }
+        }
+
+        match &mut self.output {
+            Output::File { encoder, .. } => {
+                serde_json::to_writer(&mut *encoder, entry)?;
+                encoder.write_all(b"\n")?;
+            },
+            Output::Memory(entries) => entries.push(entry.clone()),
+            Output::Discard => (),
+        }
+
+        self.last = Some(entry.file_path.clone());
+
+        Ok(())
+    }
+
+    /// The function `finish` completes the index. An index written to disk
+    /// is flushed and atomically replaces any previous index.
+    ///
+    /// Returns:
+    ///
+    /// A reader of the finished index, or the error encountered writing it.
+    pub fn finish(self) -> io::Result<IndexReader> {
+        match self.output {
+            Output::File { index_file, partial, encoder } => {
+                let file = encoder.finish()?
+                    .into_inner()
+                    .map_err(io::IntoInnerError::into_error)?;
+
+                file.sync_all()?;
+                fs::rename(&partial, &index_file)?;
+
+                IndexReader::open(&index_file)
+            },
+            Output::Memory(entries) => Ok(IndexReader::from_entries(entries)),
+            Output::Discard => Ok(IndexReader::empty()),
+        }
+    }
+}
+
+/// The function `merge` merges two indexes into a third. If both record the
+/// same file, the entry of the first is kept.
+///
+/// Arguments:
+///
+/// * `first`: The entries of the first index, in order.
+/// * `second`: The entries of the second index, in order.
+/// * `writer`: The index the entries are written to.
+pub fn merge(first: impl Iterator<Item = io::Result<IndexEntry>>, second: impl Iterator<Item = io::Result<IndexEntry>>, mut writer: IndexWriter) -> io::Result<()> {
+    let mut first = first.peekable();
+    let mut second = second.peekable();
+
+    loop {
+        let ordering = match (first.peek(), second.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(Ok(a)), Some(Ok(b))) => path_cmp(&a.file_path, &b.file_path),
+            // Surface the error of either reader
+            (Some(Err(_)), _) => Ordering::Less,
+            (_, Some(Err(_))) => Ordering::Greater,
+        };
+
+        let entry = match ordering {
+            Ordering::Less => first.next(),
+            Ordering::Greater => second.next(),
+            Ordering::Equal => {
+                second.next();
+                first.next()
+            },
+        };
+
+        if let Some(entry) = entry {
+            writer.write(&entry?)?;
+        }
+    }
+
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// The function `rebuild` writes the index of a host from its hash trackers,
+/// so that a lost index does not cause every file to be backed up again.
+///
+/// Arguments:
+///
+/// * `host_id`: The host whose files are indexed.
+/// * `index_file`: The path of the index.
+/// * `objects`: The object store, used to list the modified time of each object.
+/// * `metadata`: The metadata store, used to read every hash tracker.
+///
+/// Returns:
+///
+/// The number of files indexed, or the error encountered reading the stores
+/// or writing the index.
+pub async fn rebuild(host_id: Option<&str>, index_file: &Path, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<usize, StorageError> {
+    let entries = rebuilt_entries(host_id, objects, metadata).await?;
+
+    let mut writer = IndexWriter::create(index_file)?;
+    for entry in &entries {
+        writer.write(entry)?;
+    }
+    writer.finish()?;
+
+    info!("Index rebuilt with {} files.", entries.len());
+
+    Ok(entries.len())
+}
+
+/// The function `rebuilt_entries` reads the entries a rebuilt index of a host
+/// would have, without writing it. The files are listed by
+/// `restore::tracked_files`, as when rebuilding the local database.
+///
+/// Arguments:
+///
+/// * `host_id`: The host whose files are indexed.
+/// * `objects`: The object store, used to list the modified time of each object.
+/// * `metadata`: The metadata store, used to read every hash tracker.
+///
+/// Returns:
+///
+/// The entries, in order, or the error encountered reading the stores.
+pub async fn rebuilt_entries(host_id: Option<&str>, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<Vec<IndexEntry>, StorageError> {
+    let modified_times = objects.list().await?;
+    let hash_trackers = metadata.get_all().await?;
+
+    let mut entries: Vec<IndexEntry> = restore::tracked_files(host_id, &hash_trackers, &modified_times)
+        .map(|g_file| IndexEntry::new(&g_file, 0))
+        .collect();
+
+    entries.sort_by(|a, b| path_cmp(&a.file_path, &b.file_path));
+    entries.dedup_by(|a, b| a.file_path == b.file_path);
+
+    Ok(entries)
+}
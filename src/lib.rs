@@ -6,6 +6,25 @@ pub mod aws;
 pub mod s3;
 pub mod dynamodb;
 pub mod environment;
+pub mod lock;
+pub mod storage;
+pub mod local;
+pub mod checksum;
+pub mod delete;
+pub mod error;
+pub mod report;
+pub mod verify;
+pub mod metrics;
+pub mod notify;
+pub mod object_lock;
+pub mod smtp;
+pub mod heartbeat;
+pub mod healthcheck;
+pub mod progress;
+pub mod index;
+pub mod plan;
+pub mod retention;
+pub mod safety;
 
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
@@ -40,8 +59,9 @@ joinable!(crate::schema::local_state -> crate::schema::glacier_state (file_path)
 /// Returns:
 /// 
 /// The function `establish_connection` returns a `PgConnection` object, which
-/// represents a connection to a PostgreSQL database.
-pub fn establish_connection(args: DatabaseArgs) -> PgConnection {
+/// represents a connection to a PostgreSQL database, or the `ConnectionError`
+/// encountered connecting to it.
+pub fn establish_connection(args: DatabaseArgs) -> ConnectionResult<PgConnection> {
     dotenv().ok();
 
     let db_engine = args.db_engine.clone();
@@ -52,7 +72,6 @@ pub fn establish_connection(args: DatabaseArgs) -> PgConnection {
 
     let postgres_url: String = format!("{db_engine}://{postgres_user}:{postgres_password}@{postgres_host}/{postgres_db}");
     PgConnection::establish(&postgres_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", postgres_url))
 }
 
 /// The function checks if the glacier_state table is empty in a Rust application.
@@ -69,17 +88,36 @@ pub fn establish_connection(args: DatabaseArgs) -> PgConnection {
 /// the `glacier_state` table in the PostgreSQL database is empty or not. If the
 /// count of records in the `glacier_state` table is equal to 0, then it returns
 /// `true`, indicating that the table is empty. Otherwise, it returns `false`,
-/// indicating that the table is not empty.
-pub fn glacier_state_is_empty(conn: &mut PgConnection) -> bool {
+/// indicating that the table is not empty. Errors querying the table are
+/// returned.
+pub fn glacier_state_is_empty(conn: &mut PgConnection) -> QueryResult<bool> {
     let glacier_file_count: usize = glacier_state.limit(1)
-        .execute(conn)
-        .expect("Error when checking if glacier_state is populated.");
+        .execute(conn)?;
 
-    glacier_file_count == 0
+    Ok(glacier_file_count == 0)
+}
+
+/// The function `count_glacier_files` counts the files recorded by the
+/// previous backup in the `glacier_state` table.
+///
+/// Arguments:
+///
+/// * `conn`: The connection to the local database.
+///
+/// Returns:
+///
+/// The number of files, or the error encountered querying the table.
+pub fn count_glacier_files(conn: &mut PgConnection) -> QueryResult<usize> {
+    let glacier_file_count: i64 = glacier_state.count()
+        .get_result(conn)?;
+
+    Ok(glacier_file_count as usize)
 }
 
 /// The function `clear_local_state` deletes all records from the `local_state`
-/// table in a PostgreSQL database using Diesel in Rust.
+/// table in a PostgreSQL database using Diesel in Rust. The table is
+/// truncated, which unlike deleting every row is fast however many files were
+/// loaded.
 /// 
 /// Arguments:
 /// 
@@ -87,10 +125,9 @@ pub fn glacier_state_is_empty(conn: &mut PgConnection) -> bool {
 /// reference to a `PgConnection` object. This object represents a connection to a
 /// PostgreSQL database and is used to execute database operations such as querying
 /// or modifying data.
-pub fn clear_local_state(conn: &mut PgConnection) {
-    diesel::delete(local_state)
+pub fn clear_local_state(conn: &mut PgConnection) -> QueryResult<usize> {
+    diesel::sql_query("TRUNCATE local_state")
         .execute(conn)
-        .expect("Error clearing local_state.");
 }
 
 /// The function `clear_glacier_state` deletes all records from the `glacier_state`
@@ -102,10 +139,9 @@ pub fn clear_local_state(conn: &mut PgConnection) {
 /// object, which represents a connection to a PostgreSQL database. This connection
 /// is used to interact with the database and perform operations such as deleting
 /// records from the `glacier_state` table in this case.
-pub fn clear_glacier_state(conn: &mut PgConnection) {
+pub fn clear_glacier_state(conn: &mut PgConnection) -> QueryResult<usize> {
     diesel::delete(glacier_state)
         .execute(conn)
-        .expect("Error clearing glacier_state.");
 }
 
 /// This Rust function retrieves a GlacierFile from a database connection based on a
@@ -129,8 +165,8 @@ pub fn get_glacier_file(conn: &mut PgConnection, file_path: String) -> Result<Gl
         .first(conn)
 }
 
-/// The function `get_new_files` retrieves local files that do not have a
-/// corresponding entry in the glacier state table.
+/// The function `get_new_files` retrieves a page of local files that do not
+/// have a corresponding entry in the glacier state table, ordered by path.
 /// 
 /// Arguments:
 /// 
@@ -138,22 +174,30 @@ pub fn get_glacier_file(conn: &mut PgConnection, file_path: String) -> Result<Gl
 /// is a connection to a PostgreSQL database. The function `get_new_files` is using
 /// this connection to query the database for new files that have not been archived
 /// in the Glacier storage.
+/// * `after`: The path of the last file of the previous page, or "" for the
+/// first page. Pages are keyed by path rather than offset, so files saved to
+/// the glacier state table between pages never cause others to be skipped.
+/// * `limit`: The maximum number of files in the page.
 /// 
 /// Returns:
 /// 
-/// A vector of `LocalFile` instances is being returned.
-pub fn get_new_files(conn: &mut PgConnection) -> Vec<LocalFile> {
+/// A vector of `LocalFile` instances is being returned, or the error
+/// encountered querying the database. It is empty once every page was read.
+pub fn get_new_files(conn: &mut PgConnection, after: &str, limit: i64) -> QueryResult<Vec<LocalFile>> {
     let join = local_state.left_join(glacier_state);
 
     join
         .filter(glacier_file_path.is_null())
+        .filter(local_file_path.gt(after))
+        .order(local_file_path)
+        .limit(limit)
         .select(LocalFile::as_select())
         .load(conn)
-        .expect("Error getting new files.")
 }
 
-/// This Rust function retrieves a list of local files that have been modified more
-/// recently than their corresponding files in a glacier state.
+/// This Rust function retrieves a page of local files that have been modified
+/// more recently than their corresponding files in a glacier state, ordered by
+/// path.
 /// 
 /// Arguments:
 /// 
@@ -161,39 +205,51 @@ pub fn get_new_files(conn: &mut PgConnection) -> Vec<LocalFile> {
 /// reference to a `PgConnection` object, which represents a connection to a
 /// PostgreSQL database. This connection is used to interact with the database to
 /// retrieve information about changed files.
+/// * `after`: The path of the last file of the previous page, or "" for the
+/// first page.
+/// * `limit`: The maximum number of files in the page.
 /// 
 /// Returns:
 /// 
-/// A vector of `LocalFile` instances representing the files that have been changed
-/// locally compared to their corresponding files in the glacier state.
-pub fn get_changed_files(conn: &mut PgConnection) -> Vec<LocalFile> {
+/// Each changed file along with its entry in the glacier state table, or the
+/// error encountered querying the database. It is empty once every page was
+/// read.
+pub fn get_changed_files(conn: &mut PgConnection, after: &str, limit: i64) -> QueryResult<Vec<(LocalFile, GlacierFile)>> {
     local_state
         .inner_join(glacier_state.on(glacier_file_path.eq(local_file_path)))
         .filter(glacier_modified.lt(local_modified))
-        .select(LocalFile::as_select())
+        .filter(local_file_path.gt(after))
+        .order(local_file_path)
+        .limit(limit)
+        .select((LocalFile::as_select(), GlacierFile::as_select()))
         .load(conn)
-        .expect("Error getting updated files.")
 }
 
-/// This Rust function retrieves missing files by performing a left join and
-/// filtering for null local file paths.
+/// This Rust function retrieves a page of missing files by performing a left
+/// join and filtering for null local file paths, ordered by path.
 /// 
 /// Arguments:
 /// 
 /// * `conn`: The `conn` parameter is a mutable reference to a `PgConnection`, which
 /// is a connection to a PostgreSQL database. The function `get_missing_files` is
 /// using this connection to query the database for missing files.
+/// * `after`: The path of the last file of the previous page, or "" for the
+/// first page.
+/// * `limit`: The maximum number of files in the page.
 /// 
 /// Returns:
 /// 
 /// A vector of `GlacierFile` objects representing the missing files is being
-/// returned.
-pub fn get_missing_files(conn: &mut PgConnection) -> Vec<GlacierFile> {
+/// returned, or the error encountered querying the database. It is empty once
+/// every page was read.
+pub fn get_missing_files(conn: &mut PgConnection, after: &str, limit: i64) -> QueryResult<Vec<GlacierFile>> {
     let join = glacier_state.left_join(local_state);
 
     join
         .filter(local_file_path.is_null())
+        .filter(glacier_file_path.gt(after))
+        .order(glacier_file_path)
+        .limit(limit)
         .select(GlacierFile::as_select())
         .load(conn)
-        .expect("Error getting deleted files.")
-}
\ No newline at end of file
+}
@@ -0,0 +1,386 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, create_dir_all};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum;
+use crate::dynamodb::HashTracker;
+use crate::environment::Cli;
+use crate::progress;
+use crate::storage::{MetadataStore, ObjectInfo, ObjectLockInfo, ObjectStore, ObjectVersion, StorageError};
+
+// Directories of the local backend, relative to its root.
+const OBJECTS_DIR: &str = "objects";
+const DELETED_DIR: &str = "deleted";
+const TRACKERS_DIR: &str = "trackers";
+// File of the local backend recording the last successful backup of every host.
+const HEARTBEATS_FILE: &str = "heartbeats.json";
+
+/// The `LocalObjectStore` struct stores objects as files named after their
+/// hash. Deleted objects are moved to a separate directory, standing in for
+/// the noncurrent versions of a versioned S3 bucket, so that they can be
+/// undeleted.
+pub struct LocalObjectStore {
+    objects: PathBuf,
+    deleted: PathBuf,
+}
+
+impl LocalObjectStore {
+
+    /// The function `new` opens the object store of a local backend, creating
+    /// its directories if they do not exist.
+    ///
+    /// Arguments:
+    ///
+    /// * `root`: The directory of the local backend.
+    pub fn new(root: &Path) -> Result<LocalObjectStore, StorageError> {
+        let store = LocalObjectStore {
+            objects: root.join(OBJECTS_DIR),
+            deleted: root.join(DELETED_DIR),
+        };
+
+        create_dir_all(&store.objects)?;
+        create_dir_all(&store.deleted)?;
+
+        Ok(store)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn put(&self, key: String, file_path: String) -> Result<String, StorageError> {
+        // Copy to a temporary file first, so that a partial copy is never mistaken for an object
+        let partial = self.objects.join(format!(".{key}.partial"));
+        progress::transferred(fs::copy(file_path, &partial)?);
+
+        // Hash the copy rather than the source, so that a corrupted copy is caught on restore
+        let checksum = checksum::sha256_file(&partial.to_string_lossy())?;
+        fs::rename(partial, self.objects.join(&key))?;
+
+        remove_if_exists(&self.deleted.join(key))?;
+
+        Ok(checksum)
+    }
+
+    async fn undelete(&self, key: String) -> Result<(), StorageError> {
+        Ok(fs::rename(self.deleted.join(&key), self.objects.join(key))?)
+    }
+
+    async fn delete(&self, key: String) -> Result<(), StorageError> {
+        Ok(fs::rename(self.objects.join(&key), self.deleted.join(key))?)
+    }
+
+    async fn get(&self, cli: Cli, key: String, prefix: String, files: Vec<String>) -> Result<Vec<String>, StorageError> {
+        // A dry run never writes to disk
+        if cli.dry_run {
+            return Ok(files);
+        }
+
+        let mut object = None;
+
+        for file in files.iter() {
+            let file = prefix.clone() + file;
+            if let Some((dir, _)) = file.rsplit_once('/') {
+                create_dir_all(dir)?;
+            }
+
+            // Copy the object once, and every other file from the first copy
+            match &object {
+                None => progress::transferred(fs::copy(self.objects.join(&key), &file)?),
+                Some(first_file) => { fs::copy(first_file, &file)?; },
+            };
+
+            object.get_or_insert(file);
+        }
+
+        Ok(files)
+    }
+
+    async fn head(&self, key: String) -> Result<Option<ObjectInfo>, StorageError> {
+        let object = self.objects.join(key);
+
+        let metadata = match fs::metadata(&object) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Some(ObjectInfo {
+            size: metadata.len(),
+            checksum: Some(checksum::sha256_file(&object.to_string_lossy())?),
+            lock: ObjectLockInfo::default(),
+        }))
+    }
+
+    async fn list(&self) -> Result<HashMap<String, SystemTime>, StorageError> {
+        let mut objects = HashMap::new();
+
+        for entry in fs::read_dir(&self.objects)? {
+            let entry = entry?;
+            let key = entry.file_name().to_string_lossy().to_string();
+
+            if !key.starts_with('.') {
+                objects.insert(key, entry.metadata()?.modified()?);
+            }
+        }
+
+        Ok(objects)
+    }
+
+    // An object is its current version, and a deleted object its only noncurrent version
+    async fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, StorageError> {
+        let mut versions = vec![];
+
+        for (dir, version_id) in [(&self.objects, None), (&self.deleted, Some(DELETED_DIR))] {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let key = entry.file_name().to_string_lossy().to_string();
+
+                if key.starts_with('.') || !key.starts_with(prefix) {
+                    continue;
+                }
+
+                let metadata = entry.metadata()?;
+                versions.push(ObjectVersion {
+                    key,
+                    version_id: version_id.map(str::to_string),
+                    size: metadata.len(),
+                    last_modified: metadata.modified()?.into(),
+                    storage_class: None,
+                    is_latest: version_id.is_none(),
+                    is_delete_marker: false,
+                });
+            }
+        }
+
+        Ok(versions)
+    }
+
+    async fn delete_versions(&self, versions: Vec<ObjectVersion>) -> Vec<(ObjectVersion, Result<(), StorageError>)> {
+        versions.into_iter()
+            .map(|version| {
+                let dir = match version.version_id {
+                    None => &self.objects,
+                    Some(_) => &self.deleted,
+                };
+                let result = remove_if_exists(&dir.join(&version.key));
+
+                (version, result)
+            })
+            .collect()
+    }
+
+    // Files of a local backend cannot be locked
+    async fn lock_configuration(&self) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+}
+
+/// The `TrackerRecord` struct is the JSON representation of a hash tracker in
+/// the local backend.
+#[derive(Serialize, Deserialize)]
+struct TrackerRecord {
+    expiration: i64,
+    file_names: BTreeSet<String>,
+    version: u64,
+    #[serde(default)]
+    checksum: Option<String>,
+}
+
+/// The `LocalMetadataStore` struct stores each hash tracker as a JSON file
+/// named after its hash. It assumes a single writer, which the local database
+/// lock already guarantees for backups from one host.
+pub struct LocalMetadataStore {
+    trackers: PathBuf,
+    heartbeats: PathBuf,
+}
+
+impl LocalMetadataStore {
+
+    /// The function `new` opens the metadata store of a local backend,
+    /// creating its directory if it does not exist.
+    ///
+    /// Arguments:
+    ///
+    /// * `root`: The directory of the local backend.
+    pub fn new(root: &Path) -> Result<LocalMetadataStore, StorageError> {
+        let store = LocalMetadataStore {
+            trackers: root.join(TRACKERS_DIR),
+            heartbeats: root.join(HEARTBEATS_FILE),
+        };
+
+        create_dir_all(&store.trackers)?;
+
+        Ok(store)
+    }
+
+    /// The function `path` returns the file storing a hash tracker.
+    fn path(&self, hash: &str) -> PathBuf {
+        self.trackers.join(format!("{hash}.json"))
+    }
+
+    /// The function `read` reads the record of a hash tracker.
+    ///
+    /// Returns:
+    ///
+    /// `Ok(None)` if the hash has no tracker.
+    fn read(&self, hash: &str) -> Result<Option<TrackerRecord>, StorageError> {
+        match fs::read(self.path(hash)) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// The function `load` reads a hash tracker.
+    ///
+    /// Returns:
+    ///
+    /// `Ok(None)` if the hash has no tracker.
+    fn load(&self, hash: &str) -> Result<Option<HashTracker>, StorageError> {
+        Ok(self.read(hash)?.map(|record| {
+            let mut hash_tracker = HashTracker::import(
+                hash.to_string(),
+                DateTime::from_timestamp(record.expiration, 0).unwrap_or_default(),
+                record.file_names.into_iter().collect(),
+                record.version,
+                0,
+            );
+            hash_tracker.checksum = record.checksum;
+            hash_tracker
+        }))
+    }
+
+    /// The function `write` replaces the record of a hash tracker, through a
+    /// temporary file so that an interrupted write never corrupts it.
+    fn write(&self, hash: &str, record: &TrackerRecord) -> Result<(), StorageError> {
+        let partial = self.trackers.join(format!(".{hash}.partial"));
+        fs::write(&partial, serde_json::to_vec(record)?)?;
+        fs::rename(partial, self.path(hash))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetadataStore for LocalMetadataStore {
+    async fn get_many(&self, hashes: HashSet<String>) -> Result<HashMap<String, HashTracker>, StorageError> {
+        let mut hash_trackers = HashMap::new();
+
+        for hash in hashes {
+            if let Some(hash_tracker) = self.load(&hash)? {
+                hash_trackers.insert(hash, hash_tracker);
+            }
+        }
+
+        Ok(hash_trackers)
+    }
+
+    async fn get_all(&self) -> Result<Vec<HashTracker>, StorageError> {
+        let mut hash_trackers = vec![];
+
+        for entry in fs::read_dir(&self.trackers)? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+
+            if let Some(hash) = file_name.strip_suffix(".json").filter(|hash| !hash.starts_with('.')) {
+                hash_trackers.extend(self.load(hash)?);
+            }
+        }
+
+        Ok(hash_trackers)
+    }
+
+    async fn update(&self, hash_tracker: &mut HashTracker) -> Result<(), StorageError> {
+        let hash = hash_tracker.hash.clone();
+        let (added, removed) = hash_tracker.take_changes();
+
+        let mut record = self.read(&hash)?.unwrap_or(TrackerRecord {
+            expiration: hash_tracker.expiration.timestamp(),
+            file_names: BTreeSet::new(),
+            version: 0,
+            checksum: None,
+        });
+
+        record.file_names.extend(added);
+        record.file_names.retain(|file_name| !removed.contains(file_name));
+        record.expiration = hash_tracker.expiration.timestamp();
+        record.version = hash_tracker.version().max(record.version) + 1;
+        record.checksum = hash_tracker.checksum.clone().or(record.checksum);
+
+        if record.file_names.is_empty() && hash_tracker.is_expired() {
+            remove_if_exists(&self.path(&hash))?;
+            record.version = 0;
+        }
+        else {
+            self.write(&hash, &record)?;
+        }
+
+        let checksum = record.checksum.take();
+        *hash_tracker = HashTracker::import(hash, hash_tracker.expiration, record.file_names.into_iter().collect(), record.version, 0);
+        hash_tracker.checksum = checksum;
+
+        Ok(())
+    }
+
+    async fn clean(&self) -> Result<(usize, usize), StorageError> {
+        let mut deleted = 0;
+
+        for hash_tracker in self.get_all().await? {
+            if !hash_tracker.has_files() && hash_tracker.is_expired() {
+                remove_if_exists(&self.path(&hash_tracker.hash))?;
+                deleted += 1;
+            }
+        }
+
+        Ok((deleted, 0))
+    }
+
+    async fn put_heartbeat(&self, host: &str, last_success: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut heartbeats = self.get_heartbeats().await?;
+        heartbeats.insert(host.to_string(), last_success);
+
+        let partial = self.heartbeats.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(&heartbeats)?)?;
+        fs::rename(partial, &self.heartbeats)?;
+
+        Ok(())
+    }
+
+    async fn get_heartbeats(&self) -> Result<HashMap<String, DateTime<Utc>>, StorageError> {
+        match fs::read(&self.heartbeats) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn delete_all(&self) -> Result<(), StorageError> {
+        fs::remove_dir_all(&self.trackers)?;
+        create_dir_all(&self.trackers)?;
+        remove_if_exists(&self.heartbeats)?;
+
+        Ok(())
+    }
+
+    async fn delete_many(&self, hashes: HashSet<String>) -> Result<(), StorageError> {
+        for hash in hashes {
+            remove_if_exists(&self.path(&hash))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The function `remove_if_exists` deletes a file, ignoring files which do not
+/// exist.
+fn remove_if_exists(path: &Path) -> Result<(), StorageError> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
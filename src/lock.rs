@@ -0,0 +1,468 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool};
+use log::{debug, error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+
+use crate::dynamodb::RESERVED_KEY_SEPARATOR;
+use crate::environment::AwsArgs;
+use crate::index;
+
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::operation::delete_item::DeleteItemError;
+use aws_sdk_dynamodb::operation::get_item::GetItemError;
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_smithy_runtime_api::client::result::SdkError;
+use aws_smithy_runtime_api::http::Response;
+
+use thiserror::Error;
+
+// Key of the PostgreSQL advisory lock guarding local_state and glacier_state ("GDAB").
+const ADVISORY_LOCK_KEY: i64 = 0x4744_4142;
+// DynamoDB item used as a lease. It can never collide with a hex encoded hash.
+const LEASE_KEY: &str = "lock#backup";
+const OWNER_KEY: &str = "owner";
+// How often to retry a held lock when --wait-for-lock is supplied.
+const LOCK_POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("The local database is locked by another backup. Use --wait-for-lock to wait for it, or force-unlock to release it.")]
+    LocalStateLocked,
+
+    #[error("The index is locked by another backup. Use --wait-for-lock to wait for it.")]
+    IndexLocked,
+
+    #[error("The backup lease is held by {owner} until {expiration}. Use --wait-for-lock to wait for it, or force-unlock to release it.")]
+    LeaseHeld { owner: String, expiration: DateTime<Utc> },
+
+    #[error("The backup lease was taken over or expired, so another backup may be running. The backup stopped.")]
+    LeaseLost,
+
+    #[error("DieselError")]
+    DieselError(#[from] diesel::result::Error),
+
+    #[error("IoError")]
+    IoError(#[from] IoError),
+
+    #[error("DynamoDbSdkErrorGet")]
+    DynamoDbSdkErrorGet(#[from] SdkError<GetItemError, Response>),
+
+    #[error("DynamoDbSdkErrorPut")]
+    DynamoDbSdkErrorPut(#[from] SdkError<PutItemError, Response>),
+
+    #[error("DynamoDbSdkErrorDelete")]
+    DynamoDbSdkErrorDelete(#[from] SdkError<DeleteItemError, Response>),
+}
+
+#[derive(QueryableByName)]
+struct AdvisoryLock {
+    #[diesel(sql_type = Bool)]
+    locked: bool,
+}
+
+#[derive(QueryableByName)]
+struct TerminatedSessions {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+/// The function `owner_id` identifies this process for the purpose of holding
+/// a lease.
+///
+/// Returns:
+///
+/// A string in the form `hostname:pid`.
+pub fn owner_id() -> String {
+    let host = hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or("unknown".to_string());
+
+    format!("{host}:{}", process::id())
+}
+
+/// The function `lock_local_state` takes a session level PostgreSQL advisory
+/// lock which prevents two backups from modifying `local_state` and
+/// `glacier_state` at the same time. The lock is released by
+/// `unlock_local_state`, or when the connection is closed.
+///
+/// Arguments:
+///
+/// * `conn`: The connection which will hold the lock.
+/// * `deadline`: If supplied, the lock will be retried until this instant
+/// instead of failing immediately.
+///
+/// Returns:
+///
+/// `LockError::LocalStateLocked` if the lock is held by another session.
+pub async fn lock_local_state(conn: &mut PgConnection, deadline: Option<Instant>) -> Result<(), LockError> {
+    loop {
+        let lock: AdvisoryLock = diesel::sql_query("SELECT pg_try_advisory_lock($1) AS locked")
+            .bind::<BigInt, _>(ADVISORY_LOCK_KEY)
+            .get_result(conn)?;
+
+        if lock.locked {
+            debug!("Acquired local database lock.");
+            return Ok(());
+        }
+
+        if !wait_for_retry(deadline).await {
+            return Err(LockError::LocalStateLocked);
+        }
+
+        info!("Local database is locked by another backup. Waiting...");
+    }
+}
+
+/// The function `unlock_local_state` releases the advisory lock taken by
+/// `lock_local_state`.
+///
+/// Arguments:
+///
+/// * `conn`: The connection which holds the lock.
+pub fn unlock_local_state(conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    diesel::sql_query("SELECT pg_advisory_unlock($1) AS locked")
+        .bind::<BigInt, _>(ADVISORY_LOCK_KEY)
+        .get_result::<AdvisoryLock>(conn)?;
+
+    Ok(())
+}
+
+/// The function `force_unlock_local_state` terminates every other PostgreSQL
+/// session holding the local database lock.
+///
+/// Arguments:
+///
+/// * `conn`: A connection to the local database.
+///
+/// Returns:
+///
+/// The number of sessions which were terminated.
+pub fn force_unlock_local_state(conn: &mut PgConnection) -> Result<i64, diesel::result::Error> {
+    // A bigint advisory lock is stored as (classid = high bits, objid = low bits, objsubid = 1).
+    let terminated: TerminatedSessions = diesel::sql_query(
+        "SELECT COUNT(pg_terminate_backend(pid)) AS count FROM pg_locks \
+        WHERE locktype = 'advisory' AND classid::bigint = ($1 >> 32) AND objid::bigint = ($1 & 4294967295) \
+        AND objsubid = 1 AND pid <> pg_backend_pid()")
+        .bind::<BigInt, _>(ADVISORY_LOCK_KEY)
+        .get_result(conn)?;
+
+    Ok(terminated.count)
+}
+
+/// The `IndexLock` struct holds an exclusive lock on the lock file of an
+/// index, which prevents two backups from reading and replacing the index at
+/// the same time. The lock is released when it is dropped, or by the operating
+/// system if the process dies, so it never needs to be forcibly released.
+pub struct IndexLock {
+    index_file: PathBuf,
+    _file: File,
+}
+
+impl IndexLock {
+
+    /// The function `index_file` returns the path of the locked index.
+    pub fn index_file(&self) -> &Path {
+        &self.index_file
+    }
+}
+
+/// The function `lock_index` locks an index, creating its directory if it
+/// does not exist.
+///
+/// Arguments:
+///
+/// * `index_file`: The path of the index.
+/// * `deadline`: If supplied, the lock will be retried until this instant
+/// instead of failing immediately.
+///
+/// Returns:
+///
+/// The held `IndexLock`, or `LockError::IndexLocked` if another process holds
+/// it.
+pub async fn lock_index(index_file: &Path, deadline: Option<Instant>) -> Result<IndexLock, LockError> {
+    if let Some(dir) = index_file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(index::sibling(index_file, "lock"))?;
+
+    loop {
+        match file.try_lock() {
+            Ok(()) => {
+                debug!("Acquired index lock.");
+                return Ok(IndexLock { index_file: index_file.to_path_buf(), _file: file });
+            },
+            Err(TryLockError::WouldBlock) => (),
+            Err(TryLockError::Error(error)) => return Err(error.into()),
+        }
+
+        if !wait_for_retry(deadline).await {
+            return Err(LockError::IndexLocked);
+        }
+
+        info!("Index is locked by another backup. Waiting...");
+    }
+}
+
+/// The `Lease` struct represents ownership of the backup lease item in DynamoDB.
+/// While it is held, a background task renews it so that it does not expire
+/// during long backups.
+///
+/// Properties:
+///
+/// * `owner`: The identifier of this process, as returned by `owner_id`.
+/// * `key`: The key of the lease item, as returned by `lease_key`.
+/// * `aws_args`: Contains the DynamoDB table containing the lease and its schema.
+/// * `client`: The client used to release the lease.
+/// * `renewer`: The task which periodically extends the lease.
+/// * `status`: Whether the renewer lost the lease.
+pub struct Lease {
+    owner: String,
+    key: String,
+    aws_args: AwsArgs,
+    client: Client,
+    renewer: JoinHandle<()>,
+    status: LeaseStatus,
+}
+
+/// The `LeaseStatus` struct tells a backup whether its lease was lost, so that
+/// it stops before racing another backup which took the lease over. The
+/// default status, for backups without a lease, is never lost.
+#[derive(Clone, Default)]
+pub struct LeaseStatus {
+    lost: Option<watch::Receiver<bool>>,
+}
+
+impl LeaseStatus {
+
+    /// The function `is_lost` checks whether the lease was taken over by
+    /// another process, or could not be renewed before it expired.
+    pub fn is_lost(&self) -> bool {
+        self.lost.as_ref().is_some_and(|lost| *lost.borrow())
+    }
+}
+
+impl Lease {
+
+    /// The function `acquire` takes the backup lease in DynamoDB with a
+    /// conditional put. The put only succeeds if no lease exists, the
+    /// existing lease has expired, or it is already held by this process.
+    ///
+    /// Arguments:
+    ///
+    /// * `aws_args`: Contains the DynamoDB table holding the lease and its schema.
+    /// * `client`: The DynamoDB client.
+    /// * `host_id`: The host id of the backup. Hosts with different ids hold
+    /// separate leases, since they never modify the same file names.
+    /// * `ttl`: The number of seconds the lease is valid for without being
+    /// renewed.
+    /// * `deadline`: If supplied, the lease will be retried until this instant
+    /// instead of failing immediately.
+    ///
+    /// Returns:
+    ///
+    /// The acquired `Lease`, or `LockError::LeaseHeld` describing the holder.
+    pub async fn acquire(aws_args: AwsArgs, client: &Client, host_id: Option<&str>, ttl: u64, deadline: Option<Instant>) -> Result<Lease, LockError> {
+        let owner = owner_id();
+        let key = lease_key(host_id);
+        let table_schema = &aws_args.table_schema;
+
+        loop {
+            let now = Utc::now();
+            let expiration = now + Duration::seconds(ttl as i64);
+
+            let result = client.put_item()
+                .table_name(aws_args.dynamo_table.clone())
+                .set_item(Some(table_schema.key(&key)))
+                .item(OWNER_KEY, AttributeValue::S(owner.clone()))
+                .item(&table_schema.dynamo_expiration_attribute, AttributeValue::N(expiration.timestamp().to_string()))
+                .condition_expression("attribute_not_exists(#partition_key) OR #expiration < :now OR #owner = :owner")
+                .expression_attribute_names("#partition_key", &table_schema.dynamo_partition_key)
+                .expression_attribute_names("#expiration", &table_schema.dynamo_expiration_attribute)
+                .expression_attribute_names("#owner", OWNER_KEY)
+                .expression_attribute_values(":now", AttributeValue::N(now.timestamp().to_string()))
+                .expression_attribute_values(":owner", AttributeValue::S(owner.clone()))
+                .send().await;
+
+            match result {
+                Ok(_) => {
+                    debug!("Acquired backup lease as {owner} until {expiration}.");
+
+                    let (lost, status) = watch::channel(false);
+
+                    return Ok(Lease {
+                        owner: owner.clone(),
+                        key: key.clone(),
+                        aws_args: aws_args.clone(),
+                        client: client.clone(),
+                        renewer: tokio::spawn(renew(client.clone(), aws_args.clone(), key, owner, ttl, lost)),
+                        status: LeaseStatus { lost: Some(status) },
+                    });
+                },
+                Err(error) if error.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => {
+                    if !wait_for_retry(deadline).await {
+                        let (owner, expiration) = lease_holder(aws_args.clone(), client, key).await?;
+                        return Err(LockError::LeaseHeld { owner, expiration });
+                    }
+
+                    info!("Backup lease is held by another backup. Waiting...");
+                },
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+
+    /// The function `status` returns the status of the lease, which backups
+    /// check between pages.
+    pub fn status(&self) -> LeaseStatus {
+        self.status.clone()
+    }
+
+    /// The function `release` stops renewing the lease and deletes it, as long
+    /// as it is still owned by this process.
+    pub async fn release(self) -> Result<(), LockError> {
+        self.renewer.abort();
+
+        let result = self.client.delete_item()
+            .table_name(self.aws_args.dynamo_table)
+            .set_key(Some(self.aws_args.table_schema.key(&self.key)))
+            .condition_expression("#owner = :owner")
+            .expression_attribute_names("#owner", OWNER_KEY)
+            .expression_attribute_values(":owner", AttributeValue::S(self.owner))
+            .send().await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) if error.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => {
+                warn!("Backup lease was taken over by another process before it could be released.");
+                Ok(())
+            },
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// The function `force_release` deletes the lease regardless of its owner.
+    ///
+    /// Arguments:
+    ///
+    /// * `aws_args`: Contains the DynamoDB table holding the lease.
+    /// * `client`: The DynamoDB client.
+    /// * `host_id`: The host id of the backup holding the lease.
+    pub async fn force_release(aws_args: AwsArgs, client: &Client, host_id: Option<&str>) -> Result<(), LockError> {
+        client.delete_item()
+            .table_name(aws_args.dynamo_table)
+            .set_key(Some(aws_args.table_schema.key(&lease_key(host_id))))
+            .send().await?;
+
+        Ok(())
+    }
+}
+
+/// The function `lease_key` builds the key of the lease item for a host.
+fn lease_key(host_id: Option<&str>) -> String {
+    match host_id {
+        Some(host_id) => format!("{LEASE_KEY}{RESERVED_KEY_SEPARATOR}{host_id}"),
+        None => LEASE_KEY.to_string(),
+    }
+}
+
+/// The function `renew` extends the lease every third of its ttl until it is
+/// aborted by `Lease::release`. If another process took the lease over, or
+/// it could not be renewed for its whole ttl, `lost` is set and renewal stops.
+async fn renew(client: Client, aws_args: AwsArgs, key: String, owner: String, ttl: u64, lost: watch::Sender<bool>) {
+    let interval = StdDuration::from_secs((ttl / 3).max(1));
+    let mut renewed = Instant::now();
+
+    loop {
+        sleep(interval).await;
+
+        let expiration = Utc::now() + Duration::seconds(ttl as i64);
+
+        let result = client.update_item()
+            .table_name(aws_args.dynamo_table.clone())
+            .set_key(Some(aws_args.table_schema.key(&key)))
+            .update_expression("SET #expiration = :expiration")
+            .condition_expression("#owner = :owner")
+            .expression_attribute_names("#expiration", &aws_args.table_schema.dynamo_expiration_attribute)
+            .expression_attribute_names("#owner", OWNER_KEY)
+            .expression_attribute_values(":expiration", AttributeValue::N(expiration.timestamp().to_string()))
+            .expression_attribute_values(":owner", AttributeValue::S(owner.clone()))
+            .send().await;
+
+        match result {
+            Ok(_) => {
+                renewed = Instant::now();
+                debug!("Renewed backup lease until {expiration}.");
+            },
+            Err(error) if error.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => {
+                error!("Backup lease was taken over by another process. Stopping the backup.");
+                lost.send_replace(true);
+                return;
+            },
+            Err(error) => {
+                error!("Failed to renew backup lease: {:?}", error);
+
+                if renewed.elapsed() >= StdDuration::from_secs(ttl) {
+                    error!("Backup lease expired without being renewed. Stopping the backup.");
+                    lost.send_replace(true);
+                    return;
+                }
+            },
+        }
+    }
+}
+
+/// The function `lease_holder` reads the current owner and expiration of the
+/// lease, for use in error messages.
+async fn lease_holder(aws_args: AwsArgs, client: &Client, key: String) -> Result<(String, DateTime<Utc>), LockError> {
+    let item = client.get_item()
+        .table_name(aws_args.dynamo_table)
+        .set_key(Some(aws_args.table_schema.key(&key)))
+        .consistent_read(true)
+        .send().await?
+        .item
+        .unwrap_or_default();
+
+    let owner = item.get(OWNER_KEY)
+        .and_then(|value| value.as_s().ok())
+        .cloned()
+        .unwrap_or("unknown".to_string());
+
+    let expiration = item.get(&aws_args.table_schema.dynamo_expiration_attribute)
+        .and_then(|value| value.as_n().ok())
+        .and_then(|value| value.parse().ok())
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .unwrap_or(DateTime::UNIX_EPOCH);
+
+    Ok((owner, expiration))
+}
+
+/// The function `wait_for_retry` sleeps before a lock is retried.
+///
+/// Returns:
+///
+/// `false` if there is no deadline or it would be passed, otherwise `true`
+/// after sleeping.
+async fn wait_for_retry(deadline: Option<Instant>) -> bool {
+    match deadline {
+        Some(deadline) if Instant::now() + LOCK_POLL_INTERVAL <= deadline => {
+            sleep(LOCK_POLL_INTERVAL).await;
+            true
+        },
+        _ => false,
+    }
+}
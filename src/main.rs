@@ -1,17 +1,20 @@
 use std::env;
 use std::io::{self, Error};
+use std::process::ExitCode;
+use std::time::Duration;
 
 use aws_sdk_s3::Client as S3Client;
 use aws_sdk_dynamodb::Client as DynamoClient;
+use chrono::Utc;
 use clap::Parser;
-use diesel::prelude::PgConnection;
+use diesel::prelude::*;
 use log::{LevelFilter, error, info};
-use env_logger::Builder;
-
-use ntfy::{Auth, Dispatcher, Payload, Priority, dispatcher};
+use env_logger::{Builder, Target};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
 
 use gda_backup::environment::{
-    AwsArgs, BackupArgs, CleanDynamoArgs, ClearDatabaseArgs, Cli, Commands, DeleteBackupArgs, RestoreArgs
+    AwsArgs, Backend, BackupArgs, CheckFreshnessArgs, CleanDynamoArgs, ClearDatabaseArgs, Cli, Commands, DaemonArgs, DeleteBackupArgs, ForceUnlockArgs, LockArgs, LockCommands, LockStatusArgs, PruneArgs, RebuildStateArgs, RestoreArgs, VerifyArgs
 };
 
 use gda_backup::{
@@ -19,54 +22,131 @@ use gda_backup::{
 };
 
 use gda_backup::backup;
-
+use gda_backup::delete;
+use gda_backup::error::GdaError;
+use gda_backup::index;
+use gda_backup::healthcheck::{self, Ping};
+use gda_backup::heartbeat;
+use gda_backup::lock::{self, IndexLock, Lease, LeaseStatus};
+use gda_backup::metrics::{self, METRICS};
+use gda_backup::notify::Notifier;
+use gda_backup::object_lock;
+use gda_backup::plan::Planner;
+use gda_backup::progress;
+
+use gda_backup::report::{self, CommandReport, CommandStatus};
 use gda_backup::restore;
+use gda_backup::retention;
+use gda_backup::safety;
 use gda_backup::s3;
-use gda_backup::dynamodb::{self, HashTracker};
+use gda_backup::dynamodb;
+use gda_backup::storage::{self, MetadataStore, ObjectStore};
+use gda_backup::verify;
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> ExitCode {
 
     // ARGUMENTS
     let cli = Cli::parse();
 
-    let dispatcher = ntfy_dispatcher(cli.clone());
-
     // SET LOG LEVEL
+    progress::init(&cli);
+
+    let mut logger = Builder::new();
+
     if cli.quiet {
-        Builder::new().filter_level(LevelFilter::Error).init();
+        logger.filter_level(LevelFilter::Error);
     }
     else if cli.debug {
-        Builder::new().filter_level(LevelFilter::Debug).init();
+        logger.filter_level(LevelFilter::Debug);
     }
     else {
-        Builder::new().filter_level(LevelFilter::Info).init();
+        logger.filter_level(LevelFilter::Info);
+    }
+
+    // Log lines are printed above progress bars, rather than through them
+    if progress::bars() {
+        logger.target(Target::Pipe(Box::new(progress::LogWriter)));
     }
+
+    logger.init();
     
+    let notifier = Notifier::new(&cli.notify);
+
     // GET CONNECTIONS
-    let s3_client: &mut S3Client = &mut s3::get_client().await;
-    let dynamo_client: &mut DynamoClient = &mut dynamodb::get_client().await;
+    let s3_client: &mut S3Client = &mut s3::get_client(&cli).await;
+    let dynamo_client: &mut DynamoClient = &mut dynamodb::get_client(&cli).await;
 
     // EXECUTE COMMAND
-    match cli.clone().command {
+    let started = Instant::now();
+
+    let (command, result) = match cli.clone().command {
         Commands::Backup(args) => {
-            backup(cli, args, dispatcher, s3_client, dynamo_client).await?;
+            ("backup", backup(cli.clone(), *args, &notifier, s3_client, dynamo_client).await)
+        },
+        Commands::Daemon(args) => {
+            ("daemon", daemon(cli.clone(), *args, &notifier, s3_client, dynamo_client).await)
         },
         Commands::Restore(args) => {
-            restore(cli, args, s3_client, dynamo_client).await?;
+            ("restore", restore(cli.clone(), args, s3_client, dynamo_client).await)
+        },
+        Commands::Verify(args) => {
+            ("verify", verify(cli.clone(), args, s3_client, dynamo_client).await)
+        },
+        Commands::CheckFreshness(args) => {
+            ("check-freshness", check_freshness(cli.clone(), args, s3_client, dynamo_client).await)
+        },
+        Commands::Prune(args) => {
+            ("prune", prune(cli.clone(), args, s3_client, dynamo_client).await)
         },
         Commands::CleanDynamo(args) => {
-            clean_dynamo(args, dynamo_client).await?;
+            ("clean-dynamo", clean_dynamo(args, s3_client, dynamo_client).await)
         }
         Commands::ClearDatabase(args) => {
-            clear_database(args)?;
+            ("clear-database", clear_database(args).await)
+        },
+        Commands::RebuildState(args) => {
+            ("rebuild-state", rebuild_state(cli.clone(), args, s3_client, dynamo_client).await)
         },
         Commands::DeleteBackup(args) => {
-            delete_backup(args, s3_client, dynamo_client).await?;
+            ("delete-backup", delete_backup(cli.clone(), args, s3_client, dynamo_client).await)
+        }
+        Commands::ForceUnlock(args) => {
+            ("force-unlock", force_unlock(args, dynamo_client).await)
         }
+        Commands::Lock(LockArgs { command: LockCommands::Status(args) }) => {
+            ("lock-status", lock_status(cli.clone(), args, s3_client, dynamo_client).await)
+        }
+    };
+
+    let command_report = CommandReport::new(&cli, command, &result);
+    report::finish(&cli, &command_report);
+
+    if let Some(subject) = notification_subject(&cli.command) {
+        notifier.finished(&command_report, Some(&subject)).await;
     }
 
-    Ok(())
+    if let Commands::Backup(args) = &cli.command {
+        ping_finished(args.healthcheck_url.as_deref(), &command_report).await;
+    }
+
+    // RECORD METRICS
+    METRICS.record_run(&command_report, started.elapsed());
+
+    if let Some(path) = &cli.metrics_textfile {
+        if let Err(error) = METRICS.write_textfile(path) {
+            error!("Failed to write metrics to {}: {:?}", path.display(), error);
+        }
+    }
+
+    // EXIT
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(error) => {
+            error!("Exiting with code {}: {error}", error.exit_code());
+            ExitCode::from(error.exit_code())
+        },
+    }
 }
 
 /// The function `fix_target_dir` in Rust takes a target directory path as input,
@@ -115,11 +195,11 @@ fn fix_target_dir(target_dir: String) -> Result<String, Error> {
 /// `args.filter_delimiter`, converts each split part to a String, and collects them
 /// into a new vector of Strings. Otherwise, it returns the original
 fn fix_filter(args: BackupArgs) -> Vec<String> {
-    if args.filter.len() == 1 && args.filter_delimiter.is_some() {
-        return args.filter[0].split(&args.filter_delimiter.unwrap()).map(|s| s.to_string()).collect();
-    }
-    else {
-        return args.filter;
+    match args.filter_delimiter {
+        Some(delimiter) if args.filter.len() == 1 => {
+            args.filter[0].split(&delimiter).map(|s| s.to_string()).collect()
+        },
+        _ => args.filter,
     }
 }
 
@@ -137,11 +217,8 @@ fn fix_filter(args: BackupArgs) -> Vec<String> {
 /// a set of arguments that are used throughout the backup process. It includes
 /// fields like `target_dir`, `filter`, and possibly other configuration options
 /// needed for the backup operation.
-/// * `dispatcher`: The `dispatcher` parameter in the `backup` function is an
-/// optional `Dispatcher` type. It seems to be used for dispatching notifications or
-/// handling some kind of event dispatching within the backup process. If the
-/// `dispatcher` is provided, it can be used to send notifications or trigger
-/// certain actions
+/// * `notifier`: Sends the notification that the backup started. The
+/// notification that it finished is sent by the caller.
 /// * `s3_client`: The `s3_client` parameter in the `backup` function is a mutable
 /// reference to an instance of the `S3Client` struct. This client is used to
 /// interact with Amazon S3 services for storing and retrieving data during the
@@ -153,59 +230,276 @@ fn fix_filter(args: BackupArgs) -> Vec<String> {
 /// 
 /// Returns:
 /// 
-/// The `backup` function is returning a `Result<(), Error>`.
-async fn backup(cli: Cli, mut args: BackupArgs, dispatcher: Option<Dispatcher<dispatcher::Async>>, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(), Error> {
+/// The `backup` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if any file failed to be backed up, and the error
+/// which stopped the backup if it could not finish.
+async fn backup(cli: Cli, mut args: BackupArgs, notifier: &Notifier, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
     
     // FIX ARGUMENTS
     args.target_dir = fix_target_dir(args.target_dir.clone())?;
     args.filter = fix_filter(args.clone());
 
-    ntfy(cli.clone(), dispatcher.clone(), "Backup starting", 
-        format!("Starting backup of {}", args.target_dir.clone()), 
-        Priority::Default
-    ).await;
+    if args.save_plan.is_some() && !cli.dry_run {
+        return Err(GdaError::ConfigError("A plan can only be saved by a dry run.".to_string()));
+    }
 
-    // Connect to local database
-    let conn: &mut PgConnection = &mut establish_connection(args.clone().into());
-    
-    // Clear local_state from database
-    info!("Preparing to back up: Cleaning up previous backup data...");
-    clear_local_state(conn);
-    
-    // Load files into database from disk
-    info!("Preparing to back up: Loading all files...");
-    backup::load(args.clone(), conn);
-    
-    // If glacier_state is empty, populate it from Glacier.
-    if glacier_state_is_empty(conn) {
-        info!("Glacier state empty. Loading state from DynamoDB and S3...");
-        let _ = restore::postgres_from_aws(cli.clone(), args.clone(), conn, &s3_client, &dynamo_client).await;
+    if args.locks_objects() && args.backend.as_ref().is_some_and(|backend| *backend != Backend::Aws) {
+        return Err(GdaError::ConfigError("Object Lock is only supported by the aws backend.".to_string()));
+    }
+
+    // An unmounted target directory would otherwise look like every file was deleted
+    if let Err(error) = safety::check_sentinel(&args, cli.dry_run) {
+        error!("Backup aborted: {error}");
+        return Err(error);
+    }
+
+    notifier.started("Backup starting", format!("Starting backup of {}", args.target_dir)).await;
+
+    if let Some(url) = &args.healthcheck_url {
+        healthcheck::ping(url, Ping::Start, format!("Starting backup of {}", args.target_dir)).await;
     }
 
+    let (objects, metadata) = match storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client) {
+        Ok(stores) => stores,
+        Err(error) => {
+            error!("Backup failed to start: {error}");
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
+    };
+
+    // Lock local and remote state so that overlapping backups cannot race
+    let deadline = args.wait_for_lock.map(|seconds| Instant::now() + Duration::from_secs(seconds));
+
+    let mut local_state = match &args.index_file {
+        Some(index_file) => match lock::lock_index(index_file, deadline).await {
+            Ok(index_lock) => LocalState::Index(index_lock),
+            Err(error) => return lock_failed(error),
+        },
+        None => {
+            // Connect to local database
+            let mut conn = establish_connection(args.clone().into())?;
+
+            // A dry run changes the local database in a transaction which is never committed
+            if cli.dry_run {
+                conn.begin_test_transaction()?;
+            }
+
+            if let Err(error) = lock::lock_local_state(&mut conn, deadline).await {
+                return lock_failed(error);
+            }
+
+            LocalState::Database(conn)
+        },
+    };
+
+    // A dry run never writes to DynamoDB, so it only needs the local lock
+    let lease = if cli.dry_run || args.backend.as_ref().is_some_and(|backend| *backend != Backend::Aws) {
+        None
+    }
+    else {
+        match Lease::acquire(args.clone().into(), dynamo_client, args.host_id.as_deref(), args.lock_ttl, deadline).await {
+            Ok(lease) => Some(lease),
+            Err(error) => {
+                if let LocalState::Database(conn) = &mut local_state {
+                    let _ = lock::unlock_local_state(conn);
+                }
+                return lock_failed(error);
+            },
+        }
+    };
+
     // UPLOAD CHANGES
-    let (successes, failures) = backup::backup(cli.clone(), args.clone(), conn, s3_client, dynamo_client).await;
+    let mut planner = Planner::default();
+    let lease_status = lease.as_ref().map(Lease::status).unwrap_or_default();
+    let result = match &mut local_state {
+        LocalState::Database(conn) => match &args.apply_plan {
+            Some(plan_file) => backup::apply_plan(cli.clone(), args.clone(), plan_file, conn, objects.as_ref(), metadata.as_ref(), &mut planner, &lease_status).await,
+            None => run_backup(cli.clone(), args.clone(), conn, objects.as_ref(), metadata.as_ref(), &mut planner, &lease_status).await,
+        },
+        LocalState::Index(index_lock) => backup::backup_index(cli.clone(), args.clone(), index_lock.index_file(), objects.as_ref(), metadata.as_ref(), &mut planner, &lease_status).await,
+    };
     
     // CLEAR STATE 
     info!("Backup complete: Cleaning up...");
-    clear_local_state(conn);
+    if let LocalState::Database(conn) = &mut local_state {
+        if let Err(error) = clear_local_state(conn) {
+            error!("Failed to clear local_state: {:?}", error);
+        }
+    }
+
+    // RELEASE LOCKS
+    if let Some(lease) = lease {
+        if let Err(error) = lease.release().await {
+            error!("Failed to release backup lease: {:?}", error);
+        }
+    }
+
+    // The index lock is released when it is dropped
+    if let LocalState::Database(conn) = &mut local_state {
+        if let Err(error) = lock::unlock_local_state(conn) {
+            error!("Failed to release local database lock: {:?}", error);
+        }
+    }
 
     // PRINT RESULTS
+    let plan = cli.dry_run.then(|| planner.finish(&args));
+
+    let (successes, failures) = match result {
+        Ok(counts) => counts,
+        Err(error) => {
+            error!("Backup failed: {error}");
+            return Err(error);
+        },
+    };
+
     info!("Backup complete: {successes} succeeded, {failures} failed.");
 
-    if failures == 0 {
-        ntfy(cli, dispatcher, "Backup complete", 
-            format!("Completed backup of {}, {successes} succeeded, {failures} failed.", args.target_dir), 
-            Priority::Default
-        ).await;
+    if let Some(plan) = plan {
+        plan.log();
+        report::plan(&cli, &plan.summary);
+
+        if let Some(path) = &args.save_plan {
+            plan.save(path)?;
+        }
     }
-    else {
-        ntfy(cli, dispatcher, "Backup complete with failures", 
-            format!("Failed backup of {}, {successes} succeeded, {failures} failed.", args.target_dir),
-            Priority::High
-        ).await;
+
+    // RECORD HEARTBEAT
+    if failures == 0 && !cli.dry_run {
+        let host = heartbeat::host_name(args.host_id.as_deref());
+
+        if let Err(error) = metadata.put_heartbeat(host, Utc::now()).await {
+            error!("Failed to record heartbeat of {host}: {:?}", error);
+        }
     }
 
-    Ok(())
+    GdaError::from_counts(successes, failures)
+}
+
+/// The state a backup compares the target directory against, locked for the
+/// duration of the backup.
+enum LocalState {
+    /// The `glacier_state` table of the local database.
+    Database(PgConnection),
+    /// An index file.
+    Index(IndexLock),
+}
+
+/// The function `ping_finished` pings the healthcheck URL of a backup with
+/// its final report.
+///
+/// Arguments:
+///
+/// * `url`: The healthcheck URL, if one is configured.
+/// * `command_report`: The final report of the backup, sent as the body of
+/// the ping.
+async fn ping_finished(url: Option<&str>, command_report: &CommandReport) {
+    let Some(url) = url else {
+        return;
+    };
+
+    let ping = match command_report.status {
+        CommandStatus::Succeeded => Ping::Success,
+        CommandStatus::PartialFailure | CommandStatus::Failed => Ping::Failure,
+    };
+
+    healthcheck::ping(url, ping, serde_json::to_string(command_report).unwrap_or_default()).await;
+}
+
+/// The function `daemon` runs a backup whenever its schedule is due, and
+/// serves the metrics of every backup over HTTP in the meantime. A failed
+/// backup is reported and the daemon waits for the next one.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The schedule, metrics address and arguments of every backup.
+/// * `notifier`: Sends the notifications of every backup.
+/// * `s3_client`: The S3 client, which the object store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
+/// 
+/// Returns:
+/// 
+/// Only returns if the metrics address cannot be bound, or the schedule has
+/// no upcoming runs.
+async fn daemon(cli: Cli, args: DaemonArgs, notifier: &Notifier, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let listener = TcpListener::bind(args.metrics_listen).await?;
+    info!("Serving metrics at http://{}/metrics", args.metrics_listen);
+    tokio::spawn(metrics::serve(listener));
+
+    loop {
+        let Some(next) = args.backup_cron.upcoming(Utc).next() else {
+            return Err(GdaError::ConfigError("The backup schedule has no upcoming runs.".to_string()));
+        };
+
+        info!("Next backup at {next}.");
+        tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+
+        let started = Instant::now();
+        let result = backup(cli.clone(), args.backup.clone(), notifier, s3_client, dynamo_client).await;
+
+        let command_report = CommandReport::new(&cli, "backup", &result);
+        report::finish(&cli, &command_report);
+        notifier.finished(&command_report, Some(&args.backup.target_dir)).await;
+        ping_finished(args.backup.healthcheck_url.as_deref(), &command_report).await;
+        METRICS.record_run(&command_report, started.elapsed());
+
+        if let Err(error) = result {
+            error!("Scheduled backup failed with code {}: {error}", error.exit_code());
+        }
+    }
+}
+
+/// The function `run_backup` backs up every change once the backup holds its
+/// locks, so that the locks are released however it ends.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The arguments of the backup.
+/// * `conn`: The locked connection to the local database.
+/// * `objects`: The object store which file contents are uploaded to.
+/// * `metadata`: The metadata store which hash trackers are written to.
+/// * `planner`: Collects the plan of a dry run.
+/// * `lease`: The status of the backup lease.
+/// 
+/// Returns:
+/// 
+/// The number of hashes which were and were not backed up, or the error which
+/// stopped the backup.
+#[allow(clippy::too_many_arguments)]
+async fn run_backup(cli: Cli, args: BackupArgs, conn: &mut PgConnection, objects: &dyn ObjectStore, metadata: &dyn MetadataStore, planner: &mut Planner, lease: &LeaseStatus) -> Result<(usize, usize), GdaError> {
+    // Clear local_state from database
+    info!("Preparing to back up: Cleaning up previous backup data...");
+    clear_local_state(conn)?;
+    
+    // Load files into database from disk
+    info!("Preparing to back up: Loading all files...");
+    backup::load(args.clone(), conn)?;
+    
+    // If glacier_state is empty, populate it from Glacier.
+    if glacier_state_is_empty(conn)? {
+        info!("Glacier state empty. Loading state from DynamoDB and S3...");
+        restore::rebuild_state(args.host_id.as_deref(), conn, objects, metadata).await?;
+    }
+
+    backup::backup(cli, args, conn, objects, metadata, planner, lease).await
+}
+
+/// The function `lock_failed` reports a backup which could not start because
+/// another backup holds its lock.
+/// 
+/// Arguments:
+/// 
+/// * `error`: The reason the lock could not be acquired.
+/// 
+/// Returns:
+/// 
+/// Always returns `GdaError::LockError` so that the process exits with the
+/// lock contention exit code.
+fn lock_failed(error: lock::LockError) -> Result<(usize, usize), GdaError> {
+    error!("Backup failed to start: {error}");
+    Err(GdaError::LockError(Box::new(error)))
 }
 
 /// The function `restore` in Rust asynchronously restores data using S3 and
@@ -232,17 +526,146 @@ async fn backup(cli: Cli, mut args: BackupArgs, dispatcher: Option<Dispatcher<di
 /// 
 /// Returns:
 /// 
-/// The `restore` function is returning a `Result<(), Error>`.
-async fn restore(cli: Cli, mut args: RestoreArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(), Error> {
+/// The `restore` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if any file failed to be restored.
+async fn restore(cli: Cli, mut args: RestoreArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
     // FIX ARGUMENTS
     args.target_dir = fix_target_dir(args.clone().target_dir)?;
 
-    match restore::restore(cli, args, s3_client, dynamo_client).await {
-        Ok((restored, failed)) => info!("Restore complete: {restored} restored, {failed} failed."),
-        Err(error) => error!("Restore failed: {:?}", error),
+    let (objects, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    let (restored, failed) = match restore::restore(cli, args, objects.as_ref(), metadata.as_ref()).await {
+        Ok(counts) => counts,
+        Err(error) => {
+            error!("Restore failed: {:?}", error);
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
+    };
+
+    info!("Restore complete: {restored} restored, {failed} failed.");
+
+    GdaError::from_counts(restored, failed)
+}
+
+/// The function `verify` checks that the object of every backed up file is
+/// stored and matches its checksum.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The backup to verify.
+/// * `s3_client`: The S3 client, which the object store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
+/// 
+/// Returns:
+/// 
+/// The `verify` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if any file failed to be verified.
+async fn verify(cli: Cli, args: VerifyArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let (objects, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    let (verified, failed) = match verify::verify(cli, args, objects.as_ref(), metadata.as_ref()).await {
+        Ok(counts) => counts,
+        Err(error) => {
+            error!("Verify failed: {:?}", error);
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
+    };
+
+    GdaError::from_counts(verified, failed)
+}
+
+/// The function `prune` removes the deleted files and previous versions which
+/// retention policies no longer keep.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The retention policies and the backup to prune.
+/// * `s3_client`: The S3 client, which the object store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
+/// 
+/// Returns:
+/// 
+/// The `prune` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if any file failed to be pruned.
+async fn prune(cli: Cli, args: PruneArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let (objects, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    let (pruned, failed) = match retention::prune(cli, args, objects.as_ref(), metadata.as_ref()).await {
+        Ok(counts) => counts,
+        Err(error) => {
+            error!("Prune failed: {:?}", error);
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
+    };
+
+    GdaError::from_counts(pruned, failed)
+}
+
+/// The function `lock_status` reports the Object Lock protecting the objects
+/// of a backup.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The backup whose objects are reported.
+/// * `s3_client`: The S3 client, which the object store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
+/// 
+/// Returns:
+/// 
+/// The `lock_status` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if the lock of any file could not be read.
+async fn lock_status(cli: Cli, args: LockStatusArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let (objects, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    let (reported, failed) = match object_lock::status(cli, args, objects.as_ref(), metadata.as_ref()).await {
+        Ok(counts) => counts,
+        Err(error) => {
+            error!("Lock status failed: {:?}", error);
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
+    };
+
+    GdaError::from_counts(reported, failed)
+}
+
+/// The function `check_freshness` checks that every host backed up
+/// successfully within the maximum age.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The maximum age and the hosts which must have backed up.
+/// * `s3_client`: The S3 client, which the metadata store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
+/// 
+/// Returns:
+/// 
+/// The `check_freshness` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if any host is stale.
+async fn check_freshness(cli: Cli, args: CheckFreshnessArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let (_, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    let (fresh, stale) = match heartbeat::check_freshness(cli, args, metadata.as_ref()).await {
+        Ok(counts) => counts,
+        Err(error) => {
+            error!("Freshness check failed: {:?}", error);
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
     };
 
-    Ok(())
+    GdaError::from_counts(fresh, stale)
 }
 
 /// The `clean_dynamo` function in Rust asynchronously cleans up a DynamoDB table by
@@ -254,6 +677,7 @@ async fn restore(cli: Cli, mut args: RestoreArgs, s3_client: &mut S3Client, dyna
 /// information needed for cleaning up a DynamoDB table. It seems to include a
 /// reference to the DynamoDB table that needs to be cleaned. The specific details
 /// of the `CleanDynamoArgs` struct are not provided in the code
+/// * `s3_client`: The S3 client, which the metadata store is opened with.
 /// * `dynamo_client`: The `dynamo_client` parameter in the `clean_dynamo` function
 /// is a mutable reference to a `DynamoClient` instance. This parameter allows the
 /// function to interact with the DynamoDB service using the provided client. By
@@ -261,21 +685,21 @@ async fn restore(cli: Cli, mut args: RestoreArgs, s3_client: &mut S3Client, dyna
 /// 
 /// Returns:
 /// 
-/// The `clean_dynamo` function is returning a `Result<(), Error>`.
-async fn clean_dynamo(args: CleanDynamoArgs, dynamo_client: &mut DynamoClient) -> Result<(), Error> {
-    let aws_args = AwsArgs {
-        bucket_name: "".to_string(),
-        dynamo_table: args.dynamo_table.clone()
-    };
-    
-    let hash_trackers = HashTracker::get_all(&dynamo_client, aws_args.clone())
-        .await.unwrap_or(vec![]);
+/// The `clean_dynamo` function is returning a `Result<(), GdaError>`.
+async fn clean_dynamo(args: CleanDynamoArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let (_, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
 
-    for hash_tracker in hash_trackers {
-        let _ = hash_tracker.update(aws_args.clone(), &dynamo_client).await;
+    match metadata.clean().await {
+        Ok((deleted, rewritten)) => {
+            info!("DynamoDB clean up complete: {deleted} deleted, {rewritten} rewritten.");
+            Ok((deleted + rewritten, 0))
+        },
+        Err(error) => {
+            error!("DynamoDB clean up failed: {:?}", error);
+            Err(GdaError::StorageError(Box::new(error)))
+        },
     }
-
-    Ok(())
 }
 
 /// The `clear_database` function in Rust clears the glacier state in a local
@@ -291,134 +715,263 @@ async fn clean_dynamo(args: CleanDynamoArgs, dynamo_client: &mut DynamoClient) -
 /// 
 /// Returns:
 /// 
-/// The `clear_database` function is returning a `Result<(), Error>`. This means
+/// The `clear_database` function is returning a `Result<(), GdaError>`. This means
 /// that it is returning a `Result` enum where the success case contains an empty
-/// tuple `()` and the error case contains an `Error`.
-fn clear_database(args: ClearDatabaseArgs) -> Result<(), Error> {
+/// tuple `()` and the error case contains a `GdaError`.
+async fn clear_database(args: ClearDatabaseArgs) -> Result<(usize, usize), GdaError> {
     // Connect to local database
-    let conn: &mut PgConnection = &mut establish_connection(args.clone().into());
+    let conn: &mut PgConnection = &mut establish_connection(args.clone().into())?;
+
+    // Never clear glacier_state out from under a running backup
+    if let Err(error) = lock::lock_local_state(conn, None).await {
+        error!("Failed to clear database: {error}");
+        return Err(GdaError::LockError(Box::new(error)));
+    }
 
     // Clear glacier state
-    clear_glacier_state(conn);
+    let result = clear_glacier_state(conn);
 
-    Ok(())
+    if let Err(error) = lock::unlock_local_state(conn) {
+        error!("Failed to release local database lock: {:?}", error);
+    }
+
+    result?;
+
+    Ok((0, 0))
 }
 
-/// The function `delete_backup` prompts the user for confirmation before deleting
-/// all items in DynamoDB and S3.
+/// The function `rebuild_state` rebuilds the local database or index file from
+/// the hash trackers and objects of a backup, such as after the local database
+/// was lost, so that the next backup only uploads files which changed.
 /// 
 /// Arguments:
 /// 
-/// * `args`: `DeleteBackupArgs` - Struct containing information needed for deleting
-/// the backup, such as backup ID or other relevant data.
-/// * `s3_client`: The `s3_client` parameter in the `delete_backup` function is a
-/// mutable reference to an instance of the `S3Client` struct. This client is used
-/// to interact with an S3 (Simple Storage Service) storage system, allowing the
-/// function to perform operations such as deleting items from an
-/// * `dynamo_client`: The `dynamo_client` parameter in the `delete_backup` function
-/// is a mutable reference to a `DynamoClient` instance. This parameter allows the
-/// function to interact with DynamoDB to delete items from the database. The
-/// function uses this client to call the `permanently_delete_all` method
+/// * `cli`: The parsed command line.
+/// * `args`: The backup, and the local database or index file to rebuild.
+/// * `s3_client`: The S3 client, which the object store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
 /// 
 /// Returns:
 /// 
-/// The `delete_backup` function returns a `Result<(), Error>`.
-async fn delete_backup(args: DeleteBackupArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(), Error> {
-    // Get confirmation
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-    
-    println!("Are you sure you want to delete your backup? (y/n)");
-    stdin.read_line(&mut buffer)?;
-    buffer.retain(|c| !c.is_whitespace());
+/// The `rebuild_state` function returns `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` if any file failed to be rebuilt.
+async fn rebuild_state(cli: Cli, args: RebuildStateArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let (objects, metadata) = storage::open(args.backend.clone(), args.clone().into(), s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    // Never rebuild the state out from under a running backup
+    let (rebuilt, failed) = match &args.index_file {
+        Some(index_file) => {
+            let _index_lock = match lock::lock_index(index_file, None).await {
+                Ok(index_lock) => index_lock,
+                Err(error) => {
+                    error!("Failed to rebuild state: {error}");
+                    return Err(GdaError::LockError(Box::new(error)));
+                },
+            };
+
+            if cli.dry_run {
+                let entries = index::rebuilt_entries(args.host_id.as_deref(), objects.as_ref(), metadata.as_ref()).await.map_err(Box::new)?;
+                info!("Dry run: The index would be rebuilt with {} files.", entries.len());
+                (entries.len(), 0)
+            }
+            else {
+                (index::rebuild(args.host_id.as_deref(), index_file, objects.as_ref(), metadata.as_ref()).await.map_err(Box::new)?, 0)
+            }
+        },
+        None => {
+            // Connect to local database
+            let conn: &mut PgConnection = &mut establish_connection(args.clone().into())?;
 
-    if buffer.to_lowercase() != "y" && buffer.to_lowercase() != "yes" {
-        info!("Aborting...");
-        return Ok(());
-    }
-    
-    // Delete all items in DynamoDB
-    match HashTracker::permanently_delete_all(args.clone().into(), dynamo_client).await {
-        Ok(_) => info!("DynamoDB delete all succeeded."),
-        Err(error) => error!("DynamoDB delete all failed: {:?}", error),
-    };
+            // A dry run rebuilds the local database in a transaction which is never committed
+            if cli.dry_run {
+                conn.begin_test_transaction()?;
+            }
+
+            if let Err(error) = lock::lock_local_state(conn, None).await {
+                error!("Failed to rebuild state: {error}");
+                return Err(GdaError::LockError(Box::new(error)));
+            }
+
+            let result = restore::rebuild_state(args.host_id.as_deref(), conn, objects.as_ref(), metadata.as_ref()).await;
 
-    // Delete all items in S3
-    match s3::permanently_delete_all(&s3_client, args.clone().into()).await {
-        Ok(_) => info!("S3 delete all succeeded."),
-        Err(error) => error!("S3 delete all failed: {:?}", error),
+            if let Err(error) = lock::unlock_local_state(conn) {
+                error!("Failed to release local database lock: {:?}", error);
+            }
+
+            result?
+        },
     };
 
-    Ok(())
+    info!("Rebuild complete: {rebuilt} rebuilt, {failed} failed.");
+
+    GdaError::from_counts(rebuilt, failed)
 }
 
-/// The function `ntfy` sends a notification message with specified title, message,
-/// and priority using a dispatcher if available.
+/// The function `force_unlock` releases the local database lock and the
+/// DynamoDB backup lease, regardless of which process holds them.
 /// 
 /// Arguments:
 /// 
-/// * `cli`: The `cli` parameter seems to be of type `Cli`, which likely contains
-/// information or configurations related to the command-line interface. It could
-/// include settings, options, or other data relevant to the command-line
-/// operations.
-/// * `dispatcher`: The `dispatcher` parameter is an optional `Dispatcher` type that
-/// is used to send notifications. If a `dispatcher` is provided, the function will
-/// use it to send a notification message with the specified title, message, and
-/// priority. If the `dispatcher` is `None`, no notification will be
-/// * `title`: The `title` parameter is a reference to a string that represents the
-/// title of the notification message. It is used to provide a brief description or
-/// summary of the notification content.
-/// * `message`: The `message` parameter in the `ntfy` function represents the
-/// content of the notification message that will be sent. It is a `String` type,
-/// which means it can hold a sequence of characters to be included in the
-/// notification.
-/// * `priority`: The `priority` parameter in the function `ntfy` represents the
-/// priority level of the notification being sent. It is used to indicate the
-/// importance or urgency of the notification. The priority can be set to different
-/// levels such as low, medium, high, or critical, depending on the system's
-/// requirements
-async fn ntfy(cli: Cli, dispatcher: Option<Dispatcher<dispatcher::Async>>, title: &str, message: String, priority: Priority) {
-    if let Some(dispatcher) = dispatcher {
-        let result = dispatcher.send(&Payload::new(cli.ntfy_topic.unwrap())
-            .message(message) // Add optional message
-            .title(title) // Add optional title
-            .priority(priority) // Edit priority
-            .markdown(true)
-        ).await; // Use markdown).await.unwrap();
+/// * `args`: `ForceUnlockArgs` - Contains the DynamoDB table and local database
+/// connection information.
+/// * `dynamo_client`: The client used to delete the backup lease.
+/// 
+/// Returns:
+/// 
+/// The `force_unlock` function returns a `Result<(), GdaError>`. Both locks
+/// are released even if releasing the first fails.
+async fn force_unlock(args: ForceUnlockArgs, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let aws_args: AwsArgs = args.clone().into();
 
-        if let Err(error) = result {
-            error!("Failed to send ntfy message: {:?}", error);
-        };
+    // Connect to local database
+    let conn: &mut PgConnection = &mut establish_connection(args.clone().into())?;
+
+    let local_result = match lock::force_unlock_local_state(conn) {
+        Ok(count) => {
+            info!("Local database lock released. {count} sessions terminated.");
+            Ok(())
+        },
+        Err(error) => {
+            error!("Failed to release local database lock: {:?}", error);
+            Err(error)
+        },
+    };
+
+    let lease_result = match Lease::force_release(aws_args, dynamo_client, args.host_id.as_deref()).await {
+        Ok(_) => {
+            info!("Backup lease released.");
+            Ok(())
+        },
+        Err(error) => {
+            error!("Failed to release backup lease: {:?}", error);
+            Err(error)
+        },
     };
+
+    local_result?;
+    lease_result.map_err(Box::new)?;
+
+    Ok((0, 0))
 }
 
-/// The function `ntfy_dispatcher` checks if necessary parameters are provided to
-/// create a notification dispatcher and returns an optional dispatcher object.
+/// The function `delete_backup` permanently deletes every version of the
+/// objects of a backup, including delete markers, and their metadata, once the
+/// name of the backup is typed to confirm it. The deletion can be scoped to a
+/// key prefix and to versions older than a duration. Only the metadata of
+/// objects left with no versions is deleted. A dry run lists the
+/// versions and estimates the early deletion fee without deleting anything.
 /// 
 /// Arguments:
 /// 
-/// * `cli`: The `cli` parameter in the `ntfy_dispatcher` function seems to be a
-/// struct or object that contains the following fields:
+/// * `cli`: The parsed command line.
+/// * `args`: The backup to delete, the scope of the deletion and its
+/// confirmation.
+/// * `s3_client`: The S3 client, which the object store is opened with.
+/// * `dynamo_client`: The DynamoDB client, which the metadata store is opened
+/// with.
 /// 
 /// Returns:
 /// 
-/// The function `ntfy_dispatcher` returns an `Option<Dispatcher>`. If the `cli`
-/// argument does not have a `ntfy_topic` or `ntfy_url`, it returns `None`.
-/// Otherwise, it creates a `Dispatcher` using the provided `ntfy_url` and
-/// optionally sets credentials if `ntfy_username` and `ntfy_password` are provided.
-/// Finally, it attempts
-fn ntfy_dispatcher(cli: Cli) -> Option<Dispatcher<dispatcher::Async>> {
+/// The number of versions deleted and failed. `GdaError::PartialFailure` or
+/// `GdaError::TotalFailure` is returned if any version could not be deleted,
+/// such as because it is protected by Object Lock, and `GdaError::ConfigError`
+/// if `--confirm` does not name the backup. Hash trackers are only deleted
+/// once every version of their object was deleted, so objects which could not
+/// be deleted can still be restored.
+async fn delete_backup(cli: Cli, args: DeleteBackupArgs, s3_client: &mut S3Client, dynamo_client: &mut DynamoClient) -> Result<(usize, usize), GdaError> {
+    let aws_args: AwsArgs = args.clone().into();
+    let name = backup_name(&args.backend, aws_args.clone());
+
+    let (objects, metadata) = storage::open(args.backend.clone(), aws_args, s3_client, dynamo_client)
+        .map_err(Box::new)?;
+
+    let deletion = match delete::Deletion::find(&args, objects.as_ref()).await {
+        Ok(deletion) => deletion,
+        Err(error) => {
+            error!("Failed to list the object versions of {name}: {:?}", error);
+            return Err(GdaError::StorageError(Box::new(error)));
+        },
+    };
+    deletion.log();
 
-    if !cli.ntfy_topic.is_some() || !cli.ntfy_url.is_some() {
-        info!("ntfy disabled. Missing url or topic.");
-        return None;
+    if cli.dry_run {
+        deletion.report(&cli);
+        return Ok((deletion.versions.len(), 0));
     }
 
-    let mut dispatcher = dispatcher::builder(cli.ntfy_url.unwrap());
+    // Get confirmation, by typing the name of the backup rather than "y", so that the wrong backup is not deleted by habit
+    match &args.confirm {
+        Some(confirm) if *confirm != name => {
+            return Err(GdaError::ConfigError(format!("--confirm \"{confirm}\" does not match the backup \"{name}\".")));
+        },
+        Some(_) => (),
+        None => {
+            let mut buffer = String::new();
+
+            // Prompt on stderr, so that stdout only contains JSON output
+            eprintln!("Type the name of the backup to permanently delete it: {name}");
+            io::stdin().read_line(&mut buffer)?;
+
+            if buffer.trim() != name {
+                info!("Aborting...");
+                return Ok((0, 0));
+            }
+        },
+    }
 
-    if cli.ntfy_username.is_some() && cli.ntfy_password.is_some() {
-        dispatcher = dispatcher.credentials(Auth::credentials(cli.ntfy_username.unwrap(), cli.ntfy_password.unwrap()))
+    // Delete objects before their metadata, so that versions which could not be deleted, such as locked ones, can still be restored
+    let (deleted, failed, emptied) = deletion.delete(&cli, objects.as_ref()).await;
+    info!("Delete backup: {deleted} versions deleted, {failed} failed.");
+
+    // Only a backup whose every version was deleted loses all of its metadata, including its heartbeats
+    let metadata_result = match !args.is_scoped() && failed == 0 {
+        true => metadata.delete_all().await,
+        false => metadata.delete_many(emptied).await,
     };
 
-    dispatcher.build_async().ok()
-}
\ No newline at end of file
+    if let Err(error) = &metadata_result {
+        error!("Failed to delete the metadata of {name}: {:?}", error);
+    }
+
+    metadata_result.map_err(Box::new)?;
+
+    GdaError::from_counts(deleted, failed)
+}
+
+/// The function `notification_subject` describes what a command runs on, for
+/// its notifications.
+/// 
+/// Arguments:
+/// 
+/// * `command`: The parsed command.
+/// 
+/// Returns:
+/// 
+/// The target directory or backup the command runs on, or `None` if the
+/// command does not send notifications.
+fn notification_subject(command: &Commands) -> Option<String> {
+    match command {
+        Commands::Backup(args) => Some(args.target_dir.clone()),
+        Commands::Daemon(args) => Some(args.backup.target_dir.clone()),
+        Commands::Restore(args) => Some(args.target_dir.clone()),
+        Commands::Verify(args) => Some(backup_name(&args.backend, args.clone().into())),
+        Commands::CheckFreshness(args) => match &args.backend {
+            Some(backend @ Backend::Local(_)) => Some(backend.to_string()),
+            _ => Some(AwsArgs::from(args.clone()).dynamo_table),
+        },
+        Commands::Prune(args) => Some(backup_name(&args.backend, args.clone().into())),
+        Commands::DeleteBackup(args) => Some(backup_name(&args.backend, args.clone().into())),
+        Commands::CleanDynamo(_) | Commands::ClearDatabase(_) | Commands::RebuildState(_) | Commands::ForceUnlock(_) | Commands::Lock(_) => None,
+    }
+}
+
+/// The function `backup_name` names a backup by its local directory or S3
+/// bucket.
+fn backup_name(backend: &Option<Backend>, aws_args: AwsArgs) -> String {
+    match backend {
+        Some(backend @ Backend::Local(_)) => backend.to_string(),
+        _ => aws_args.bucket_name,
+    }
+}
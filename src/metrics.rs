@@ -0,0 +1,290 @@
+use std::fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::interceptors::context::{BeforeSerializationInterceptorContextRef, FinalizerInterceptorContextRef};
+use aws_smithy_runtime_api::client::interceptors::Intercept;
+use aws_smithy_runtime_api::client::orchestrator::Metadata;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use aws_smithy_types::config_bag::{ConfigBag, Storable, StoreReplace};
+use log::{debug, error};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::report::CommandReport;
+
+// The metric recording the last successful run, which a textfile keeps across failed runs
+const LAST_SUCCESS_METRIC: &str = "gda_backup_last_success_timestamp_seconds";
+
+/// The `Metrics` struct holds every metric exported by gda_backup.
+pub struct Metrics {
+    registry: Registry,
+    pub last_run_timestamp: GaugeVec,
+    pub last_success_timestamp: GaugeVec,
+    pub last_run_duration: GaugeVec,
+    pub last_run_exit_code: IntGaugeVec,
+    pub last_run_files: IntGaugeVec,
+    pub files_scanned: IntCounter,
+    pub hashed_bytes: IntCounter,
+    pub uploaded_bytes: IntCounter,
+    pub uploaded_objects: IntCounter,
+    pub failures: IntCounterVec,
+    pub aws_requests: IntCounterVec,
+    pub aws_request_duration: HistogramVec,
+}
+
+/// The metrics of this process.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+
+    /// The function `new` creates and registers every metric.
+    fn new() -> Metrics {
+        let registry = Registry::new();
+
+        let metrics = Metrics {
+            last_run_timestamp: GaugeVec::new(
+                Opts::new("gda_backup_last_run_timestamp_seconds", "When the last run of a command finished, in seconds since the epoch."),
+                &["command"],
+            ).expect("Invalid metric."),
+            last_success_timestamp: GaugeVec::new(
+                Opts::new(LAST_SUCCESS_METRIC, "When the last successful run of a command finished, in seconds since the epoch."),
+                &["command"],
+            ).expect("Invalid metric."),
+            last_run_duration: GaugeVec::new(
+                Opts::new("gda_backup_last_run_duration_seconds", "How long the last run of a command took."),
+                &["command"],
+            ).expect("Invalid metric."),
+            last_run_exit_code: IntGaugeVec::new(
+                Opts::new("gda_backup_last_run_exit_code", "The exit code of the last run of a command. 0 is success."),
+                &["command"],
+            ).expect("Invalid metric."),
+            last_run_files: IntGaugeVec::new(
+                Opts::new("gda_backup_last_run_files", "The number of files the last run of a command succeeded and failed on."),
+                &["command", "result"],
+            ).expect("Invalid metric."),
+            files_scanned: IntCounter::new("gda_backup_files_scanned_total", "Files found while scanning the backup target.")
+                .expect("Invalid metric."),
+            hashed_bytes: IntCounter::new("gda_backup_hashed_bytes_total", "Bytes read while hashing changed files.")
+                .expect("Invalid metric."),
+            uploaded_bytes: IntCounter::new("gda_backup_uploaded_bytes_total", "Bytes uploaded to the object store.")
+                .expect("Invalid metric."),
+            uploaded_objects: IntCounter::new("gda_backup_uploaded_objects_total", "Objects uploaded to the object store.")
+                .expect("Invalid metric."),
+            failures: IntCounterVec::new(
+                Opts::new("gda_backup_failures_total", "Failed operations, by the kind of operation."),
+                &["kind"],
+            ).expect("Invalid metric."),
+            aws_requests: IntCounterVec::new(
+                Opts::new("gda_backup_aws_requests_total", "AWS requests, including their retries, by result."),
+                &["service", "operation", "result"],
+            ).expect("Invalid metric."),
+            aws_request_duration: HistogramVec::new(
+                HistogramOpts::new("gda_backup_aws_request_duration_seconds", "How long AWS requests took, including their retries."),
+                &["service", "operation"],
+            ).expect("Invalid metric."),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 12] = [
+            Box::new(metrics.last_run_timestamp.clone()),
+            Box::new(metrics.last_success_timestamp.clone()),
+            Box::new(metrics.last_run_duration.clone()),
+            Box::new(metrics.last_run_exit_code.clone()),
+            Box::new(metrics.last_run_files.clone()),
+            Box::new(metrics.files_scanned.clone()),
+            Box::new(metrics.hashed_bytes.clone()),
+            Box::new(metrics.uploaded_bytes.clone()),
+            Box::new(metrics.uploaded_objects.clone()),
+            Box::new(metrics.failures.clone()),
+            Box::new(metrics.aws_requests.clone()),
+            Box::new(metrics.aws_request_duration.clone()),
+        ];
+
+        for collector in collectors {
+            metrics.registry.register(collector).expect("Metric registered twice.");
+        }
+
+        metrics
+    }
+
+    /// The function `failure` counts a failed operation.
+    ///
+    /// Arguments:
+    ///
+    /// * `kind`: The kind of operation, such as "upload" or "restore".
+    pub fn failure(&self, kind: &str) {
+        self.failures.with_label_values(&[kind]).inc();
+    }
+
+    /// The function `record_run` records the outcome of a command.
+    ///
+    /// Arguments:
+    ///
+    /// * `command_report`: The final report of the command.
+    /// * `duration`: How long the command took.
+    pub fn record_run(&self, command_report: &CommandReport, duration: Duration) {
+        let command = command_report.command.as_str();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+
+        self.last_run_timestamp.with_label_values(&[command]).set(now);
+        self.last_run_duration.with_label_values(&[command]).set(duration.as_secs_f64());
+        self.last_run_exit_code.with_label_values(&[command]).set(command_report.exit_code.into());
+        self.last_run_files.with_label_values(&[command, "succeeded"]).set(command_report.succeeded as i64);
+        self.last_run_files.with_label_values(&[command, "failed"]).set(command_report.failed as i64);
+
+        if command_report.exit_code == 0 {
+            self.last_success_timestamp.with_label_values(&[command]).set(now);
+        }
+    }
+
+    /// The function `render` formats every metric in the Prometheus text
+    /// exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+
+        if let Err(error) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {:?}", error);
+        }
+
+        String::from_utf8_lossy(&buffer).to_string()
+    }
+
+    /// The function `write_textfile` writes every metric to a file read by
+    /// node_exporter's textfile collector. The file is replaced atomically, and
+    /// the last success times of commands which did not succeed in this run are
+    /// carried over from the previous file.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path of the file, which should end in ".prom".
+    ///
+    /// Returns:
+    ///
+    /// The error encountered writing the file, if any.
+    pub fn write_textfile(&self, path: &Path) -> Result<(), IoError> {
+        if let Ok(previous) = fs::read_to_string(path) {
+            for (command, timestamp) in previous.lines().filter_map(parse_last_success) {
+                let gauge = self.last_success_timestamp.with_label_values(&[command]);
+                if gauge.get() == 0.0 {
+                    gauge.set(timestamp);
+                }
+            }
+        }
+
+        let partial = path.with_extension("prom.partial");
+        fs::write(&partial, self.render())?;
+        fs::rename(partial, path)
+    }
+}
+
+/// The function `parse_last_success` parses a line of a textfile recording the
+/// last success of a command.
+///
+/// Arguments:
+///
+/// * `line`: A line such as `gda_backup_last_success_timestamp_seconds{command="backup"} 1700000000`.
+///
+/// Returns:
+///
+/// The command and timestamp, or `None` if the line records something else.
+fn parse_last_success(line: &str) -> Option<(&str, f64)> {
+    let labels = line.strip_prefix(LAST_SUCCESS_METRIC)?.strip_prefix("{command=\"")?;
+    let (command, value) = labels.split_once("\"}")?;
+
+    Some((command, value.trim().parse().ok()?))
+}
+
+/// The function `serve` serves the metrics at `/metrics` over HTTP until the
+/// process exits.
+///
+/// Arguments:
+///
+/// * `listener`: The bound listener to accept connections from.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                error!("Failed to accept metrics connection: {:?}", error);
+                continue;
+            },
+        };
+
+        tokio::spawn(async move {
+            if let Err(error) = respond(&mut stream).await {
+                debug!("Failed to serve metrics: {:?}", error);
+            }
+        });
+    }
+}
+
+/// The function `respond` answers a single HTTP request.
+async fn respond(stream: &mut TcpStream) -> Result<(), IoError> {
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<&str>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// When an AWS request started, stored for the duration of the request.
+#[derive(Debug, Clone)]
+struct RequestStart(Instant);
+
+impl Storable for RequestStart {
+    type Storer = StoreReplace<Self>;
+}
+
+/// The `AwsMetricsInterceptor` struct counts and times every request made by
+/// an S3 or DynamoDB client.
+#[derive(Debug)]
+pub struct AwsMetricsInterceptor;
+
+impl Intercept for AwsMetricsInterceptor {
+    fn name(&self) -> &'static str {
+        "AwsMetricsInterceptor"
+    }
+
+    fn read_before_execution(&self, _context: &BeforeSerializationInterceptorContextRef<'_>, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        cfg.interceptor_state().store_put(RequestStart(Instant::now()));
+        Ok(())
+    }
+
+    fn read_after_execution(&self, context: &FinalizerInterceptorContextRef<'_>, _runtime_components: &RuntimeComponents, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        let (service, operation) = match cfg.load::<Metadata>() {
+            Some(metadata) => (metadata.service().to_string(), metadata.name().to_string()),
+            None => ("unknown".to_string(), "unknown".to_string()),
+        };
+
+        let result = match context.output_or_error() {
+            Some(Ok(_)) => "success",
+            _ => "error",
+        };
+
+        METRICS.aws_requests.with_label_values(&[service.as_str(), operation.as_str(), result]).inc();
+
+        if let Some(RequestStart(start)) = cfg.load::<RequestStart>() {
+            METRICS.aws_request_duration.with_label_values(&[service.as_str(), operation.as_str()]).observe(start.elapsed().as_secs_f64());
+        }
+
+        Ok(())
+    }
+}
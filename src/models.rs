@@ -76,6 +76,25 @@ impl LocalFile {
             .get_result(conn)
     }
 
+    /// The function `insert_many` inserts several records into the
+    /// `local_state` table with a single statement.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `conn`: The connection to the local database.
+    /// * `files`: The files to insert. Each file binds two parameters, and a
+    /// statement may bind at most 65535, so at most 32767 files can be inserted
+    /// at once.
+    /// 
+    /// Returns:
+    /// 
+    /// The number of records inserted, or the error which caused none to be.
+    pub fn insert_many(conn: &mut PgConnection, files: &[LocalFile]) -> Result<usize, Error> {
+        diesel::insert_into(local_state)
+            .values(files)
+            .execute(conn)
+    }
+
     /// The function deletes a record from a PostgreSQL database table based on the
     /// file path provided.
     /// 
@@ -122,6 +141,26 @@ impl GlacierFile {
             .get_result(conn)
     }
 
+    /// The function `insert_many` inserts several records into the
+    /// `glacier_state` table with a single statement. Unlike `insert`, records
+    /// which already exist are not updated, and fail the whole statement.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `conn`: The connection to the local database.
+    /// * `files`: The files to insert. Each file binds three parameters, and a
+    /// statement may bind at most 65535, so at most 21845 files can be inserted
+    /// at once.
+    /// 
+    /// Returns:
+    /// 
+    /// The number of records inserted, or the error which caused none to be.
+    pub fn insert_many(conn: &mut PgConnection, files: &[GlacierFile]) -> Result<usize, Error> {
+        diesel::insert_into(glacier_state)
+            .values(files)
+            .execute(conn)
+    }
+
     /// The function deletes a record from a database table based on the file path.
     /// 
     /// Arguments:
@@ -0,0 +1,499 @@
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use ntfy::{Auth, Dispatcher, Payload, Priority, dispatcher};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::environment::{NotifyArgs, NotifyDigest, NotifyOn};
+use crate::error::EXIT_ABORTED;
+use crate::report::{self, CommandReport, CommandStatus, FileAction};
+use crate::smtp::{self, SmtpConfig, SmtpError};
+
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("NtfyError: {0}")]
+    NtfyError(#[from] ntfy::Error),
+
+    #[error("HttpError: {0}")]
+    HttpError(#[from] reqwest::Error),
+
+    #[error("SmtpError: {0}")]
+    SmtpError(#[from] SmtpError),
+
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// How urgent a notification is.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationPriority {
+    Default,
+    High,
+}
+
+/// A file which failed, as listed in a notification.
+#[derive(Serialize, Debug, Clone)]
+pub struct FailedFile {
+    pub path: String,
+    pub action: FileAction,
+    /// The kind of error, such as "S3PutError" or "ChecksumMismatch".
+    pub kind: String,
+    pub error: String,
+}
+
+/// The `Notification` struct is a message sent to every notification sink.
+/// Webhooks receive it as JSON.
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub title: String,
+    /// A markdown message, listing failed files if there are any.
+    pub message: String,
+    pub priority: NotificationPriority,
+    /// The final report of the command, absent from start notifications and
+    /// digests.
+    pub report: Option<CommandReport>,
+    pub failures: Vec<FailedFile>,
+    /// The number of failed files left out of `failures`.
+    pub omitted_failures: usize,
+}
+
+/// The `NotificationSink` trait sends notifications to a single channel.
+#[async_trait]
+pub trait NotificationSink: Send + Sync {
+
+    /// The name of the channel, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Sends a notification.
+    async fn send(&self, notification: &Notification) -> Result<(), NotifyError>;
+}
+
+/// The `NtfySink` struct publishes notifications to an ntfy topic.
+pub struct NtfySink {
+    dispatcher: Dispatcher<dispatcher::Async>,
+    topic: String,
+}
+
+#[async_trait]
+impl NotificationSink for NtfySink {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), NotifyError> {
+        let priority = match notification.priority {
+            NotificationPriority::Default => Priority::Default,
+            NotificationPriority::High => Priority::High,
+        };
+
+        Ok(self.dispatcher.send(&Payload::new(&self.topic)
+            .message(&notification.message)
+            .title(&notification.title)
+            .priority(priority)
+            .markdown(true)
+        ).await?)
+    }
+}
+
+/// The `WebhookSink` struct POSTs notifications to a URL as JSON.
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl NotificationSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), NotifyError> {
+        self.client.post(&self.url)
+            .json(notification)
+            .send().await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// The `GotifySink` struct sends notifications as messages of a Gotify
+/// application.
+pub struct GotifySink {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+#[async_trait]
+impl NotificationSink for GotifySink {
+    fn name(&self) -> &'static str {
+        "gotify"
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), NotifyError> {
+        let priority = match notification.priority {
+            NotificationPriority::Default => 5,
+            NotificationPriority::High => 8,
+        };
+
+        self.client.post(format!("{}/message", self.url.trim_end_matches('/')))
+            .header("X-Gotify-Key", &self.token)
+            .json(&json!({
+                "title": notification.title,
+                "message": notification.message,
+                "priority": priority,
+                "extras": { "client::display": { "contentType": "text/markdown" } },
+            }))
+            .send().await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// The `SmtpSink` struct emails notifications.
+pub struct SmtpSink {
+    config: SmtpConfig,
+}
+
+#[async_trait]
+impl NotificationSink for SmtpSink {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), NotifyError> {
+        Ok(smtp::send(&self.config, &notification.title, &notification.message).await?)
+    }
+}
+
+/// A command recorded for the next digest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DigestRun {
+    finished: DateTime<Utc>,
+    report: CommandReport,
+}
+
+/// The commands run during the current digest period, as stored in the
+/// digest file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Digest {
+    /// The day or week the runs belong to, such as "2024-05-01" or "2024-W18".
+    period: Option<String>,
+    runs: Vec<DigestRun>,
+}
+
+/// The `Notifier` struct sends notifications to every configured sink,
+/// deciding which notifications are sent and collecting digests.
+pub struct Notifier {
+    args: NotifyArgs,
+    sinks: Vec<Box<dyn NotificationSink>>,
+}
+
+impl Notifier {
+
+    /// The function `new` creates a sink for every channel whose settings are
+    /// supplied.
+    ///
+    /// Arguments:
+    ///
+    /// * `args`: The notification settings.
+    pub fn new(args: &NotifyArgs) -> Notifier {
+        let mut sinks: Vec<Box<dyn NotificationSink>> = vec![];
+        let client = reqwest::Client::new();
+
+        if let (Some(url), Some(topic)) = (&args.ntfy_url, &args.ntfy_topic) {
+            let mut dispatcher = dispatcher::builder(url);
+
+            if let (Some(username), Some(password)) = (&args.ntfy_username, &args.ntfy_password) {
+                dispatcher = dispatcher.credentials(Auth::credentials(username, password));
+            }
+
+            match dispatcher.build_async() {
+                Ok(dispatcher) => sinks.push(Box::new(NtfySink { dispatcher, topic: topic.clone() })),
+                Err(error) => error!("ntfy disabled. Failed to create dispatcher: {:?}", error),
+            }
+        }
+
+        if let Some(url) = &args.notify_webhook_url {
+            sinks.push(Box::new(WebhookSink { client: client.clone(), url: url.clone() }));
+        }
+
+        if let (Some(url), Some(token)) = (&args.gotify_url, &args.gotify_token) {
+            sinks.push(Box::new(GotifySink { client: client.clone(), url: url.clone(), token: token.clone() }));
+        }
+
+        if let (Some(host), Some(from)) = (&args.smtp_host, &args.smtp_from) {
+            if args.smtp_to.is_empty() {
+                error!("SMTP disabled. Missing recipients.");
+            }
+            else {
+                sinks.push(Box::new(SmtpSink { config: SmtpConfig {
+                    host: host.clone(),
+                    port: args.smtp_port,
+                    security: args.smtp_security,
+                    username: args.smtp_username.clone(),
+                    password: args.smtp_password.clone(),
+                    from: from.clone(),
+                    to: args.smtp_to.clone(),
+                }}));
+            }
+        }
+
+        if sinks.is_empty() {
+            info!("Notifications disabled. No ntfy, webhook, Gotify or SMTP settings supplied.");
+        }
+
+        Notifier { args: args.clone(), sinks }
+    }
+
+    /// The function `started` notifies that a command started, unless only
+    /// failures are notified.
+    ///
+    /// Arguments:
+    ///
+    /// * `title`: The title of the notification, such as "Backup starting".
+    /// * `message`: The message of the notification.
+    pub async fn started(&self, title: &str, message: String) {
+        if self.args.notify_on != NotifyOn::Always {
+            return;
+        }
+
+        self.send(&Notification {
+            title: title.to_string(),
+            message,
+            priority: NotificationPriority::Default,
+            report: None,
+            failures: vec![],
+            omitted_failures: 0,
+        }).await;
+    }
+
+    /// The function `finished` notifies that a command finished, listing the
+    /// files which failed, and records it for the next digest.
+    ///
+    /// Arguments:
+    ///
+    /// * `command_report`: The final report of the command.
+    /// * `subject`: What the command ran on, such as the target directory.
+    pub async fn finished(&self, command_report: &CommandReport, subject: Option<&str>) {
+        let notification = self.finished_notification(command_report, subject);
+
+        let notify = match self.args.notify_on {
+            NotifyOn::Always => true,
+            NotifyOn::Failure => command_report.status != CommandStatus::Succeeded,
+            NotifyOn::Never => false,
+        };
+
+        if notify {
+            self.send(&notification).await;
+        }
+
+        if let Err(error) = self.record_digest(command_report).await {
+            error!("Failed to record digest in {}: {:?}", self.args.notify_digest_file.display(), error);
+        }
+    }
+
+    /// The function `finished_notification` creates the notification of a
+    /// finished command, taking the files which failed from `report`.
+    fn finished_notification(&self, command_report: &CommandReport, subject: Option<&str>) -> Notification {
+        let name = command_name(&command_report.command);
+        let of_subject = subject.map(|subject| format!(" of {subject}")).unwrap_or_default();
+        let counts = format!("{} succeeded, {} failed.", command_report.succeeded, command_report.failed);
+
+        let (title, message, priority) = match command_report.status {
+            CommandStatus::Succeeded => (
+                format!("{} complete", capitalize(&name)),
+                format!("Completed {name}{of_subject}, {counts}"),
+                NotificationPriority::Default,
+            ),
+            CommandStatus::PartialFailure => (
+                format!("{} complete with failures", capitalize(&name)),
+                format!("Failed {name}{of_subject}, {counts}"),
+                NotificationPriority::High,
+            ),
+            CommandStatus::Failed if command_report.exit_code == EXIT_ABORTED => (
+                format!("{} aborted", capitalize(&name)),
+                format!("Aborted {name}{of_subject} to protect the backup. Nothing was changed."),
+                NotificationPriority::High,
+            ),
+            CommandStatus::Failed => (
+                format!("{} failed", capitalize(&name)),
+                format!("Failed {name}{of_subject}, {counts}"),
+                NotificationPriority::High,
+            ),
+        };
+
+        let mut failures: Vec<FailedFile> = report::take_failures().into_iter()
+            .map(|file_report| {
+                let error = file_report.error.unwrap_or_default();
+
+                FailedFile {
+                    path: file_report.path,
+                    action: file_report.action,
+                    kind: error.split(':').next().unwrap_or_default().trim().to_string(),
+                    error,
+                }
+            })
+            .collect();
+
+        let omitted_failures = failures.len().saturating_sub(self.args.notify_max_failures);
+        failures.truncate(self.args.notify_max_failures);
+
+        let mut message = message;
+
+        if let Some(error) = &command_report.error {
+            message += &format!("\n\nError: {error}");
+        }
+
+        if !failures.is_empty() {
+            message += "\n\nFailures:";
+            for failure in &failures {
+                message += &format!("\n- {} ({})", failure.path, failure.kind);
+            }
+        }
+
+        if omitted_failures > 0 {
+            message += &format!("\n- ...and {omitted_failures} more.");
+        }
+
+        Notification {
+            title,
+            message,
+            priority,
+            report: Some(command_report.clone()),
+            failures,
+            omitted_failures,
+        }
+    }
+
+    /// The function `record_digest` adds a command to the digest file. When
+    /// the command is the first of a new day or week, the digest of the
+    /// previous one is sent first.
+    ///
+    /// Arguments:
+    ///
+    /// * `command_report`: The final report of the command.
+    ///
+    /// Returns:
+    ///
+    /// The error encountered reading or writing the digest file, if any.
+    async fn record_digest(&self, command_report: &CommandReport) -> Result<(), NotifyError> {
+        let Some(digest_period) = self.args.notify_digest else {
+            return Ok(());
+        };
+
+        let path = &self.args.notify_digest_file;
+
+        let mut digest: Digest = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => Digest::default(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let now = Utc::now();
+        let period = match digest_period {
+            NotifyDigest::Daily => now.format("%Y-%m-%d").to_string(),
+            NotifyDigest::Weekly => now.format("%G-W%V").to_string(),
+        };
+
+        if digest.period.as_ref().is_some_and(|previous| *previous != period) && !digest.runs.is_empty() {
+            self.send(&self.digest_notification(digest_period, &digest)).await;
+            digest.runs.clear();
+        }
+
+        digest.period = Some(period);
+        digest.runs.push(DigestRun { finished: now, report: command_report.clone() });
+
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_string(&digest)?)?;
+        fs::rename(partial, path)?;
+
+        Ok(())
+    }
+
+    /// The function `digest_notification` summarises every command of a
+    /// digest, listing those which failed.
+    fn digest_notification(&self, digest_period: NotifyDigest, digest: &Digest) -> Notification {
+        let failed_runs: Vec<&DigestRun> = digest.runs.iter()
+            .filter(|run| run.report.status != CommandStatus::Succeeded)
+            .collect();
+
+        let last_success = digest.runs.iter()
+            .filter(|run| run.report.command == "backup" && run.report.status == CommandStatus::Succeeded)
+            .map(|run| run.finished.format("%Y-%m-%d %H:%M UTC").to_string())
+            .next_back()
+            .unwrap_or("none".to_string());
+
+        let mut message = format!(
+            "{}: {} commands run, {} failed. Last successful backup: {last_success}.",
+            digest.period.as_deref().unwrap_or_default(),
+            digest.runs.len(),
+            failed_runs.len(),
+        );
+
+        for run in failed_runs.iter().take(self.args.notify_max_failures) {
+            message += &format!(
+                "\n- {} {}: {} succeeded, {} failed.{}",
+                run.finished.format("%Y-%m-%d %H:%M UTC"),
+                command_name(&run.report.command),
+                run.report.succeeded,
+                run.report.failed,
+                run.report.error.as_ref().map(|error| format!(" {error}")).unwrap_or_default(),
+            );
+        }
+
+        if failed_runs.len() > self.args.notify_max_failures {
+            message += &format!("\n- ...and {} more.", failed_runs.len() - self.args.notify_max_failures);
+        }
+
+        let title = match digest_period {
+            NotifyDigest::Daily => "Daily backup digest",
+            NotifyDigest::Weekly => "Weekly backup digest",
+        };
+
+        Notification {
+            title: title.to_string(),
+            message,
+            priority: if failed_runs.is_empty() { NotificationPriority::Default } else { NotificationPriority::High },
+            report: None,
+            failures: vec![],
+            omitted_failures: 0,
+        }
+    }
+
+    /// The function `send` sends a notification to every sink, logging those
+    /// which fail.
+    async fn send(&self, notification: &Notification) {
+        for sink in &self.sinks {
+            if let Err(error) = sink.send(notification).await {
+                error!("Failed to send {} notification: {:?}", sink.name(), error);
+            }
+        }
+    }
+}
+
+/// The function `command_name` converts a command, such as "delete-backup",
+/// into words.
+fn command_name(command: &str) -> String {
+    command.replace('-', " ")
+}
+
+fn capitalize(words: &str) -> String {
+    let mut characters = words.chars();
+
+    match characters.next() {
+        Some(first) => first.to_uppercase().chain(characters).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info};
+
+use crate::backup::is_empty_hash;
+use crate::environment::{Cli, LockStatusArgs};
+use crate::metrics::METRICS;
+use crate::report::{self, FileAction, FileReport};
+use crate::storage::{MetadataStore, ObjectLockInfo, ObjectStore, StorageError};
+
+/// The function `status` reports the Object Lock configuration of the object
+/// store, and the retention and legal hold of the object of every backed up
+/// file. Objects are never downloaded.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line.
+/// * `args`: The arguments of the lock status command.
+/// * `objects`: The object store which file contents are stored in.
+/// * `metadata`: The metadata store which hash trackers are read from.
+///
+/// Returns:
+///
+/// The number of files whose lock was and was not read, or the error
+/// encountered reading the configuration or the hash trackers.
+pub async fn status(cli: Cli, args: LockStatusArgs, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<(usize, usize), StorageError> {
+
+    match objects.lock_configuration().await? {
+        Some(configuration) => info!("Object Lock: {configuration}."),
+        None => info!("Object Lock: not enabled."),
+    }
+
+    let now = Utc::now();
+
+    let mut reported = 0;
+    let mut failed = 0;
+
+    let mut locked_objects = 0;
+    let mut unlocked_objects = 0;
+    let mut legal_holds = 0;
+    // The earliest and latest time a retention ends
+    let mut retain_until: Option<(DateTime<Utc>, DateTime<Utc>)> = None;
+
+    for hash_tracker in metadata.get_all().await? {
+
+        let files = hash_tracker.restore_paths(args.host_id.as_deref());
+
+        if files.is_empty() {
+            continue;
+        }
+
+        let result = if is_empty_hash(&hash_tracker.hash) {
+            Ok(ObjectLockInfo::default())
+        }
+        else {
+            match objects.head(hash_tracker.hash.clone()).await {
+                Ok(Some(object)) => Ok(object.lock),
+                Ok(None) => Err(StorageError::MissingObject(hash_tracker.hash.clone())),
+                Err(error) => Err(error),
+            }
+        };
+
+        match &result {
+            Ok(lock) => {
+                reported += files.len();
+
+                match lock.is_locked(now) {
+                    true => locked_objects += 1,
+                    false => unlocked_objects += 1,
+                }
+                if lock.legal_hold {
+                    legal_holds += 1;
+                }
+                if let Some(until) = lock.retain_until.filter(|until| *until > now) {
+                    retain_until = Some(match retain_until {
+                        Some((earliest, latest)) => (earliest.min(until), latest.max(until)),
+                        None => (until, until),
+                    });
+                }
+            },
+            Err(error) => {
+                failed += files.len();
+                error!("Failed to read the lock of {} files: {:?}\nError: {:?}", files.len(), files, error);
+                METRICS.failure("lock_status");
+            },
+        }
+
+        for file in files {
+            let file_report = FileReport::new(&cli, file, FileAction::LockStatus, Some(hash_tracker.hash.clone()));
+
+            report::file(&cli, &match &result {
+                Ok(lock) => FileReport {
+                    reason: Some(lock.describe(now)),
+                    ..file_report
+                },
+                Err(error) => file_report.failed(error),
+            });
+        }
+    }
+
+    info!("Lock status: {locked_objects} objects locked, {unlocked_objects} unlocked, {legal_holds} with a legal hold.");
+
+    if let Some((earliest, latest)) = retain_until {
+        info!("Lock status: Retentions end between {} and {}.",
+            earliest.to_rfc3339_opts(SecondsFormat::Secs, true),
+            latest.to_rfc3339_opts(SecondsFormat::Secs, true),
+        );
+    }
+
+    Ok((reported, failed))
+}
@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use aws_sdk_s3::types::StorageClass;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::dynamodb::HashTracker;
+use crate::environment::BackupArgs;
+
+// Bytes in a GB, as S3 storage is billed
+const GB: f64 = (1u64 << 30) as f64;
+
+/// What backing up a hash does to its object and hash tracker.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    /// The object is uploaded.
+    Upload,
+    /// The deleted object is brought back.
+    Undelete,
+    /// The object is deleted.
+    Delete,
+    /// Only the hash tracker is written, such as when a file with existing
+    /// contents is added.
+    Update,
+    /// Nothing is written, such as when a file is touched without changing its
+    /// contents.
+    None,
+}
+
+/// A file which a backup records under a hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedFile {
+    pub file_path: String,
+    pub modified: SystemTime,
+}
+
+/// The `PlannedHash` struct records what backing up the changes to one hash
+/// would do.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedHash {
+    pub hash: String,
+    pub action: PlanAction,
+    /// The size of the object in bytes, if it is uploaded, undeleted or
+    /// deleted and its size is known.
+    pub size: Option<u64>,
+    /// The storage class of the object, if it is uploaded, undeleted or
+    /// deleted. `None` is the bucket's default storage class.
+    pub storage_class: Option<String>,
+    /// The number of writes to the metadata store, such as DynamoDB.
+    pub metadata_writes: usize,
+    /// Files which are new or changed, and now have this hash.
+    pub created_files: Vec<PlannedFile>,
+    /// Files which are missing or changed, and no longer have this hash.
+    pub deleted_files: Vec<String>,
+}
+
+/// The `PlanSummary` struct totals what a plan would do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PlanSummary {
+    pub uploads: usize,
+    pub upload_bytes: u64,
+    pub undeletes: usize,
+    pub undelete_bytes: u64,
+    pub deletes: usize,
+    pub delete_bytes: u64,
+    pub metadata_writes: usize,
+    /// The change in the bytes stored once the plan is applied.
+    pub storage_delta_bytes: i64,
+    /// The estimated change in the monthly storage bill in USD, at S3 list
+    /// prices in us-east-1. Deleted objects are still billed until their
+    /// minimum storage duration has passed.
+    pub monthly_cost_delta: f64,
+}
+
+/// The `Plan` struct records every change a dry run of a backup found, so
+/// that it can be reviewed and applied later by `backup --apply-plan`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Plan {
+    pub target_dir: String,
+    pub host_id: Option<String>,
+    pub created: DateTime<Utc>,
+    pub summary: PlanSummary,
+    pub hashes: Vec<PlannedHash>,
+}
+
+/// The `Planner` struct collects the plan of a dry run as its pages are
+/// planned. Each backup starts its own, so runs of the daemon never share one.
+#[derive(Debug, Default)]
+pub struct Planner {
+    hashes: Vec<PlannedHash>,
+    /// Hash trackers as the dry run would have written them, by hash.
+    trackers: HashMap<String, HashTracker>,
+}
+
+impl Planner {
+
+    /// The function `record` adds a hash to the plan.
+    ///
+    /// Arguments:
+    ///
+    /// * `planned_hash`: What backing up the hash would do.
+    /// * `hash_tracker`: The hash tracker as backing up the hash would write it.
+    pub fn record(&mut self, planned_hash: PlannedHash, hash_tracker: HashTracker) {
+        self.hashes.push(planned_hash);
+        self.trackers.insert(hash_tracker.hash.clone(), hash_tracker);
+    }
+
+    /// The function `overlay` replaces hash trackers read from the metadata
+    /// store with the trackers the dry run would have written, so that each
+    /// page of a dry run is planned as if the pages before it were backed up.
+    ///
+    /// Arguments:
+    ///
+    /// * `hashes`: The hashes which were read.
+    /// * `hash_trackers`: The trackers which were read, by hash.
+    pub fn overlay(&self, hashes: &HashSet<String>, hash_trackers: &mut HashMap<String, HashTracker>) {
+        for hash in hashes {
+            if let Some(hash_tracker) = self.trackers.get(hash) {
+                hash_trackers.insert(hash.clone(), hash_tracker.clone());
+            }
+        }
+    }
+
+    /// The function `finish` returns the plan of the dry run.
+    ///
+    /// Arguments:
+    ///
+    /// * `args`: The arguments of the backup which was planned.
+    pub fn finish(self, args: &BackupArgs) -> Plan {
+        Plan {
+            target_dir: args.target_dir.clone(),
+            host_id: args.host_id.clone(),
+            created: Utc::now(),
+            summary: summarize(&self.hashes),
+            hashes: self.hashes,
+        }
+    }
+}
+
+/// The function `summarize` totals the hashes of a plan.
+fn summarize(hashes: &[PlannedHash]) -> PlanSummary {
+    let mut summary = PlanSummary::default();
+
+    for planned_hash in hashes {
+        let size = planned_hash.size.unwrap_or_default();
+        let storage_class = planned_hash.storage_class.as_deref().map(StorageClass::from);
+        let cost = size as f64 / GB * monthly_price(storage_class.as_ref());
+
+        summary.metadata_writes += planned_hash.metadata_writes;
+
+        match planned_hash.action {
+            PlanAction::Upload => {
+                summary.uploads += 1;
+                summary.upload_bytes += size;
+                summary.storage_delta_bytes += size as i64;
+                summary.monthly_cost_delta += cost;
+            },
+            PlanAction::Undelete => {
+                summary.undeletes += 1;
+                summary.undelete_bytes += size;
+                summary.storage_delta_bytes += size as i64;
+                summary.monthly_cost_delta += cost;
+            },
+            PlanAction::Delete => {
+                summary.deletes += 1;
+                summary.delete_bytes += size;
+                summary.storage_delta_bytes -= size as i64;
+                summary.monthly_cost_delta -= cost;
+            },
+            PlanAction::Update | PlanAction::None => (),
+        }
+    }
+
+    summary
+}
+
+/// The function `monthly_price` returns the S3 list price in us-east-1 of
+/// storing a GB for a month, in USD.
+///
+/// Arguments:
+///
+/// * `storage_class`: The storage class, or `None` for the bucket's default,
+/// which is assumed to be STANDARD.
+pub fn monthly_price(storage_class: Option<&StorageClass>) -> f64 {
+    match storage_class {
+        Some(StorageClass::StandardIa) => 0.0125,
+        Some(StorageClass::OnezoneIa) => 0.01,
+        Some(StorageClass::GlacierIr) => 0.004,
+        Some(StorageClass::Glacier) => 0.0036,
+        Some(StorageClass::DeepArchive) => 0.00099,
+        Some(StorageClass::ReducedRedundancy) => 0.024,
+        _ => 0.023,
+    }
+}
+
+/// The function `min_storage_days` returns the minimum number of days S3
+/// bills an object of a storage class for, even if it is deleted sooner.
+///
+/// Arguments:
+///
+/// * `storage_class`: The storage class, or `None` for STANDARD.
+pub fn min_storage_days(storage_class: Option<&StorageClass>) -> i64 {
+    match storage_class {
+        Some(StorageClass::StandardIa | StorageClass::OnezoneIa) => 30,
+        Some(StorageClass::GlacierIr | StorageClass::Glacier) => 90,
+        Some(StorageClass::DeepArchive) => 180,
+        _ => 0,
+    }
+}
+
+/// The function `early_deletion_fee` estimates the fee S3 charges for
+/// deleting an object before its minimum storage duration, which is the price
+/// of storing it for the rest of that duration.
+///
+/// Arguments:
+///
+/// * `size`: The size of the object in bytes.
+/// * `storage_class`: The storage class, or `None` for STANDARD.
+/// * `age_days`: The number of days since the object was stored.
+///
+/// Returns:
+///
+/// The fee in USD, or 0 once the minimum storage duration has passed.
+pub fn early_deletion_fee(size: u64, storage_class: Option<&StorageClass>, age_days: i64) -> f64 {
+    let remaining_days = (min_storage_days(storage_class) - age_days).max(0);
+
+    size as f64 / GB * monthly_price(storage_class) * remaining_days as f64 / 30.0
+}
+
+impl Plan {
+
+    /// The function `log` logs what the plan would do.
+    pub fn log(&self) {
+        let summary = &self.summary;
+
+        info!("Plan: {} uploads ({} bytes), {} undeletes ({} bytes), {} deletes ({} bytes), {} metadata writes.",
+            summary.uploads, summary.upload_bytes,
+            summary.undeletes, summary.undelete_bytes,
+            summary.deletes, summary.delete_bytes,
+            summary.metadata_writes,
+        );
+        info!("Plan: Storage changes by {} bytes, estimated at ${:.4} per month.", summary.storage_delta_bytes, summary.monthly_cost_delta);
+    }
+
+    /// The function `save` writes the plan to a file as JSON.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path of the file, which is replaced if it exists.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+
+        info!("Plan saved to {}.", path.display());
+
+        Ok(())
+    }
+
+    /// The function `load` reads a plan saved by `save`.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path of the file.
+    pub fn load(path: &Path) -> io::Result<Plan> {
+        let reader = BufReader::new(File::open(path)?);
+
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
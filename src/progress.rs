@@ -0,0 +1,390 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use indicatif::{BinaryBytes, HumanCount, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::info;
+
+use crate::environment::{Cli, ProgressMode};
+
+// How progress is reported, set once by `init`. Progress is not reported before it is set.
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+// Every progress bar is drawn through this, so that log lines can be printed above them.
+static BARS: LazyLock<MultiProgress> = LazyLock::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()));
+// The stage which uploads and downloads deep within the object stores are reported to.
+static TRANSFER: Mutex<Option<Progress>> = Mutex::new(None);
+
+struct Settings {
+    bars: bool,
+    logs: bool,
+    interval: Duration,
+}
+
+/// The function `init` decides how progress is reported for the rest of the
+/// process.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line, which selects the progress mode and the
+/// interval between progress log lines.
+pub fn init(cli: &Cli) {
+    let bars = !cli.quiet && match cli.progress {
+        ProgressMode::Auto => io::stderr().is_terminal(),
+        ProgressMode::Bar => true,
+        ProgressMode::Log | ProgressMode::Never => false,
+    };
+
+    let _ = SETTINGS.set(Settings {
+        bars,
+        logs: !bars && !cli.quiet && cli.progress != ProgressMode::Never,
+        interval: Duration::from_secs(cli.progress_interval.max(1)),
+    });
+}
+
+/// The function `enabled` checks whether progress is reported, so that
+/// totals which are costly to compute can be skipped when it is not.
+pub fn enabled() -> bool {
+    SETTINGS.get().is_some_and(|settings| settings.bars || settings.logs)
+}
+
+/// The function `bars` checks whether progress is drawn as bars, in which
+/// case log lines must be written through `LogWriter`.
+pub fn bars() -> bool {
+    SETTINGS.get().is_some_and(|settings| settings.bars)
+}
+
+/// The `LogWriter` struct writes log lines to stderr above any progress bars,
+/// rather than through them.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        BARS.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// What the progress of a stage is counted in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Files,
+    Bytes,
+}
+
+impl Unit {
+    fn amount(self, amount: u64) -> String {
+        match self {
+            Unit::Files => format!("{} files", HumanCount(amount)),
+            Unit::Bytes => BinaryBytes(amount).to_string(),
+        }
+    }
+
+    fn rate(self, rate: f64) -> String {
+        match self {
+            Unit::Files => format!("{rate:.0} files/s"),
+            Unit::Bytes => format!("{}/s", BinaryBytes(rate as u64)),
+        }
+    }
+}
+
+/// The `Stage` struct holds the progress of one stage of a command, such as
+/// hashing.
+struct Stage {
+    name: &'static str,
+    unit: Unit,
+    /// The amount the stage will reach, if known. It may grow as more work is found.
+    total: Option<AtomicU64>,
+    bar: Option<ProgressBar>,
+    position: AtomicU64,
+    /// Bytes transferred by a stage counted in files.
+    bytes: AtomicU64,
+    detail: Mutex<String>,
+    started: Instant,
+    last_log: Mutex<Instant>,
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// The `Progress` struct reports the progress of a stage as a bar or as
+/// periodic log lines. Clones report to the same stage. When progress is not
+/// reported, every method does nothing.
+#[derive(Clone)]
+pub struct Progress {
+    stage: Option<Arc<Stage>>,
+}
+
+impl Progress {
+
+    /// The function `start` starts reporting the progress of a stage.
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: The name of the stage, such as "Hashing".
+    /// * `unit`: What the stage's progress is counted in.
+    /// * `total`: The amount the stage will reach when it is done, if known.
+    /// Stages with a total report an ETA.
+    pub fn start(name: &'static str, unit: Unit, total: Option<u64>) -> Progress {
+        let Some(settings) = SETTINGS.get().filter(|settings| settings.bars || settings.logs) else {
+            return Progress { stage: None };
+        };
+
+        let bar = settings.bars.then(|| {
+            let (bar, template) = match (unit, total) {
+                (Unit::Files, Some(total)) => (ProgressBar::new(total), "{prefix} [{bar:30}] {human_pos}/{human_len} files, ETA {eta} {msg}"),
+                (Unit::Files, None) => (ProgressBar::new_spinner(), "{spinner} {prefix}: {human_pos} files ({per_sec}) {msg}"),
+                (Unit::Bytes, Some(total)) => (ProgressBar::new(total), "{prefix} [{bar:30}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}), ETA {eta} {msg}"),
+                (Unit::Bytes, None) => (ProgressBar::new_spinner(), "{spinner} {prefix}: {binary_bytes} ({binary_bytes_per_sec}) {msg}"),
+            };
+
+            let bar = BARS.add(bar);
+            if let Ok(style) = ProgressStyle::with_template(template) {
+                bar.set_style(style.progress_chars("=> "));
+            }
+            bar.set_prefix(name);
+            bar.enable_steady_tick(Duration::from_millis(200));
+            bar
+        });
+
+        Progress {
+            stage: Some(Arc::new(Stage {
+                name,
+                unit,
+                total: total.map(AtomicU64::new),
+                bar,
+                position: AtomicU64::new(0),
+                bytes: AtomicU64::new(0),
+                detail: Mutex::new(String::new()),
+                started: Instant::now(),
+                last_log: Mutex::new(Instant::now()),
+            })),
+        }
+    }
+
+    /// The function `start_transfer` starts reporting the progress of a stage
+    /// which uploads or downloads objects, so that `transferred` and `part`
+    /// report to it until it finishes.
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: The name of the stage, such as "Uploading".
+    /// * `unit`: What the stage's progress is counted in. The bytes
+    /// transferred by a stage counted in files are reported alongside it.
+    /// * `total`: The amount the stage will reach when it is done, if known.
+    pub fn start_transfer(name: &'static str, unit: Unit, total: Option<u64>) -> Progress {
+        let progress = Progress::start(name, unit, total);
+
+        if let Ok(mut transfer) = TRANSFER.lock() {
+            *transfer = Some(progress.clone());
+        }
+
+        progress
+    }
+
+    /// The function `inc` advances the stage, logging its progress if the
+    /// progress interval has passed since it was last logged.
+    pub fn inc(&self, amount: u64) {
+        let Some(stage) = &self.stage else {
+            return;
+        };
+
+        stage.position.fetch_add(amount, Ordering::Relaxed);
+
+        if let Some(bar) = &stage.bar {
+            bar.inc(amount);
+        }
+
+        self.log_if_due();
+    }
+
+    /// The function `add_total` adds work found after the stage started to
+    /// its total.
+    pub fn add_total(&self, amount: u64) {
+        let Some(stage) = &self.stage else {
+            return;
+        };
+
+        if let Some(total) = &stage.total {
+            total.fetch_add(amount, Ordering::Relaxed);
+
+            if let Some(bar) = &stage.bar {
+                bar.inc_length(amount);
+            }
+        }
+    }
+
+    /// The function `set_detail` describes what the stage is working on, such
+    /// as the part being uploaded.
+    pub fn set_detail(&self, detail: String) {
+        let Some(stage) = &self.stage else {
+            return;
+        };
+
+        if let Some(bar) = &stage.bar {
+            bar.set_message(detail.clone());
+        }
+
+        if let Ok(mut current) = stage.detail.lock() {
+            *current = detail;
+        }
+    }
+
+    /// The function `finish` stops reporting the stage, and logs how much it
+    /// did and how quickly, unless it did nothing.
+    pub fn finish(&self) {
+        let Some(stage) = &self.stage else {
+            return;
+        };
+
+        if let Some(bar) = &stage.bar {
+            bar.finish_and_clear();
+        }
+
+        if let Ok(mut transfer) = TRANSFER.lock() {
+            if transfer.as_ref().is_some_and(|transfer| transfer.is(self)) {
+                *transfer = None;
+            }
+        }
+
+        let position = stage.position.load(Ordering::Relaxed);
+        let elapsed = stage.started.elapsed();
+
+        if position == 0 {
+            return;
+        }
+
+        info!(
+            "{} complete: {} in {} ({}).",
+            stage.name,
+            stage.unit.amount(position),
+            HumanDuration(elapsed),
+            stage.unit.rate(position as f64 / elapsed.as_secs_f64().max(0.001)),
+        );
+    }
+
+    /// The function `transfer_bytes` counts bytes uploaded or downloaded by
+    /// the stage.
+    fn transfer_bytes(&self, bytes: u64) {
+        let Some(stage) = &self.stage else {
+            return;
+        };
+
+        match stage.unit {
+            Unit::Bytes => self.inc(bytes),
+            Unit::Files => {
+                let transferred = stage.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+                if let Some(bar) = &stage.bar {
+                    bar.set_message(format!("{} transferred", BinaryBytes(transferred)));
+                }
+
+                self.log_if_due();
+            },
+        }
+    }
+
+    /// The function `log_if_due` logs the progress of the stage if progress
+    /// is logged and the progress interval has passed.
+    fn log_if_due(&self) {
+        let (Some(stage), Some(settings)) = (&self.stage, SETTINGS.get()) else {
+            return;
+        };
+
+        if !settings.logs {
+            return;
+        }
+
+        let Ok(mut last_log) = stage.last_log.lock() else {
+            return;
+        };
+
+        if last_log.elapsed() < settings.interval {
+            return;
+        }
+
+        *last_log = Instant::now();
+        info!("{}", self.status());
+    }
+
+    /// The function `status` describes the progress of the stage, such as
+    /// "Hashing: 1.20 GiB of 10.00 GiB (12%), 85.31 MiB/s, ETA 2 minutes".
+    fn status(&self) -> String {
+        let Some(stage) = &self.stage else {
+            return String::new();
+        };
+
+        let position = stage.position.load(Ordering::Relaxed);
+        let rate = position as f64 / stage.started.elapsed().as_secs_f64().max(0.001);
+
+        let total = stage.total.as_ref().map(|total| total.load(Ordering::Relaxed));
+        let mut status = format!("{}: {}", stage.name, stage.unit.amount(position));
+
+        if let Some(total) = total.filter(|total| *total > 0) {
+            status += &format!(" of {} ({}%)", stage.unit.amount(total), position.min(total) * 100 / total);
+        }
+
+        status += &format!(", {}", stage.unit.rate(rate));
+
+        if let Some(total) = total.filter(|_| rate > 0.0) {
+            let remaining = total.saturating_sub(position) as f64 / rate;
+            status += &format!(", ETA {}", HumanDuration(Duration::from_secs_f64(remaining)));
+        }
+
+        let bytes = stage.bytes.load(Ordering::Relaxed);
+        if bytes > 0 {
+            status += &format!(", {} transferred", BinaryBytes(bytes));
+        }
+
+        if let Some(detail) = stage.detail.lock().ok().filter(|detail| !detail.is_empty()) {
+            status += &format!(", {detail}");
+        }
+
+        status
+    }
+
+    fn is(&self, other: &Progress) -> bool {
+        match (&self.stage, &other.stage) {
+            (Some(stage), Some(other)) => Arc::ptr_eq(stage, other),
+            _ => false,
+        }
+    }
+}
+
+/// The function `transferred` reports bytes uploaded or downloaded to the
+/// current transfer stage, if there is one.
+///
+/// Arguments:
+///
+/// * `bytes`: The number of bytes transferred since the last report.
+pub fn transferred(bytes: u64) {
+    if let Some(transfer) = current_transfer() {
+        transfer.transfer_bytes(bytes);
+    }
+}
+
+/// The function `part` reports the part of a multipart upload which was
+/// just uploaded to the current transfer stage, if there is one.
+///
+/// Arguments:
+///
+/// * `part`: The number of the part, starting at 1.
+/// * `parts`: The number of parts of the upload.
+/// * `key`: The key of the object being uploaded.
+pub fn part(part: u64, parts: u64, key: &str) {
+    if let Some(transfer) = current_transfer() {
+        transfer.set_detail(format!("part {part}/{parts} of {key}"));
+    }
+}
+
+fn current_transfer() -> Option<Progress> {
+    TRANSFER.lock().ok()?.clone()
+}
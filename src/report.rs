@@ -0,0 +1,203 @@
+use std::mem;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::environment::{Cli, OutputFormat};
+use crate::error::{GdaError, EXIT_FAILURE, EXIT_PARTIAL_FAILURE};
+use crate::plan::PlanSummary;
+
+/// What a command did, or would do in a dry run, to a file.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+    Backup,
+    Delete,
+    Restore,
+    Verify,
+    CheckFreshness,
+    Prune,
+    LockStatus,
+}
+
+/// Whether the action on a file succeeded.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    /// The action would be taken, but this is a dry run.
+    Planned,
+    Succeeded,
+    Failed,
+    /// The action was not taken, such as because the object is locked.
+    Skipped,
+}
+
+/// The `FileReport` struct records the action taken on a single file.
+#[derive(Serialize, Debug, Clone)]
+pub struct FileReport {
+    pub path: String,
+    pub action: FileAction,
+    pub status: FileStatus,
+    pub hash: Option<String>,
+    pub size: Option<u64>,
+    pub reason: Option<String>,
+    pub error: Option<String>,
+}
+
+impl FileReport {
+
+    /// The function `new` creates the report of an action on a file, which
+    /// succeeded, or is planned in a dry run, unless it is marked as failed.
+    ///
+    /// Arguments:
+    ///
+    /// * `cli`: The parsed command line, used to tell whether this is a dry run.
+    /// * `path`: The path of the file.
+    /// * `action`: The action taken on the file.
+    /// * `hash`: The hash of the file's contents, if known.
+    pub fn new(cli: &Cli, path: String, action: FileAction, hash: Option<String>) -> FileReport {
+        FileReport {
+            path,
+            action,
+            status: if cli.dry_run { FileStatus::Planned } else { FileStatus::Succeeded },
+            hash,
+            size: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    /// The function `failed` marks the action as failed with an error.
+    pub fn failed(mut self, error: impl ToString) -> FileReport {
+        self.status = FileStatus::Failed;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+/// How a command ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    Succeeded,
+    PartialFailure,
+    Failed,
+}
+
+/// The `CommandReport` struct is the final record printed by a command.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandReport {
+    pub command: String,
+    pub dry_run: bool,
+    pub status: CommandStatus,
+    pub exit_code: u8,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub error: Option<String>,
+}
+
+impl CommandReport {
+
+    /// The function `new` creates the final report of a command from its
+    /// result.
+    ///
+    /// Arguments:
+    ///
+    /// * `cli`: The parsed command line.
+    /// * `command`: The name of the command.
+    /// * `result`: The number of files the command succeeded and failed on, or
+    /// the error it failed with.
+    pub fn new(cli: &Cli, command: &str, result: &Result<(usize, usize), GdaError>) -> CommandReport {
+        let (status, exit_code, succeeded, failed, error) = match result {
+            Ok((succeeded, failed)) => (CommandStatus::Succeeded, 0, *succeeded, *failed, None),
+            Err(GdaError::PartialFailure { succeeded, failed }) => (CommandStatus::PartialFailure, EXIT_PARTIAL_FAILURE, *succeeded, *failed, None),
+            Err(GdaError::TotalFailure(failed)) => (CommandStatus::Failed, EXIT_FAILURE, 0, *failed, None),
+            Err(error) => (CommandStatus::Failed, error.exit_code(), 0, 0, Some(error.to_string())),
+        };
+
+        CommandReport {
+            command: command.to_string(),
+            dry_run: cli.dry_run,
+            status,
+            exit_code,
+            succeeded,
+            failed,
+            error,
+        }
+    }
+}
+
+// Files which failed during the current command, kept for its notification
+static FAILED_FILES: Mutex<Vec<FileReport>> = Mutex::new(vec![]);
+
+/// A line of JSON output, tagged with its type.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    File(&'a FileReport),
+    Plan(&'a PlanSummary),
+    Report(&'a CommandReport),
+}
+
+/// The function `enabled` checks whether reports are printed, so that
+/// building them can be skipped otherwise. Reports of failed files are always
+/// built, as they are also sent in notifications.
+pub fn enabled(cli: &Cli) -> bool {
+    cli.output == OutputFormat::Json
+}
+
+/// The function `file` prints the report of a file to stdout, if JSON output
+/// is enabled, and keeps it until `take_failures` is called if it failed.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line, which selects the output format.
+/// * `file_report`: The report of the file.
+pub fn file(cli: &Cli, file_report: &FileReport) {
+    if file_report.status == FileStatus::Failed {
+        if let Ok(mut failed_files) = FAILED_FILES.lock() {
+            failed_files.push(file_report.clone());
+        }
+    }
+
+    print(cli, &Record::File(file_report));
+}
+
+/// The function `take_failures` returns the reports of every file which
+/// failed since it was last called.
+pub fn take_failures() -> Vec<FileReport> {
+    FAILED_FILES.lock().map(|mut failed_files| mem::take(&mut *failed_files)).unwrap_or_default()
+}
+
+/// The function `plan` prints the summary of a dry run's plan to stdout, if
+/// JSON output is enabled.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line, which selects the output format.
+/// * `summary`: The summary of the plan.
+pub fn plan(cli: &Cli, summary: &PlanSummary) {
+    print(cli, &Record::Plan(summary));
+}
+
+/// The function `finish` prints the final report of a command to stdout, if
+/// JSON output is enabled.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line, which selects the output format.
+/// * `command_report`: The final report of the command.
+pub fn finish(cli: &Cli, command_report: &CommandReport) {
+    print(cli, &Record::Report(command_report));
+}
+
+fn print(cli: &Cli, record: &Record) {
+    if !enabled(cli) {
+        return;
+    }
+
+    match serde_json::to_string(record) {
+        Ok(line) => println!("{line}"),
+        Err(error) => log::error!("Failed to serialize output: {:?}", error),
+    }
+}
@@ -1,80 +1,170 @@
-use std::io::{Error, ErrorKind};
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, File};
+use std::time::SystemTime;
 
-use crate::{dynamodb::HashTracker, environment::RestoreArgs};
-use crate::environment::{BackupArgs, Cli};
+use crate::clear_glacier_state;
+use crate::dynamodb::HashTracker;
+use crate::environment::{Cli, RestoreArgs};
+use crate::error::GdaError;
 use crate::models::GlacierFile;
-use log::{error, info};
+use log::{debug, error, info, warn};
 
-use crate::s3;
-use aws_sdk_dynamodb::Client as DynamoDbClient;
-use aws_sdk_s3::Client as S3Client;
-use diesel::prelude::PgConnection;
+use crate::backup::is_empty_hash;
+use crate::checksum;
+use crate::metrics::METRICS;
+use crate::progress::{Progress, Unit};
+use crate::report::{self, FileAction, FileReport};
+use crate::retention;
+use crate::storage::{MetadataStore, ObjectStore, StorageError};
+use diesel::prelude::*;
 
-/// The function `postgres_from_s3` asynchronously retrieves objects from S3 and DynamoDB,
-/// and inserts files into a local database.
+// Files inserted into the local database per statement, within Postgres' limit of 65535 bound parameters
+const INSERT_BATCH_SIZE: usize = 10_000;
+
+/// The function `rebuild_state` rebuilds the `glacier_state` table from the
+/// hash trackers in DynamoDB and the objects in S3, so that a lost local
+/// database does not cause every file to be backed up again. The table is
+/// replaced in a single transaction, with files inserted in batches.
 /// 
 /// Arguments:
 /// 
-/// * `args`: The `args` parameter in the `postgres_from_s3` function represents the
-/// arguments needed for the function to operate. These arguments could include
-/// configuration settings, file paths, bucket names, table names, and other
-/// parameters required for interacting with Amazon S3, DynamoDB, and the local
-/// database. The
-/// * `conn`: The `conn` parameter in the function `postgres_from_s3` is a mutable
-/// reference to a `PgConnection`, which is likely a connection to a PostgreSQL
-/// database. This connection is used to interact with the local database where
-/// files are being inserted.
-/// * `s3_client`: The `s3_client` parameter in your function `postgres_from_s3` is a
-/// reference to an S3 client object that is used to interact with Amazon S3
-/// service. This client is likely responsible for performing operations such as
-/// listing objects in an S3 bucket.
-/// * `dynamo_client`: The `dynamo_client` parameter in the function `postgres_from_s3` is
-/// of type `&DynamoDbClient`, which is likely a client for interacting with
-/// DynamoDB, a NoSQL database service provided by AWS. This client would be used to
-/// perform operations such as querying, inserting,
+/// * `host_id`: The host whose files are rebuilt.
+/// * `conn`: The connection to the local database. A dry run rebuilds it
+/// within a transaction which is never committed.
+/// * `objects`: The object store, used to list the modified time of each object.
+/// * `metadata`: The metadata store, used to read every hash tracker.
 /// 
 /// Returns:
 /// 
-/// The function `postgres_from_s3` returns an `Option<()>`.
-pub async fn postgres_from_aws(cli: Cli, args: BackupArgs, conn: &mut PgConnection, s3_client: &S3Client, dynamo_client: &DynamoDbClient) -> Option<()> {
-    
-    if cli.dry_run {
-        return Some(())
-    }
+/// The number of files which were and were not rebuilt, or the error
+/// encountered reading the stores or replacing the table.
+pub async fn rebuild_state(host_id: Option<&str>, conn: &mut PgConnection, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<(usize, usize), GdaError> {
 
     // Get all objects in S3
-    let modified_times = s3::list(&s3_client, args.clone().into()).await.ok()?;
-    
+    let modified_times = objects.list().await.map_err(Box::new)?;
+
     // Get all objects in DynamoDB
-    let hash_trackers = HashTracker::get_all(dynamo_client, args.clone().into()).await?;
+    let hash_trackers = metadata.get_all().await.map_err(Box::new)?;
 
-    // For every object in DynamoDB
-    let _ = hash_trackers.iter().map(|hash_tracker| {
+    info!("Rebuilding local state from {} hash trackers and {} objects...", hash_trackers.len(), modified_times.len());
 
-        let modified = modified_times.get(&hash_tracker.hash.clone())?;
+    let files = tracked_files(host_id, &hash_trackers, &modified_times);
 
-        // For every local file referenced by the DynamoDB object
-        let _ = hash_tracker.files().map(|file| {
+    let counts = conn.transaction::<_, GdaError, _>(|conn| {
+        clear_glacier_state(conn)?;
 
-            // Insert the file into the local database
-            let result = GlacierFile {
-                file_path: file.to_string(),
-                file_hash: Some(hash_tracker.hash.clone()),
-                modified: *modified,
-            }.insert(conn);
+        let mut rebuilt = 0;
+        let mut failed = 0;
+        let mut batch: Vec<GlacierFile> = Vec::with_capacity(INSERT_BATCH_SIZE);
+
+        for g_file in files {
+            batch.push(g_file);
+
+            if batch.len() == INSERT_BATCH_SIZE {
+                let (batch_rebuilt, batch_failed) = insert_batch(conn, &mut batch);
+                rebuilt += batch_rebuilt;
+                failed += batch_failed;
+            }
+        }
+
+        let (batch_rebuilt, batch_failed) = insert_batch(conn, &mut batch);
+
+        Ok((rebuilt + batch_rebuilt, failed + batch_failed))
+    })?;
+
+    info!("Local state rebuilt: {} files rebuilt, {} failed.", counts.0, counts.1);
+
+    Ok(counts)
+}
+
+/// The function `tracked_files` lists the files of a host recorded in the
+/// hash trackers, as they were when they were backed up.
+/// 
+/// The modified time of each file is taken from its object, so files modified
+/// after they were uploaded are hashed again by the next backup. Empty files
+/// have no object, so they are always hashed again, which is free. Files whose
+/// object is missing are left out, and logged, so that they are backed up as
+/// new files.
+/// 
+/// Arguments:
+/// 
+/// * `host_id`: The host whose files are listed.
+/// * `hash_trackers`: Every hash tracker.
+/// * `modified_times`: The time every object was last modified, by key.
+/// 
+/// Returns:
+/// 
+/// The files, in no particular order.
+pub fn tracked_files<'a>(host_id: Option<&'a str>, hash_trackers: &'a [HashTracker], modified_times: &'a HashMap<String, SystemTime>) -> impl Iterator<Item = GlacierFile> + 'a {
+    let missing: Vec<&str> = hash_trackers.iter()
+        .filter(|hash_tracker| !is_empty_hash(&hash_tracker.hash) && !modified_times.contains_key(&hash_tracker.hash))
+        .filter(|hash_tracker| !hash_tracker.host_files(host_id).is_empty())
+        .map(|hash_tracker| hash_tracker.hash.as_str())
+        .collect();
 
-            match result {
-                Ok(_) => (),
-                Err(error) => error!("Failed to load file into local database from DynamoDB and S3: {:?}\n Error: {:?}", file, error),
+    if !missing.is_empty() {
+        warn!("{} hashes have no object, so their files will be backed up as new files: {:?}", missing.len(), missing);
+    }
+
+    hash_trackers.iter()
+        .filter_map(move |hash_tracker| {
+            let modified = match modified_times.get(&hash_tracker.hash) {
+                Some(modified) => *modified,
+                None if is_empty_hash(&hash_tracker.hash) => SystemTime::UNIX_EPOCH,
+                None => return None,
             };
 
-            Some(())
-        });
+            Some((hash_tracker, modified))
+        })
+        .flat_map(move |(hash_tracker, modified)| {
+            hash_tracker.host_files(host_id).into_iter().map(move |file_path| GlacierFile {
+                file_path,
+                file_hash: Some(hash_tracker.hash.clone()),
+                modified,
+            })
+        })
+}
 
-        Some(())
-    });
+/// The function `insert_batch` inserts a batch of rebuilt files into the
+/// `glacier_state` table and empties it. If the batch fails, such as when two
+/// trackers record the same file, its files are inserted one at a time.
+/// 
+/// Arguments:
+/// 
+/// * `conn`: The connection to the local database, within a transaction.
+/// * `batch`: The files to insert.
+/// 
+/// Returns:
+/// 
+/// The number of files which were and were not inserted.
+fn insert_batch(conn: &mut PgConnection, batch: &mut Vec<GlacierFile>) -> (usize, usize) {
+    let mut inserted = 0;
+    let mut failed = 0;
 
-    Some(())
+    if batch.is_empty() {
+        return (inserted, failed);
+    }
+
+    match conn.transaction(|conn| GlacierFile::insert_many(conn, batch)) {
+        Ok(count) => inserted += count,
+        Err(error) => {
+            debug!("Failed to rebuild batch of {} files, rebuilding them one at a time: {:?}", batch.len(), error);
+
+            for g_file in batch.iter() {
+                match conn.transaction(|conn| g_file.insert(conn)) {
+                    Ok(_) => inserted += 1,
+                    Err(error) => {
+                        error!("Failed to load file into local database from DynamoDB and S3: {:?}\n Error: {:?}", g_file, error);
+                        failed += 1;
+                    },
+                }
+            }
+        },
+    }
+
+    batch.clear();
+
+    (inserted, failed)
 }
 
 /// The `restore` function in Rust asynchronously restores files from an S3 bucket
@@ -87,46 +177,158 @@ pub async fn postgres_from_aws(cli: Cli, args: BackupArgs, conn: &mut PgConnecti
 /// It may include information such as the DynamoDB table name, S3 bucket name,
 /// target directory for restored files, and other relevant settings needed for the
 /// restoration
-/// * `s3_client`: The `s3_client` parameter in the `restore` function is a
-/// reference to an instance of the S3Client struct, which is used to interact with
-/// an Amazon S3 service. This client is responsible for performing operations such
-/// as uploading, downloading, and managing objects in an S3 bucket.
-/// * `dynamo_client`: The `dynamo_client` parameter in the `restore` function is of
-/// type `&DynamoDbClient`, which is a reference to a client for interacting with
-/// DynamoDB. This client is used to perform operations on the DynamoDB table
-/// specified in the `cli` parameter.
+/// * `objects`: The object store which file contents are downloaded from.
+/// * `metadata`: The metadata store which hash trackers are read from.
 /// 
 /// Returns:
 /// 
 /// The `restore` function is returning a `Result` containing a tuple with two
 /// elements: the number of files successfully restored (`restored`) and the number
-/// of files that failed to be restored (`failed`).
-pub async fn restore(cli: Cli, args: RestoreArgs, s3_client: &S3Client, dynamo_client: &DynamoDbClient) -> Result<(usize, usize), Error> {
+/// of files that failed to be restored (`failed`), or the error encountered
+/// reading the hash trackers.
+pub async fn restore(cli: Cli, args: RestoreArgs, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<(usize, usize), StorageError> {
     
     let mut restored = 0;
     let mut failed = 0;
 
     // Get all objects in DynamoDB
-    let hash_trackers = match HashTracker::get_all(dynamo_client, args.clone().into()).await {
-        Some(value) => value,
-        None => return Err(Error::new(ErrorKind::NotConnected, "Unable to connect to DynamoDB.")),
-    };
+    let hash_trackers = metadata.get_all().await?;
+
+    // Deleted files and previous versions which were asked for
+    let mut retained_paths = retention::restore_paths(&args, &hash_trackers);
+
+    let restoring = Progress::start_transfer(
+        "Restoring",
+        Unit::Files,
+        Some(hash_trackers.iter().map(|hash_tracker| hash_tracker.restore_paths(args.host_id.as_deref()).len() as u64).sum::<u64>()
+            + retained_paths.values().map(|paths| paths.len() as u64).sum::<u64>()),
+    );
 
     for hash_tracker in hash_trackers {
 
-        match s3::get_object(cli.clone(), args.clone().into(), s3_client, hash_tracker.hash.clone(), args.target_dir.clone(), hash_tracker.files()).await {
+        let mut files = hash_tracker.restore_paths(args.host_id.as_deref());
+        files.extend(retained_paths.remove(&hash_tracker.hash).unwrap_or_default());
+
+        let result = if is_empty_hash(&hash_tracker.hash) {
+            match cli.dry_run {
+                true => Ok(files.clone()),
+                false => create_empty_files(args.target_dir.clone(), files.clone()),
+            }
+        }
+        else {
+            match objects.get(cli.clone(), hash_tracker.hash.clone(), args.target_dir.clone(), files.clone()).await {
+                Ok(files) if !cli.dry_run => verify_checksum(args.target_dir.clone(), files, hash_tracker.checksum.as_deref()),
+                result => result,
+            }
+        };
+
+        restoring.inc(files.len() as u64);
+
+        match &result {
             Ok(files) => {
-                if files.len() > 0 {
+                if !files.is_empty() {
                     restored += files.len();
                     info!("{} files successfully restored: {:?}", files.len(), files);
                 }
             },
             Err(error) => {
-                failed += hash_tracker.files().len();
-                error!("{} files failed to be restored: {:?}\nError: {:?}", hash_tracker.files().len(), hash_tracker, error);
+                failed += files.len();
+                error!("{} files failed to be restored: {:?}\nError: {:?}", files.len(), hash_tracker, error);
+                METRICS.failure("restore");
             },
         };
+
+        if report::enabled(&cli) || result.is_err() {
+            report_files(&cli, &args.target_dir, &hash_tracker.hash, files, &result);
+        }
     };
 
+    restoring.finish();
+
     Ok((restored, failed))
-}
\ No newline at end of file
+}
+
+/// The function `report_files` reports every file of a restored hash.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `prefix`: The directory files were restored to.
+/// * `hash`: The restored hash.
+/// * `files`: The paths of the files, appended to `prefix`.
+/// * `result`: The result of restoring the files.
+fn report_files(cli: &Cli, prefix: &str, hash: &str, files: Vec<String>, result: &Result<Vec<String>, StorageError>) {
+    for file in files {
+        let path = prefix.to_string() + &file;
+        let file_report = FileReport::new(cli, file, FileAction::Restore, Some(hash.to_string()));
+
+        let file_report = match result {
+            Ok(_) => FileReport {
+                size: fs::metadata(path).ok().map(|metadata| metadata.len()),
+                ..file_report
+            },
+            Err(error) => file_report.failed(error),
+        };
+
+        report::file(cli, &file_report);
+    }
+}
+
+/// The function `verify_checksum` compares a restored object with the
+/// checksum recorded when it was uploaded. Every file is copied from the first,
+/// so only the first is checked.
+/// 
+/// Arguments:
+/// 
+/// * `prefix`: The directory files were restored to.
+/// * `files`: The paths of the restored files, appended to `prefix`.
+/// * `checksum`: The recorded checksum, or `None` if the object was uploaded
+/// before checksums were recorded.
+/// 
+/// Returns:
+/// 
+/// The restored files, or `StorageError::ChecksumMismatch` if the first file
+/// does not match the checksum.
+fn verify_checksum(prefix: String, files: Vec<String>, checksum: Option<&str>) -> Result<Vec<String>, StorageError> {
+    let (Some(expected), Some(first_file)) = (checksum, files.first()) else {
+        return Ok(files);
+    };
+
+    // Multipart uploads record the composite checksum of their parts
+    let file_path = prefix + first_file;
+    let actual = match expected.contains('-') {
+        true => checksum::sha256_file_multipart(&file_path, expected)?,
+        false => checksum::sha256_file(&file_path)?,
+    };
+    if actual != expected {
+        return Err(StorageError::ChecksumMismatch(
+            format!("Restored {first_file} has checksum {actual}, expected {expected}.")
+        ));
+    }
+
+    Ok(files)
+}
+
+/// The function `create_empty_files` restores the files of the empty hash,
+/// which has no object in S3.
+/// 
+/// Arguments:
+/// 
+/// * `prefix`: The directory files are restored to.
+/// * `files`: The paths of the files, appended to `prefix`.
+/// 
+/// Returns:
+/// 
+/// The restored files, or the first error encountered.
+fn create_empty_files(prefix: String, files: Vec<String>) -> Result<Vec<String>, StorageError> {
+    for file in files.iter() {
+        let file = prefix.clone() + file;
+        if let Some((dir, _)) = file.rsplit_once('/') {
+            create_dir_all(dir)?;
+        }
+
+        File::create(file)?;
+    }
+
+    Ok(files)
+}
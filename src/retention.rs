@@ -0,0 +1,361 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+
+use crate::backup::is_empty_hash;
+use crate::dynamodb::{restore_path, split_host, HashTracker, HOST_SEPARATOR};
+use crate::environment::{Cli, PruneArgs, RestoreArgs, RetentionArgs};
+use crate::metrics::METRICS;
+use crate::report::{self, FileAction, FileReport, FileStatus};
+use crate::storage::{MetadataStore, ObjectStore, StorageError};
+
+// File names kept by a retention policy start with this. Stored paths are
+// absolute and host ids may not contain it, so no file is stored with it.
+const RETAINED_PREFIX: char = '#';
+
+/// Why a file was kept by a retention policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetainedKind {
+    /// The file was deleted.
+    Deleted,
+    /// The file changed, and this was its previous version.
+    Replaced,
+}
+
+impl RetainedKind {
+
+    /// The function `as_str` returns the name of the kind, as stored in
+    /// retained file names.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetainedKind::Deleted => "deleted",
+            RetainedKind::Replaced => "replaced",
+        }
+    }
+}
+
+/// The `RetainedFile` struct is a deleted file, or a previous version of a
+/// changed file, which a retention policy kept. It is stored in the hash
+/// tracker of its contents as `#kind:timestamp:file_name`, so that the
+/// object is not deleted while it is kept.
+///
+/// Properties:
+///
+/// * `kind`: Whether the file was deleted or replaced.
+/// * `removed`: When the file was deleted or replaced, to the second.
+/// * `file_name`: The file name of the file, including its host id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetainedFile {
+    pub kind: RetainedKind,
+    pub removed: DateTime<Utc>,
+    pub file_name: String,
+}
+
+impl RetainedFile {
+
+    /// The function `new` keeps a file which was just deleted or replaced.
+    ///
+    /// Arguments:
+    ///
+    /// * `kind`: Whether the file was deleted or replaced.
+    /// * `file_name`: The file name of the file, including its host id.
+    pub fn new(kind: RetainedKind, file_name: String) -> RetainedFile {
+        RetainedFile {
+            kind,
+            removed: DateTime::from_timestamp(Utc::now().timestamp(), 0).unwrap_or_default(),
+            file_name,
+        }
+    }
+
+    /// The function `name` returns the file name the retained file is stored
+    /// as in its hash tracker.
+    pub fn name(&self) -> String {
+        format!("{RETAINED_PREFIX}{}{HOST_SEPARATOR}{}{HOST_SEPARATOR}{}", self.kind.as_str(), self.removed.timestamp(), self.file_name)
+    }
+
+    /// The function `parse` reads a file name stored by `name`.
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: A file name stored in a hash tracker.
+    ///
+    /// Returns:
+    ///
+    /// The retained file, or `None` if the name is not a retained file.
+    pub fn parse(name: &str) -> Option<RetainedFile> {
+        let mut parts = name.strip_prefix(RETAINED_PREFIX)?.splitn(3, HOST_SEPARATOR);
+
+        let kind = match parts.next()? {
+            "deleted" => RetainedKind::Deleted,
+            "replaced" => RetainedKind::Replaced,
+            _ => return None,
+        };
+        let removed = DateTime::from_timestamp(parts.next()?.parse().ok()?, 0)?;
+
+        Some(RetainedFile {
+            kind,
+            removed,
+            file_name: parts.next()?.to_string(),
+        })
+    }
+
+    /// The function `is_retained` checks whether a file name stored in a hash
+    /// tracker is a retained file, rather than a file which currently exists.
+    pub fn is_retained(name: &str) -> bool {
+        name.starts_with(RETAINED_PREFIX)
+    }
+
+    /// The function `path` returns the local path of the file, without its
+    /// host id.
+    pub fn path(&self) -> &str {
+        split_host(&self.file_name).1
+    }
+}
+
+/// The function `prune` removes the deleted files and previous versions which
+/// retention policies no longer keep, and deletes their objects once no file
+/// references them. An object protected by Object Lock is not deleted, and its
+/// files are kept until a prune after its lock has passed.
+///
+/// Changes are made in the order DynamoDB -> S3, as when a backup deletes
+/// files, so that an object is only deleted once a conditional write
+/// confirms that no other process still references it.
+///
+/// Arguments:
+///
+/// * `cli`: The parsed command line.
+/// * `args`: The retention policies, and the host whose files are pruned.
+/// * `objects`: The object store.
+/// * `metadata`: The metadata store.
+///
+/// Returns:
+///
+/// The number of retained files which were and were not pruned, or the error
+/// encountered reading the hash trackers. Files kept because their object is
+/// locked are neither.
+pub async fn prune(cli: Cli, args: PruneArgs, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<(usize, usize), StorageError> {
+
+    let mut pruned = 0;
+    let mut locked = 0;
+    let mut failed = 0;
+
+    let now = Utc::now();
+    let hash_trackers = metadata.get_all().await?;
+    let mut expired = expired_files(&args.retention, args.host_id.as_deref(), &hash_trackers, now);
+
+    for mut hash_tracker in hash_trackers {
+        let Some(retained_files) = expired.remove(&hash_tracker.hash) else {
+            continue;
+        };
+
+        for retained_file in &retained_files {
+            hash_tracker.del_file_name(retained_file.name());
+        }
+
+        // The object is only deleted once no file references it, so only then can its lock matter
+        let lock = match !hash_tracker.has_files() && !is_empty_hash(&hash_tracker.hash) {
+            true => objects.head(hash_tracker.hash.clone()).await
+                .map(|object| object.map(|object| object.lock).filter(|lock| lock.is_locked(now))),
+            false => Ok(None),
+        };
+
+        if let Ok(Some(lock)) = &lock {
+            locked += retained_files.len();
+            info!("{} files kept as their object {} is locked: {}", retained_files.len(), hash_tracker.hash, lock.describe(now));
+
+            for retained_file in retained_files {
+                let mut file_report = FileReport::new(&cli, retained_file.file_name, FileAction::Prune, Some(hash_tracker.hash.clone()));
+                file_report.status = FileStatus::Skipped;
+                file_report.reason = Some(format!("locked: {}", lock.describe(now)));
+
+                report::file(&cli, &file_report);
+            }
+
+            continue;
+        }
+
+        let result = match (lock, cli.dry_run) {
+            (Err(error), _) => Err(error),
+            (Ok(_), true) => Ok(()),
+            (Ok(_), false) => prune_hash(objects, metadata, &mut hash_tracker).await,
+        };
+
+        match &result {
+            Ok(_) => {
+                pruned += retained_files.len();
+                for retained_file in &retained_files {
+                    info!("Pruned: {} ({} {})", retained_file.file_name, retained_file.kind.as_str(), retained_file.removed);
+                }
+            },
+            Err(error) => {
+                failed += retained_files.len();
+                error!("{} files failed to be pruned: {:?}\nError: {:?}", retained_files.len(), retained_files, error);
+                METRICS.failure("prune");
+            },
+        }
+
+        for retained_file in retained_files {
+            let mut file_report = FileReport::new(&cli, retained_file.file_name, FileAction::Prune, Some(hash_tracker.hash.clone()));
+            file_report.reason = Some(retained_file.kind.as_str().to_string());
+
+            report::file(&cli, &match &result {
+                Ok(_) => file_report,
+                Err(error) => file_report.failed(error),
+            });
+        }
+    }
+
+    info!("Prune complete: {pruned} pruned, {locked} locked, {failed} failed.");
+
+    Ok((pruned, failed))
+}
+
+/// The function `prune_hash` writes a hash tracker whose expired files were
+/// removed, then deletes its object if no file references it.
+///
+/// Arguments:
+///
+/// * `objects`: The object store.
+/// * `metadata`: The metadata store.
+/// * `hash_tracker`: The hash tracker.
+///
+/// Returns:
+///
+/// The error of the step which failed.
+async fn prune_hash(objects: &dyn ObjectStore, metadata: &dyn MetadataStore, hash_tracker: &mut HashTracker) -> Result<(), StorageError> {
+    metadata.update(hash_tracker).await?;
+
+    // Another process may have added a file with this hash while it was being updated
+    if !hash_tracker.has_files() && !is_empty_hash(&hash_tracker.hash) {
+        objects.delete(hash_tracker.hash.clone()).await?;
+    }
+
+    Ok(())
+}
+
+/// The function `expired_files` finds the retained files which no retention
+/// policy keeps any longer. Files matching a keep forever pattern are always
+/// kept. Deleted files are kept for `keep_deleted`, and the newest
+/// `keep_versions` previous versions of each file are kept while the file
+/// exists, or is kept as a deleted file.
+///
+/// Arguments:
+///
+/// * `retention`: The retention policies.
+/// * `host_id`: If supplied, only the files of this host are expired.
+/// * `hash_trackers`: Every hash tracker.
+/// * `now`: The current time.
+///
+/// Returns:
+///
+/// The expired files, by the hash they are stored with.
+fn expired_files(retention: &RetentionArgs, host_id: Option<&str>, hash_trackers: &[HashTracker], now: DateTime<Utc>) -> HashMap<String, Vec<RetainedFile>> {
+    let is_expired = |removed: DateTime<Utc>| retention.keep_deleted.is_none_or(|keep_deleted| removed + keep_deleted < now);
+
+    let live_files: HashSet<&String> = hash_trackers.iter().flat_map(HashTracker::live_files).collect();
+
+    // When each file was last deleted, and the previous versions of each file
+    let mut deleted: HashMap<String, DateTime<Utc>> = HashMap::new();
+    let mut versions: HashMap<String, Vec<(String, RetainedFile)>> = HashMap::new();
+
+    let mut expired: HashMap<String, Vec<RetainedFile>> = HashMap::new();
+
+    for hash_tracker in hash_trackers {
+        for retained_file in hash_tracker.retained_files() {
+            if host_id.is_some() && split_host(&retained_file.file_name).0 != host_id {
+                continue;
+            }
+
+            if retention.keeps_forever(retained_file.path()) {
+                continue;
+            }
+
+            match retained_file.kind {
+                RetainedKind::Deleted => {
+                    let removed = deleted.entry(retained_file.file_name.clone()).or_insert(retained_file.removed);
+                    *removed = retained_file.removed.max(*removed);
+
+                    if is_expired(retained_file.removed) {
+                        expired.entry(hash_tracker.hash.clone()).or_default().push(retained_file);
+                    }
+                },
+                RetainedKind::Replaced => {
+                    versions.entry(retained_file.file_name.clone()).or_default().push((hash_tracker.hash.clone(), retained_file));
+                },
+            }
+        }
+    }
+
+    for (file_name, mut file_versions) in versions {
+        // Versions of a file which no longer exists go with the file
+        let file_expired = !live_files.contains(&file_name) && deleted.get(&file_name).is_none_or(|removed| is_expired(*removed));
+
+        file_versions.sort_by_key(|(_, retained_file)| Reverse(retained_file.removed));
+
+        for (index, (hash, retained_file)) in file_versions.into_iter().enumerate() {
+            if file_expired || index >= retention.keep_versions {
+                expired.entry(hash).or_default().push(retained_file);
+            }
+        }
+    }
+
+    expired
+}
+
+/// The function `restore_paths` returns the paths retained files should be
+/// restored to, relative to the restore target directory. Only the most
+/// recently deleted file of each path is restored, unless a file with the
+/// same path exists. Previous versions are restored next to their file, with
+/// the time they were replaced appended to their path.
+///
+/// Arguments:
+///
+/// * `args`: The restore arguments, which select the host and whether
+/// deleted files and previous versions are restored.
+/// * `hash_trackers`: Every hash tracker.
+///
+/// Returns:
+///
+/// The paths of the retained files, by the hash they are stored with.
+pub fn restore_paths(args: &RestoreArgs, hash_trackers: &[HashTracker]) -> HashMap<String, Vec<String>> {
+    let mut paths: HashMap<String, Vec<String>> = HashMap::new();
+
+    if !args.include_deleted && !args.include_versions {
+        return paths;
+    }
+
+    let live_files: HashSet<&String> = hash_trackers.iter().flat_map(HashTracker::live_files).collect();
+
+    // The most recently deleted file of each path, and its hash
+    let mut deleted: HashMap<String, (DateTime<Utc>, String)> = HashMap::new();
+
+    for hash_tracker in hash_trackers {
+        for retained_file in hash_tracker.retained_files() {
+            let Some(path) = restore_path(args.host_id.as_deref(), &retained_file.file_name) else {
+                continue;
+            };
+
+            match retained_file.kind {
+                RetainedKind::Deleted if args.include_deleted && !live_files.contains(&retained_file.file_name) => {
+                    let latest = deleted.entry(path).or_insert((retained_file.removed, hash_tracker.hash.clone()));
+                    if retained_file.removed > latest.0 {
+                        *latest = (retained_file.removed, hash_tracker.hash.clone());
+                    }
+                },
+                RetainedKind::Replaced if args.include_versions => {
+                    let version = format!("{path}.{}", retained_file.removed.format("%Y%m%dT%H%M%SZ"));
+                    paths.entry(hash_tracker.hash.clone()).or_default().push(version);
+                },
+                _ => (),
+            }
+        }
+    }
+
+    for (path, (_, hash)) in deleted {
+        paths.entry(hash).or_default().push(path);
+    }
+
+    paths
+}
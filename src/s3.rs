@@ -1,4 +1,3 @@
-use std::collections::hash_set::Iter as SetIter;
 use std::{
     collections::HashMap,
     fs::{
@@ -11,27 +10,38 @@ use std::{
         Write,
         ErrorKind
     },
+    io::SeekFrom,
     path::Path,
     time::SystemTime,
 };
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use aws_sdk_s3::operation::list_object_versions::ListObjectVersionsError;
+use aws_sdk_s3::operation::complete_multipart_upload::CompleteMultipartUploadError;
+use aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadError;
 use aws_sdk_s3::operation::upload_part::UploadPartError;
+use aws_sdk_s3::operation::get_object_lock_configuration::GetObjectLockConfigurationError;
 use aws_sdk_s3::types::{
+    ChecksumAlgorithm,
+    ChecksumMode,
     CompletedMultipartUpload,
     CompletedPart,
     Delete,
-    ObjectIdentifier
+    ObjectIdentifier,
+    ObjectLockEnabled,
+    ObjectLockLegalHoldStatus,
+    Error as S3Error
 };
+use aws_smithy_types::DateTime as SmithyDateTime;
 use aws_sdk_s3::{
     error::SdkError,
     operation::{
         delete_object::{
             DeleteObjectError, 
             DeleteObjectOutput
-        }, get_object::GetObjectError, list_objects_v2::{
-            ListObjectsV2Error, 
-            ListObjectsV2Output
-        }, put_object::PutObjectError,
+        }, get_object::GetObjectError, head_object::{
+            HeadObjectError,
+            HeadObjectOutput
+        }, list_objects_v2::ListObjectsV2Error, put_object::PutObjectError,
         restore_object::{
             RestoreObjectError,
             RestoreObjectOutput
@@ -44,18 +54,23 @@ use aws_smithy_runtime_api::http::Response;
 use aws_sdk_s3::primitives::SdkBody;
 use aws_smithy_types::byte_stream::error::Error as AwsSmithyError;
 use aws_smithy_runtime_api::client::result::SdkError as AwsSmithySdkError;
-use aws_smithy_types::byte_stream::Length;
 
 use crate::aws;
+use crate::checksum::{self, MultipartChecksum};
 use crate::environment::{
     AwsArgs,
     Cli
 };
+use crate::metrics::AwsMetricsInterceptor;
+use crate::progress;
+use crate::storage::ObjectVersion;
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 
-use aws_sdk_s3::error::BuildError;
+use aws_sdk_s3::config::Builder;
+use aws_sdk_s3::error::{BuildError, ProvideErrorMetadata};
 use aws_sdk_s3::operation::delete_objects::DeleteObjectsError;
-use log::info;
+use log::{debug, error};
 
 // Use multipart upload if file is greater than 100 Mib
 const MULTIPART_UPLOAD_THRESHOLD: u64 = 1024 * 1024 * 100;
@@ -64,11 +79,19 @@ const MIN_CHUNK_SIZE: u64 = 1024 * 1024 * 5;
 const MAX_CHUNKS: u64 = 10000;
 //Set max S3 object size to 5TiB
 const MAX_S3_OBJECT_SIZE: u64 = 1024 * 1024 * 1024 * 1024 * 5;
+// Error codes returned when the provider or the object's storage class does not support restoring objects
+const RESTORE_UNSUPPORTED_CODES: [&str; 3] = ["NotImplemented", "InvalidObjectState", "InvalidStorageClass"];
+// Error code of a bucket without an Object Lock configuration
+const LOCK_CONFIGURATION_NOT_FOUND: &str = "ObjectLockConfigurationNotFoundError";
+// Error code of an object version which cannot be deleted, such as because it is locked
+const ACCESS_DENIED: &str = "AccessDenied";
+// The most object versions a DeleteObjects request can delete
+const DELETE_BATCH_SIZE: usize = 1000;
 
 #[derive(Error, Debug)]
 pub enum S3GetError {
     #[error("S3GetObjectError")]
-    S3GetObjectError(#[from] SdkError<GetObjectError, Response<SdkBody>>),
+    S3GetObjectError(#[from] Box<SdkError<GetObjectError, Response<SdkBody>>>),
 
     #[error("IoError")]
     IoError(#[from] IoError),
@@ -90,6 +113,24 @@ pub enum S3DeleteError {
 
     #[error("S3BuildError")]
     S3BuildError(#[from] BuildError),
+
+    #[error("S3VersionLocked: {0}. Versions protected by Object Lock can only be deleted once their retention has passed and their legal hold is removed.")]
+    S3VersionLocked(String),
+
+    #[error("S3VersionNotDeleted: {0}")]
+    S3VersionNotDeleted(String),
+}
+
+#[derive(Error, Debug)]
+pub enum S3UndeleteError {
+    #[error("S3RestoreObjectError")]
+    S3RestoreObjectError(#[from] SdkError<RestoreObjectError, Response>),
+
+    #[error("S3ListObjectVersionsError")]
+    S3ListObjectVersionsError(#[from] SdkError<ListObjectVersionsError, Response>),
+
+    #[error("S3DeleteObjectError")]
+    S3DeleteObjectError(#[from] SdkError<DeleteObjectError, Response>),
 }
 
 #[derive(Error, Debug)]
@@ -102,19 +143,77 @@ pub enum S3PutError {
 
     #[error("S3PutUploadPartError")]
     S3PutUploadPartError(#[from] AwsSmithySdkError<UploadPartError, Response>),
+
+    #[error("S3PutCreateMultipartUploadError")]
+    S3PutCreateMultipartUploadError(#[from] AwsSmithySdkError<CreateMultipartUploadError, Response>),
+
+    #[error("S3PutCompleteMultipartUploadError")]
+    S3PutCompleteMultipartUploadError(#[from] AwsSmithySdkError<CompleteMultipartUploadError, Response>),
+
+    #[error("S3PutMissingUploadId: {0}")]
+    S3PutMissingUploadId(String),
+
+    #[error("S3PutByteStreamError")]
+    S3PutByteStreamError(#[from] AwsSmithyError),
+
+    #[error("ChecksumMismatch: {0}")]
+    ChecksumMismatch(String),
+}
+
+impl S3PutError {
+
+    /// The function `is_transient` checks whether the upload may succeed if it
+    /// is attempted again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            S3PutError::S3PutObjectError(error) => aws::is_transient(error),
+            S3PutError::S3PutUploadPartError(error) => aws::is_transient(error),
+            S3PutError::S3PutCreateMultipartUploadError(error) => aws::is_transient(error),
+            S3PutError::S3PutCompleteMultipartUploadError(error) => aws::is_transient(error),
+            // A checksum mismatch means the object was corrupted in transit
+            S3PutError::ChecksumMismatch(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl S3UndeleteError {
+
+    /// The function `is_transient` checks whether the undelete may succeed if
+    /// it is attempted again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            S3UndeleteError::S3RestoreObjectError(error) => aws::is_transient(error),
+            S3UndeleteError::S3ListObjectVersionsError(error) => aws::is_transient(error),
+            S3UndeleteError::S3DeleteObjectError(error) => aws::is_transient(error),
+        }
+    }
 }
 
 /// The function `get_client` asynchronously retrieves a client using AWS
 /// configuration.
 /// 
+/// Arguments:
+/// 
+/// * `cli`: The global arguments, which may point the client at an S3
+/// compatible endpoint and force path style addressing.
+/// 
 /// Returns:
 /// 
 /// The `get_client` function is returning a `Client` instance. The function first
 /// awaits the result of `aws::get_config()` to get the AWS configuration, then
 /// creates a new `Client` instance using that configuration and returns it.
-pub async fn get_client() -> Client {
-    let config = aws::get_config().await;
-    Client::new(&config)
+pub async fn get_client(cli: &Cli) -> Client {
+    let config = aws::get_config(&cli.retry).await;
+    let mut builder = Builder::from(&config)
+        .force_path_style(cli.s3_force_path_style)
+        .interceptor(AwsMetricsInterceptor);
+
+    if let Some(endpoint) = &cli.s3_endpoint {
+        builder = builder.endpoint_url(endpoint);
+    }
+
+    Client::from_conf(builder.build())
 }
 
 /// The function `put` asynchronously uploads a file to a specified bucket in Rust
@@ -139,9 +238,9 @@ pub async fn get_client() -> Client {
 /// 
 /// Returns:
 /// 
-/// The `put` function returns a `Result` containing either a `PutObjectOutput` or
-/// an `SdkError` with a `PutObjectError`.
-pub async fn put(aws_args: AwsArgs, client: &Client, key: String, file_path: String) -> Result<(), S3PutError> {
+/// The `put` function returns a `Result` containing either the base64 encoded
+/// SHA-256 checksum of the uploaded object, or an `S3PutError`.
+pub async fn put(aws_args: AwsArgs, client: &Client, key: String, file_path: String) -> Result<String, S3PutError> {
 
     let file_size = tokio::fs::metadata(file_path.clone()).await?.len();
 
@@ -149,17 +248,27 @@ pub async fn put(aws_args: AwsArgs, client: &Client, key: String, file_path: Str
         return put_multipart(aws_args, client, key, file_path.clone(), file_size).await;
     }
 
+    // S3 rejects the upload if the object it receives does not match the checksum
+    let checksum = checksum::sha256_file(&file_path)?;
     let body = ByteStream::from_path(Path::new(&file_path)).await;
 
     client
         .put_object()
-        .bucket(aws_args.bucket_name)
+        .bucket(aws_args.bucket_name.clone())
         .key(key)
-        .body(body.unwrap())
+        .set_storage_class(aws_args.storage_classes.select(&file_path, file_size))
+        .set_object_lock_mode(aws_args.object_lock.object_lock_mode.clone())
+        .set_object_lock_retain_until_date(retain_until(&aws_args))
+        .set_object_lock_legal_hold_status(legal_hold(&aws_args))
+        .checksum_algorithm(ChecksumAlgorithm::Sha256)
+        .checksum_sha256(checksum.clone())
+        .body(body?)
         .send()
         .await?;
 
-    Ok(())
+    progress::transferred(file_size);
+
+    Ok(checksum)
 }
 
 
@@ -188,22 +297,78 @@ pub async fn put(aws_args: AwsArgs, client: &Client, key: String, file_path: Str
 /// 
 /// Returns:
 /// 
-/// The `put_multipart` function returns a `Result<(), S3PutError>`. This means that
-/// it returns a `Result` type where the success case contains an empty tuple `()`
-/// and the error case contains an `S3PutError`.
+/// The `put_multipart` function returns a `Result<String, S3PutError>`. This means
+/// that it returns a `Result` type where the success case contains the
+/// composite SHA-256 checksum of the parts, as S3 reports it, and the error
+/// case contains an `S3PutError`.
 /// 
 /// https://github.com/awsdocs/aws-doc-sdk-examples/blob/main/rustv1/examples/s3/src/bin/s3-multipart-upload.rs#L136
-pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file_path: String, file_size: u64) -> Result<(), S3PutError> {
+pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file_path: String, file_size: u64) -> Result<String, S3PutError> {
 
     let multipart_upload_res = client
         .create_multipart_upload()
         .bucket(&aws_args.bucket_name)
         .key(&key)
+        .set_storage_class(aws_args.storage_classes.select(&file_path, file_size))
+        .set_object_lock_mode(aws_args.object_lock.object_lock_mode.clone())
+        .set_object_lock_retain_until_date(retain_until(&aws_args))
+        .set_object_lock_legal_hold_status(legal_hold(&aws_args))
+        .checksum_algorithm(ChecksumAlgorithm::Sha256)
         .send()
-        .await
-        .unwrap();
+        .await?;
+
+    let upload_id = multipart_upload_res.upload_id()
+        .ok_or(S3PutError::S3PutMissingUploadId(key.clone()))?;
+
+    let result = upload_parts(aws_args.clone(), client, key.clone(), file_path, file_size, upload_id).await;
+
+    // Parts of an upload which is never completed are billed until it is aborted
+    if result.is_err() {
+        let abort = client
+            .abort_multipart_upload()
+            .bucket(aws_args.bucket_name)
+            .key(key.clone())
+            .upload_id(upload_id)
+            .send()
+            .await;
+
+        if let Err(error) = abort {
+            error!("Failed to abort multipart upload of {key}: {error:?}");
+        }
+    }
+
+    result
+}
 
-    let upload_id = multipart_upload_res.upload_id().unwrap();
+/// The function `retain_until` returns when an object uploaded now is locked
+/// until, in the format of the AWS SDK, or `None` if objects are not locked.
+fn retain_until(aws_args: &AwsArgs) -> Option<SmithyDateTime> {
+    aws_args.object_lock.retain_until().map(|retain_until| SmithyDateTime::from_secs(retain_until.timestamp()))
+}
+
+/// The function `legal_hold` returns the legal hold status objects are
+/// uploaded with, or `None` if they are not held.
+fn legal_hold(aws_args: &AwsArgs) -> Option<ObjectLockLegalHoldStatus> {
+    aws_args.object_lock.object_lock_legal_hold.then_some(ObjectLockLegalHoldStatus::On)
+}
+
+/// The function `upload_parts` uploads every part of a multipart upload and
+/// completes it.
+/// 
+/// Arguments:
+/// 
+/// * `aws_args`: The bucket being uploaded to.
+/// * `client`: The S3 client.
+/// * `key`: The key of the object.
+/// * `file_path`: The path of the file being uploaded.
+/// * `file_size`: The size of the file in bytes.
+/// * `upload_id`: The id of the multipart upload.
+/// 
+/// Returns:
+/// 
+/// The composite SHA-256 checksum of the parts, or the error which prevented
+/// the upload from completing.
+async fn upload_parts(aws_args: AwsArgs, client: &Client, key: String, file_path: String, file_size: u64, upload_id: &str) -> Result<String, S3PutError> {
 
     let mut chunk_size = MIN_CHUNK_SIZE;
     let mut chunk_count = MAX_CHUNKS + 1;
@@ -231,6 +396,8 @@ pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file
     };
 
     let mut upload_parts: Vec<CompletedPart> = Vec::new();
+    let mut checksum = MultipartChecksum::default();
+    let mut file = tokio::fs::File::open(&file_path).await?;
 
     for chunk_index in 0..chunk_count {
         let this_chunk = if chunk_count - 1 == chunk_index {
@@ -238,13 +405,14 @@ pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file
         } else {
             MIN_CHUNK_SIZE
         };
-        let stream = ByteStream::read_from()
-            .path(file_path.clone())
-            .offset(chunk_index * MIN_CHUNK_SIZE)
-            .length(Length::Exact(this_chunk))
-            .build()
-            .await
-            .unwrap();
+
+        // Parts are read into memory, so that each part is hashed from the same bytes that are sent
+        let mut chunk = vec![0; this_chunk as usize];
+        file.seek(SeekFrom::Start(chunk_index * MIN_CHUNK_SIZE)).await?;
+        file.read_exact(&mut chunk).await?;
+
+        let part_checksum = checksum.add_part(&chunk);
+        let stream = ByteStream::from(chunk);
         //Chunk index needs to start at 0, but part numbers start at 1.
         let part_number = (chunk_index as i32) + 1;
         // snippet-start:[rust.example_code.s3.upload_part]
@@ -255,12 +423,18 @@ pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file
             .upload_id(upload_id)
             .body(stream)
             .part_number(part_number)
+            .checksum_algorithm(ChecksumAlgorithm::Sha256)
+            .checksum_sha256(part_checksum.clone())
             .send()
             .await?;
+        progress::transferred(this_chunk);
+        progress::part(chunk_index + 1, chunk_count, &key);
+
         upload_parts.push(
             CompletedPart::builder()
                 .e_tag(upload_part_res.e_tag.unwrap_or_default())
                 .part_number(part_number)
+                .checksum_sha256(part_checksum)
                 .build(),
         );
     };
@@ -269,17 +443,26 @@ pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file
         .set_parts(Some(upload_parts))
         .build();
 
-    let _complete_multipart_upload_res = client
+    let complete_multipart_upload_res = client
         .complete_multipart_upload()
         .bucket(aws_args.bucket_name)
-        .key(key)
+        .key(key.clone())
         .multipart_upload(completed_multipart_upload)
         .upload_id(upload_id)
         .send()
-        .await
-        .unwrap();
+        .await?;
 
-    Ok(())
+    // Providers which do not support checksums return none
+    if let Some(completed) = complete_multipart_upload_res.checksum_sha256() {
+        let expected = checksum.composite();
+        if completed != expected {
+            return Err(S3PutError::ChecksumMismatch(
+                format!("Multipart upload of {key} completed with checksum {completed}, expected {expected}.")
+            ));
+        }
+    }
+
+    Ok(checksum.composite())
 }
 
 /// The function `delete` deletes an object from a specified bucket using the AWS
@@ -299,6 +482,11 @@ pub async fn put_multipart(aws_args: AwsArgs, client: &Client, key: String, file
 /// It is used to identify the specific object within the bucket that you want to
 /// remove.
 /// 
+/// In a versioned bucket, no version is deleted: a delete marker hides the
+/// object, so deleting an object protected by Object Lock succeeds, and the
+/// locked version is kept until its retention passes and a lifecycle rule
+/// removes it.
+/// 
 /// Returns:
 /// 
 /// The `delete` function is returning a `Result` type with the success case being
@@ -314,6 +502,34 @@ pub async fn delete(aws_args: AwsArgs, client: &Client, key: String) -> Result<D
 }
 
 
+/// The function `head` reads the size and checksum of an object without
+/// downloading it, which works for objects in every storage class.
+/// 
+/// Arguments:
+/// 
+/// * `aws_args`: The bucket containing the object.
+/// * `client`: The S3 client.
+/// * `key`: The key of the object.
+/// 
+/// Returns:
+/// 
+/// The object's metadata, `None` if the object does not exist, or the error of
+/// the failed request.
+pub async fn head(aws_args: AwsArgs, client: &Client, key: String) -> Result<Option<HeadObjectOutput>, SdkError<HeadObjectError, Response>> {
+    let result = client.head_object()
+        .bucket(aws_args.bucket_name)
+        .key(key)
+        .checksum_mode(ChecksumMode::Enabled)
+        .send()
+        .await;
+
+    match result {
+        Ok(output) => Ok(Some(output)),
+        Err(error) if error.as_service_error().is_some_and(|error| error.is_not_found()) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
 /// The `restore` function in Rust asynchronously restores an object in a bucket
 /// using the AWS SDK for Rust.
 /// 
@@ -342,38 +558,89 @@ pub async fn restore(aws_args: AwsArgs, client: &Client, key: String) -> Result<
         .await
 }
 
-pub async fn get_object<'a>(cli: Cli, aws_args: AwsArgs, client: &Client, key: String, prefix: String, file_paths: SetIter<'a, String>) -> Result<Vec<String>, S3GetError> {
+/// The function `undelete` brings back an object deleted within its minimum
+/// storage duration. The object is restored from S3 Glacier, or, on providers
+/// and storage classes which do not support restoring objects, its delete
+/// marker is removed from the versioned bucket instead.
+/// 
+/// Arguments:
+/// 
+/// * `aws_args`: The bucket containing the object.
+/// * `client`: The S3 client.
+/// * `key`: The key of the object.
+/// 
+/// Returns:
+/// 
+/// `Ok(())` once the object has been brought back, or the error of the failed
+/// request.
+pub async fn undelete(aws_args: AwsArgs, client: &Client, key: String) -> Result<(), S3UndeleteError> {
+    match restore(aws_args.clone(), client, key.clone()).await {
+        Ok(_) => Ok(()),
+        Err(error) if error.code().is_some_and(|code| RESTORE_UNSUPPORTED_CODES.contains(&code)) => {
+            debug!("Restoring {key} is not supported, removing its delete marker instead.");
+            remove_delete_marker(aws_args, client, key).await
+        },
+        Err(error) => Err(error.into()),
+    }
+}
 
-    if file_paths.len() == 0 {
-        return Ok(vec![]);
+/// The function `remove_delete_marker` deletes the delete marker hiding the
+/// latest version of an object, if there is one.
+async fn remove_delete_marker(aws_args: AwsArgs, client: &Client, key: String) -> Result<(), S3UndeleteError> {
+    let versions = client.list_object_versions()
+        .bucket(aws_args.bucket_name.clone())
+        .prefix(key.clone())
+        .send()
+        .await?;
+
+    let delete_marker = versions.delete_markers().iter().find(|marker| {
+        marker.key() == Some(key.as_str()) && marker.is_latest() == Some(true)
+    });
+
+    if let Some(version_id) = delete_marker.and_then(|marker| marker.version_id()) {
+        client.delete_object()
+            .bucket(aws_args.bucket_name)
+            .key(key)
+            .version_id(version_id)
+            .send()
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_object(cli: Cli, aws_args: AwsArgs, client: &Client, key: String, prefix: String, files: Vec<String>) -> Result<Vec<String>, S3GetError> {
+
+    // A dry run never writes to disk
+    if files.is_empty() || cli.dry_run {
+        return Ok(files);
     }
-    
-    let files: Vec<String> = file_paths.map(|s| s.clone()).collect();
     
     let first_file = prefix.clone() + &files[0];
-    let (first_dir, _) = first_file.rsplit_once('/').unwrap();
+    let (first_dir, _) = first_file.rsplit_once('/')
+        .ok_or_else(|| S3GetError::S3GetError(format!("Restore path has no directory: {first_file}")))?;
     
     create_dir_all(first_dir)?;
     let mut file = File::create(first_file.clone())?;
 
-    if cli.dry_run {
-        return Ok(files);
-    }
-    
     let mut object = client
         .get_object()
         .bucket(aws_args.bucket_name)
         .key(key)
+        .checksum_mode(ChecksumMode::Enabled)
         .send()
-        .await?;
+        .await
+        .map_err(Box::new)?;
     
     while let Some(bytes) = object.body.try_next().await? {
         file.write_all(&bytes)?;
+        progress::transferred(bytes.len() as u64);
     }
     
-    for i in 1..files.len() {
-        let file = prefix.clone() + &files[i];
-        let (dir, _) = file.rsplit_once('/').unwrap();
+    for file in files.iter().skip(1) {
+        let file = prefix.clone() + file;
+        let (dir, _) = file.rsplit_once('/')
+            .ok_or_else(|| S3GetError::S3GetError(format!("Restore path has no directory: {file}")))?;
 
         create_dir_all(dir)?;
         fs::copy(first_file.clone(), file)?;
@@ -447,54 +714,247 @@ pub async fn list(client: &Client, aws_args: AwsArgs) -> Result<HashMap<String,
 
     let mut output = HashMap::new();
 
-    let _ = client
+    let mut pages = client
         .list_objects_v2()
         .bucket(aws_args.bucket_name)
         .into_paginator()
-        .send()
-        .collect::<Result<Vec<ListObjectsV2Output>, SdkError<ListObjectsV2Error>>>()
-        .await?
-        
-        // For every page in the results
-        .iter().map(|page| {
-
-            // For every file in the page
-            let _ = page.contents().iter().map(|file| {
-                output.insert(
-                    file.key()?.to_owned(), 
-                    SystemTime::try_from(*file.last_modified()?).ok()?
-                );
-
-                Some(())
-            });
-        });
+        .send();
+
+    // For every page in the results
+    while let Some(page) = pages.next().await {
+
+        // For every file in the page
+        for file in page?.contents() {
+            let (Some(key), Some(last_modified)) = (file.key(), file.last_modified()) else {
+                continue;
+            };
+
+            if let Ok(modified) = SystemTime::try_from(*last_modified) {
+                output.insert(key.to_owned(), modified);
+            }
+        }
+    }
     
     Ok(output)
 }
 
-pub async fn permanently_delete_all(client: &Client, aws_args: AwsArgs) -> Result<(), S3DeleteError> {
-    let versions = client.list_object_versions()
-        .bucket(aws_args.bucket_name.clone())
-        .send().await?;
-
-    let delete_objects: Vec<ObjectIdentifier> = versions.versions().iter().flat_map(|version| {
-        ObjectIdentifier::builder()
-            .set_key(version.key.clone())
-            .set_version_id(version.version_id.clone())
-            .build()
-    }).collect();
-
-    if delete_objects.is_empty() {
-        info!("No objects found to delete.");
-        return Ok(());
+/// The function `list_versions` lists every version of the objects in a
+/// bucket, including delete markers, following every page of results.
+/// 
+/// Arguments:
+/// 
+/// * `client`: The S3 client.
+/// * `aws_args`: The bucket.
+/// * `prefix`: Only versions of keys starting with this are listed.
+/// 
+/// Returns:
+/// 
+/// The versions, or the error of the failed request.
+pub async fn list_versions(client: &Client, aws_args: AwsArgs, prefix: &str) -> Result<Vec<ObjectVersion>, S3DeleteError> {
+    let mut versions = vec![];
+
+    let mut key_marker = None;
+    let mut version_id_marker = None;
+
+    loop {
+        let page = client.list_object_versions()
+            .bucket(aws_args.bucket_name.clone())
+            .prefix(prefix)
+            .set_key_marker(key_marker.take())
+            .set_version_id_marker(version_id_marker.take())
+            .send()
+            .await?;
+
+        for version in page.versions() {
+            versions.push(ObjectVersion {
+                key: version.key().unwrap_or_default().to_string(),
+                version_id: version.version_id().map(str::to_string),
+                size: version.size().unwrap_or_default().max(0) as u64,
+                last_modified: last_modified(version.last_modified()),
+                storage_class: version.storage_class().map(|storage_class| storage_class.as_str().to_string()),
+                is_latest: version.is_latest().unwrap_or_default(),
+                is_delete_marker: false,
+            });
+        }
+
+        for marker in page.delete_markers() {
+            versions.push(ObjectVersion {
+                key: marker.key().unwrap_or_default().to_string(),
+                version_id: marker.version_id().map(str::to_string),
+                size: 0,
+                last_modified: last_modified(marker.last_modified()),
+                storage_class: None,
+                is_latest: marker.is_latest().unwrap_or_default(),
+                is_delete_marker: true,
+            });
+        }
+
+        if page.is_truncated() != Some(true) {
+            break;
+        }
+
+        key_marker = page.next_key_marker().map(str::to_string);
+        version_id_marker = page.next_version_id_marker().map(str::to_string);
     }
 
-    client
-        .delete_objects()
+    Ok(versions)
+}
+
+/// The function `last_modified` converts the time a version was last
+/// modified, as returned by the AWS SDK.
+fn last_modified(last_modified: Option<&SmithyDateTime>) -> DateTime<Utc> {
+    last_modified.and_then(|last_modified| DateTime::from_timestamp(last_modified.secs(), 0)).unwrap_or_default()
+}
+
+/// The function `version_is_locked` checks whether Object Lock protects a
+/// version of an object, by reading its retention and legal hold.
+/// 
+/// Arguments:
+/// 
+/// * `client`: The S3 client.
+/// * `aws_args`: The bucket.
+/// * `version`: The version.
+/// 
+/// Returns:
+/// 
+/// Whether the version is locked. Delete markers are never locked, and a
+/// version whose lock cannot be read is not assumed to be.
+async fn version_is_locked(client: &Client, aws_args: &AwsArgs, version: &ObjectVersion) -> bool {
+    if version.is_delete_marker {
+        return false;
+    }
+
+    let output = client.head_object()
         .bucket(aws_args.bucket_name.clone())
-        .delete(Delete::builder().set_objects(Some(delete_objects)).build()?)
+        .key(&version.key)
+        .set_version_id(version.version_id.clone())
         .send()
-        .await?;
+        .await;
 
-    Ok(())
+    let Ok(output) = output else {
+        return false;
+    };
+
+    let now = Utc::now();
+    let retained = output.object_lock_retain_until_date()
+        .and_then(|retain_until| DateTime::from_timestamp(retain_until.secs(), 0))
+        .is_some_and(|retain_until| retain_until > now);
+
+    retained || output.object_lock_legal_hold_status() == Some(&ObjectLockLegalHoldStatus::On)
+}
+
+/// The function `delete_versions` permanently deletes object versions and
+/// delete markers, in `DeleteObjects` requests of up to 1000 versions.
+/// 
+/// Arguments:
+/// 
+/// * `client`: The S3 client.
+/// * `aws_args`: The bucket.
+/// * `versions`: The versions to delete.
+/// 
+/// Returns:
+/// 
+/// Each version, with the error which prevented it from being deleted, if
+/// any. Versions protected by Object Lock fail with
+/// `S3DeleteError::S3VersionLocked`, and versions which could not be deleted
+/// for any other reason, such as missing permissions, with
+/// `S3DeleteError::S3VersionNotDeleted`.
+pub async fn delete_versions(client: &Client, aws_args: AwsArgs, versions: Vec<ObjectVersion>) -> Vec<(ObjectVersion, Result<(), S3DeleteError>)> {
+    let mut results = vec![];
+
+    for chunk in versions.chunks(DELETE_BATCH_SIZE) {
+        let objects = chunk.iter()
+            .map(|version| ObjectIdentifier::builder().key(&version.key).set_version_id(version.version_id.clone()).build())
+            .collect::<Result<Vec<ObjectIdentifier>, BuildError>>();
+
+        let output = match objects.and_then(|objects| Delete::builder().set_objects(Some(objects)).quiet(true).build()) {
+            Ok(delete) => client.delete_objects()
+                .bucket(aws_args.bucket_name.clone())
+                .delete(delete)
+                .send()
+                .await
+                .map_err(S3DeleteError::from),
+            Err(error) => Err(error.into()),
+        };
+
+        let output = match output {
+            Ok(output) => output,
+            Err(error) => {
+                // Every version of a request which failed failed with it
+                let error = error.to_string();
+                results.extend(chunk.iter().map(|version| (version.clone(), Err(S3DeleteError::S3VersionNotDeleted(error.clone())))));
+                continue;
+            },
+        };
+
+        // Versions are denied individually, such as when they are protected by Object Lock, rather than failing the request
+        let mut errors: HashMap<(&str, &str), &S3Error> = HashMap::new();
+        for error in output.errors() {
+            errors.insert((error.key().unwrap_or_default(), error.version_id().unwrap_or_default()), error);
+        }
+
+        for version in chunk {
+            let Some(error) = errors.remove(&(version.key.as_str(), version.version_id.as_deref().unwrap_or_default())) else {
+                results.push((version.clone(), Ok(())));
+                continue;
+            };
+
+            let message = format!("{} {}", error.code().unwrap_or_default(), error.message().unwrap_or_default());
+
+            // Access to a version is also denied without s3:DeleteObjectVersion, so only a version which is locked is reported as locked
+            let error = match error.code() {
+                Some(ACCESS_DENIED) if version_is_locked(client, &aws_args, version).await => S3DeleteError::S3VersionLocked(message),
+                _ => S3DeleteError::S3VersionNotDeleted(message),
+            };
+
+            results.push((version.clone(), Err(error)));
+        }
+    }
+
+    results
+}
+
+/// The function `lock_configuration` reads the Object Lock configuration of a
+/// bucket.
+/// 
+/// Arguments:
+/// 
+/// * `aws_args`: The bucket.
+/// * `client`: The S3 client.
+/// 
+/// Returns:
+/// 
+/// A description of the bucket's default retention, such as "enabled,
+/// default retention compliance for 30 days", `None` if Object Lock is not
+/// enabled, or the error of the failed request.
+pub async fn lock_configuration(aws_args: AwsArgs, client: &Client) -> Result<Option<String>, SdkError<GetObjectLockConfigurationError, Response>> {
+    let result = client.get_object_lock_configuration()
+        .bucket(aws_args.bucket_name)
+        .send()
+        .await;
+
+    let configuration = match result {
+        Ok(output) => output.object_lock_configuration,
+        Err(error) if error.code() == Some(LOCK_CONFIGURATION_NOT_FOUND) => None,
+        Err(error) => return Err(error),
+    };
+
+    let Some(configuration) = configuration.filter(|configuration| configuration.object_lock_enabled() == Some(&ObjectLockEnabled::Enabled)) else {
+        return Ok(None);
+    };
+
+    let default_retention = configuration.rule().and_then(|rule| rule.default_retention()).map(|retention| {
+        let mode = retention.mode().map(|mode| mode.as_str().to_lowercase()).unwrap_or_default();
+
+        match (retention.days(), retention.years()) {
+            (Some(days), _) => format!("default retention {mode} for {days} days"),
+            (_, Some(years)) => format!("default retention {mode} for {years} years"),
+            _ => format!("default retention {mode}"),
+        }
+    });
+
+    Ok(Some(match default_retention {
+        Some(default_retention) => format!("enabled, {default_retention}"),
+        None => "enabled, no default retention".to_string(),
+    }))
 }
@@ -0,0 +1,261 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::environment::{BackupArgs, SafetyArgs};
+use crate::error::GdaError;
+
+// Bytes read from the start of a changed file to measure its entropy
+const ENTROPY_SAMPLE_BYTES: usize = 64 * 1024;
+// Files smaller than this are too small for their entropy to say much about their contents
+const MIN_ENTROPY_SAMPLE_BYTES: usize = 4 * 1024;
+// Bits of entropy per byte above which contents look encrypted. Text is usually below 5.
+const HIGH_ENTROPY: f64 = 7.5;
+// Extensions of formats which are compressed, and so look encrypted even when they are not
+const COMPRESSED_EXTENSIONS: [&str; 36] = [
+    "7z", "aac", "apk", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "kdbx",
+    "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "pdf", "png", "pptx", "rar", "tgz",
+    "webm", "webp", "xlsx", "xz",
+];
+
+/// The `ChangeStats` struct counts the changes a backup found, before any of
+/// them are backed up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChangeStats {
+    /// The number of files in the previous backup.
+    pub previous: usize,
+    /// The number of files in the previous backup which no longer exist.
+    pub deleted: usize,
+    /// The number of changed files whose contents look encrypted, and new
+    /// files which look like a deleted file with a new extension.
+    pub suspicious: usize,
+}
+
+/// The `ChangeCounter` struct counts changes as they are found, measuring
+/// changed files and matching renamed files only if suspicious changes are
+/// limited.
+pub struct ChangeCounter {
+    heuristics: bool,
+    stats: ChangeStats,
+    // Paths of new files, and of deleted files with and without their extension
+    new_files: Vec<String>,
+    deleted_files: HashSet<String>,
+}
+
+impl ChangeCounter {
+
+    /// The function `new` starts counting the changes of a backup.
+    ///
+    /// Arguments:
+    ///
+    /// * `args`: The safety thresholds of the backup.
+    pub fn new(args: &SafetyArgs) -> ChangeCounter {
+        ChangeCounter {
+            heuristics: args.max_suspicious_percent.is_some(),
+            stats: ChangeStats::default(),
+            new_files: vec![],
+            deleted_files: HashSet::new(),
+        }
+    }
+
+    /// The function `previous` counts files of the previous backup.
+    pub fn previous(&mut self, count: usize) {
+        self.stats.previous += count;
+    }
+
+    /// The function `new_file` counts a file which was not in the previous
+    /// backup.
+    pub fn new_file(&mut self, file_path: &str) {
+        if self.heuristics {
+            self.new_files.push(file_path.to_string());
+        }
+    }
+
+    /// The function `changed_file` counts a file which was modified since
+    /// the previous backup.
+    pub fn changed_file(&mut self, file_path: &str) {
+        if self.heuristics && looks_encrypted(file_path) {
+            info!("Changed file looks encrypted: {file_path}");
+            self.stats.suspicious += 1;
+        }
+    }
+
+    /// The function `deleted_file` counts a file of the previous backup which
+    /// no longer exists.
+    pub fn deleted_file(&mut self, file_path: &str) {
+        self.stats.deleted += 1;
+
+        if self.heuristics {
+            self.deleted_files.insert(file_path.to_string());
+            if let Some((stem, _)) = split_extension(file_path) {
+                self.deleted_files.insert(stem.to_string());
+            }
+        }
+    }
+
+    /// The function `finish` returns the counted changes. New files whose
+    /// path without its extension is the path of a deleted file, with or
+    /// without its extension, are counted as suspicious renames.
+    pub fn finish(mut self) -> ChangeStats {
+        for file_path in &self.new_files {
+            if split_extension(file_path).is_some_and(|(stem, _)| self.deleted_files.contains(stem)) {
+                info!("New file looks like a deleted file with a new extension: {file_path}");
+                self.stats.suspicious += 1;
+            }
+        }
+
+        self.stats
+    }
+}
+
+/// The function `check_sentinel` checks that the sentinel file of a backup
+/// exists, so that an unmounted or empty target directory is not mistaken for
+/// every file being deleted.
+///
+/// Arguments:
+///
+/// * `args`: The target directory and safety thresholds of the backup.
+/// * `dry_run`: Whether the backup is a dry run, which only warns.
+///
+/// Returns:
+///
+/// `GdaError::SafetyAbort` if a sentinel file is required and missing.
+pub fn check_sentinel(args: &BackupArgs, dry_run: bool) -> Result<(), GdaError> {
+    let Some(sentinel_file) = &args.safety.sentinel_file else {
+        return Ok(());
+    };
+
+    let path = Path::new(&args.target_dir).join(sentinel_file.trim_start_matches('/'));
+
+    if path.is_file() {
+        return Ok(());
+    }
+
+    let reason = format!("The sentinel file {} is missing. Is the target directory mounted?", path.display());
+
+    if dry_run {
+        warn!("A backup would abort: {reason}");
+        return Ok(());
+    }
+
+    Err(GdaError::SafetyAbort(reason))
+}
+
+/// The function `enabled` checks whether a backup must count its changes
+/// before backing them up.
+pub fn enabled(args: &SafetyArgs) -> bool {
+    args.max_delete_percent.is_some() || args.max_delete_count.is_some() || args.max_suspicious_percent.is_some()
+}
+
+/// The function `check` compares the changes a backup found with its safety
+/// thresholds. A dry run only warns, so that the changes can still be
+/// reviewed.
+///
+/// Arguments:
+///
+/// * `args`: The safety thresholds.
+/// * `stats`: The counted changes.
+/// * `dry_run`: Whether the backup is a dry run.
+///
+/// Returns:
+///
+/// `GdaError::SafetyAbort` describing every threshold which was exceeded.
+pub fn check(args: &SafetyArgs, stats: &ChangeStats, dry_run: bool) -> Result<(), GdaError> {
+    info!("Safety check: {} of {} files deleted, {} suspicious changes.", stats.deleted, stats.previous, stats.suspicious);
+
+    let mut reasons = vec![];
+
+    if let Some(max_delete_count) = args.max_delete_count.filter(|max_delete_count| stats.deleted > *max_delete_count) {
+        reasons.push(format!("{} files would be deleted, more than the maximum of {max_delete_count}", stats.deleted));
+    }
+
+    let deleted_percent = percent(stats.deleted, stats.previous);
+    if let Some(max_delete_percent) = args.max_delete_percent.filter(|max_delete_percent| deleted_percent > *max_delete_percent) {
+        reasons.push(format!("{deleted_percent:.1}% of files would be deleted, more than the maximum of {max_delete_percent}%"));
+    }
+
+    let suspicious_percent = percent(stats.suspicious, stats.previous);
+    if let Some(max_suspicious_percent) = args.max_suspicious_percent.filter(|max_suspicious_percent| suspicious_percent > *max_suspicious_percent) {
+        reasons.push(format!("{suspicious_percent:.1}% of files look encrypted or renamed, more than the maximum of {max_suspicious_percent}%"));
+    }
+
+    if reasons.is_empty() {
+        return Ok(());
+    }
+
+    let reason = reasons.join(", and ") + ". Nothing was backed up.";
+
+    if dry_run {
+        warn!("A backup would abort: {reason}");
+        return Ok(());
+    }
+
+    Err(GdaError::SafetyAbort(reason))
+}
+
+/// The function `percent` returns a count as a percentage of the files in
+/// the previous backup, or 0 for the first backup.
+fn percent(count: usize, previous: usize) -> f64 {
+    match previous {
+        0 => 0.0,
+        previous => count as f64 * 100.0 / previous as f64,
+    }
+}
+
+/// The function `split_extension` splits the extension from the name of a
+/// file.
+///
+/// Returns:
+///
+/// The path without its extension and the extension, or `None` if the file
+/// has no extension.
+fn split_extension(file_path: &str) -> Option<(&str, &str)> {
+    let (stem, extension) = file_path.rsplit_once('.')?;
+
+    match stem.rsplit_once('/') {
+        Some((_, "")) => None,
+        _ if extension.contains('/') || stem.is_empty() => None,
+        _ => Some((stem, extension)),
+    }
+}
+
+/// The function `looks_encrypted` checks whether the start of a file has the
+/// entropy of encrypted data, unless its extension is of a compressed
+/// format.
+fn looks_encrypted(file_path: &str) -> bool {
+    let compressed = split_extension(file_path)
+        .is_some_and(|(_, extension)| COMPRESSED_EXTENSIONS.contains(&extension.to_lowercase().as_str()));
+
+    if compressed {
+        return false;
+    }
+
+    let mut sample = Vec::with_capacity(ENTROPY_SAMPLE_BYTES);
+    let read = File::open(file_path)
+        .and_then(|file| file.take(ENTROPY_SAMPLE_BYTES as u64).read_to_end(&mut sample));
+
+    match read {
+        Ok(size) if size >= MIN_ENTROPY_SAMPLE_BYTES => entropy(&sample) > HIGH_ENTROPY,
+        _ => false,
+    }
+}
+
+/// The function `entropy` measures the Shannon entropy of data, in bits per
+/// byte, from 0 for a single repeated byte to 8 for random data.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+
+    counts.iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let probability = *count as f64 / data.len() as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
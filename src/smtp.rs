@@ -0,0 +1,217 @@
+use std::io::Error as IoError;
+use std::sync::Arc;
+
+use aws_smithy_types::base64;
+use chrono::Utc;
+use log::debug;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use thiserror::Error;
+
+use crate::environment::SmtpSecurity;
+
+#[derive(Error, Debug)]
+pub enum SmtpError {
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+
+    #[error("TlsError: {0}")]
+    TlsError(#[from] tokio_rustls::rustls::Error),
+
+    #[error("InvalidHost: {0}")]
+    InvalidHost(String),
+
+    #[error("ProtocolError: {0}")]
+    ProtocolError(String),
+
+    /// The server rejected a command. Only the command's verb is recorded, so
+    /// that credentials are never logged.
+    #[error("Rejected: {command} was answered with {reply}")]
+    Rejected { command: String, reply: String },
+}
+
+/// The `SmtpConfig` struct holds the server and addresses an email is sent
+/// with.
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// The function `send` emails a plain text message.
+///
+/// Arguments:
+///
+/// * `config`: The server to send through, and the sender and recipients.
+/// * `subject`: The subject of the email.
+/// * `body`: The plain text body of the email.
+///
+/// Returns:
+///
+/// The error of the first step of the SMTP session which failed, if any.
+pub async fn send(config: &SmtpConfig, subject: &str, body: &str) -> Result<(), SmtpError> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+
+    match config.security {
+        SmtpSecurity::Tls => {
+            let stream = tls_connector()?.connect(server_name(&config.host)?, stream).await?;
+            let mut stream = BufReader::new(stream);
+            expect_reply(&mut stream, "CONNECT", 220).await?;
+            session(&mut stream, config, subject, body).await
+        },
+        SmtpSecurity::Starttls => {
+            let mut stream = BufReader::new(stream);
+            expect_reply(&mut stream, "CONNECT", 220).await?;
+            command(&mut stream, &format!("EHLO {}", hello_name()), 250).await?;
+            command(&mut stream, "STARTTLS", 220).await?;
+
+            let stream = tls_connector()?.connect(server_name(&config.host)?, stream.into_inner()).await?;
+            session(&mut BufReader::new(stream), config, subject, body).await
+        },
+        SmtpSecurity::None => {
+            let mut stream = BufReader::new(stream);
+            expect_reply(&mut stream, "CONNECT", 220).await?;
+            session(&mut stream, config, subject, body).await
+        },
+    }
+}
+
+/// The function `session` authenticates and sends an email over a connection
+/// whose greeting has been read.
+async fn session<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufReader<S>, config: &SmtpConfig, subject: &str, body: &str) -> Result<(), SmtpError> {
+    command(stream, &format!("EHLO {}", hello_name()), 250).await?;
+
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        let credentials = base64::encode(format!("\0{username}\0{password}"));
+        command(stream, &format!("AUTH PLAIN {credentials}"), 235).await?;
+    }
+
+    command(stream, &format!("MAIL FROM:<{}>", config.from), 250).await?;
+
+    for recipient in &config.to {
+        command(stream, &format!("RCPT TO:<{recipient}>"), 250).await?;
+    }
+
+    command(stream, "DATA", 354).await?;
+    stream.get_mut().write_all(message(config, subject, body).as_bytes()).await?;
+    expect_reply(stream, "DATA", 250).await?;
+
+    command(stream, "QUIT", 221).await?;
+
+    Ok(())
+}
+
+/// The function `message` formats an email, ending with the line which
+/// terminates the DATA command.
+fn message(config: &SmtpConfig, subject: &str, body: &str) -> String {
+    let subject = match subject.is_ascii() {
+        true => subject.to_string(),
+        false => format!("=?UTF-8?B?{}?=", base64::encode(subject)),
+    };
+
+    let mut message = format!(
+        "From: <{}>\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        config.from,
+        config.to.iter().map(|recipient| format!("<{recipient}>")).collect::<Vec<String>>().join(", "),
+        Utc::now().to_rfc2822(),
+    );
+
+    // Lines starting with '.' are escaped, so they cannot end the message early
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+
+    message.push_str(".\r\n");
+    message
+}
+
+/// The function `command` sends a command and checks the class of its reply.
+///
+/// Arguments:
+///
+/// * `stream`: The connection to the server.
+/// * `line`: The command, without its line ending.
+/// * `expected`: The expected reply code. Any code of the same class, such as
+/// 2xx, is accepted.
+///
+/// Returns:
+///
+/// The reply, or `SmtpError::Rejected` naming the command's verb.
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufReader<S>, line: &str, expected: u16) -> Result<String, SmtpError> {
+    let verb = line.split_whitespace().next().unwrap_or_default();
+    debug!("Sending SMTP command: {verb}");
+
+    stream.get_mut().write_all(format!("{line}\r\n").as_bytes()).await?;
+    stream.get_mut().flush().await?;
+
+    expect_reply(stream, verb, expected).await
+}
+
+/// The function `expect_reply` reads a reply, which may span several lines,
+/// and checks its class.
+async fn expect_reply<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufReader<S>, verb: &str, expected: u16) -> Result<String, SmtpError> {
+    let mut reply = String::new();
+
+    loop {
+        let mut line = String::new();
+
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(SmtpError::ProtocolError(format!("The server closed the connection after {verb}.")));
+        }
+
+        reply.push_str(&line);
+
+        // Every line but the last of a reply continues with '-' after its code
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+
+    let code: u16 = reply.get(..3)
+        .and_then(|code| code.parse().ok())
+        .ok_or(SmtpError::ProtocolError(format!("Invalid reply to {verb}: {}", reply.trim_end())))?;
+
+    if code / 100 != expected / 100 {
+        return Err(SmtpError::Rejected { command: verb.to_string(), reply: reply.trim_end().to_string() });
+    }
+
+    Ok(reply)
+}
+
+/// The function `tls_connector` creates a TLS connector trusting the Mozilla
+/// root certificates.
+fn tls_connector() -> Result<TlsConnector, SmtpError> {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let config = ClientConfig::builder_with_provider(Arc::new(tokio_rustls::rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn server_name(host: &str) -> Result<ServerName<'static>, SmtpError> {
+    ServerName::try_from(host.to_string()).map_err(|_| SmtpError::InvalidHost(host.to_string()))
+}
+
+/// The name this host introduces itself with.
+fn hello_name() -> String {
+    hostname::get().ok()
+        .and_then(|name| name.into_string().ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("localhost".to_string())
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Error as IoError;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use aws_sdk_dynamodb::Client as DynamoClient;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::delete_object::DeleteObjectError;
+use aws_sdk_s3::operation::get_object_lock_configuration::GetObjectLockConfigurationError;
+use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Error;
+use aws_sdk_s3::types::ObjectLockLegalHoldStatus;
+use thiserror::Error;
+
+use crate::aws;
+use crate::heartbeat;
+use crate::dynamodb::{HashTracker, HashTrackerError};
+use crate::environment::{AwsArgs, Backend, Cli};
+use crate::local::{LocalMetadataStore, LocalObjectStore};
+use crate::s3::{self, S3DeleteError, S3GetError, S3PutError, S3UndeleteError};
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("HashTrackerError")]
+    HashTrackerError(#[from] Box<HashTrackerError>),
+
+    #[error("S3PutError")]
+    S3PutError(#[from] Box<S3PutError>),
+
+    #[error("S3GetError")]
+    S3GetError(#[from] Box<S3GetError>),
+
+    #[error("S3DeleteError")]
+    S3DeleteError(#[from] Box<S3DeleteError>),
+
+    #[error("S3DeleteObjectError")]
+    S3DeleteObjectError(#[from] Box<SdkError<DeleteObjectError>>),
+
+    #[error("S3UndeleteError")]
+    S3UndeleteError(#[from] Box<S3UndeleteError>),
+
+    #[error("S3ListObjectsError")]
+    S3ListObjectsError(#[from] Box<SdkError<ListObjectsV2Error>>),
+
+    #[error("S3HeadObjectError")]
+    S3HeadObjectError(#[from] Box<SdkError<HeadObjectError>>),
+
+    #[error("S3GetObjectLockConfigurationError")]
+    S3GetObjectLockConfigurationError(#[from] Box<SdkError<GetObjectLockConfigurationError>>),
+
+    #[error("IoError")]
+    IoError(#[from] IoError),
+
+    #[error("JsonError")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("ChecksumMismatch: {0}")]
+    ChecksumMismatch(String),
+
+    #[error("MissingObject: {0}")]
+    MissingObject(String),
+
+    #[error("InternalError: {0}")]
+    InternalError(String),
+
+    #[error("ConfigError: {0}")]
+    ConfigError(String),
+}
+
+impl StorageError {
+
+    /// The function `is_transient` checks whether the failed operation may
+    /// succeed if it is attempted again later, such as after throttling.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            StorageError::HashTrackerError(error) => error.is_transient(),
+            StorageError::S3PutError(error) => error.is_transient(),
+            StorageError::S3UndeleteError(error) => error.is_transient(),
+            StorageError::S3DeleteObjectError(error) => aws::is_transient(error),
+            StorageError::S3ListObjectsError(error) => aws::is_transient(error),
+            StorageError::S3HeadObjectError(error) => aws::is_transient(error),
+            StorageError::S3GetObjectLockConfigurationError(error) => aws::is_transient(error),
+            _ => false,
+        }
+    }
+}
+
+/// The size and checksum of a stored object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    pub size: u64,
+    /// The base64 encoded SHA-256 checksum, if the store recorded one. The
+    /// checksum of a multipart upload is a composite, formatted as "<checksum>-<parts>".
+    pub checksum: Option<String>,
+    /// The Object Lock protecting the current version of the object.
+    pub lock: ObjectLockInfo,
+}
+
+/// The `ObjectVersion` struct describes a version of an object, or a delete
+/// marker, as listed when deleting a backup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectVersion {
+    pub key: String,
+    /// The id of the version, or `None` if the store does not version objects.
+    pub version_id: Option<String>,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+    /// The storage class of the version, such as "DEEP_ARCHIVE", if the store
+    /// reported one.
+    pub storage_class: Option<String>,
+    /// Whether this is the current version of the object.
+    pub is_latest: bool,
+    /// Whether this is a delete marker, which hides the noncurrent versions of
+    /// a deleted object.
+    pub is_delete_marker: bool,
+}
+
+/// The `ObjectLockInfo` struct describes the Object Lock protecting a
+/// version of an object. An object without a lock is unlocked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectLockInfo {
+    /// "GOVERNANCE" or "COMPLIANCE", if the object has a retention.
+    pub mode: Option<String>,
+    /// When the retention of the object ends.
+    pub retain_until: Option<DateTime<Utc>>,
+    /// Whether the object has a legal hold, which protects it until it is
+    /// removed.
+    pub legal_hold: bool,
+}
+
+impl ObjectLockInfo {
+
+    /// The function `is_locked` checks whether the object version cannot be
+    /// deleted yet.
+    /// 
+    /// Arguments:
+    /// 
+    /// * `now`: The current time.
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        self.legal_hold || self.retain_until.is_some_and(|retain_until| retain_until > now)
+    }
+
+    /// The function `describe` describes the lock for reports, such as
+    /// "compliance until 2025-01-01T00:00:00Z, legal hold".
+    /// 
+    /// Arguments:
+    /// 
+    /// * `now`: The current time. Retentions which have passed are not
+    /// described.
+    pub fn describe(&self, now: DateTime<Utc>) -> String {
+        let mut parts = vec![];
+
+        if let (Some(mode), Some(retain_until)) = (&self.mode, self.retain_until.filter(|retain_until| *retain_until > now)) {
+            parts.push(format!("{} until {}", mode.to_lowercase(), retain_until.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if self.legal_hold {
+            parts.push("legal hold".to_string());
+        }
+
+        match parts.is_empty() {
+            true => "unlocked".to_string(),
+            false => parts.join(", "),
+        }
+    }
+}
+
+/// The `ObjectStore` trait stores the contents of backed up files, keyed by
+/// their hash.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+
+    /// Uploads the file at `file_path` as the object `key`, returning the
+    /// base64 encoded SHA-256 checksum of the stored object.
+    async fn put(&self, key: String, file_path: String) -> Result<String, StorageError>;
+
+    /// Brings back an object deleted within its minimum storage duration.
+    async fn undelete(&self, key: String) -> Result<(), StorageError>;
+
+    /// Deletes an object. The object may be kept until its minimum storage
+    /// duration has passed, so that it can be undeleted.
+    async fn delete(&self, key: String) -> Result<(), StorageError>;
+
+    /// Writes the object `key` to every file in `files`, relative to `prefix`.
+    async fn get(&self, cli: Cli, key: String, prefix: String, files: Vec<String>) -> Result<Vec<String>, StorageError>;
+
+    /// Reads the size and checksum of an object, or `None` if it does not exist.
+    async fn head(&self, key: String) -> Result<Option<ObjectInfo>, StorageError>;
+
+    /// Lists every object, with the time it was last modified.
+    async fn list(&self) -> Result<HashMap<String, SystemTime>, StorageError>;
+
+    /// Lists every version of the objects whose key starts with `prefix`,
+    /// including deleted ones and delete markers.
+    async fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, StorageError>;
+
+    /// Permanently deletes object versions and delete markers, returning each
+    /// with the error which prevented it from being deleted, if any.
+    async fn delete_versions(&self, versions: Vec<ObjectVersion>) -> Vec<(ObjectVersion, Result<(), StorageError>)>;
+
+    /// Describes the default retention of the store's Object Lock
+    /// configuration, or `None` if Object Lock is not enabled.
+    async fn lock_configuration(&self) -> Result<Option<String>, StorageError>;
+}
+
+/// The `MetadataStore` trait stores the hash trackers recording which files
+/// reference each object.
+#[async_trait]
+pub trait MetadataStore: Send + Sync {
+
+    /// Reads the trackers of the given hashes. Hashes without a tracker are absent.
+    async fn get_many(&self, hashes: HashSet<String>) -> Result<HashMap<String, HashTracker>, StorageError>;
+
+    /// Reads every tracker.
+    async fn get_all(&self) -> Result<Vec<HashTracker>, StorageError>;
+
+    /// Writes the changes made to a tracker since it was read, merging them
+    /// with changes made by other processes. On success the tracker reflects
+    /// what was written.
+    async fn update(&self, hash_tracker: &mut HashTracker) -> Result<(), StorageError>;
+
+    /// Removes dangling trackers, returning the number deleted and rewritten.
+    async fn clean(&self) -> Result<(usize, usize), StorageError>;
+
+    /// Records the time of the last successful backup of `host`.
+    async fn put_heartbeat(&self, host: &str, last_success: DateTime<Utc>) -> Result<(), StorageError>;
+
+    /// Reads the time of the last successful backup of every host.
+    async fn get_heartbeats(&self) -> Result<HashMap<String, DateTime<Utc>>, StorageError>;
+
+    /// Permanently deletes every tracker and heartbeat.
+    async fn delete_all(&self) -> Result<(), StorageError>;
+
+    /// Permanently deletes the trackers of the given hashes.
+    async fn delete_many(&self, hashes: HashSet<String>) -> Result<(), StorageError>;
+}
+
+/// The `S3Store` struct is the `ObjectStore` of the aws backend.
+pub struct S3Store {
+    aws_args: AwsArgs,
+    client: S3Client,
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: String, file_path: String) -> Result<String, StorageError> {
+        Ok(s3::put(self.aws_args.clone(), &self.client, key, file_path).await.map_err(Box::new)?)
+    }
+
+    async fn undelete(&self, key: String) -> Result<(), StorageError> {
+        Ok(s3::undelete(self.aws_args.clone(), &self.client, key).await.map_err(Box::new)?)
+    }
+
+    async fn delete(&self, key: String) -> Result<(), StorageError> {
+        s3::delete(self.aws_args.clone(), &self.client, key).await.map_err(Box::new)?;
+        Ok(())
+    }
+
+    async fn get(&self, cli: Cli, key: String, prefix: String, files: Vec<String>) -> Result<Vec<String>, StorageError> {
+        Ok(s3::get_object(cli, self.aws_args.clone(), &self.client, key, prefix, files).await.map_err(Box::new)?)
+    }
+
+    async fn head(&self, key: String) -> Result<Option<ObjectInfo>, StorageError> {
+        let output = s3::head(self.aws_args.clone(), &self.client, key).await.map_err(Box::new)?;
+
+        Ok(output.map(|output| ObjectInfo {
+            size: output.content_length().unwrap_or_default().max(0) as u64,
+            checksum: output.checksum_sha256().map(str::to_string),
+            lock: ObjectLockInfo {
+                mode: output.object_lock_mode().map(|mode| mode.as_str().to_string()),
+                retain_until: output.object_lock_retain_until_date().and_then(|retain_until| DateTime::from_timestamp(retain_until.secs(), 0)),
+                legal_hold: output.object_lock_legal_hold_status() == Some(&ObjectLockLegalHoldStatus::On),
+            },
+        }))
+    }
+
+    async fn list(&self) -> Result<HashMap<String, SystemTime>, StorageError> {
+        Ok(s3::list(&self.client, self.aws_args.clone()).await.map_err(Box::new)?)
+    }
+
+    async fn list_versions(&self, prefix: &str) -> Result<Vec<ObjectVersion>, StorageError> {
+        Ok(s3::list_versions(&self.client, self.aws_args.clone(), prefix).await.map_err(Box::new)?)
+    }
+
+    async fn delete_versions(&self, versions: Vec<ObjectVersion>) -> Vec<(ObjectVersion, Result<(), StorageError>)> {
+        s3::delete_versions(&self.client, self.aws_args.clone(), versions).await
+            .into_iter()
+            .map(|(version, result)| (version, result.map_err(|error| Box::new(error).into())))
+            .collect()
+    }
+
+    async fn lock_configuration(&self) -> Result<Option<String>, StorageError> {
+        Ok(s3::lock_configuration(self.aws_args.clone(), &self.client).await.map_err(Box::new)?)
+    }
+}
+
+/// The `DynamoDbStore` struct is the `MetadataStore` of the aws backend.
+pub struct DynamoDbStore {
+    aws_args: AwsArgs,
+    client: DynamoClient,
+}
+
+#[async_trait]
+impl MetadataStore for DynamoDbStore {
+    async fn get_many(&self, hashes: HashSet<String>) -> Result<HashMap<String, HashTracker>, StorageError> {
+        Ok(HashTracker::get_many(self.aws_args.clone(), &self.client, hashes).await.map_err(Box::new)?)
+    }
+
+    async fn get_all(&self) -> Result<Vec<HashTracker>, StorageError> {
+        HashTracker::get_all(&self.client, self.aws_args.clone()).await
+            .ok_or(Box::new(HashTrackerError::DynamoDbGetItemError("Unable to scan DynamoDB.".to_string())).into())
+    }
+
+    async fn update(&self, hash_tracker: &mut HashTracker) -> Result<(), StorageError> {
+        Ok(hash_tracker.update(self.aws_args.clone(), &self.client).await.map_err(Box::new)?)
+    }
+
+    async fn clean(&self) -> Result<(usize, usize), StorageError> {
+        Ok(HashTracker::clean(self.aws_args.clone(), &self.client).await.map_err(Box::new)?)
+    }
+
+    async fn put_heartbeat(&self, host: &str, last_success: DateTime<Utc>) -> Result<(), StorageError> {
+        Ok(heartbeat::put(&self.aws_args, &self.client, host, last_success).await.map_err(Box::new)?)
+    }
+
+    async fn get_heartbeats(&self) -> Result<HashMap<String, DateTime<Utc>>, StorageError> {
+        Ok(heartbeat::get_all(&self.aws_args, &self.client).await.map_err(Box::new)?)
+    }
+
+    async fn delete_all(&self) -> Result<(), StorageError> {
+        HashTracker::permanently_delete_all(self.aws_args.clone(), &self.client).await.map_err(Box::new)?;
+        Ok(heartbeat::delete(&self.aws_args, &self.client).await.map_err(Box::new)?)
+    }
+
+    async fn delete_many(&self, hashes: HashSet<String>) -> Result<(), StorageError> {
+        // Trackers with many files are sharded across several items
+        let ids = self.get_many(hashes).await?
+            .values()
+            .flat_map(HashTracker::item_ids)
+            .collect();
+
+        Ok(HashTracker::delete_many(self.aws_args.clone(), &self.client, ids).await.map_err(Box::new)?)
+    }
+}
+
+/// The object store and metadata store of a backend.
+pub type Stores = (Box<dyn ObjectStore>, Box<dyn MetadataStore>);
+
+/// The function `open` creates the object and metadata stores of a backend.
+///
+/// Arguments:
+///
+/// * `backend`: The backend to use. `None` selects the aws backend.
+/// * `aws_args`: The bucket, table and table schema used by the aws backend.
+/// * `s3_client`: The S3 client used by the aws backend.
+/// * `dynamo_client`: The DynamoDB client used by the aws backend.
+///
+/// Returns:
+///
+/// The object store and metadata store, or `StorageError::ConfigError` if the
+/// aws backend is selected without a bucket or table.
+pub fn open(backend: Option<Backend>, aws_args: AwsArgs, s3_client: &S3Client, dynamo_client: &DynamoClient) -> Result<Stores, StorageError> {
+    match backend.unwrap_or(Backend::Aws) {
+        Backend::Aws => {
+            if aws_args.bucket_name.is_empty() && aws_args.dynamo_table.is_empty() {
+                return Err(StorageError::ConfigError("The aws backend requires a bucket name or DynamoDB table.".to_string()));
+            }
+
+            Ok((
+                Box::new(S3Store { aws_args: aws_args.clone(), client: s3_client.clone() }),
+                Box::new(DynamoDbStore { aws_args, client: dynamo_client.clone() }),
+            ))
+        },
+        Backend::Local(path) => Ok((
+            Box::new(LocalObjectStore::new(&path)?),
+            Box::new(LocalMetadataStore::new(&path)?),
+        )),
+    }
+}
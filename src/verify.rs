@@ -0,0 +1,98 @@
+use log::{error, info};
+
+use crate::backup::is_empty_hash;
+use crate::dynamodb::HashTracker;
+use crate::environment::{Cli, VerifyArgs};
+use crate::metrics::METRICS;
+use crate::report::{self, FileAction, FileReport};
+use crate::storage::{MetadataStore, ObjectStore, StorageError};
+
+/// The `verify` function checks that the object of every backed up file is
+/// stored, and that its checksum matches the one recorded when it was
+/// uploaded. Objects are never downloaded, so objects in Glacier can be
+/// verified without restoring them.
+/// 
+/// Arguments:
+/// 
+/// * `cli`: The parsed command line.
+/// * `args`: The arguments of the verify command.
+/// * `objects`: The object store which file contents are stored in.
+/// * `metadata`: The metadata store which hash trackers are read from.
+/// 
+/// Returns:
+/// 
+/// The number of files which were and were not verified, or the error
+/// encountered reading the hash trackers.
+pub async fn verify(cli: Cli, args: VerifyArgs, objects: &dyn ObjectStore, metadata: &dyn MetadataStore) -> Result<(usize, usize), StorageError> {
+
+    let mut verified = 0;
+    let mut failed = 0;
+
+    for hash_tracker in metadata.get_all().await? {
+
+        let files = hash_tracker.restore_paths(args.host_id.as_deref());
+
+        if files.is_empty() {
+            continue;
+        }
+
+        let result = if is_empty_hash(&hash_tracker.hash) {
+            Ok(0)
+        }
+        else {
+            verify_object(objects, &hash_tracker).await
+        };
+
+        match &result {
+            Ok(_) => verified += files.len(),
+            Err(error) => {
+                failed += files.len();
+                error!("{} files failed to be verified: {:?}\nError: {:?}", files.len(), files, error);
+                METRICS.failure("verify");
+            },
+        };
+
+        for file in files {
+            let mut file_report = FileReport::new(&cli, file, FileAction::Verify, Some(hash_tracker.hash.clone()));
+
+            file_report = match &result {
+                Ok(size) => {
+                    file_report.size = Some(*size);
+                    file_report
+                },
+                Err(error) => file_report.failed(error),
+            };
+
+            report::file(&cli, &file_report);
+        }
+    };
+
+    info!("Verify complete: {verified} verified, {failed} failed.");
+
+    Ok((verified, failed))
+}
+
+/// The function `verify_object` checks the object of a hash tracker.
+/// 
+/// Arguments:
+/// 
+/// * `objects`: The object store.
+/// * `hash_tracker`: The hash tracker whose object is checked.
+/// 
+/// Returns:
+/// 
+/// The size of the object, `StorageError::MissingObject` if it is not stored,
+/// or `StorageError::ChecksumMismatch` if it does not match its checksum.
+/// Objects uploaded before checksums were recorded are only checked to exist.
+async fn verify_object(objects: &dyn ObjectStore, hash_tracker: &HashTracker) -> Result<u64, StorageError> {
+    let Some(object) = objects.head(hash_tracker.hash.clone()).await? else {
+        return Err(StorageError::MissingObject(hash_tracker.hash.clone()));
+    };
+
+    match (&hash_tracker.checksum, &object.checksum) {
+        (Some(expected), Some(actual)) if expected != actual => {
+            Err(StorageError::ChecksumMismatch(format!("Object {} has checksum {actual}, expected {expected}.", hash_tracker.hash)))
+        },
+        _ => Ok(object.size),
+    }
+}
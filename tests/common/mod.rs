@@ -1,42 +1,81 @@
-use assert_cmd::cargo;
+use assert_cmd::{cargo, Command};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::client::Waiters;
+use aws_sdk_dynamodb::config::Credentials;
+use aws_sdk_dynamodb::types::{AttributeDefinition, BillingMode, KeySchemaElement, KeyType, ScalarAttributeType};
+use aws_sdk_s3::types::{BucketVersioningStatus, VersioningConfiguration};
+use clap::Parser;
+use diesel::PgConnection;
+use gda_backup::environment::{Cli, Commands, DatabaseArgs};
+use gda_backup::{dynamodb, s3, storage};
 use std::{env, fs};
-use std::io::{Error, Write};
+use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
 
 pub const TEST_DIR: &str = "./test_dir/";
 pub const TEST_DIR_BACKUP: &str = "./test_dir/backup/";
 pub const TEST_DIR_RESTORE: &str = "./test_dir/restore/";
 
+pub const LOCAL_BACKEND: &str = "local:./test_dir/local/";
+
 pub const DB_ENGINE: &str = "postgres";
 pub const POSTGRES_USER: &str = "postgres";
 pub const POSTGRES_PASSWORD: &str = "password";
 pub const POSTGRES_HOST: &str = "localhost";
 pub const POSTGRES_DB: &str = "postgres";
 
+// The MinIO and DynamoDB Local services of docker-compose.yml
+pub const S3_ENDPOINT: &str = "http://localhost:9000";
+pub const DYNAMO_ENDPOINT: &str = "http://localhost:8000";
+pub const ENDPOINT_ACCESS_KEY: &str = "minioadmin";
+pub const ENDPOINT_SECRET_KEY: &str = "minioadmin";
+pub const ENDPOINT_BUCKET: &str = "gda-backup-test";
+pub const ENDPOINT_TABLE: &str = "gda-backup-test";
+
 pub fn setup() {
 
+    setup_local();
+
+    let mut delete_backup = cargo::cargo_bin_cmd!("gda_backup");
+    let assert = delete_backup
+        .arg("delete-backup")
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .env("DRY_RUN", "false")
+        .write_stdin("disciple153-test")
+        .assert();
+
+    assert.success();
+}
+
+pub fn setup_local() {
+
     let _ = fs::remove_dir_all(TEST_DIR);
 
     let mut clear_local_db = cargo::cargo_bin_cmd!("gda_backup");
     let assert = clear_local_db
         .arg("clear-database")
-        .args(&["--db-engine", DB_ENGINE])
-        .args(&["--postgres-user", POSTGRES_USER])
-        .args(&["--postgres-password", POSTGRES_PASSWORD])
-        .args(&["--postgres-host", POSTGRES_HOST])
-        .args(&["--postgres-db", POSTGRES_DB])
+        .args(["--db-engine", DB_ENGINE])
+        .args(["--postgres-user", POSTGRES_USER])
+        .args(["--postgres-password", POSTGRES_PASSWORD])
+        .args(["--postgres-host", POSTGRES_HOST])
+        .args(["--postgres-db", POSTGRES_DB])
         .assert();
 
     assert.success();
+}
 
-    let mut delete_backup = cargo::cargo_bin_cmd!("gda_backup");
-    let assert = delete_backup
-        .arg("delete-backup")
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"])
-        .write_stdin("y")
-        .assert();
-
-    assert.success();
+pub fn establish_connection() -> PgConnection {
+    gda_backup::establish_connection(DatabaseArgs {
+        db_engine: DB_ENGINE.to_string(),
+        postgres_user: POSTGRES_USER.to_string(),
+        postgres_password: POSTGRES_PASSWORD.to_string(),
+        postgres_host: POSTGRES_HOST.to_string(),
+        postgres_db: POSTGRES_DB.to_string(),
+    }).expect("Error connecting to the test database.")
 }
 
 pub fn get_pwd() -> Result<String, Error> {
@@ -44,8 +83,8 @@ pub fn get_pwd() -> Result<String, Error> {
 }
 
 pub fn create_file(file_name: &str, contents: &str) {
-    let mut file = fs::File::create(TEST_DIR_BACKUP.to_owned() + &file_name).unwrap();
-    file.write(contents.as_bytes()).unwrap();
+    let mut file = fs::File::create(TEST_DIR_BACKUP.to_owned() + file_name).unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
 }
 
 pub fn build_restore_path(file_name: &str) -> String {
@@ -66,4 +105,184 @@ pub fn build_restore_path(file_name: &str) -> String {
 
 pub fn read_file(file_name: &str) -> Result<String, Error> {
     fs::read_to_string(build_restore_path(file_name))
-}
\ No newline at end of file
+}
+pub fn json_records(stdout: &[u8]) -> Vec<serde_json::Value> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+pub fn http_get(address: &str, path: &str) -> Result<String, Error> {
+    let mut stream = TcpStream::connect(address)?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {address}\r\n\r\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    Ok(response)
+}
+
+/// Stands in for a webhook, answering every request with 200 OK and sending
+/// its path and body to the returned receiver.
+pub fn webhook_stand_in() -> (String, Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            let _ = sender.send((path, String::from_utf8_lossy(&body).to_string()));
+        }
+    });
+
+    (format!("http://{address}/hook"), receiver)
+}
+
+/// Parses the command line of a backup with the given arguments.
+pub fn backup_cli(args: &[&str]) -> Cli {
+    Cli::parse_from(
+        ["gda_backup", "backup", "--target-dir", TEST_DIR_BACKUP, "--index-file", "./test_dir/index"].iter().chain(args)
+    )
+}
+
+/// Opens the object and metadata stores a backup with the given arguments
+/// would use, such as `["--backend", LOCAL_BACKEND]`.
+pub async fn open_stores(args: &[&str]) -> storage::Stores {
+    let cli = backup_cli(args);
+
+    let Commands::Backup(backup_args) = &cli.command else { unreachable!() };
+
+    storage::open(
+        backup_args.backend.clone(),
+        (**backup_args).clone().into(),
+        &s3::get_client(&cli).await,
+        &dynamodb::get_client(&cli).await,
+    ).unwrap()
+}
+
+/// Creates a DynamoDB table keyed by a "pk" partition key and "sk" sort key,
+/// unlike the "hash" key of the table created by gda-backup.tf, if it does
+/// not exist yet.
+pub async fn create_custom_key_table(table_name: &str) {
+    let client = dynamodb::get_client(&backup_cli(&["--bucket-name", "disciple153-test", "--dynamo-table", table_name])).await;
+
+    if client.describe_table().table_name(table_name).send().await.is_ok() {
+        return;
+    }
+
+    let attribute = |name: &str| AttributeDefinition::builder()
+        .attribute_name(name)
+        .attribute_type(ScalarAttributeType::S)
+        .build()
+        .unwrap();
+    let key = |name: &str, key_type| KeySchemaElement::builder()
+        .attribute_name(name)
+        .key_type(key_type)
+        .build()
+        .unwrap();
+
+    client.create_table()
+        .table_name(table_name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .attribute_definitions(attribute("pk"))
+        .attribute_definitions(attribute("sk"))
+        .key_schema(key("pk", KeyType::Hash))
+        .key_schema(key("sk", KeyType::Range))
+        .send()
+        .await
+        .unwrap();
+
+    client.wait_until_table_exists()
+        .table_name(table_name)
+        .wait(Duration::from_secs(120))
+        .await
+        .unwrap();
+}
+
+/// Builds a gda_backup command which stores backups in the MinIO and
+/// DynamoDB Local services rather than AWS. The subcommand is added after.
+pub fn endpoint_cmd() -> Command {
+    let mut cmd = cargo::cargo_bin_cmd!("gda_backup");
+
+    cmd.args(["--s3-endpoint", S3_ENDPOINT])
+        .arg("--s3-force-path-style")
+        .args(["--dynamo-endpoint", DYNAMO_ENDPOINT])
+        .env("AWS_ACCESS_KEY_ID", ENDPOINT_ACCESS_KEY)
+        .env("AWS_SECRET_ACCESS_KEY", ENDPOINT_SECRET_KEY)
+        .env("AWS_DEFAULT_REGION", "us-east-1");
+
+    cmd
+}
+
+/// Creates the versioned bucket and the table of the MinIO and DynamoDB Local
+/// services if they do not exist yet, and deletes any backup left in them.
+pub async fn setup_endpoints() {
+    setup_local();
+
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new(ENDPOINT_ACCESS_KEY, ENDPOINT_SECRET_KEY, None, None, "test"))
+        .load()
+        .await;
+
+    let s3_client = aws_sdk_s3::Client::from_conf(
+        aws_sdk_s3::config::Builder::from(&config).endpoint_url(S3_ENDPOINT).force_path_style(true).build()
+    );
+
+    if s3_client.head_bucket().bucket(ENDPOINT_BUCKET).send().await.is_err() {
+        s3_client.create_bucket().bucket(ENDPOINT_BUCKET).send().await.unwrap();
+    }
+
+    s3_client.put_bucket_versioning()
+        .bucket(ENDPOINT_BUCKET)
+        .versioning_configuration(VersioningConfiguration::builder().status(BucketVersioningStatus::Enabled).build())
+        .send()
+        .await
+        .unwrap();
+
+    let dynamo_client = aws_sdk_dynamodb::Client::from_conf(
+        aws_sdk_dynamodb::config::Builder::from(&config).endpoint_url(DYNAMO_ENDPOINT).build()
+    );
+
+    if dynamo_client.describe_table().table_name(ENDPOINT_TABLE).send().await.is_err() {
+        dynamo_client.create_table()
+            .table_name(ENDPOINT_TABLE)
+            .billing_mode(BillingMode::PayPerRequest)
+            .attribute_definitions(AttributeDefinition::builder().attribute_name("hash").attribute_type(ScalarAttributeType::S).build().unwrap())
+            .key_schema(KeySchemaElement::builder().attribute_name("hash").key_type(KeyType::Hash).build().unwrap())
+            .send()
+            .await
+            .unwrap();
+    }
+
+    endpoint_cmd()
+        .arg("delete-backup")
+        .args(["--bucket-name", ENDPOINT_BUCKET])
+        .args(["--dynamo-table", ENDPOINT_TABLE])
+        .env("DRY_RUN", "false")
+        .write_stdin(ENDPOINT_BUCKET)
+        .assert()
+        .success();
+}
@@ -1,7 +1,20 @@
 use assert_cmd::cargo;
+use aws_sdk_s3::operation::put_object::PutObjectError;
+use aws_sdk_s3::types::StorageClass;
+use aws_smithy_runtime_api::client::result::SdkError;
+use aws_smithy_runtime_api::http::{Response, StatusCode};
+use aws_smithy_types::body::SdkBody;
+use aws_smithy_types::error::ErrorMetadata;
+use chrono::Utc;
 use rand::{distr::Alphanumeric, Rng};
-use std::{fs::{self}, path::Path};
+use std::{collections::HashSet, fs::{self}, io, path::Path, process::Command, thread, time::Duration};
 use serial_test::serial;
+use gda_backup::{aws, checksum, dynamodb, error, lock};
+use gda_backup::checksum::MultipartChecksum;
+use gda_backup::dynamodb::HashTracker;
+use gda_backup::environment::{AwsArgs, Commands};
+use gda_backup::storage::MetadataStore;
+use gda_backup::s3::S3PutError;
 
 // importing common module.
 mod common;
@@ -31,23 +44,23 @@ fn backup_test() {
 
     let backup = backup
         .arg("backup")
-        .args(&["--target-dir", common::TEST_DIR_BACKUP])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"])
-        .args(&["--db-engine", common::DB_ENGINE])
-        .args(&["--postgres-user", common::POSTGRES_USER])
-        .args(&["--postgres-password", common::POSTGRES_PASSWORD])
-        .args(&["--postgres-host", common::POSTGRES_HOST])
-        .args(&["--postgres-db", common::POSTGRES_DB])
-        .args(&["--min-storage-duration", "1"]);
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .args(["--min-storage-duration", "1"]);
 
     let mut restore = cargo::cargo_bin_cmd!("gda_backup");
 
     let restore = restore
         .arg("restore")
-        .args(&["--target-dir", common::TEST_DIR_RESTORE])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"]);
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"]);
 
     let assert_backup = backup.assert();
     dbg!(assert_backup.get_output());
@@ -97,22 +110,22 @@ fn backup_test_no_min_storage_duration() {
 
     let backup = backup
         .arg("backup")
-        .args(&["--target-dir", common::TEST_DIR_BACKUP])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"])
-        .args(&["--db-engine", common::DB_ENGINE])
-        .args(&["--postgres-user", common::POSTGRES_USER])
-        .args(&["--postgres-password", common::POSTGRES_PASSWORD])
-        .args(&["--postgres-host", common::POSTGRES_HOST])
-        .args(&["--postgres-db", common::POSTGRES_DB]);
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB]);
 
     let mut restore = cargo::cargo_bin_cmd!("gda_backup");
 
     let restore = restore
         .arg("restore")
-        .args(&["--target-dir", common::TEST_DIR_RESTORE])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"]);
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"]);
 
     let assert_backup = backup.assert();
     dbg!(assert_backup.get_output());
@@ -137,6 +150,65 @@ fn backup_test_no_min_storage_duration() {
     assert_eq!(backup_test_3, restore_test3);
 }
 
+#[tokio::test]
+#[serial]
+async fn endpoint_test() {
+    // using common code.
+    common::setup_endpoints().await;
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    let backup_test_file = "test1.txt";
+    let backup_test = "hello endpoint";
+
+    let backup = || {
+        let mut backup = common::endpoint_cmd();
+
+        backup
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--bucket-name", common::ENDPOINT_BUCKET])
+            .args(["--dynamo-table", common::ENDPOINT_TABLE])
+            .env("DRY_RUN", "false")
+            .args(["--db-engine", common::DB_ENGINE])
+            .args(["--postgres-user", common::POSTGRES_USER])
+            .args(["--postgres-password", common::POSTGRES_PASSWORD])
+            .args(["--postgres-host", common::POSTGRES_HOST])
+            .args(["--postgres-db", common::POSTGRES_DB]);
+
+        let assert_backup = backup.assert();
+        dbg!(assert_backup.get_output());
+
+        assert_backup.success();
+    };
+
+    common::create_file(backup_test_file, backup_test);
+    backup();
+
+    // Deleting the file hides its object behind a delete marker while its hash tracker is kept
+    fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + backup_test_file).unwrap();
+    backup();
+
+    // MinIO cannot restore objects, so the object is undeleted by removing its delete marker
+    common::create_file(backup_test_file, backup_test);
+    backup();
+
+    let mut restore = common::endpoint_cmd();
+
+    let restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", common::ENDPOINT_BUCKET])
+        .args(["--dynamo-table", common::ENDPOINT_TABLE]);
+
+    let assert_restore = restore.assert();
+    dbg!(assert_restore.get_output());
+
+    assert_restore.success();
+
+    assert_eq!(backup_test, common::read_file(backup_test_file).unwrap());
+}
 
 #[test]
 #[serial]
@@ -160,24 +232,24 @@ fn regex_test() {
 
     let backup = backup
         .arg("backup")
-        .args(&["--target-dir", common::TEST_DIR_BACKUP])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"])
-        .args(&["--db-engine", common::DB_ENGINE])
-        .args(&["--postgres-user", common::POSTGRES_USER])
-        .args(&["--postgres-password", common::POSTGRES_PASSWORD])
-        .args(&["--postgres-host", common::POSTGRES_HOST])
-        .args(&["--postgres-db", common::POSTGRES_DB])
-        .args(&["--min-storage-duration", "1"])
-        .args(&["--filter", r".txt$"]);
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .args(["--min-storage-duration", "1"])
+        .args(["--filter", r".txt$"]);
 
     let mut restore = cargo::cargo_bin_cmd!("gda_backup");
 
     let restore = restore
         .arg("restore")
-        .args(&["--target-dir", common::TEST_DIR_RESTORE])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"]);
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"]);
 
     let assert_backup = backup.assert();
     dbg!(assert_backup.get_output());
@@ -234,9 +306,9 @@ fn regex_test_env() {
 
     let restore = restore
         .arg("restore")
-        .args(&["--target-dir", common::TEST_DIR_RESTORE])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"]);
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"]);
 
     let assert_backup = backup.assert();
     dbg!(assert_backup.get_output());
@@ -294,9 +366,9 @@ fn regex_test_env_dry() {
 
     let restore = restore
         .arg("restore")
-        .args(&["--target-dir", common::TEST_DIR_RESTORE])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"]);
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"]);
 
     let assert_backup = backup.assert();
     dbg!(assert_backup.get_output());
@@ -338,23 +410,23 @@ fn multipart_upload_test() {
 
     let backup = backup
         .arg("backup")
-        .args(&["--target-dir", common::TEST_DIR_BACKUP])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"])
-        .args(&["--db-engine", common::DB_ENGINE])
-        .args(&["--postgres-user", common::POSTGRES_USER])
-        .args(&["--postgres-password", common::POSTGRES_PASSWORD])
-        .args(&["--postgres-host", common::POSTGRES_HOST])
-        .args(&["--postgres-db", common::POSTGRES_DB])
-        .args(&["--min-storage-duration", "1"]);
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .args(["--min-storage-duration", "1"]);
 
     let mut restore = cargo::cargo_bin_cmd!("gda_backup");
 
     let restore = restore
         .arg("restore")
-        .args(&["--target-dir", common::TEST_DIR_RESTORE])
-        .args(&["--bucket-name", "disciple153-test"])
-        .args(&["--dynamo-table", "gda-backup-test"]);
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"]);
 
     let assert_backup = backup.assert();
     dbg!(assert_backup.get_output());
@@ -371,4 +443,1833 @@ fn multipart_upload_test() {
     let restore_test1 = common::read_file(backup_test_file).unwrap();
 
     assert_eq!(backup_test, restore_test1);
-}
\ No newline at end of file
+
+    // The composite checksum of the parts is compared with the one S3 reports
+    let mut verify = cargo::cargo_bin_cmd!("gda_backup");
+
+    let verify = verify
+        .args(["--output", "json"])
+        .arg("verify")
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .env("DRY_RUN", "false");
+
+    let assert_verify = verify.assert();
+    dbg!(assert_verify.get_output());
+
+    let records = common::json_records(&assert_verify.get_output().stdout);
+    assert_verify.success();
+
+    let report = records.last().unwrap();
+    assert_eq!(report["succeeded"], 1);
+    assert_eq!(report["failed"], 0);
+}
+
+#[test]
+#[serial]
+fn local_backend_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    let backup_test_file_1 = "test1.txt";
+    let backup_test_file_2 = "test2.txt";
+    let backup_test_file_3 = "test3.txt";
+
+    let backup_test_1 = "hello world";
+    let backup_test_2 = "hello world";
+    let backup_test_3 = "";
+
+    common::create_file(backup_test_file_1, backup_test_1);
+    common::create_file(backup_test_file_2, backup_test_2);
+    common::create_file(backup_test_file_3, backup_test_3);
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB]);
+
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false");
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+
+    assert_backup.success();
+
+    let assert_restore = restore.assert();
+    dbg!(assert_restore.get_output());
+
+    assert_restore.success();
+
+    let restore_test1 = common::read_file(backup_test_file_1).unwrap();
+    let restore_test2 = common::read_file(backup_test_file_2).unwrap();
+    let restore_test3 = common::read_file(backup_test_file_3).unwrap();
+
+    assert_eq!(backup_test_1, restore_test1);
+    assert_eq!(backup_test_2, restore_test2);
+    assert_eq!(backup_test_3, restore_test3);
+}
+
+#[test]
+#[serial]
+fn local_backend_checksum_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    let backup_test_file = "test1.txt";
+    common::create_file(backup_test_file, "hello world");
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB]);
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+
+    assert_backup.success();
+
+    // Corrupt the stored object
+    for object in fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap() {
+        fs::write(object.unwrap().path(), "hello world!").unwrap();
+    }
+
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false");
+
+    let assert_restore = restore.assert();
+    dbg!(assert_restore.get_output());
+
+    let stderr = String::from_utf8_lossy(&assert_restore.get_output().stderr).to_string();
+    assert_restore.code(i32::from(error::EXIT_FAILURE));
+    assert!(stderr.contains("ChecksumMismatch"));
+    assert!(stderr.contains("0 restored, 1 failed"));
+}
+
+#[tokio::test]
+#[serial]
+async fn backup_lock_test() {
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    // Hold the local database lock as if another backup were running.
+    let conn = &mut common::establish_connection();
+    lock::lock_local_state(conn, None).await.unwrap();
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .arg("--dry-run")
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB]);
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+
+    let stderr = String::from_utf8_lossy(&assert_backup.get_output().stderr).to_string();
+    assert_backup.code(i32::from(error::EXIT_LOCKED));
+    assert!(stderr.contains("locked by another backup"));
+
+    lock::unlock_local_state(conn).unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn lease_lost_test() {
+    let cli = common::backup_cli(&["--bucket-name", "disciple153-test", "--dynamo-table", "gda-backup-test"]);
+    let Commands::Backup(args) = &cli.command else { unreachable!() };
+    let aws_args = AwsArgs::from(*args.clone());
+    let client = dynamodb::get_client(&cli).await;
+
+    let lease = lock::Lease::acquire(aws_args.clone(), &client, Some("lease-lost-test"), 3, None).await.unwrap();
+    let status = lease.status();
+    assert!(!status.is_lost());
+
+    // Take the lease away as force-unlock would, so that the next renewal fails its condition
+    lock::Lease::force_release(aws_args, &client, Some("lease-lost-test")).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    assert!(status.is_lost());
+
+    lease.release().await.unwrap();
+}
+
+#[test]
+#[serial]
+fn backup_config_error_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .args(["--filter", "(unclosed"])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB]);
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+
+    let stderr = String::from_utf8_lossy(&assert_backup.get_output().stderr).to_string();
+    assert_backup.code(i32::from(error::EXIT_CONFIG_ERROR));
+    assert!(stderr.contains("Invalid filter (unclosed"));
+}
+
+#[test]
+#[serial]
+fn local_backend_json_output_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "");
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .args(["--output", "json"])
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB]);
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    let files: Vec<&serde_json::Value> = records.iter().filter(|record| record["type"] == "file").collect();
+    assert_eq!(files.len(), 2);
+    assert!(files.iter().all(|file| file["action"] == "backup" && file["status"] == "succeeded"));
+    assert!(files.iter().any(|file| file["reason"] == "new object" && file["size"] == 11));
+    assert!(files.iter().any(|file| file["reason"] == "empty file" && file["size"] == 0));
+
+    let report = records.last().unwrap();
+    assert_eq!(report["type"], "report");
+    assert_eq!(report["command"], "backup");
+    assert_eq!(report["status"], "succeeded");
+    assert_eq!(report["exit_code"], 0);
+
+    // Verify the intact backup
+    let mut verify = cargo::cargo_bin_cmd!("gda_backup");
+
+    let verify = verify
+        .args(["--output", "json"])
+        .arg("verify")
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false");
+
+    let assert_verify = verify.assert();
+    dbg!(assert_verify.get_output());
+
+    let records = common::json_records(&assert_verify.get_output().stdout);
+    assert_verify.success();
+
+    let report = records.last().unwrap();
+    assert_eq!(report["command"], "verify");
+    assert_eq!(report["succeeded"], 2);
+    assert_eq!(report["failed"], 0);
+
+    // Corrupt the stored object and verify again
+    for object in fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap() {
+        fs::write(object.unwrap().path(), "hello world!").unwrap();
+    }
+
+    let mut verify = cargo::cargo_bin_cmd!("gda_backup");
+
+    let verify = verify
+        .args(["--output", "json"])
+        .arg("verify")
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false");
+
+    let assert_verify = verify.assert();
+    dbg!(assert_verify.get_output());
+
+    let records = common::json_records(&assert_verify.get_output().stdout);
+    assert_verify.code(i32::from(error::EXIT_PARTIAL_FAILURE));
+
+    let failed: Vec<&serde_json::Value> = records.iter().filter(|record| record["status"] == "failed").collect();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0]["error"].as_str().unwrap().contains("ChecksumMismatch"));
+
+    let report = records.last().unwrap();
+    assert_eq!(report["status"], "partial_failure");
+    assert_eq!(report["succeeded"], 1);
+    assert_eq!(report["failed"], 1);
+}
+
+#[test]
+#[serial]
+fn local_backend_metrics_textfile_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+
+    let textfile = format!("{}gda_backup.prom", common::TEST_DIR);
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = backup
+        .args(["--metrics-textfile", &textfile])
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.success();
+
+    let metrics = fs::read_to_string(&textfile).unwrap();
+    assert!(metrics.contains("gda_backup_last_run_exit_code{command=\"backup\"} 0"));
+    assert!(metrics.contains("gda_backup_files_scanned_total 1"));
+    assert!(metrics.contains("gda_backup_hashed_bytes_total 11"));
+    assert!(metrics.contains("gda_backup_uploaded_bytes_total 11"));
+    assert!(metrics.contains("gda_backup_last_run_files{command=\"backup\",result=\"succeeded\"} 1"));
+
+    let last_success = metrics.lines()
+        .find(|line| line.starts_with("gda_backup_last_success_timestamp_seconds{command=\"backup\"}"))
+        .unwrap()
+        .to_string();
+
+    // A failed run keeps the time of the last success
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = backup
+        .args(["--metrics-textfile", &textfile])
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .args(["--filter", "(unclosed"])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.code(i32::from(error::EXIT_CONFIG_ERROR));
+
+    let metrics = fs::read_to_string(&textfile).unwrap();
+    assert!(metrics.contains("gda_backup_last_run_exit_code{command=\"backup\"} 2"));
+    assert!(metrics.contains(&last_success));
+}
+
+#[test]
+#[serial]
+fn daemon_metrics_endpoint_test() {
+    let mut daemon = Command::new(cargo::cargo_bin!("gda_backup"))
+        .arg("daemon")
+        .args(["--backup-cron", "0 0 1 1 *"])
+        .args(["--metrics-listen", "127.0.0.1:19898"])
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .spawn()
+        .unwrap();
+
+    // Wait for the daemon to start listening
+    let response = (0..100).find_map(|_| {
+        thread::sleep(Duration::from_millis(200));
+        common::http_get("127.0.0.1:19898", "/metrics").ok()
+    });
+
+    let not_found = common::http_get("127.0.0.1:19898", "/");
+
+    daemon.kill().unwrap();
+    let _ = daemon.wait();
+
+    let response = response.expect("The daemon did not serve metrics.");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("# TYPE gda_backup_files_scanned_total counter"));
+
+    assert!(not_found.unwrap().starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[test]
+#[serial]
+fn webhook_notification_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "");
+
+    let (webhook_url, notifications) = common::webhook_stand_in();
+    let received = || -> Vec<serde_json::Value> {
+        notifications.try_iter().map(|(_, body)| serde_json::from_str(&body).unwrap()).collect()
+    };
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .env("NOTIFY_WEBHOOK_URL", &webhook_url)
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.success();
+
+    let received_backup = received();
+    assert_eq!(received_backup.len(), 2);
+    assert_eq!(received_backup[0]["title"], "Backup starting");
+    assert_eq!(received_backup[1]["title"], "Backup complete");
+    assert_eq!(received_backup[1]["report"]["succeeded"], 2);
+
+    // Only failures are notified
+    let mut verify = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = verify
+        .args(["--notify-on", "failure"])
+        .arg("verify")
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("NOTIFY_WEBHOOK_URL", &webhook_url)
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.success();
+    assert!(received().is_empty());
+
+    // Corrupt the stored object and verify again
+    for object in fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap() {
+        fs::write(object.unwrap().path(), "hello world!").unwrap();
+    }
+
+    let mut verify = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = verify
+        .args(["--notify-on", "failure"])
+        .arg("verify")
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("NOTIFY_WEBHOOK_URL", &webhook_url)
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.code(i32::from(error::EXIT_PARTIAL_FAILURE));
+
+    let received_verify = received();
+    assert_eq!(received_verify.len(), 1);
+    assert_eq!(received_verify[0]["title"], "Verify complete with failures");
+    assert_eq!(received_verify[0]["priority"], "high");
+    assert_eq!(received_verify[0]["failures"][0]["kind"], "ChecksumMismatch");
+    assert!(received_verify[0]["failures"][0]["path"].as_str().unwrap().ends_with("test1.txt"));
+    assert!(received_verify[0]["message"].as_str().unwrap().contains("test1.txt (ChecksumMismatch)"));
+
+    // The first command of a new day sends the digest of the previous day
+    let digest_file = format!("{}digest.json", common::TEST_DIR);
+    fs::write(&digest_file, r#"{"period":"2000-01-01","runs":[{"finished":"2000-01-01T03:00:00Z","report":{"command":"backup","dry_run":false,"status":"failed","exit_code":1,"succeeded":0,"failed":0,"error":"LockError: held"}}]}"#).unwrap();
+
+    let mut verify = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = verify
+        .args(["--notify-on", "never"])
+        .args(["--notify-digest", "daily"])
+        .args(["--notify-digest-file", &digest_file])
+        .arg("verify")
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("NOTIFY_WEBHOOK_URL", &webhook_url)
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.code(i32::from(error::EXIT_PARTIAL_FAILURE));
+
+    let received_digest = received();
+    assert_eq!(received_digest.len(), 1);
+    assert_eq!(received_digest[0]["title"], "Daily backup digest");
+    assert!(received_digest[0]["message"].as_str().unwrap().starts_with("2000-01-01: 1 commands run, 1 failed."));
+
+    let digest: serde_json::Value = serde_json::from_str(&fs::read_to_string(&digest_file).unwrap()).unwrap();
+    assert_eq!(digest["runs"].as_array().unwrap().len(), 1);
+    assert_eq!(digest["runs"][0]["report"]["command"], "verify");
+}
+
+#[test]
+#[serial]
+fn local_backend_freshness_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+
+    let (webhook_url, requests) = common::webhook_stand_in();
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .args(["--host-id", "server1"])
+        .env("DRY_RUN", "false")
+        .env("HEALTHCHECK_URL", &webhook_url)
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.success();
+
+    // The start and success of the backup are pinged
+    let pings: Vec<(String, String)> = requests.try_iter().collect();
+    assert_eq!(pings.len(), 2);
+    assert_eq!(pings[0].0, "/hook/start");
+    assert_eq!(pings[1].0, "/hook");
+
+    let ping_report: serde_json::Value = serde_json::from_str(&pings[1].1).unwrap();
+    assert_eq!(ping_report["status"], "succeeded");
+
+    let heartbeats: serde_json::Value = serde_json::from_str(&fs::read_to_string(common::TEST_DIR.to_owned() + "local/heartbeats.json").unwrap()).unwrap();
+    assert!(heartbeats["server1"].is_string());
+
+    // The host backed up within the maximum age
+    let mut check_freshness = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = check_freshness
+        .arg("check-freshness")
+        .args(["--max-age", "1h"])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.success();
+
+    // A host which never backed up is stale, and is notified
+    let mut check_freshness = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = check_freshness
+        .args(["--notify-on", "failure"])
+        .arg("check-freshness")
+        .args(["--max-age", "1h"])
+        .args(["--expected-hosts", "server1,server2"])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("NOTIFY_WEBHOOK_URL", &webhook_url)
+        .assert();
+
+    dbg!(assert.get_output());
+    assert.code(i32::from(error::EXIT_PARTIAL_FAILURE));
+
+    let notifications: Vec<serde_json::Value> = requests.try_iter().map(|(_, body)| serde_json::from_str(&body).unwrap()).collect();
+    assert_eq!(notifications.len(), 1);
+    assert_eq!(notifications[0]["title"], "Check freshness complete with failures");
+    assert_eq!(notifications[0]["failures"].as_array().unwrap().len(), 1);
+    assert_eq!(notifications[0]["failures"][0]["path"], "server2");
+    assert_eq!(notifications[0]["failures"][0]["kind"], "Missing");
+}
+
+#[test]
+#[serial]
+fn local_backend_progress_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello world!");
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = backup
+        .args(["--progress", "log"])
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+
+    dbg!(assert.get_output());
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert.success();
+
+    assert!(stderr.contains("Scanning complete: 2 files"));
+    assert!(stderr.contains("Hashing complete: 23 B"));
+    assert!(stderr.contains("Uploading complete: 23 B"));
+
+    // Restored files are counted, along with the bytes downloaded
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert = restore
+        .args(["--progress", "log"])
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .assert();
+
+    dbg!(assert.get_output());
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert.success();
+
+    assert!(stderr.contains("Restoring complete: 2 files"));
+}
+
+#[test]
+#[serial]
+fn local_backend_incremental_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello world");
+    common::create_file("test3.txt", "");
+
+    let backup = || {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .args(["--output", "json"])
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .env("DRY_RUN", "false")
+            .args(["--db-engine", common::DB_ENGINE])
+            .args(["--postgres-user", common::POSTGRES_USER])
+            .args(["--postgres-password", common::POSTGRES_PASSWORD])
+            .args(["--postgres-host", common::POSTGRES_HOST])
+            .args(["--postgres-db", common::POSTGRES_DB])
+            .assert()
+    };
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+
+    // Move, change and delete files
+    fs::rename(common::TEST_DIR_BACKUP.to_owned() + "test1.txt", common::TEST_DIR_BACKUP.to_owned() + "test4.txt").unwrap();
+    common::create_file("test2.txt", "hello world!");
+    fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + "test3.txt").unwrap();
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    let reason = |path: &str, action: &str| -> String {
+        records.iter()
+            .find(|record| record["action"] == action && record["path"].as_str().unwrap().ends_with(path))
+            .map(|record| record["reason"].as_str().unwrap().to_string())
+            .unwrap_or_default()
+    };
+
+    assert_eq!(reason("test4.txt", "backup"), "existing object");
+    assert_eq!(reason("test1.txt", "delete"), "object still referenced");
+    assert_eq!(reason("test2.txt", "backup"), "new object");
+    assert_eq!(reason("test2.txt", "delete"), "object still referenced");
+    assert_eq!(reason("test3.txt", "delete"), "empty file");
+
+    // The moved file's object was never deleted, even though its old path is missing
+    assert_eq!(fs::read_dir(common::TEST_DIR.to_owned() + "local/deleted").unwrap().count(), 0);
+
+    // Nothing changed since the last backup
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["succeeded"], 0);
+
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .assert();
+
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+
+    assert_eq!(common::read_file("test4.txt").unwrap(), "hello world");
+    assert_eq!(common::read_file("test2.txt").unwrap(), "hello world!");
+    assert!(common::read_file("test1.txt").is_err());
+    assert!(common::read_file("test3.txt").is_err());
+}
+
+#[test]
+#[serial]
+fn local_backend_index_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello world");
+    common::create_file("test3.txt", "");
+
+    let index_file = common::TEST_DIR.to_owned() + "index/state.json.gz";
+
+    // No local database is needed with an index
+    let backup = || {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .args(["--output", "json"])
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(["--index-file", &index_file])
+            .env("DRY_RUN", "false")
+            .assert()
+    };
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+
+    assert!(fs::exists(&index_file).unwrap());
+    assert!(!fs::exists(index_file.clone() + ".partial").unwrap());
+    assert!(!fs::exists(index_file.clone() + ".kept").unwrap());
+
+    // Move, change and delete files
+    fs::rename(common::TEST_DIR_BACKUP.to_owned() + "test1.txt", common::TEST_DIR_BACKUP.to_owned() + "test4.txt").unwrap();
+    common::create_file("test2.txt", "hello world!");
+    fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + "test3.txt").unwrap();
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    let reason = |path: &str, action: &str| -> String {
+        records.iter()
+            .find(|record| record["action"] == action && record["path"].as_str().unwrap().ends_with(path))
+            .map(|record| record["reason"].as_str().unwrap().to_string())
+            .unwrap_or_default()
+    };
+
+    assert_eq!(reason("test4.txt", "backup"), "existing object");
+    assert_eq!(reason("test1.txt", "delete"), "object still referenced");
+    assert_eq!(reason("test2.txt", "backup"), "new object");
+    assert_eq!(reason("test2.txt", "delete"), "object still referenced");
+    assert_eq!(reason("test3.txt", "delete"), "empty file");
+
+    assert_eq!(fs::read_dir(common::TEST_DIR.to_owned() + "local/deleted").unwrap().count(), 0);
+
+    // A lost index is rebuilt, and nothing is backed up again
+    fs::remove_file(&index_file).unwrap();
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["succeeded"], 0);
+    assert!(fs::exists(&index_file).unwrap());
+
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .assert();
+
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+
+    assert_eq!(common::read_file("test4.txt").unwrap(), "hello world");
+    assert_eq!(common::read_file("test2.txt").unwrap(), "hello world!");
+    assert!(common::read_file("test1.txt").is_err());
+    assert!(common::read_file("test3.txt").is_err());
+}
+
+#[test]
+#[serial]
+fn local_backend_rebuild_state_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello world");
+    common::create_file("test3.txt", "");
+
+    let database_args = [
+        "--db-engine", common::DB_ENGINE,
+        "--postgres-user", common::POSTGRES_USER,
+        "--postgres-password", common::POSTGRES_PASSWORD,
+        "--postgres-host", common::POSTGRES_HOST,
+        "--postgres-db", common::POSTGRES_DB,
+    ];
+
+    let backup = || {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .args(["--output", "json"])
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .env("DRY_RUN", "false")
+            .args(database_args)
+            .assert()
+    };
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+
+    // Lose the local database, then rebuild it
+    let mut clear_database = cargo::cargo_bin_cmd!("gda_backup");
+    clear_database
+        .arg("clear-database")
+        .args(database_args)
+        .assert()
+        .success();
+
+    let mut rebuild_state = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_rebuild = rebuild_state
+        .args(["--output", "json"])
+        .arg("rebuild-state")
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(database_args)
+        .assert();
+
+    dbg!(assert_rebuild.get_output());
+
+    let records = common::json_records(&assert_rebuild.get_output().stdout);
+    assert_rebuild.success();
+    assert_eq!(records.last().unwrap()["succeeded"], 3);
+    assert_eq!(records.last().unwrap()["failed"], 0);
+
+    // Touch a file without changing its contents
+    common::create_file("test1.txt", "hello world");
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    // The touched file is hashed again, but its version is kept
+    let test1 = records.iter().find(|record| record["path"].as_str().unwrap().ends_with("test1.txt")).unwrap();
+    assert_eq!(test1["action"], "backup");
+    assert_eq!(test1["reason"], "existing object");
+    assert!(!records.iter().any(|record| record["action"] == "delete"));
+    assert!(!records.iter().any(|record| record["path"].as_str().is_some_and(|path| path.ends_with("test2.txt"))));
+
+    assert_eq!(fs::read_dir(common::TEST_DIR.to_owned() + "local/deleted").unwrap().count(), 0);
+
+    // Nothing changed since the last backup
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["succeeded"], 0);
+}
+
+#[test]
+#[serial]
+fn local_backend_plan_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello world");
+    common::create_file("test3.txt", "");
+
+    let plan_file = common::TEST_DIR.to_owned() + "plan.json";
+    let objects = || fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap().count();
+
+    let backup = |dry_run: &str, plan_arg: &str| {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .args(["--output", "json"])
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args([plan_arg, &plan_file])
+            .env("DRY_RUN", dry_run)
+            .args(["--db-engine", common::DB_ENGINE])
+            .args(["--postgres-user", common::POSTGRES_USER])
+            .args(["--postgres-password", common::POSTGRES_PASSWORD])
+            .args(["--postgres-host", common::POSTGRES_HOST])
+            .args(["--postgres-db", common::POSTGRES_DB])
+            .assert()
+    };
+
+    let plan = |stdout: &[u8]| common::json_records(stdout).into_iter()
+        .find(|record| record["type"] == "plan")
+        .unwrap();
+
+    // A dry run plans without writing anything
+    let assert_backup = backup("true", "--save-plan");
+    dbg!(assert_backup.get_output());
+
+    let summary = plan(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    assert_eq!(summary["uploads"], 1);
+    assert_eq!(summary["upload_bytes"], 11);
+    assert_eq!(summary["deletes"], 0);
+    assert_eq!(summary["metadata_writes"], 2);
+    assert_eq!(summary["storage_delta_bytes"], 11);
+    assert_eq!(objects(), 0);
+    assert!(gda_backup::glacier_state_is_empty(&mut common::establish_connection()).unwrap());
+
+    // Applying the plan backs up what was planned
+    let assert_backup = backup("false", "--apply-plan");
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+    assert_eq!(objects(), 1);
+
+    let mut backup_all = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_backup = backup_all
+        .args(["--output", "json"])
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["succeeded"], 0);
+
+    // A plan which deletes the only object
+    common::create_file("test1.txt", "hello world!");
+    fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + "test2.txt").unwrap();
+
+    let assert_backup = backup("true", "--save-plan");
+    dbg!(assert_backup.get_output());
+
+    let summary = plan(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    assert_eq!(summary["uploads"], 1);
+    assert_eq!(summary["upload_bytes"], 12);
+    assert_eq!(summary["deletes"], 1);
+    assert_eq!(summary["delete_bytes"], 11);
+    assert_eq!(summary["storage_delta_bytes"], 1);
+
+    // A plan is not applied once its files changed
+    thread::sleep(Duration::from_millis(10));
+    common::create_file("test1.txt", "hello again");
+
+    let assert_backup = backup("false", "--apply-plan");
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_CONFIG_ERROR));
+
+    assert_eq!(objects(), 1);
+    assert_eq!(fs::read_dir(common::TEST_DIR.to_owned() + "local/deleted").unwrap().count(), 0);
+
+    // A dry run restore writes nothing
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "true")
+        .assert();
+
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+    assert!(!Path::new(common::TEST_DIR_RESTORE).exists());
+}
+
+#[test]
+#[serial]
+fn local_backend_retention_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello test2");
+    common::create_file("test3.txt", "hello test3");
+
+    let objects = || fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap().count();
+    let deleted = || fs::read_dir(common::TEST_DIR.to_owned() + "local/deleted").unwrap().count();
+
+    let backup = || {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .args(["--output", "json"])
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(["--keep-deleted", "30d"])
+            .args(["--keep-versions", "1"])
+            .env("DRY_RUN", "false")
+            .args(["--db-engine", common::DB_ENGINE])
+            .args(["--postgres-user", common::POSTGRES_USER])
+            .args(["--postgres-password", common::POSTGRES_PASSWORD])
+            .args(["--postgres-host", common::POSTGRES_HOST])
+            .args(["--postgres-db", common::POSTGRES_DB])
+            .assert()
+    };
+
+    let prune = |policy: &[&str]| {
+        let mut prune = cargo::cargo_bin_cmd!("gda_backup");
+
+        prune
+            .args(["--output", "json"])
+            .arg("prune")
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(policy)
+            .env("DRY_RUN", "false")
+            .assert()
+    };
+
+    let restore = || {
+        let _ = fs::remove_dir_all(common::TEST_DIR_RESTORE);
+        let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+        restore
+            .arg("restore")
+            .args(["--target-dir", common::TEST_DIR_RESTORE])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(["--include-deleted", "--include-versions"])
+            .env("DRY_RUN", "false")
+            .assert()
+    };
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+    assert_eq!(objects(), 3);
+
+    // Deleted files and previous versions are kept
+    thread::sleep(Duration::from_millis(10));
+    common::create_file("test1.txt", "hello again");
+    fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + "test2.txt").unwrap();
+
+    let assert_backup = backup();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    let test2 = records.iter().find(|record| record["path"].as_str().unwrap_or_default().ends_with("test2.txt")).unwrap();
+    assert_eq!(test2["action"], "delete");
+    assert_eq!(test2["reason"], "object retained");
+    assert_eq!(objects(), 4);
+    assert_eq!(deleted(), 0);
+
+    // Deleted files and previous versions can be restored
+    let assert_restore = restore();
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+
+    assert_eq!(common::read_file("test1.txt").unwrap(), "hello again");
+    assert_eq!(common::read_file("test2.txt").unwrap(), "hello test2");
+    assert_eq!(common::read_file("test3.txt").unwrap(), "hello test3");
+
+    let versions: Vec<String> = fs::read_dir(Path::new(&common::build_restore_path("test1.txt")).parent().unwrap()).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|file_name| file_name.starts_with("test1.txt."))
+        .collect();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(common::read_file(&versions[0]).unwrap(), "hello world");
+
+    // Nothing is pruned while the policy keeps it
+    let assert_prune = prune(&["--keep-deleted", "30d", "--keep-versions", "1"]);
+    dbg!(assert_prune.get_output());
+
+    let records = common::json_records(&assert_prune.get_output().stdout);
+    assert_prune.success();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["succeeded"], 0);
+    assert_eq!(objects(), 4);
+
+    // Files matching a keep forever pattern outlive the other policies
+    let assert_prune = prune(&["--keep-forever", "test2"]);
+    dbg!(assert_prune.get_output());
+
+    let records = common::json_records(&assert_prune.get_output().stdout);
+    assert_prune.success();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["action"], "prune");
+    assert_eq!(records[0]["reason"], "replaced");
+    assert!(records[0]["path"].as_str().unwrap().ends_with("test1.txt"));
+    assert_eq!(objects(), 3);
+    assert_eq!(deleted(), 1);
+
+    let assert_prune = prune(&[]);
+    dbg!(assert_prune.get_output());
+    assert_prune.success();
+    assert_eq!(objects(), 2);
+
+    // Pruned files are no longer restored
+    let assert_restore = restore();
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+
+    assert_eq!(common::read_file("test1.txt").unwrap(), "hello again");
+    assert!(common::read_file("test2.txt").is_err());
+}
+
+#[test]
+#[serial]
+fn local_backend_safety_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file(".sentinel", "");
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello test2");
+    common::create_file("test3.txt", "hello test3");
+    common::create_file("test4.txt", "hello test4");
+
+    let objects = || fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap().count();
+
+    let backup = |index_file: Option<&str>| {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(["--sentinel-file", ".sentinel"])
+            .args(["--max-delete-percent", "50"])
+            .args(["--max-suspicious-percent", "25"])
+            .env("DRY_RUN", "false");
+
+        match index_file {
+            Some(index_file) => backup.args(["--index-file", index_file]),
+            None => backup
+                .args(["--db-engine", common::DB_ENGINE])
+                .args(["--postgres-user", common::POSTGRES_USER])
+                .args(["--postgres-password", common::POSTGRES_PASSWORD])
+                .args(["--postgres-host", common::POSTGRES_HOST])
+                .args(["--postgres-db", common::POSTGRES_DB]),
+        };
+
+        backup.assert()
+    };
+
+    let assert_backup = backup(None);
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+    assert_eq!(objects(), 4);
+
+    // Deleting most files aborts the backup without deleting their objects
+    for file_name in ["test1.txt", "test2.txt", "test3.txt"] {
+        fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + file_name).unwrap();
+    }
+
+    let assert_backup = backup(None);
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_ABORTED));
+    assert_eq!(objects(), 4);
+
+    let index_file = common::TEST_DIR.to_owned() + "index.zst";
+    let assert_backup = backup(Some(&index_file));
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_ABORTED));
+    assert_eq!(objects(), 4);
+
+    // A missing sentinel file aborts the backup
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "hello test2");
+    common::create_file("test3.txt", "hello test3");
+    fs::remove_file(common::TEST_DIR_BACKUP.to_owned() + ".sentinel").unwrap();
+
+    let assert_backup = backup(None);
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_ABORTED));
+    assert_eq!(objects(), 4);
+
+    // Files which look encrypted, or were renamed to a new extension, abort the backup
+    common::create_file(".sentinel", "");
+    thread::sleep(Duration::from_millis(10));
+
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let random: Vec<u8> = (0..16 * 1024).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+    }).collect();
+
+    fs::write(common::TEST_DIR_BACKUP.to_owned() + "test1.txt", &random).unwrap();
+    fs::rename(common::TEST_DIR_BACKUP.to_owned() + "test2.txt", common::TEST_DIR_BACKUP.to_owned() + "test2.txt.locked").unwrap();
+
+    let assert_backup = backup(None);
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_ABORTED));
+    assert_eq!(objects(), 4);
+
+    // Ordinary changes are backed up
+    common::create_file("test1.txt", "hello again");
+    fs::rename(common::TEST_DIR_BACKUP.to_owned() + "test2.txt.locked", common::TEST_DIR_BACKUP.to_owned() + "test2.txt").unwrap();
+
+    let assert_backup = backup(None);
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+    assert_eq!(objects(), 4);
+}
+
+#[test]
+#[serial]
+fn local_backend_object_lock_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+
+    let backup = |lock: &[&str]| {
+        let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        backup
+            .arg("backup")
+            .args(["--target-dir", common::TEST_DIR_BACKUP])
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(lock)
+            .env("DRY_RUN", "false")
+            .args(["--db-engine", common::DB_ENGINE])
+            .args(["--postgres-user", common::POSTGRES_USER])
+            .args(["--postgres-password", common::POSTGRES_PASSWORD])
+            .args(["--postgres-host", common::POSTGRES_HOST])
+            .args(["--postgres-db", common::POSTGRES_DB])
+            .assert()
+    };
+
+    // Objects are locked for the minimum storage duration, which must be supplied
+    let assert_backup = backup(&["--object-lock-mode", "compliance"]);
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_CONFIG_ERROR));
+
+    // Files of a local backend cannot be locked
+    let assert_backup = backup(&["--object-lock-mode", "governance", "--min-storage-duration", "30"]);
+    dbg!(assert_backup.get_output());
+    assert_backup.code(i32::from(error::EXIT_CONFIG_ERROR));
+
+    let assert_backup = backup(&[]);
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+
+    let mut lock_status = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_lock_status = lock_status
+        .args(["--output", "json"])
+        .args(["lock", "status"])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .assert();
+    dbg!(assert_lock_status.get_output());
+
+    let records = common::json_records(&assert_lock_status.get_output().stdout);
+    assert_lock_status.success();
+
+    let test1 = records.iter().find(|record| record["path"].as_str().unwrap_or_default().ends_with("test1.txt")).unwrap();
+    assert_eq!(test1["action"], "lock_status");
+    assert_eq!(test1["reason"], "unlocked");
+}
+
+#[test]
+#[serial]
+fn local_backend_delete_backup_test() {
+    // using common code.
+    common::setup_local();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "goodbye world");
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let assert_backup = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--backend", common::LOCAL_BACKEND])
+        .env("DRY_RUN", "false")
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .assert();
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+
+    let objects = || {
+        let mut objects: Vec<String> = fs::read_dir(common::TEST_DIR.to_owned() + "local/objects").unwrap()
+            .map(|object| object.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        objects.sort();
+        objects
+    };
+    let trackers = || fs::read_dir(common::TEST_DIR.to_owned() + "local/trackers").unwrap().count();
+
+    let delete_backup = |args: &[&str], dry_run: &str, stdin: &str| {
+        let mut delete_backup = cargo::cargo_bin_cmd!("gda_backup");
+
+        delete_backup
+            .args(["--output", "json"])
+            .arg("delete-backup")
+            .args(["--backend", common::LOCAL_BACKEND])
+            .args(args)
+            .env("DRY_RUN", dry_run)
+            .write_stdin(stdin)
+            .assert()
+    };
+
+    let hashes = objects();
+    assert_eq!(hashes.len(), 2);
+    assert_eq!(trackers(), 2);
+
+    // A dry run lists every version without deleting anything
+    let assert_delete = delete_backup(&[], "true", "");
+    dbg!(assert_delete.get_output());
+
+    let records = common::json_records(&assert_delete.get_output().stdout);
+    assert_delete.success();
+
+    let planned = records.iter().filter(|record| record["action"] == "delete" && record["status"] == "planned").count();
+    assert_eq!(planned, 2);
+    assert_eq!(objects(), hashes);
+
+    // Typing anything but the name of the backup aborts
+    let assert_delete = delete_backup(&[], "false", "y");
+    dbg!(assert_delete.get_output());
+    assert_delete.success();
+    assert_eq!(objects(), hashes);
+
+    // A --confirm which does not name the backup is a configuration error
+    let assert_delete = delete_backup(&["--confirm", "local:./elsewhere/"], "false", "");
+    dbg!(assert_delete.get_output());
+    assert_delete.code(i32::from(error::EXIT_CONFIG_ERROR));
+    assert_eq!(objects(), hashes);
+
+    // A prefix only deletes the objects starting with it, and their trackers
+    let prefix = (1..=hashes[0].len())
+        .map(|length| &hashes[0][..length])
+        .find(|prefix| !hashes[1].starts_with(prefix))
+        .unwrap();
+
+    let assert_delete = delete_backup(&["--prefix", prefix], "false", common::LOCAL_BACKEND);
+    dbg!(assert_delete.get_output());
+    assert_delete.success();
+    assert_eq!(objects(), vec![hashes[1].clone()]);
+    assert_eq!(trackers(), 1);
+
+    // Versions newer than --older-than are kept
+    let assert_delete = delete_backup(&["--older-than", "1d", "--confirm", common::LOCAL_BACKEND], "false", "");
+    dbg!(assert_delete.get_output());
+    assert_delete.success();
+    assert_eq!(objects(), vec![hashes[1].clone()]);
+    assert_eq!(trackers(), 1);
+
+    let assert_delete = delete_backup(&["--confirm", common::LOCAL_BACKEND], "false", "");
+    dbg!(assert_delete.get_output());
+    assert_delete.success();
+    assert!(objects().is_empty());
+    assert_eq!(trackers(), 0);
+}
+
+#[test]
+fn transient_error_test() {
+    let service_error = |status: u16, code: &str| SdkError::service_error(
+        PutObjectError::generic(ErrorMetadata::builder().code(code).build()),
+        Response::new(StatusCode::try_from(status).unwrap(), SdkBody::empty()),
+    );
+
+    // Timeouts, connection failures, throttling and server errors may succeed if sent again
+    assert!(aws::is_transient::<PutObjectError>(&SdkError::timeout_error("timed out")));
+    assert!(aws::is_transient(&service_error(503, "SlowDown")));
+    assert!(aws::is_transient(&service_error(500, "InternalError")));
+    assert!(aws::is_transient(&service_error(400, "ThrottlingException")));
+    assert!(aws::is_transient(&service_error(400, "RequestTimeout")));
+
+    // Errors which would fail again are not retried
+    assert!(!aws::is_transient(&service_error(403, "AccessDenied")));
+    assert!(!aws::is_transient(&service_error(404, "NoSuchBucket")));
+    assert!(!aws::is_transient(&service_error(400, "InvalidRequest")));
+    assert!(!aws::is_transient::<PutObjectError>(&SdkError::construction_failure("invalid bucket name")));
+
+    // An upload is retried if its request failed transiently, or the object was corrupted in transit
+    assert!(S3PutError::S3PutObjectError(service_error(503, "SlowDown")).is_transient());
+    assert!(!S3PutError::S3PutObjectError(service_error(403, "AccessDenied")).is_transient());
+    assert!(S3PutError::ChecksumMismatch("checksum".to_string()).is_transient());
+    assert!(!S3PutError::S3PutMissingUploadId("missing".to_string()).is_transient());
+    assert!(!S3PutError::PutError(io::Error::from(io::ErrorKind::NotFound)).is_transient());
+}
+
+/// Makes two updates of the same hash from copies read at the same version,
+/// as two backups sharing a file would, and checks neither loses the other's
+/// file name.
+async fn conflicting_update(metadata: &dyn MetadataStore) {
+    let hash: String = rand::rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    let expiration = Utc::now() + chrono::Duration::days(1);
+
+    let mut hash_tracker = HashTracker::new(hash.clone(), expiration);
+    hash_tracker.add_file_name("original.txt".to_string());
+    metadata.update(&mut hash_tracker).await.unwrap();
+    let version = hash_tracker.version();
+
+    let mut first = metadata.get_many(HashSet::from([hash.clone()])).await.unwrap().remove(&hash).unwrap();
+    let mut second = metadata.get_many(HashSet::from([hash.clone()])).await.unwrap().remove(&hash).unwrap();
+    assert_eq!(first.version(), version);
+    assert_eq!(second.version(), version);
+
+    first.add_file_name("first.txt".to_string());
+    second.add_file_name("second.txt".to_string());
+    metadata.update(&mut first).await.unwrap();
+    metadata.update(&mut second).await.unwrap();
+
+    let latest = metadata.get_many(HashSet::from([hash.clone()])).await.unwrap().remove(&hash).unwrap();
+    let mut file_names: Vec<_> = latest.files().cloned().collect();
+    file_names.sort();
+
+    assert_eq!(file_names, ["first.txt", "original.txt", "second.txt"]);
+    assert_eq!(latest.version(), version + 2);
+
+    metadata.delete_many(HashSet::from([hash])).await.unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn local_backend_conflicting_update_test() {
+    let _ = fs::remove_dir_all(common::TEST_DIR);
+
+    let (_, metadata) = common::open_stores(&["--backend", common::LOCAL_BACKEND]).await;
+    conflicting_update(metadata.as_ref()).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn conflicting_update_test() {
+    let (_, metadata) = common::open_stores(&["--bucket-name", "disciple153-test", "--dynamo-table", "gda-backup-test"]).await;
+    conflicting_update(metadata.as_ref()).await;
+}
+
+/// Writes more hash trackers than fit in one batch read (100) or batch delete
+/// (25), and checks every one is read and deleted.
+async fn batch_get_and_delete(metadata: &dyn MetadataStore) {
+    let prefix: String = rand::rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+    let hashes: HashSet<String> = (0..130).map(|i| format!("{prefix}{i}")).collect();
+    let expiration = Utc::now() + chrono::Duration::days(1);
+
+    for hash in &hashes {
+        let mut hash_tracker = HashTracker::new(hash.clone(), expiration);
+        hash_tracker.add_file_name(format!("{hash}.txt"));
+        metadata.update(&mut hash_tracker).await.unwrap();
+    }
+
+    let hash_trackers = metadata.get_many(hashes.clone()).await.unwrap();
+    assert_eq!(hash_trackers.keys().cloned().collect::<HashSet<_>>(), hashes);
+    for (hash, hash_tracker) in &hash_trackers {
+        assert_eq!(hash_tracker.files().cloned().collect::<Vec<_>>(), [format!("{hash}.txt")]);
+    }
+
+    metadata.delete_many(hashes.clone()).await.unwrap();
+    assert!(metadata.get_many(hashes).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+#[serial]
+async fn local_backend_batch_test() {
+    let _ = fs::remove_dir_all(common::TEST_DIR);
+
+    let (_, metadata) = common::open_stores(&["--backend", common::LOCAL_BACKEND]).await;
+    batch_get_and_delete(metadata.as_ref()).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn batch_test() {
+    let (_, metadata) = common::open_stores(&["--bucket-name", "disciple153-test", "--dynamo-table", "gda-backup-test"]).await;
+    batch_get_and_delete(metadata.as_ref()).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn custom_table_schema_test() {
+    let table_schema = [
+        "--dynamo-table", "gda-backup-test-custom-keys",
+        "--dynamo-partition-key", "pk",
+        "--dynamo-sort-key", "sk",
+        "--dynamo-key-prefix", "gda-backup#",
+        "--dynamo-file-names-attribute", "files",
+        "--dynamo-expiration-attribute", "expires",
+        "--dynamo-version-attribute", "revision",
+    ];
+
+    common::setup_local();
+    common::create_custom_key_table("gda-backup-test-custom-keys").await;
+
+    let mut delete_backup = cargo::cargo_bin_cmd!("gda_backup");
+    delete_backup
+        .arg("delete-backup")
+        .args(["--bucket-name", "disciple153-test"])
+        .args(table_schema)
+        .env("DRY_RUN", "false")
+        .write_stdin("disciple153-test")
+        .assert()
+        .success();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    common::create_file("test1.txt", "hello world");
+    common::create_file("test2.txt", "goodbye world");
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(table_schema)
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .args(["--min-storage-duration", "1"])
+        .env("DRY_RUN", "false");
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+    assert_backup.success();
+
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(table_schema)
+        .env("DRY_RUN", "false");
+
+    let assert_restore = restore.assert();
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+
+    assert_eq!(common::read_file("test1.txt").unwrap(), "hello world");
+    assert_eq!(common::read_file("test2.txt").unwrap(), "goodbye world");
+}
+
+#[tokio::test]
+#[serial]
+async fn sharded_hash_tracker_test() {
+    let (_, metadata) = common::open_stores(&["--bucket-name", "disciple153-test", "--dynamo-table", "gda-backup-test"]).await;
+
+    let hash: String = rand::rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    let expiration = Utc::now() + chrono::Duration::days(1);
+
+    // 25,000 file names of 200 bytes fill 15 items of 350 KB, more than one 4 MB transaction could write.
+    let file_names: HashSet<String> = (0..25_000).map(|i| format!("{}/{i:0>8}.txt", "d".repeat(187))).collect();
+
+    let mut hash_tracker = HashTracker::new(hash.clone(), expiration);
+    for file_name in &file_names {
+        hash_tracker.add_file_name(file_name.clone());
+    }
+    metadata.update(&mut hash_tracker).await.unwrap();
+
+    let mut hash_tracker = metadata.get_many(HashSet::from([hash.clone()])).await.unwrap().remove(&hash).unwrap();
+    assert!(hash_tracker.item_ids().len() > 12);
+    assert_eq!(hash_tracker.files().cloned().collect::<HashSet<_>>(), file_names);
+
+    // Removing file names from every shard keeps the rest.
+    let kept: HashSet<String> = file_names.iter().filter(|file_name| !file_name.ends_with("0.txt")).cloned().collect();
+    for file_name in file_names.difference(&kept) {
+        hash_tracker.del_file_name(file_name.clone());
+    }
+    metadata.update(&mut hash_tracker).await.unwrap();
+
+    let hash_tracker = metadata.get_many(HashSet::from([hash.clone()])).await.unwrap().remove(&hash).unwrap();
+    assert_eq!(hash_tracker.files().cloned().collect::<HashSet<_>>(), kept);
+
+    metadata.delete_many(HashSet::from([hash.clone()])).await.unwrap();
+    assert!(metadata.get_many(HashSet::from([hash])).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+#[serial]
+async fn empty_file_test() {
+    // using common code.
+    common::setup();
+
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+    fs::create_dir_all(common::TEST_DIR_RESTORE).unwrap();
+
+    common::create_file("empty.txt", "");
+
+    let mut backup = cargo::cargo_bin_cmd!("gda_backup");
+
+    let backup = backup
+        .args(["--output", "json"])
+        .arg("backup")
+        .args(["--target-dir", common::TEST_DIR_BACKUP])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .args(["--db-engine", common::DB_ENGINE])
+        .args(["--postgres-user", common::POSTGRES_USER])
+        .args(["--postgres-password", common::POSTGRES_PASSWORD])
+        .args(["--postgres-host", common::POSTGRES_HOST])
+        .args(["--postgres-db", common::POSTGRES_DB])
+        .args(["--min-storage-duration", "1"])
+        .env("DRY_RUN", "false");
+
+    let assert_backup = backup.assert();
+    dbg!(assert_backup.get_output());
+
+    let records = common::json_records(&assert_backup.get_output().stdout);
+    assert_backup.success();
+
+    let file = records.iter().find(|record| record["type"] == "file").unwrap();
+    assert_eq!(file["reason"], "empty file");
+
+    // Empty files are only tracked, never uploaded.
+    let (objects, _) = common::open_stores(&["--bucket-name", "disciple153-test", "--dynamo-table", "gda-backup-test"]).await;
+    assert!(objects.head(file["hash"].as_str().unwrap().to_string()).await.unwrap().is_none());
+
+    let mut restore = cargo::cargo_bin_cmd!("gda_backup");
+
+    let restore = restore
+        .arg("restore")
+        .args(["--target-dir", common::TEST_DIR_RESTORE])
+        .args(["--bucket-name", "disciple153-test"])
+        .args(["--dynamo-table", "gda-backup-test"])
+        .env("DRY_RUN", "false");
+
+    let assert_restore = restore.assert();
+    dbg!(assert_restore.get_output());
+    assert_restore.success();
+
+    assert_eq!(common::read_file("empty.txt").unwrap(), "");
+}
+
+#[test]
+fn storage_class_test() {
+    let cli = common::backup_cli(&[
+        "--backend", common::LOCAL_BACKEND,
+        "--storage-class", "DEEP_ARCHIVE",
+        "--small-file-storage-class", "STANDARD_IA",
+        "--small-file-threshold", "1000",
+        "--storage-class-override", r"\.db$=STANDARD;^/photos/=GLACIER_IR",
+    ]);
+
+    let Commands::Backup(args) = cli.command else { unreachable!() };
+    let storage_classes = AwsArgs::from(*args).storage_classes;
+
+    // Files below the threshold take the small file storage class, others the default.
+    assert_eq!(storage_classes.select("/docs/a.txt", 999), Some(StorageClass::StandardIa));
+    assert_eq!(storage_classes.select("/docs/a.txt", 1000), Some(StorageClass::DeepArchive));
+
+    // Overrides take precedence over the threshold, in the order given.
+    assert_eq!(storage_classes.select("/docs/a.db", 999), Some(StorageClass::Standard));
+    assert_eq!(storage_classes.select("/docs/a.db", 1000), Some(StorageClass::Standard));
+    assert_eq!(storage_classes.select("/photos/a.db", 1000), Some(StorageClass::Standard));
+    assert_eq!(storage_classes.select("/photos/a.jpg", 1000), Some(StorageClass::GlacierIr));
+    assert_eq!(storage_classes.select("/docs/photos/a.jpg", 1000), Some(StorageClass::DeepArchive));
+
+    // Without any option, objects take the bucket's default storage class.
+    let cli = common::backup_cli(&["--backend", common::LOCAL_BACKEND]);
+    let Commands::Backup(args) = cli.command else { unreachable!() };
+    let storage_classes = AwsArgs::from(*args).storage_classes;
+
+    assert_eq!(storage_classes.select("/docs/a.txt", 0), None);
+    assert_eq!(storage_classes.select("/docs/a.txt", u64::MAX), None);
+}
+
+#[test]
+#[serial]
+fn multipart_checksum_test() {
+    fs::create_dir_all(common::TEST_DIR_BACKUP).unwrap();
+
+    let part_size = 1024;
+    let contents: Vec<u8> = rand::rng().sample_iter(&Alphanumeric).take(2 * part_size + 100).collect();
+    let file_path = common::TEST_DIR_BACKUP.to_owned() + "parts.bin";
+    fs::write(&file_path, &contents).unwrap();
+
+    let mut expected = MultipartChecksum::default();
+    for part in contents.chunks(part_size) {
+        expected.add_part(part);
+    }
+
+    let composite = checksum::sha256_file_parts(&file_path, part_size as u64).unwrap();
+    assert_eq!(composite, expected.composite());
+    assert!(composite.ends_with("-3"));
+    assert_ne!(composite.split_once('-').unwrap().0, checksum::sha256_file(&file_path).unwrap());
+
+    // The part size of a multipart upload is found from the size of the file and its number of parts
+    let mib = 1024 * 1024;
+    let contents: Vec<u8> = (0..12 * mib + 100).map(|i| (i % 251) as u8).collect();
+    fs::write(&file_path, &contents).unwrap();
+
+    for part_size in [5 * mib, 6 * mib] {
+        let mut expected = MultipartChecksum::default();
+        for part in contents.chunks(part_size) {
+            expected.add_part(part);
+        }
+
+        let expected = expected.composite();
+        assert!(expected.ends_with("-3"));
+        assert_eq!(checksum::sha256_file_multipart(&file_path, &expected).unwrap(), expected);
+    }
+
+    let corrupted = format!("{}-3", checksum::sha256_file(&file_path).unwrap());
+    assert_ne!(checksum::sha256_file_multipart(&file_path, &corrupted).unwrap(), corrupted);
+}
+
+/// Leaves one hash tracker without files and another with a file once their
+/// minimum storage duration passed, and checks `clean` only deletes the first.
+async fn clean_dangling(metadata: &dyn MetadataStore) {
+    let random_hash = || rand::rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect::<String>();
+    let (dangling, kept) = (random_hash(), random_hash());
+    let expiration = Utc::now() + chrono::Duration::seconds(2);
+
+    let mut hash_tracker = HashTracker::new(dangling.clone(), expiration);
+    hash_tracker.add_file_name("dangling.txt".to_string());
+    metadata.update(&mut hash_tracker).await.unwrap();
+    hash_tracker.del_file_name("dangling.txt".to_string());
+    metadata.update(&mut hash_tracker).await.unwrap();
+
+    let mut hash_tracker = HashTracker::new(kept.clone(), expiration);
+    hash_tracker.add_file_name("kept.txt".to_string());
+    metadata.update(&mut hash_tracker).await.unwrap();
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    metadata.clean().await.unwrap();
+
+    let hash_trackers = metadata.get_many(HashSet::from([dangling, kept.clone()])).await.unwrap();
+    assert_eq!(hash_trackers.keys().collect::<Vec<_>>(), [&kept]);
+
+    metadata.delete_many(HashSet::from([kept])).await.unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn local_backend_clean_test() {
+    let _ = fs::remove_dir_all(common::TEST_DIR);
+
+    let (_, metadata) = common::open_stores(&["--backend", common::LOCAL_BACKEND]).await;
+    clean_dangling(metadata.as_ref()).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn clean_test() {
+    let (_, metadata) = common::open_stores(&["--bucket-name", "disciple153-test", "--dynamo-table", "gda-backup-test"]).await;
+    clean_dangling(metadata.as_ref()).await;
+}